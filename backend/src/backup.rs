@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::{Connection, OpenFlags};
+use tokio::time::{sleep, Duration};
+
+use crate::config::BackupConfig;
+use crate::storage::{Storage, StorageResult};
+
+const BACKUP_PREFIX: &str = "solarmeter-";
+const BACKUP_SUFFIX: &str = ".db";
+
+/// Makes a backup now and then every `interval_hours`, counted from the
+/// newest backup so restarts don't add extra ones.
+pub struct BackupService {
+    db: Arc<dyn Storage>,
+    config: BackupConfig,
+}
+
+impl BackupService {
+    pub fn new(db: Arc<dyn Storage>, config: BackupConfig) -> Self {
+        Self { db, config }
+    }
+
+    pub async fn run(&self) {
+        let interval = Duration::from_secs(self.config.interval_hours * 3600);
+        info!("Starting backup service, every {}h into {}", self.config.interval_hours, self.config.directory);
+
+        loop {
+            let newest = newest_backup(Path::new(&self.config.directory))
+                .and_then(|path| fs::metadata(path).ok()?.modified().ok());
+            let age = newest.and_then(|time| SystemTime::now().duration_since(time).ok());
+            if let Some(age) = age.filter(|&age| age < interval) {
+                sleep(interval - age).await;
+                continue;
+            }
+
+            let (directory, keep) = (PathBuf::from(&self.config.directory), self.config.keep);
+            match self.db.run_blocking(move |db| backup_now(db, &directory, keep)).await {
+                Ok(path) => info!("Database backed up to {}", path.display()),
+                Err(e) => {
+                    error!("Database backup failed: {}", e);
+                    // Try again in an hour rather than a whole interval
+                    sleep(Duration::from_secs(3600).min(interval)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Writes a backup into `directory` and deletes all but the newest `keep`.
+pub fn backup_now(db: &dyn Storage, directory: &Path, keep: usize) -> StorageResult<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let name = format!("{}{}{}", BACKUP_PREFIX, Utc::now().format("%Y%m%d-%H%M%S"), BACKUP_SUFFIX);
+    let path = directory.join(name);
+    write_checked(db, &path)?;
+
+    for old in backups(directory).iter().rev().skip(keep) {
+        match fs::remove_file(old) {
+            Ok(()) => info!("Deleted old backup {}", old.display()),
+            Err(e) => warn!("Could not delete old backup {}: {}", old.display(), e),
+        }
+    }
+    Ok(path)
+}
+
+/// Writes a backup into `directory` for `GET /backup/snapshot`, named so
+/// rotation never picks it up.
+pub fn snapshot(db: &dyn Storage, directory: &Path) -> StorageResult<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let path = directory.join(format!(".snapshot-{}-{}.db", std::process::id(), Utc::now().timestamp_nanos_opt().unwrap_or_default()));
+    write_checked(db, &path)?;
+    Ok(path)
+}
+
+/// Backs up to a temporary file and moves it to `path` once it passed the
+/// integrity check, so `path` is never a partial copy.
+fn write_checked(db: &dyn Storage, path: &Path) -> StorageResult<()> {
+    let partial = path.with_extension("partial");
+    let result = db.backup(&partial).and_then(|_| check_integrity(&partial));
+    match result {
+        Ok(()) => Ok(fs::rename(&partial, path)?),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Scheduled backups in `directory`, oldest first.
+fn backups(directory: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut backups: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX))
+        })
+        .collect();
+    // The timestamp in the name sorts chronologically
+    backups.sort();
+    backups
+}
+
+fn newest_backup(directory: &Path) -> Option<PathBuf> {
+    backups(directory).pop()
+}
+
+/// Runs `PRAGMA integrity_check` and makes sure the file is a solarmeter database.
+pub fn check_integrity(path: &Path) -> StorageResult<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = conn.prepare("PRAGMA integrity_check")?;
+    let problems: Vec<String> = statement.query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    if problems != ["ok"] {
+        return Err(format!("{} failed the integrity check: {}", path.display(), problems.join("; ")).into());
+    }
+
+    let tables: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('schema_version', 'meter_names', 'meter_readings')",
+        [],
+        |row| row.get(0),
+    )?;
+    if tables != 3 {
+        return Err(format!("{} is not a solarmeter database", path.display()).into());
+    }
+    Ok(())
+}
+
+/// Replaces the database at `database_url` with `backup` after checking it.
+/// The replaced database is kept next to it, its path is returned. The
+/// service must be stopped, it would keep writing to the replaced file.
+pub fn restore(backup: &Path, database_url: &str) -> StorageResult<Option<PathBuf>> {
+    check_integrity(backup)?;
+
+    let live = Path::new(database_url);
+    let incoming = live.with_extension("restore");
+    fs::copy(backup, &incoming)?;
+    if let Err(e) = check_integrity(&incoming) {
+        let _ = fs::remove_file(&incoming);
+        return Err(e);
+    }
+
+    let replaced = live.exists().then(|| {
+        PathBuf::from(format!("{}.replaced-{}", database_url, Utc::now().format("%Y%m%d-%H%M%S")))
+    });
+    if let Some(replaced) = &replaced {
+        fs::rename(live, replaced)?;
+        // The WAL belongs to the replaced database and must not be applied to the restored one
+        for suffix in ["-wal", "-shm"] {
+            let file = format!("{}{}", database_url, suffix);
+            if Path::new(&file).exists() {
+                fs::rename(&file, format!("{}{}", replaced.display(), suffix))?;
+            }
+        }
+    }
+    fs::rename(&incoming, live)?;
+    Ok(replaced)
+}
+
+/// Compares a presented token without revealing through timing how much of it matched.
+pub fn token_matches(expected: &str, presented: &str) -> bool {
+    let (expected, presented) = (expected.as_bytes(), presented.as_bytes());
+    expected.len() == presented.len()
+        && expected.iter().zip(presented).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
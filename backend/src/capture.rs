@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
+
+use crate::storage::Storage;
+use crate::meters::{Channel, MeterReader};
+
+/// Longest capture an API call may request.
+pub const MAX_CAPTURE_DURATION_SECS: u64 = 15 * 60;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureParams {
+    /// Meter key, see `MeterConfig::key`
+    pub meter: String,
+    pub channels: Vec<Channel>,
+    /// Minimum gap between samples; omitted or 0 polls as fast as the bus allows.
+    #[serde(default)]
+    pub interval_ms: u64,
+    pub duration_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl CaptureStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaptureStatus::Pending => "pending",
+            CaptureStatus::Running => "running",
+            CaptureStatus::Done => "done",
+            CaptureStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(CaptureStatus::Pending),
+            "running" => Some(CaptureStatus::Running),
+            "done" => Some(CaptureStatus::Done),
+            "failed" => Some(CaptureStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureSession {
+    pub session_id: i64,
+    pub meter_name: String,
+    pub channels: Vec<Channel>,
+    pub interval_ms: u64,
+    pub duration_secs: u64,
+    pub status: CaptureStatus,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub sample_count: i64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureSample {
+    pub timestamp_ms: i64,
+    pub values: HashMap<Channel, f32>,
+}
+
+/// Handed to a meter task to run one capture session.
+#[derive(Debug)]
+pub struct CaptureRequest {
+    pub session_id: i64,
+    pub channels: Vec<Channel>,
+    pub interval: Duration,
+    pub duration: Duration,
+}
+
+/// Routes capture requests from the web server to the owning meter task.
+#[derive(Clone, Default)]
+pub struct CaptureManager {
+    meters: Arc<Mutex<HashMap<String, mpsc::Sender<CaptureRequest>>>>,
+    channels: Arc<Mutex<HashMap<String, Vec<Channel>>>>,
+}
+
+impl CaptureManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a meter task under its key and returns the receiver it should poll for captures.
+    pub fn register(&self, meter_key: &str, meter: &dyn MeterReader) -> mpsc::Receiver<CaptureRequest> {
+        let (tx, rx) = mpsc::channel(1);
+        self.meters.lock().unwrap().insert(meter_key.to_string(), tx);
+        self.channels.lock().unwrap().insert(meter_key.to_string(), meter.channels().to_vec());
+        rx
+    }
+
+    /// Records a new session and queues it on the meter's task.
+    pub fn start(&self, db: &dyn Storage, params: CaptureParams) -> Result<i64, Box<dyn std::error::Error>> {
+        if params.channels.is_empty() {
+            return Err("At least one channel is required".into());
+        }
+        if let Some(declared) = self.channels.lock().unwrap().get(&params.meter) {
+            if let Some(channel) = params.channels.iter().find(|c| !declared.contains(c)) {
+                return Err(format!("Meter {} has no channel {}", params.meter, channel).into());
+            }
+        }
+        if params.duration_secs == 0 || params.duration_secs > MAX_CAPTURE_DURATION_SECS {
+            return Err(format!("duration_secs must be between 1 and {}", MAX_CAPTURE_DURATION_SECS).into());
+        }
+
+        let sender = self.meters.lock().unwrap().get(&params.meter).cloned()
+            .ok_or_else(|| format!("Unknown meter: {}", params.meter))?;
+
+        if sender.capacity() == 0 {
+            return Err(format!("A capture is already queued for {}", params.meter).into());
+        }
+
+        let session_id = db.create_capture_session(&params)?;
+        let request = CaptureRequest {
+            session_id,
+            channels: params.channels,
+            interval: Duration::from_millis(params.interval_ms),
+            duration: Duration::from_secs(params.duration_secs),
+        };
+
+        if let Err(e) = sender.try_send(request) {
+            db.finish_capture_session(session_id, CaptureStatus::Failed, Some("Meter task unavailable"))?;
+            return Err(format!("Failed to queue capture for {}: {}", params.meter, e).into());
+        }
+
+        info!("Queued capture session {} for {}", session_id, params.meter);
+        Ok(session_id)
+    }
+}
+
+/// Polls `meter` back to back for the duration of the request and stores every
+/// sample in `capture_samples`. Regular polling of this meter pauses meanwhile;
+/// other meters on the same bus may miss cycles while the capture holds it.
+pub async fn run_capture(meter: &mut dyn MeterReader, db: &Arc<dyn Storage>, request: CaptureRequest) {
+    let session_id = request.session_id;
+    if let Err(e) = db.run_blocking(move |db| db.set_capture_running(session_id)).await {
+        error!("Failed to start capture session {}: {}", session_id, e);
+        return;
+    }
+
+    let deadline = Instant::now() + request.duration;
+    let mut samples = 0u64;
+    let mut failure = None;
+
+    while Instant::now() < deadline {
+        let cycle_start = Instant::now();
+
+        match meter.read_channels(&request.channels).await {
+            Ok(measurements) => {
+                if let Err(e) = db.run_blocking(move |db| db.insert_capture_samples(session_id, &measurements)).await {
+                    failure = Some(format!("Failed to store sample: {}", e));
+                    break;
+                }
+                samples += 1;
+            }
+            Err(e) => {
+                failure = Some(format!("Failed to read meter: {}", e));
+                break;
+            }
+        }
+
+        let elapsed = cycle_start.elapsed();
+        if elapsed < request.interval {
+            sleep(request.interval - elapsed).await;
+        } else {
+            // Give other tasks sharing the bus a chance to run
+            tokio::task::yield_now().await;
+        }
+    }
+
+    let status = if failure.is_some() { CaptureStatus::Failed } else { CaptureStatus::Done };
+    match &failure {
+        Some(e) => error!("Capture session {} failed after {} samples: {}", session_id, samples, e),
+        None => info!("Capture session {} finished with {} samples", session_id, samples),
+    }
+
+    if let Err(e) = db.run_blocking(move |db| db.finish_capture_session(session_id, status, failure.as_deref())).await {
+        error!("Failed to finish capture session {}: {}", session_id, e);
+    }
+}
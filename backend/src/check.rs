@@ -0,0 +1,478 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::database_sync::DatabaseSync;
+use crate::encoding::PowerEncoding;
+use crate::energy::MAX_METER_POWER_KW;
+use crate::storage::StorageResult;
+
+/// Readings before this can't come from this logger, 2000-01-01.
+const MIN_TIMESTAMP: i64 = 946_684_800;
+/// How far ahead of the clock a reading may be.
+const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::days(1);
+/// Problems listed per meter in the report, the rest are only counted.
+const LISTED_PROBLEMS: usize = 20;
+const POWER_COLUMNS: [&str; 3] = ["total_power", "import_power", "export_power"];
+
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// Only check this meter key
+    pub meter: Option<String>,
+    /// Gaps longer than this many polling intervals are reported
+    pub gap_factor: f64,
+    /// Expected seconds between readings per meter key
+    pub intervals: HashMap<String, u32>,
+    /// Interval of meters not in `intervals`, e.g. retired ones
+    pub default_interval: u32,
+    /// Larger power values are out of range
+    pub max_power_w: f32,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        Self {
+            meter: None,
+            gap_factor: 3.0,
+            intervals: HashMap::new(),
+            default_interval: 60,
+            max_power_w: MAX_METER_POWER_KW as f32 * 1000.0,
+        }
+    }
+}
+
+/// What `repair` does about a problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// Nothing can be fixed, e.g. readings missing in a gap
+    None,
+    /// Quarantine the row and set this column to NULL
+    ClearColumn(&'static str),
+    /// Move the whole row to the quarantine
+    QuarantineRow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProblemKind {
+    /// No readings for much longer than the polling interval
+    Gap,
+    /// `total_kwh` went backwards or jumped and stayed there: a reset or a new meter
+    CounterReset,
+    /// A single `total_kwh` value out of line with its neighbours
+    CounterGlitch,
+    /// Infinite or NaN value, e.g. power above the f16 range
+    NonFinite,
+    OutOfRange,
+    /// Timestamp before 2000 or in the future
+    BadTimestamp,
+}
+
+impl ProblemKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProblemKind::Gap => "gap",
+            ProblemKind::CounterReset => "counter reset",
+            ProblemKind::CounterGlitch => "counter glitch",
+            ProblemKind::NonFinite => "non-finite",
+            ProblemKind::OutOfRange => "out of range",
+            ProblemKind::BadTimestamp => "bad timestamp",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// Row the problem is in; for gaps the last row before it
+    pub timestamp: i64,
+    pub detail: String,
+    pub repair: Repair,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeterCheck {
+    pub meter_id: i64,
+    pub meter_key: String,
+    pub rows: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl MeterCheck {
+    fn count(&self, kind: ProblemKind) -> usize {
+        self.problems.iter().filter(|p| p.kind == kind).count()
+    }
+}
+
+/// Rows of a `meter_id` that has no entry in `meter_names`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphans {
+    pub meter_id: i64,
+    pub readings: u64,
+    pub channel_readings: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub meters: Vec<MeterCheck>,
+    pub orphans: Vec<Orphans>,
+}
+
+impl CheckReport {
+    pub fn count(&self, kind: ProblemKind) -> usize {
+        self.meters.iter().map(|m| m.count(kind)).sum()
+    }
+
+    /// Whether anything was found, gaps included.
+    pub fn is_clean(&self) -> bool {
+        self.orphans.is_empty() && self.meters.iter().all(|m| m.problems.is_empty())
+    }
+
+    /// Problems `repair` would change something about.
+    pub fn repairable(&self) -> usize {
+        self.meters.iter().flat_map(|m| &m.problems).filter(|p| p.repair != Repair::None).count()
+            + self.orphans.len()
+    }
+}
+
+/// Scans `meter_readings` meter by meter. Reads only, see `repair`.
+pub fn check(db: &DatabaseSync, options: &CheckOptions) -> StorageResult<CheckReport> {
+    let conn = db.read_connection()?;
+    let mut meters: Vec<(i64, String)> = conn
+        .prepare("SELECT meter_id, meter_key FROM meter_names ORDER BY meter_key")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    if let Some(meter) = &options.meter {
+        meters.retain(|(_, key)| key == meter);
+        if meters.is_empty() {
+            return Err(format!("Unknown meter {}", meter).into());
+        }
+    }
+
+    let legacy_until: Option<i64> = conn
+        .query_row("SELECT value FROM db_settings WHERE key = 'legacy_downsampled_until'", [], |row| row.get::<_, String>(0))
+        .optional()?
+        .and_then(|value| value.parse().ok());
+
+    let mut report = CheckReport::default();
+    for (meter_id, meter_key) in meters {
+        let interval = options.intervals.get(&meter_key).copied().unwrap_or(options.default_interval);
+        report.meters.push(check_meter(&conn, db.power_encoding(), meter_id, meter_key, interval, legacy_until, options)?);
+    }
+    if options.meter.is_none() {
+        report.orphans = find_orphans(&conn)?;
+    }
+    Ok(report)
+}
+
+struct Row {
+    timestamp: i64,
+    power: [Option<f32>; 3],
+    total_kwh: Option<f64>,
+}
+
+fn check_meter(
+    conn: &Connection,
+    encoding: PowerEncoding,
+    meter_id: i64,
+    meter_key: String,
+    interval: u32,
+    legacy_until: Option<i64>,
+    options: &CheckOptions,
+) -> StorageResult<MeterCheck> {
+    let mut statement = conn.prepare(
+        "SELECT timestamp, total_power, import_power, export_power, total_kwh
+         FROM meter_readings WHERE meter_id = ? ORDER BY timestamp",
+    )?;
+    // Power is decoded here rather than with decode_power(), SQLite would turn NaN into NULL
+    let rows: Vec<Row> = statement
+        .query_map([meter_id], |row| {
+            Ok(Row {
+                timestamp: row.get(0)?,
+                power: [
+                    encoding.decode(row.get_ref(1)?),
+                    encoding.decode(row.get_ref(2)?),
+                    encoding.decode(row.get_ref(3)?),
+                ],
+                total_kwh: row.get(4)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let now = Utc::now();
+    let mut problems = Vec::new();
+    let mut valid = Vec::with_capacity(rows.len());
+    for row in &rows {
+        if row.timestamp < MIN_TIMESTAMP || row.timestamp > (now + MAX_CLOCK_SKEW).timestamp() {
+            problems.push(Problem {
+                kind: ProblemKind::BadTimestamp,
+                timestamp: row.timestamp,
+                detail: format!("timestamp {}", row.timestamp),
+                repair: Repair::QuarantineRow,
+            });
+            continue;
+        }
+        check_values(row, options.max_power_w, &mut problems);
+        valid.push(row);
+    }
+
+    for pair in valid.windows(2) {
+        let (before, after) = (pair[0].timestamp, pair[1].timestamp);
+        let legacy = legacy_until.filter(|&until| before < until).map_or(0, |until| legacy_interval(until - before));
+        let expected = (interval as i64).max(legacy);
+        if (after - before) as f64 > options.gap_factor * expected as f64 {
+            problems.push(Problem {
+                kind: ProblemKind::Gap,
+                timestamp: before,
+                detail: format!("no readings for {} until {}", format_duration(after - before), format_time(after)),
+                repair: Repair::None,
+            });
+        }
+    }
+
+    let counter: Vec<(i64, f64)> = valid.iter()
+        .filter_map(|row| Some((row.timestamp, row.total_kwh.filter(|v| v.is_finite() && *v >= 0.0)?)))
+        .collect();
+    check_counter(&counter, &mut problems);
+
+    problems.sort_by_key(|p| (p.timestamp, p.kind));
+    Ok(MeterCheck { meter_id, meter_key, rows: rows.len() as u64, problems })
+}
+
+/// Seconds between the rows retention left before it kept raw readings, by
+/// their age when the database was upgraded.
+fn legacy_interval(age_secs: i64) -> i64 {
+    match age_secs {
+        age if age >= 7 * 86400 => 3600,
+        age if age >= 86400 => 1200,
+        age if age >= 3600 => 600,
+        _ => 0,
+    }
+}
+
+fn check_values(row: &Row, max_power_w: f32, problems: &mut Vec<Problem>) {
+    for (column, value) in POWER_COLUMNS.into_iter().zip(row.power) {
+        let Some(value) = value else { continue };
+        // Import and export power are magnitudes, only the net total_power has a sign
+        let min = if column == "total_power" { -max_power_w } else { 0.0 };
+        let kind = if !value.is_finite() {
+            ProblemKind::NonFinite
+        } else if value < min || value > max_power_w {
+            ProblemKind::OutOfRange
+        } else {
+            continue;
+        };
+        problems.push(Problem {
+            kind,
+            timestamp: row.timestamp,
+            detail: format!("{} = {} W", column, value),
+            repair: Repair::ClearColumn(column),
+        });
+    }
+
+    if let Some(kwh) = row.total_kwh {
+        let kind = if !kwh.is_finite() {
+            ProblemKind::NonFinite
+        } else if kwh < 0.0 {
+            ProblemKind::OutOfRange
+        } else {
+            return;
+        };
+        problems.push(Problem {
+            kind,
+            timestamp: row.timestamp,
+            detail: format!("total_kwh = {}", kwh),
+            repair: Repair::ClearColumn("total_kwh"),
+        });
+    }
+}
+
+/// A counter value that is implausible after the previous one is a glitch if
+/// the value after it continues from the previous one, else the counter was
+/// reset or replaced and continues from the new value.
+fn check_counter(counter: &[(i64, f64)], problems: &mut Vec<Problem>) {
+    let plausible = |(t0, v0): (i64, f64), (t1, v1): (i64, f64)| {
+        v1 >= v0 && v1 - v0 <= MAX_METER_POWER_KW * (t1 - t0) as f64 / 3600.0
+    };
+
+    let Some(&first) = counter.first() else { return };
+    let mut previous = first;
+    for (i, &current) in counter.iter().enumerate().skip(1) {
+        if plausible(previous, current) {
+            previous = current;
+            continue;
+        }
+        let detail = format!("total_kwh {} after {} at {}", current.1 as f32, previous.1 as f32, format_time(previous.0));
+        if counter.get(i + 1).is_some_and(|&next| plausible(previous, next)) {
+            problems.push(Problem {
+                kind: ProblemKind::CounterGlitch,
+                timestamp: current.0,
+                detail,
+                repair: Repair::ClearColumn("total_kwh"),
+            });
+        } else {
+            problems.push(Problem { kind: ProblemKind::CounterReset, timestamp: current.0, detail, repair: Repair::None });
+            previous = current;
+        }
+    }
+}
+
+fn find_orphans(conn: &Connection) -> rusqlite::Result<Vec<Orphans>> {
+    let mut orphans: Vec<Orphans> = Vec::new();
+    let mut statement = conn.prepare(
+        "SELECT meter_id, SUM(source = 'readings'), SUM(source = 'channels') FROM (
+             SELECT meter_id, 'readings' AS source FROM meter_readings
+             UNION ALL SELECT meter_id, 'channels' FROM channel_readings
+         )
+         WHERE meter_id NOT IN (SELECT meter_id FROM meter_names)
+         GROUP BY meter_id ORDER BY meter_id",
+    )?;
+    for row in statement.query_map([], |row| {
+        Ok(Orphans { meter_id: row.get(0)?, readings: row.get(1)?, channel_readings: row.get(2)? })
+    })? {
+        orphans.push(row?);
+    }
+    Ok(orphans)
+}
+
+/// Applies the repairs of `report` in one transaction. Every changed or
+/// removed row is copied to `quarantined_readings` or
+/// `quarantined_channel_readings` first. Returns the number of problems fixed.
+pub fn repair(db: &DatabaseSync, report: &CheckReport) -> StorageResult<usize> {
+    let now = Utc::now().timestamp();
+    let mut conn = db.get_connection()?;
+    let tx = conn.transaction()?;
+    let mut fixed = 0;
+    // Rows already copied, a row with several problems is kept as it was before the first repair
+    let mut quarantined = HashSet::new();
+
+    for meter in &report.meters {
+        for problem in &meter.problems {
+            let (meter_id, timestamp) = (meter.meter_id, problem.timestamp);
+            let reason = format!("{}: {}", problem.kind.as_str(), problem.detail);
+            if problem.repair != Repair::None && quarantined.insert((meter_id, timestamp)) {
+                quarantine_row(&tx, meter_id, timestamp, &reason, now)?;
+            }
+            match problem.repair {
+                Repair::None => continue,
+                Repair::ClearColumn(column) => {
+                    tx.execute(
+                        &format!("UPDATE meter_readings SET {} = NULL WHERE meter_id = ? AND timestamp = ?", column),
+                        params![meter_id, timestamp],
+                    )?;
+                }
+                Repair::QuarantineRow => {
+                    tx.execute("DELETE FROM meter_readings WHERE meter_id = ? AND timestamp = ?", params![meter_id, timestamp])?;
+                    tx.execute(
+                        "INSERT INTO quarantined_channel_readings
+                         SELECT meter_id, channel, timestamp, value, ?3, ?4 FROM channel_readings
+                         WHERE meter_id = ?1 AND timestamp = ?2",
+                        params![meter_id, timestamp, reason, now],
+                    )?;
+                    tx.execute("DELETE FROM channel_readings WHERE meter_id = ? AND timestamp = ?", params![meter_id, timestamp])?;
+                }
+            }
+            fixed += 1;
+        }
+        // Rows whose only values were cleared are left empty
+        tx.execute(
+            "DELETE FROM meter_readings WHERE meter_id = ? AND total_power IS NULL AND import_power IS NULL
+             AND export_power IS NULL AND total_kwh IS NULL",
+            [meter.meter_id],
+        )?;
+    }
+
+    for orphans in &report.orphans {
+        let reason = format!("orphaned meter_id {}", orphans.meter_id);
+        tx.execute(
+            "INSERT INTO quarantined_readings
+             SELECT meter_id, timestamp, total_power, import_power, export_power, total_kwh, ?2, ?3
+             FROM meter_readings WHERE meter_id = ?1",
+            params![orphans.meter_id, reason, now],
+        )?;
+        tx.execute("DELETE FROM meter_readings WHERE meter_id = ?", [orphans.meter_id])?;
+        tx.execute(
+            "INSERT INTO quarantined_channel_readings
+             SELECT meter_id, channel, timestamp, value, ?2, ?3 FROM channel_readings WHERE meter_id = ?1",
+            params![orphans.meter_id, reason, now],
+        )?;
+        tx.execute("DELETE FROM channel_readings WHERE meter_id = ?", [orphans.meter_id])?;
+        fixed += 1;
+    }
+
+    tx.commit()?;
+    Ok(fixed)
+}
+
+fn quarantine_row(conn: &Connection, meter_id: i64, timestamp: i64, reason: &str, now: i64) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT INTO quarantined_readings
+         SELECT meter_id, timestamp, total_power, import_power, export_power, total_kwh, ?3, ?4
+         FROM meter_readings WHERE meter_id = ?1 AND timestamp = ?2",
+        params![meter_id, timestamp, reason, now],
+    )
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn format_duration(seconds: i64) -> String {
+    match seconds {
+        s if s >= 86400 => format!("{:.1}d", s as f64 / 86400.0),
+        s if s >= 3600 => format!("{:.1}h", s as f64 / 3600.0),
+        s => format!("{}min", s / 60),
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const KINDS: [ProblemKind; 6] = [
+            ProblemKind::Gap,
+            ProblemKind::CounterReset,
+            ProblemKind::CounterGlitch,
+            ProblemKind::NonFinite,
+            ProblemKind::OutOfRange,
+            ProblemKind::BadTimestamp,
+        ];
+
+        write!(f, "{:<20} {:>10}", "meter", "rows")?;
+        for kind in KINDS {
+            write!(f, " {:>14}", kind.as_str())?;
+        }
+        writeln!(f)?;
+        for meter in &self.meters {
+            write!(f, "{:<20} {:>10}", meter.meter_key, meter.rows)?;
+            for kind in KINDS {
+                write!(f, " {:>14}", meter.count(kind))?;
+            }
+            writeln!(f)?;
+        }
+
+        for meter in self.meters.iter().filter(|m| !m.problems.is_empty()) {
+            writeln!(f, "\n{}:", meter.meter_key)?;
+            for problem in meter.problems.iter().take(LISTED_PROBLEMS) {
+                let fix = if problem.repair == Repair::None { "" } else { " (repairable)" };
+                writeln!(f, "  {} {}: {}{}", format_time(problem.timestamp), problem.kind.as_str(), problem.detail, fix)?;
+            }
+            if meter.problems.len() > LISTED_PROBLEMS {
+                writeln!(f, "  ... and {} more", meter.problems.len() - LISTED_PROBLEMS)?;
+            }
+        }
+
+        for orphans in &self.orphans {
+            writeln!(
+                f,
+                "\nmeter_id {} is not in meter_names: {} readings, {} channel readings (repairable)",
+                orphans.meter_id, orphans.readings, orphans.channel_readings
+            )?;
+        }
+        if self.is_clean() {
+            writeln!(f, "\nNo problems found")?;
+        }
+        Ok(())
+    }
+}
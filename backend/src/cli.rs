@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use clap::{Args, Parser, Subcommand};
+
+use crate::backup;
+use crate::check::{self, CheckOptions};
+use crate::config::{AppConfig, StorageBackend};
+use crate::data_retention::RetentionService;
+use crate::database_sync::DatabaseSync;
+use crate::export::{self, CsvOptions, ExportFormat, ExportRequest};
+use crate::import::{self, ColumnMapping, MeterMap, TableLayout};
+use crate::query::{self, BucketWidth};
+use crate::storage;
+
+/// Logs solar and grid meters. Without a command it runs the logger.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Export readings in real units as CSV or Parquet
+    Export(ExportArgs),
+    /// Import historical readings. Readings the database already has are kept
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+    /// Look for gaps, counter resets, impossible values and orphaned rows
+    Check {
+        /// Only check this meter key
+        #[arg(long)]
+        meter: Option<String>,
+        /// Report gaps longer than this many polling intervals
+        #[arg(long, default_value_t = 3.0)]
+        gap_factor: f64,
+        /// Polling interval in seconds of meters that are not in the config
+        #[arg(long, default_value_t = 60)]
+        interval: u32,
+        /// Power above this many kW is out of range
+        #[arg(long, default_value_t = 100.0)]
+        max_power: f32,
+        /// Fix what can be fixed. Changed and removed rows are kept in the
+        /// quarantined_readings and quarantined_channel_readings tables
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Roll up and delete old data as the retention policy says, now
+    Retention {
+        /// Only report what a run would aggregate and delete
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Replace the database with a backup after checking its integrity. The
+    /// service must be stopped
+    Restore {
+        /// Backup file, e.g. from the backup directory or /backup/snapshot
+        file: PathBuf,
+        /// Restore even though the web server port answers
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Meter key to export, repeat for several. All meters if not given
+    #[arg(long = "meter")]
+    meters: Vec<String>,
+    /// Unix seconds or RFC 3339
+    #[arg(long, value_parser = parse_time)]
+    start: DateTime<Utc>,
+    /// Unix seconds or RFC 3339, now if not given
+    #[arg(long, value_parser = parse_time)]
+    end: Option<DateTime<Utc>>,
+    /// csv or parquet
+    #[arg(long, default_value = "csv", value_parser = parse_format)]
+    format: ExportFormat,
+    /// File to write, stdout if not given
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Comma separated channels, e.g. power,energy_import. All declared channels if not given
+    #[arg(long)]
+    channels: Option<String>,
+    /// Average into buckets of this width, e.g. 15m or 1h
+    #[arg(long, value_parser = parse_width)]
+    resample: Option<BucketWidth>,
+    /// CSV field delimiter, `tab` for tabs
+    #[arg(long, default_value = ",", value_parser = parse_char)]
+    delimiter: char,
+    /// CSV decimal separator
+    #[arg(long, default_value = ".", value_parser = parse_char)]
+    decimal: char,
+    /// Timezone of the CSV time column and resampling buckets, location.timezone if not given
+    #[arg(long, value_parser = parse_timezone)]
+    tz: Option<Tz>,
+}
+
+#[derive(Subcommand)]
+pub enum ImportSource {
+    /// CSV file with a header row
+    Csv {
+        file: PathBuf,
+        /// Field delimiter, `tab` for tabs
+        #[arg(long, default_value = ",", value_parser = parse_char)]
+        delimiter: char,
+        #[command(flatten)]
+        layout: LayoutArgs,
+    },
+    /// Another solarmeter database, or any SQLite database with --query
+    Sqlite {
+        file: PathBuf,
+        /// Query whose result is imported like a CSV file, e.g.
+        /// "SELECT ts AS time, watts FROM log"
+        #[arg(long)]
+        query: Option<String>,
+        #[command(flatten)]
+        layout: LayoutArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct LayoutArgs {
+    /// Meter key of every row, for sources without a meter column
+    #[arg(long)]
+    meter: Option<String>,
+    /// Column naming each row's meter [default: meter]
+    #[arg(long)]
+    meter_column: Option<String>,
+    /// Column with the time of each row [default: timestamp or time]
+    #[arg(long)]
+    time_column: Option<String>,
+    /// strftime format of the time column, e.g. "%d.%m.%Y %H:%M"
+    #[arg(long)]
+    time_format: Option<String>,
+    /// Timezone of times without an offset, location.timezone if not given
+    #[arg(long, value_parser = parse_timezone)]
+    tz: Option<Tz>,
+    /// Source column to import as column=channel[:unit], e.g.
+    /// "Active power=power:kW". Repeat for several. Columns named after
+    /// channels, as written by export, if not given
+    #[arg(long = "column", value_parser = ColumnMapping::parse)]
+    columns: Vec<ColumnMapping>,
+    /// Decimal separator of values
+    #[arg(long, default_value = ".", value_parser = parse_char)]
+    decimal: char,
+    /// Import a source meter under another key as source=key. Repeat for several
+    #[arg(long = "map-meter", value_parser = parse_meter_mapping)]
+    meter_map: Vec<(String, String)>,
+}
+
+impl LayoutArgs {
+    fn layout(self, config: &AppConfig) -> (TableLayout, MeterMap) {
+        let layout = TableLayout {
+            time_column: self.time_column,
+            time_format: self.time_format,
+            timezone: self.tz.unwrap_or_else(|| config.timezone()),
+            meter_column: self.meter_column,
+            meter: self.meter,
+            columns: self.columns,
+            decimal_separator: self.decimal,
+        };
+        (layout, self.meter_map.into_iter().collect())
+    }
+}
+
+fn parse_meter_mapping(value: &str) -> Result<(String, String), String> {
+    value.split_once('=')
+        .map(|(source, key)| (source.to_string(), key.to_string()))
+        .ok_or_else(|| "expected source=key".to_string())
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    query::parse_timestamp(value).ok_or_else(|| "expected unix seconds or RFC 3339".to_string())
+}
+
+fn parse_format(value: &str) -> Result<ExportFormat, String> {
+    ExportFormat::parse(value).ok_or_else(|| "expected csv or parquet".to_string())
+}
+
+fn parse_width(value: &str) -> Result<BucketWidth, String> {
+    BucketWidth::parse(value).ok_or_else(|| "expected a width such as 900, 15m, 1h or 1d".to_string())
+}
+
+fn parse_char(value: &str) -> Result<char, String> {
+    export::parse_delimiter(value).ok_or_else(|| "expected a single character".to_string())
+}
+
+fn parse_timezone(value: &str) -> Result<Tz, String> {
+    value.parse().map_err(|e| format!("{}", e))
+}
+
+/// Runs a command against the configured storage. Blocks, so async callers
+/// must move it off the runtime threads.
+pub fn run(config: &AppConfig, command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Export(args) => run_export(config, args),
+        Command::Import { source } => run_import(config, source),
+        Command::Check { meter, gap_factor, interval, max_power, repair } => {
+            let options = CheckOptions {
+                meter,
+                gap_factor,
+                intervals: config.meters.iter()
+                    .map(|(id, meter)| (meter.key(id).to_string(), meter.reading_interval()))
+                    .collect(),
+                default_interval: interval,
+                max_power_w: max_power * 1000.0,
+            };
+            run_check(config, &options, repair)
+        }
+        Command::Retention { dry_run } => run_retention(config, dry_run),
+        Command::Restore { file, force } => run_restore(config, &file, force),
+    }
+}
+
+fn run_retention(config: &AppConfig, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let db = storage::open(&config.global, &config.storage)?;
+    let report = RetentionService::process_retention(db.as_ref(), &config.retention, Utc::now(), dry_run)?;
+    print!("{}", report);
+    Ok(())
+}
+
+fn run_export(config: &AppConfig, args: ExportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut request = ExportRequest {
+        meters: args.meters,
+        channels: match &args.channels {
+            Some(channels) => query::parse_channels(channels)?,
+            None => Vec::new(),
+        },
+        start: args.start,
+        end: args.end.unwrap_or_else(Utc::now),
+        resample: args.resample,
+        timezone: args.tz.unwrap_or_else(|| config.timezone()),
+        format: args.format,
+        csv: CsvOptions { delimiter: args.delimiter, decimal_separator: args.decimal },
+    };
+    request.validate()?;
+
+    let db = storage::open(&config.global, &config.storage)?;
+    request.meters = export::resolve_meters(db.as_ref(), &request.meters)?
+        .map_err(|unknown| format!("Unknown meter {}", unknown))?;
+
+    let out: Box<dyn Write + Send> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    let rows = export::export(db.as_ref(), &request, out)?;
+    if let Some(path) = &args.output {
+        eprintln!("Exported {} rows to {}", rows, path.display());
+    }
+    Ok(())
+}
+
+fn run_import(config: &AppConfig, source: ImportSource) -> Result<(), Box<dyn std::error::Error>> {
+    let db = storage::open(&config.global, &config.storage)?;
+    let report = match source {
+        ImportSource::Csv { file, delimiter, layout } => {
+            let (layout, meter_map) = layout.layout(config);
+            import::import_csv(db.as_ref(), File::open(&file)?, delimiter, &layout, &meter_map)?
+        }
+        ImportSource::Sqlite { file, query, layout } => {
+            let (layout, meter_map) = layout.layout(config);
+            import::import_sqlite(db.as_ref(), &file, query.as_deref(), &layout, &meter_map)?
+        }
+    };
+    print!("{}", report);
+    Ok(())
+}
+
+fn run_check(config: &AppConfig, options: &CheckOptions, repair: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if config.storage.backend != StorageBackend::Sqlite {
+        return Err("check only works for the sqlite backend".into());
+    }
+    let db = DatabaseSync::new(&config.global.database_url, false, &config.storage)?;
+    let report = check::check(&db, options)?;
+    print!("{}", report);
+
+    if repair && report.repairable() > 0 {
+        let fixed = check::repair(&db, &report)?;
+        println!("\nRepaired {} problems, the original rows are in the quarantine tables", fixed);
+    } else if report.repairable() > 0 {
+        println!("\n{} problems can be repaired with --repair", report.repairable());
+    }
+    Ok(())
+}
+
+fn run_restore(config: &AppConfig, file: &Path, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if config.storage.backend != StorageBackend::Sqlite {
+        return Err("restore only works for the sqlite backend, use pg_restore for PostgreSQL".into());
+    }
+    if !force && service_running(config) {
+        return Err("The service seems to be running, stop it first or pass --force".into());
+    }
+
+    let replaced = backup::restore(file, &config.global.database_url)?;
+    println!("Restored {} to {}", file.display(), config.global.database_url);
+    if let Some(replaced) = replaced {
+        println!("The replaced database was kept as {}", replaced.display());
+    }
+    Ok(())
+}
+
+/// Whether something answers on the configured web server port.
+fn service_running(config: &AppConfig) -> bool {
+    let port = config.global.web_server_port.unwrap_or(8080);
+    let address = match config.global.bind_address.as_str() {
+        "0.0.0.0" | "::" | "" => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        host => match (host, port).to_socket_addrs().ok().and_then(|mut a| a.next()) {
+            Some(address) => address,
+            None => return false,
+        },
+    };
+    TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_ok()
+}
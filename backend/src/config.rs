@@ -2,8 +2,16 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use chrono::TimeDelta;
+use chrono_tz::Tz;
 use log::LevelFilter;
-use std::str::FromStr;
+
+use crate::encoding::PowerEncoding;
+use crate::maintenance::MaintenanceTask;
+use crate::meters::Channel;
+use crate::query::BucketWidth;
+use crate::rollup::{Aggregate, Policy, Tier};
+use crate::storage::MeterMetadata;
 
 #[derive(Debug, Deserialize)]
 pub struct GlobalConfig {
@@ -19,6 +27,328 @@ pub struct GlobalConfig {
     pub bind_address: String,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerEncodingKind {
+    F16,
+    F32,
+    Scaled,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Sqlite,
+    Postgres,
+}
+
+/// Connection to a PostgreSQL server, used with `backend = "postgres"`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PostgresConfig {
+    /// libpq style connection string, e.g. `host=db.example user=solar dbname=solar`
+    pub url: String,
+    /// Store readings in a TimescaleDB hypertable; requires the extension on the server
+    #[serde(default)]
+    pub timescaledb: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageConfig {
+    /// Where readings are stored. `sqlite` uses `global.database_url`.
+    #[serde(default)]
+    pub backend: StorageBackend,
+    pub postgres: Option<PostgresConfig>,
+    /// Storage format for power values. Changing it re-encodes existing rows
+    /// once at the next start.
+    #[serde(default = "default_power_encoding")]
+    pub power_encoding: PowerEncodingKind,
+    /// Integer steps per watt for the `scaled` encoding, 10 = deci-watts
+    #[serde(default = "default_power_scale")]
+    pub power_scale: u32,
+    /// Allows re-encoding existing rows to f16, which loses precision above 2 kW
+    #[serde(default)]
+    pub allow_lossy_power_encoding: bool,
+    /// Polling cycles written per transaction
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Seconds a reading may wait in the queue before the batch is flushed
+    #[serde(default = "default_batch_interval")]
+    pub batch_interval: u64,
+    /// Cycles held in memory while the disk is slow; newer ones are dropped beyond this
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    #[serde(default = "default_wal_mode")]
+    pub wal_mode: bool,
+    /// WAL size in pages after which SQLite checkpoints it into the database
+    #[serde(default = "default_wal_autocheckpoint")]
+    pub wal_autocheckpoint: u32,
+    /// Read-only connections for API queries, separate from the writer
+    #[serde(default = "default_read_connections")]
+    pub read_connections: usize,
+    /// Journal file for cycles that don't fit into the queue, `<database_url>.spill`
+    /// when loaded from a config file
+    pub spill_journal: Option<String>,
+    /// Size limit of the journal in MiB, 0 disables it
+    #[serde(default = "default_spill_max_mb")]
+    pub spill_max_mb: u64,
+}
+
+impl StorageConfig {
+    pub fn power_encoding(&self) -> PowerEncoding {
+        match self.power_encoding {
+            PowerEncodingKind::F16 => PowerEncoding::F16,
+            PowerEncodingKind::F32 => PowerEncoding::F32,
+            PowerEncodingKind::Scaled => PowerEncoding::Scaled(self.power_scale),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::default(),
+            postgres: None,
+            power_encoding: default_power_encoding(),
+            power_scale: default_power_scale(),
+            allow_lossy_power_encoding: false,
+            batch_size: default_batch_size(),
+            batch_interval: default_batch_interval(),
+            queue_capacity: default_queue_capacity(),
+            wal_mode: default_wal_mode(),
+            wal_autocheckpoint: default_wal_autocheckpoint(),
+            read_connections: default_read_connections(),
+            spill_journal: None,
+            spill_max_mb: default_spill_max_mb(),
+        }
+    }
+}
+
+fn default_power_encoding() -> PowerEncodingKind {
+    PowerEncodingKind::F32
+}
+
+fn default_power_scale() -> u32 {
+    10
+}
+
+fn default_batch_size() -> usize {
+    50
+}
+
+fn default_batch_interval() -> u64 {
+    60
+}
+
+fn default_queue_capacity() -> usize {
+    5000
+}
+
+fn default_wal_mode() -> bool {
+    true
+}
+
+fn default_wal_autocheckpoint() -> u32 {
+    1000
+}
+
+fn default_read_connections() -> usize {
+    4
+}
+
+fn default_spill_max_mb() -> u64 {
+    256
+}
+
+/// Copies of the SQLite database made while it is in use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackupConfig {
+    /// Where backups and snapshots are written, relative to the config file
+    pub directory: String,
+    /// Hours between scheduled backups, 0 for none
+    #[serde(default = "default_backup_interval")]
+    pub interval_hours: u64,
+    /// Scheduled backups kept, older ones are deleted
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    /// Bearer token for `GET /backup/snapshot`, which is disabled without one
+    pub api_token: Option<String>,
+}
+
+fn default_backup_interval() -> u64 {
+    24
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+/// Which rollup tiers are kept and for how long, see `rollup::Policy`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetentionConfig {
+    /// Minutes between retention runs
+    #[serde(default = "default_retention_interval")]
+    pub interval_minutes: u64,
+    /// Days raw readings are kept once the first tier covers them, 0 for ever
+    #[serde(default = "default_raw_days")]
+    pub raw_days: u32,
+    /// Finest first, each built from the one before it
+    #[serde(default = "default_tiers")]
+    pub tiers: Vec<TierConfig>,
+    /// Days after which readings and rollups are deleted, 0 for no limit
+    #[serde(default)]
+    pub max_age_days: u32,
+    /// The oldest days of all meters are deleted while readings and rollups
+    /// take up more than this, 0 for no limit
+    #[serde(default)]
+    pub max_database_mb: u64,
+    /// Overrides by meter key
+    #[serde(default)]
+    pub meters: HashMap<String, RetentionOverride>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierConfig {
+    /// Bucket width such as `10m`, `1h` or `1d`
+    pub bucket: String,
+    /// Days buckets are kept, 0 for ever
+    #[serde(default)]
+    pub keep_days: u32,
+    /// Kept besides count and average, which every tier has
+    #[serde(default = "default_aggregates")]
+    pub aggregates: Vec<Aggregate>,
+}
+
+/// Settings of one meter that differ from `[retention]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetentionOverride {
+    pub raw_days: Option<u32>,
+    pub tiers: Option<Vec<TierConfig>>,
+    pub max_age_days: Option<u32>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            interval_minutes: default_retention_interval(),
+            raw_days: default_raw_days(),
+            tiers: default_tiers(),
+            max_age_days: 0,
+            max_database_mb: 0,
+            meters: HashMap::new(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Policy of one meter, with its overrides applied.
+    pub fn policy(&self, meter_key: &str) -> Result<Policy, String> {
+        let overrides = self.meters.get(meter_key).cloned().unwrap_or_default();
+        let days = |days: u32| (days > 0).then(|| TimeDelta::days(days as i64));
+        let tiers = overrides.tiers.as_ref().unwrap_or(&self.tiers).iter()
+            .map(|tier| match BucketWidth::parse(&tier.bucket) {
+                Some(BucketWidth::Seconds(width_secs)) => Ok(Tier {
+                    width_secs,
+                    aggregates: tier.aggregates.clone(),
+                    keep: days(tier.keep_days),
+                }),
+                _ => Err(format!("invalid bucket {:?}", tier.bucket)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Policy {
+            raw: days(overrides.raw_days.unwrap_or(self.raw_days)),
+            tiers,
+            max_age: days(overrides.max_age_days.unwrap_or(self.max_age_days)),
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.interval_minutes == 0 {
+            return Err("retention.interval_minutes must be greater than 0".to_string());
+        }
+        self.policy("").and_then(|policy| policy.validate()).map_err(|e| format!("[retention]: {}", e))?;
+        for meter_key in self.meters.keys() {
+            self.policy(meter_key).and_then(|policy| policy.validate())
+                .map_err(|e| format!("[retention.meters.{}]: {}", meter_key, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn default_retention_interval() -> u64 {
+    60
+}
+
+fn default_raw_days() -> u32 {
+    30
+}
+
+fn default_tiers() -> Vec<TierConfig> {
+    ["10m", "1h", "1d"].iter()
+        .map(|bucket| TierConfig { bucket: bucket.to_string(), keep_days: 0, aggregates: default_aggregates() })
+        .collect()
+}
+
+fn default_aggregates() -> Vec<Aggregate> {
+    Aggregate::ALL.to_vec()
+}
+
+/// Hours between runs of each database maintenance task, 0 disables it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceConfig {
+    /// Hand pages freed by deletes back to the file system
+    #[serde(default = "default_daily")]
+    pub vacuum_hours: u64,
+    /// `PRAGMA optimize`, which analyzes tables whose statistics are stale
+    #[serde(default = "default_daily")]
+    pub optimize_hours: u64,
+    /// `ANALYZE` of every table
+    #[serde(default = "default_weekly")]
+    pub analyze_hours: u64,
+    /// Copy the WAL into the database and truncate it
+    #[serde(default = "default_checkpoint_hours")]
+    pub checkpoint_hours: u64,
+    /// `PRAGMA integrity_check`, which reads the whole database
+    #[serde(default = "default_weekly")]
+    pub integrity_check_hours: u64,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            vacuum_hours: default_daily(),
+            optimize_hours: default_daily(),
+            analyze_hours: default_weekly(),
+            checkpoint_hours: default_checkpoint_hours(),
+            integrity_check_hours: default_weekly(),
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    pub fn interval_hours(&self, task: MaintenanceTask) -> u64 {
+        match task {
+            MaintenanceTask::Vacuum => self.vacuum_hours,
+            MaintenanceTask::Optimize => self.optimize_hours,
+            MaintenanceTask::Analyze => self.analyze_hours,
+            MaintenanceTask::Checkpoint => self.checkpoint_hours,
+            MaintenanceTask::IntegrityCheck => self.integrity_check_hours,
+        }
+    }
+}
+
+fn default_daily() -> u64 {
+    24
+}
+
+fn default_weekly() -> u64 {
+    168
+}
+
+fn default_checkpoint_hours() -> u64 {
+    6
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
@@ -60,21 +390,155 @@ pub enum MeterType {
     Mock,
 }
 
-#[derive(Debug, Deserialize)]
+impl MeterType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MeterType::Sdm72d => "sdm72d",
+            MeterType::Mock => "mock",
+        }
+    }
+}
+
+/// A set of channels that are read together at their own interval (seconds).
+#[derive(Debug, Clone, Deserialize)]
+pub struct PollingGroup {
+    pub interval: u32,
+    pub channels: Vec<Channel>,
+}
+
+/// Adaptive polling: power groups speed up to `min_interval` when power moves by
+/// more than `change_threshold` watts between samples, and back off by
+/// `backoff_factor` per steady sample up to `max_interval`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdaptiveConfig {
+    pub min_interval: u32,
+    pub max_interval: u32,
+    pub change_threshold: f32,
+    #[serde(default = "default_backoff_factor")]
+    pub backoff_factor: f32,
+    /// Poll at `night_interval` while the sun is down at `[location]`.
+    #[serde(default)]
+    pub daylight_throttling: bool,
+    pub night_interval: Option<u32>,
+}
+
+fn default_backoff_factor() -> f32 {
+    1.5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocationConfig {
+    pub city: Option<String>,
+    pub timezone: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct MeterConfig {
+    /// Key the meter's history is stored under, defaults to its `[meters.<id>]`
+    /// table id. Keep it when renaming the meter so its history carries over.
+    pub id: Option<String>,
+    /// Display name, free to change
     pub name: String,
     pub port: String,
     pub baud_rate: u32,
     pub timeout: u32,
     pub polling_rate: u32,
     pub modbus_address: u8,
+    #[serde(default)]
+    pub polling_groups: Vec<PollingGroup>,
+    pub adaptive: Option<AdaptiveConfig>,
     #[serde(flatten)]
     pub meter_type: MeterType,
+    #[serde(flatten)]
+    pub metadata: MeterMetadata,
+}
+
+impl MeterConfig {
+    /// Metadata stored with the meter, including its driver type.
+    pub fn metadata(&self) -> MeterMetadata {
+        MeterMetadata {
+            meter_type: Some(self.meter_type.as_str().to_string()),
+            ..self.metadata.clone()
+        }
+    }
+
+    /// The meter's storage key, `id` if set, else the config table id.
+    pub fn key<'a>(&'a self, table_id: &'a str) -> &'a str {
+        self.id.as_deref().unwrap_or(table_id)
+    }
+
+    /// Seconds between two readings of this meter at most, when polling
+    /// is as slow as adaptive polling lets it get.
+    pub fn reading_interval(&self) -> u32 {
+        let fastest = self.polling_groups.iter().map(|g| g.interval).min().unwrap_or(self.polling_rate);
+        match &self.adaptive {
+            Some(adaptive) => fastest.max(adaptive.max_interval).max(adaptive.night_interval.unwrap_or(0)),
+            None => fastest,
+        }
+    }
+
+    /// Returns the configured polling groups, or a single group reading every
+    /// declared channel at `polling_rate` when none are configured. Channels the
+    /// meter doesn't declare are dropped from configured groups.
+    pub fn effective_polling_groups(&self, declared: &[Channel]) -> Vec<PollingGroup> {
+        if self.polling_groups.is_empty() {
+            return vec![PollingGroup {
+                interval: self.polling_rate,
+                channels: declared.to_vec(),
+            }];
+        }
+
+        self.polling_groups
+            .iter()
+            .map(|group| PollingGroup {
+                interval: group.interval,
+                channels: group.channels.iter().copied().filter(|c| declared.contains(c)).collect(),
+            })
+            .filter(|group| !group.channels.is_empty())
+            .collect()
+    }
+
+    fn validate(&self, meter_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let (Some(installed), Some(removed)) = (self.metadata.installed_on, self.metadata.removed_on) {
+            if removed < installed {
+                return Err(format!("Meter {}: removed_on is before installed_on", meter_id).into());
+            }
+        }
+
+        for group in &self.polling_groups {
+            if group.interval == 0 {
+                return Err(format!("Meter {}: polling group interval must be greater than 0", meter_id).into());
+            }
+            if group.channels.is_empty() {
+                return Err(format!("Meter {}: polling group with interval {}s has no channels", meter_id, group.interval).into());
+            }
+        }
+
+        if let Some(adaptive) = &self.adaptive {
+            if adaptive.min_interval == 0 || adaptive.min_interval > adaptive.max_interval {
+                return Err(format!("Meter {}: adaptive polling needs 0 < min_interval <= max_interval", meter_id).into());
+            }
+            if adaptive.backoff_factor < 1.0 {
+                return Err(format!("Meter {}: adaptive backoff_factor must be at least 1.0", meter_id).into());
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub global: GlobalConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    pub location: Option<LocationConfig>,
+    pub backup: Option<BackupConfig>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
     pub meters: HashMap<String, MeterConfig>,
 }
 
@@ -115,7 +579,76 @@ impl AppConfig {
             let absolute_db_path = config_dir.join(&config.global.database_url);
             config.global.database_url = absolute_db_path.to_string_lossy().into_owned();
         }
+
+        if matches!(config.storage.power_encoding, PowerEncodingKind::Scaled) && config.storage.power_scale == 0 {
+            return Err("storage.power_scale must be greater than 0".into());
+        }
+        if config.storage.batch_size == 0 || config.storage.queue_capacity < config.storage.batch_size {
+            return Err("storage needs 0 < batch_size <= queue_capacity".into());
+        }
+        if config.storage.backend == StorageBackend::Postgres && config.storage.postgres.is_none() {
+            return Err("storage.backend = \"postgres\" requires a [storage.postgres] section".into());
+        }
+        if config.storage.read_connections == 0 {
+            return Err("storage.read_connections must be greater than 0".into());
+        }
+
+        if config.storage.spill_max_mb == 0 {
+            config.storage.spill_journal = None;
+        } else {
+            let journal = config.storage.spill_journal.take()
+                .unwrap_or_else(|| format!("{}.spill", config.global.database_url));
+            let config_dir = path.parent().unwrap_or(Path::new("."));
+            config.storage.spill_journal = Some(config_dir.join(journal).to_string_lossy().into_owned());
+        }
+
+        if let Some(backup) = &mut config.backup {
+            if config.storage.backend != StorageBackend::Sqlite {
+                return Err("[backup] needs the sqlite backend, back up PostgreSQL with pg_dump".into());
+            }
+            if backup.keep == 0 {
+                return Err("backup.keep must be greater than 0".into());
+            }
+            if backup.api_token.as_deref().is_some_and(|token| token.len() < 16) {
+                return Err("backup.api_token must be at least 16 characters".into());
+            }
+            if !Path::new(&backup.directory).is_absolute() {
+                let config_dir = path.parent().unwrap_or(Path::new("."));
+                backup.directory = config_dir.join(&backup.directory).to_string_lossy().into_owned();
+            }
+        }
+
+        config.retention.validate()?;
+        if config.retention.max_database_mb > 0 && config.storage.backend != StorageBackend::Sqlite {
+            return Err("retention.max_database_mb needs the sqlite backend, PostgreSQL files don't shrink after deletes".into());
+        }
+
+        if let Some(timezone) = config.location.as_ref().and_then(|l| l.timezone.as_deref()) {
+            timezone.parse::<Tz>().map_err(|e| format!("location.timezone: {}", e))?;
+        }
+
+        let mut keys = HashMap::new();
+        for (meter_id, meter_config) in &config.meters {
+            if let Some(other) = keys.insert(meter_config.key(meter_id), meter_id) {
+                return Err(format!("Meters {} and {} have the same id {}", other, meter_id, meter_config.key(meter_id)).into());
+            }
+        }
+
+        for (meter_id, meter_config) in &config.meters {
+            meter_config.validate(meter_id)?;
+            if meter_config.adaptive.as_ref().is_some_and(|a| a.daylight_throttling) && config.location.is_none() {
+                return Err(format!("Meter {}: daylight_throttling requires a [location] section", meter_id).into());
+            }
+        }
         
         Ok(config)
     }
+
+    /// Timezone for local day boundaries, `location.timezone` or UTC.
+    pub fn timezone(&self) -> Tz {
+        self.location.as_ref()
+            .and_then(|l| l.timezone.as_deref())
+            .and_then(|tz| tz.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
 }
\ No newline at end of file
@@ -1,222 +1,252 @@
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::time::{sleep, Duration};
-use chrono::{DateTime, Utc, TimeDelta};
+use chrono::{DateTime, TimeDelta, Utc};
 use log::{info, error};
-use rusqlite::Transaction;
-
-use crate::database_sync::{DatabaseSync, Model};
+use serde::Serialize;
+
+use crate::config::RetentionConfig;
+use crate::rollup::{self, Policy};
+use crate::storage::Storage;
+
+/// Reports kept in the database, older ones are deleted.
+pub const REPORTS_KEPT: usize = 1000;
+
+/// Rows retention read, wrote and deleted for one meter and tier.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RetentionStep {
+    pub meter_key: String,
+    /// Bucket width of the tier, `None` for raw readings. What max_age and the
+    /// size limit delete is counted here too, rollups included.
+    pub width_secs: Option<i64>,
+    pub rows_read: u64,
+    pub rows_written: u64,
+    pub rows_deleted: u64,
+}
 
-pub struct RetentionService {
-    db: Arc<DatabaseSync>,
+/// What one retention run did, or would do for a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RetentionReport {
+    /// `None` for dry runs, which are not stored
+    pub run_id: Option<i64>,
+    pub started_at: i64,  // Unix timestamp
+    pub duration_ms: u64,
+    pub dry_run: bool,
+    pub size_before_bytes: u64,
+    pub size_after_bytes: u64,
+    pub steps: Vec<RetentionStep>,
+    /// Meters the policy failed for and the size limit if it did, with the error
+    pub errors: Vec<String>,
+    /// A dry run leaves out the size limit, which depends on the space real
+    /// deletes free up, so a real run may delete more
+    pub size_limit_skipped: bool,
 }
 
-impl RetentionService {
-    pub fn new(db: Arc<DatabaseSync>) -> Self {
-        Self { db }
+impl RetentionReport {
+    fn step(&mut self, meter_key: &str, width_secs: Option<i64>) -> &mut RetentionStep {
+        let index = match self.steps.iter().position(|s| s.meter_key == meter_key && s.width_secs == width_secs) {
+            Some(index) => index,
+            None => {
+                self.steps.push(RetentionStep { meter_key: meter_key.to_string(), width_secs, ..Default::default() });
+                self.steps.len() - 1
+            }
+        };
+        &mut self.steps[index]
     }
 
-    /// Aggregates data points within a time window into a single data point
-    fn aggregate_data_points(readings: &[Model]) -> Option<Model> {
-        if readings.is_empty() {
-            return None;
-        }
-
-        let count = readings.len() as f32;
-        let first = &readings[0];
-
-        // Calculate averages for power readings
-        let total_power = readings.iter().map(|r| r.total_power).sum::<f32>() / count;
-        let import_power = readings.iter().map(|r| r.import_power).sum::<f32>() / count;
-        let export_power = readings.iter().map(|r| r.export_power).sum::<f32>() / count;
-
-        // Use the latest total_kwh reading as it's cumulative
-        let total_kwh = readings.iter().map(|r| r.total_kwh).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-
-        // Use the timestamp from the middle of the interval
-        let middle_idx = readings.len() / 2;
-        let timestamp = readings[middle_idx].timestamp;
-
-        Some(Model {
-            meter_name: first.meter_name.clone(),
-            timestamp,
-            total_power,
-            import_power,
-            export_power,
-            total_kwh,
-        })
+    pub fn rows_written(&self) -> u64 {
+        self.steps.iter().map(|s| s.rows_written).sum()
     }
 
-    async fn process_retention(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let now = Utc::now();
-
-        // Define time windows and their target intervals
-        let windows = [
-            // (start_age, end_age, interval_minutes)
-            (TimeDelta::try_hours(24 * 7).unwrap(), None, 60), // Older than 1 week -> 1 hour
-            (TimeDelta::try_hours(24).unwrap(), Some(TimeDelta::try_hours(24 * 7).unwrap()), 20), // 1 day to 1 week -> 20 min
-            (TimeDelta::try_hours(1).unwrap(), Some(TimeDelta::try_hours(24).unwrap()), 10), // 1 hour to 1 day -> 10 min
-        ];
-
-        let mut conn = match self.db.get_connection() {
-            Ok(conn) => conn,
-            Err(e) => {
-                error!("Failed to get database connection: {}", e);
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
-            }
-        };
-
-        for (start_age, end_age, interval_minutes) in windows {
-            let start_time = now - start_age;
-            let end_time = end_age.map(|age| now - age);
+    pub fn rows_deleted(&self) -> u64 {
+        self.steps.iter().map(|s| s.rows_deleted).sum()
+    }
+}
 
-            if let Err(e) = self.process_time_window(&mut conn, start_time, end_time, interval_minutes) {
-                error!("Error processing time window: {}", e);
-            }
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<20} {:>8} {:>12} {:>12} {:>12}", "meter", "tier", "read", "written", "deleted")?;
+        for step in &self.steps {
+            let tier = step.width_secs.map_or("raw".to_string(), |w| format!("{}s", w));
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>12} {:>12} {:>12}",
+                step.meter_key, tier, step.rows_read, step.rows_written, step.rows_deleted,
+            )?;
+        }
+        for error in &self.errors {
+            writeln!(f, "failed: {}", error)?;
         }
+        if self.size_limit_skipped {
+            writeln!(f, "size limit not applied, a real run may delete more")?;
+        }
+        writeln!(
+            f,
+            "{} in {} ms, {} bytes before, {} after",
+            if self.dry_run { "Dry run" } else { "Run" },
+            self.duration_ms,
+            self.size_before_bytes,
+            self.size_after_bytes,
+        )
+    }
+}
 
-        Ok(())
+/// Keeps the rollup tiers up to date and deletes what each meter's
+/// retention policy no longer keeps.
+#[derive(Clone)]
+pub struct RetentionService {
+    db: Arc<dyn Storage>,
+    config: RetentionConfig,
+    /// Held for a run, so a manual one never overlaps the scheduled one
+    running: Arc<Mutex<()>>,
+}
+
+impl RetentionService {
+    pub fn new(db: Arc<dyn Storage>, config: RetentionConfig) -> Self {
+        Self { db, config, running: Arc::new(Mutex::new(())) }
     }
 
-    fn process_time_window(
-        &self,
-        conn: &mut rusqlite::Connection,
-        start_time: DateTime<Utc>,
-        end_time: Option<DateTime<Utc>>,
-        interval_minutes: i64,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let tx = conn.transaction()?;
-
-        // Get all meter IDs
-        let meter_ids: Vec<i64> = {
-            let mut stmt = tx.prepare("SELECT DISTINCT meter_id FROM meter_readings")?;
-            let ids = stmt
-                .query_map([], |row| row.get(0))?
-                .filter_map(Result::ok)
-                .collect();
-            stmt.finalize()?;
-            ids
+    /// One pass as of `now`. Rolls up only buckets that became complete since
+    /// the last pass, so running it twice with the same `now` changes nothing.
+    ///
+    /// A dry run changes nothing and is not stored. Tiers built from other
+    /// tiers only see the rollups stored so far, and the size limit is left
+    /// out as it depends on the space deletes free up, see `size_limit_skipped`.
+    pub fn process_retention(
+        db: &dyn Storage,
+        config: &RetentionConfig,
+        now: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<RetentionReport, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let mut report = RetentionReport {
+            started_at: now.timestamp(),
+            dry_run,
+            size_before_bytes: db.used_bytes()?,
+            ..Default::default()
         };
-
-        for meter_id in meter_ids {
-            if let Err(e) = self.aggregate_meter_data(&tx, meter_id, start_time, end_time, interval_minutes) {
-                error!("Error aggregating data for meter {}: {}", meter_id, e);
-                return Err(e);
+        for meter_key in db.meter_keys()? {
+            if let Err(e) = Self::apply_policy(db, &meter_key, &config.policy(&meter_key)?, now, dry_run, &mut report) {
+                error!("Error processing retention of {}: {}", meter_key, e);
+                report.errors.push(format!("{}: {}", meter_key, e));
+            }
+        }
+        if config.max_database_mb > 0 {
+            if dry_run {
+                report.size_limit_skipped = true;
+            } else if let Err(e) = Self::limit_size(db, config.max_database_mb * 1024 * 1024, now, &mut report) {
+                error!("Error applying the retention size limit: {}", e);
+                report.errors.push(format!("size limit: {}", e));
             }
         }
 
-        tx.commit()?;
-        Ok(())
+        report.size_after_bytes = db.used_bytes()?;
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        if !dry_run {
+            report.run_id = Some(db.insert_retention_report(&report)?);
+        }
+        Ok(report)
     }
 
-    fn aggregate_meter_data(
-        &self,
-        transaction: &Transaction,
-        meter_id: i64,
-        start_time: DateTime<Utc>,
-        end_time: Option<DateTime<Utc>>,
-        interval_minutes: i64,
+    fn apply_policy(
+        db: &dyn Storage,
+        meter_key: &str,
+        policy: &Policy,
+        now: DateTime<Utc>,
+        dry_run: bool,
+        report: &mut RetentionReport,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let start_timestamp = start_time.timestamp();
-        let end_timestamp = end_time.map(|t| t.timestamp());
-
-        // Create temporary table for the new aggregated data
-        transaction.execute(
-            "CREATE TEMPORARY TABLE IF NOT EXISTS temp_aggregated (
-                meter_id INTEGER,
-                timestamp INTEGER,
-                total_power SMALLINT,
-                import_power SMALLINT,
-                export_power SMALLINT,
-                total_kwh REAL
-            )",
-            [],
-        )?;
-
-        // Group data by intervals and calculate averages
-        let interval_seconds = interval_minutes * 60;
-        let query = format!(
-            "INSERT INTO temp_aggregated
-             SELECT 
-                meter_id,
-                (timestamp / ?) * ? as interval_start,
-                CAST(AVG(total_power) as INTEGER) as avg_total_power,
-                CAST(AVG(import_power) as INTEGER) as avg_import_power,
-                CAST(AVG(export_power) as INTEGER) as avg_export_power,
-                MAX(total_kwh) as max_total_kwh
-             FROM meter_readings
-             WHERE meter_id = ? 
-             AND timestamp <= ?
-             {} 
-             GROUP BY meter_id, interval_start",
-            if end_timestamp.is_some() {
-                "AND timestamp >= ?"
-            } else {
-                ""
+        // Each tier is built from the one before it, so they run finest first
+        let mut source = None;
+        // How far a dry run would have rolled up the first tier
+        let mut rehearsed = None;
+        for tier in &policy.tiers {
+            let counts = db.rollup(meter_key, tier, source, now - rollup::SETTLE, dry_run)?;
+            let step = report.step(meter_key, Some(tier.width_secs));
+            step.rows_read += counts.read;
+            step.rows_written += counts.written;
+            if dry_run && source.is_none() {
+                rehearsed = counts.rolled_until;
             }
-        );
-
-        let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![
-            Box::new(interval_seconds),
-            Box::new(interval_seconds),
-            Box::new(meter_id),
-            Box::new(start_timestamp),
-        ];
-
-        if let Some(end_ts) = end_timestamp {
-            params.push(Box::new(end_ts));
+            source = Some(tier.width_secs);
         }
 
-        transaction.execute(&query, rusqlite::params_from_iter(params))?;
-
-        // Delete original data and replace with aggregated data
-        let delete_query = format!(
-            "DELETE FROM meter_readings 
-             WHERE meter_id = ? 
-             AND timestamp <= ?
-             {}",
-            if end_timestamp.is_some() {
-                "AND timestamp >= ?"
-            } else {
-                ""
+        if let (Some(raw), Some(first)) = (policy.raw, policy.tiers.first()) {
+            report.step(meter_key, None).rows_deleted += db.expire_raw(meter_key, now - raw, first.width_secs, rehearsed, dry_run)?;
+        }
+        for tier in &policy.tiers {
+            if let Some(keep) = tier.keep {
+                report.step(meter_key, Some(tier.width_secs)).rows_deleted +=
+                    db.expire_rollups(meter_key, tier.width_secs, now - keep, dry_run)?;
             }
-        );
-
-        let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![
-            Box::new(meter_id),
-            Box::new(start_timestamp),
-        ];
-
-        if let Some(end_ts) = end_timestamp {
-            params.push(Box::new(end_ts));
         }
+        if let Some(max_age) = policy.max_age {
+            report.step(meter_key, None).rows_deleted += db.delete_before(meter_key, now - max_age, dry_run)?;
+        }
+        Ok(())
+    }
 
-        transaction.execute(&delete_query, rusqlite::params_from_iter(params))?;
-
-        // Insert aggregated data
-        transaction.execute(
-            "INSERT INTO meter_readings 
-             SELECT * FROM temp_aggregated 
-             WHERE meter_id = ?",
-            [meter_id],
-        )?;
-
-        // Clean up temporary table
-        transaction.execute("DELETE FROM temp_aggregated", [])?;
-
+    /// Deletes the oldest day of every meter until readings and rollups fit
+    /// into `max_bytes`. What retention never deletes doesn't count towards
+    /// the limit, so a large capture can't cost meter history, but it is an
+    /// error for it to take up more than the limit on its own. The last day
+    /// is never deleted.
+    fn limit_size(db: &dyn Storage, max_bytes: u64, now: DateTime<Utc>, report: &mut RetentionReport) -> Result<(), Box<dyn std::error::Error>> {
+        let undeletable = db.undeletable_bytes()?;
+        if undeletable > max_bytes {
+            return Err(format!(
+                "capture samples, quarantined readings and reports take up {} bytes, more than {}, retention doesn't delete them",
+                undeletable, max_bytes,
+            ).into());
+        }
+        let mut cutoff = None;
+        while db.used_bytes()?.saturating_sub(undeletable) > max_bytes {
+            let Some(oldest) = db.oldest_data()? else {
+                break;
+            };
+            // Buckets longer than a day may outlive a cutoff, so it moves on regardless
+            let next = oldest.max(cutoff.unwrap_or(oldest)) + TimeDelta::days(1);
+            if next > now - TimeDelta::days(1) {
+                return Err(format!("readings still take up more than {} bytes, only the last day is left", max_bytes).into());
+            }
+            let mut deleted = 0;
+            for meter_key in db.meter_keys()? {
+                let rows = db.delete_before(&meter_key, next, false)?;
+                report.step(&meter_key, None).rows_deleted += rows;
+                deleted += rows;
+            }
+            info!("Deleted {} rows before {} to stay within {} bytes", deleted, next, max_bytes);
+            cutoff = Some(next);
+        }
         Ok(())
     }
 
+    /// Runs retention now, waiting for a scheduled run in progress to finish.
+    pub async fn run_now(&self, dry_run: bool) -> Result<RetentionReport, Box<dyn std::error::Error + Send + Sync>> {
+        let (config, running) = (self.config.clone(), Arc::clone(&self.running));
+        self.db.run_blocking(move |db| {
+            let _running = running.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            Self::process_retention(db, &config, Utc::now(), dry_run)
+        }).await
+    }
+
     pub async fn run(&self) {
         info!("Starting data retention service");
 
         loop {
-            match self.process_retention().await {
-                Ok(_) => info!("Successfully processed data retention"),
+            match self.run_now(false).await {
+                Ok(report) => info!(
+                    "Processed data retention in {} ms: {} rows written, {} deleted, {} meters failed",
+                    report.duration_ms,
+                    report.rows_written(),
+                    report.rows_deleted(),
+                    report.errors.len(),
+                ),
                 Err(e) => error!("Error processing data retention: {}", e),
             }
 
-            // Run every hour
-            sleep(Duration::from_secs(3600)).await;
+            sleep(Duration::from_secs(self.config.interval_minutes * 60)).await;
         }
     }
-}
\ No newline at end of file
+}
@@ -1,165 +1,1032 @@
-use rusqlite::{Connection, params};
-use half::f16;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+use log::info;
 use std::fs;
+use std::ops::Deref;
 use std::path::Path;
-use std::sync::Mutex;
-use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 use chrono::{DateTime, TimeZone, Utc};
 use serde::{Serialize, Deserialize};
-use std::convert::TryFrom;
 
+use crate::capture::{CaptureParams, CaptureSample, CaptureSession, CaptureStatus};
+use crate::config::StorageConfig;
+use crate::data_retention::{RetentionReport, RetentionStep, REPORTS_KEPT};
+use crate::encoding::PowerEncoding;
+use crate::energy::{self, EnergySamples, EnergySource, PeriodEnergy};
+use crate::maintenance::{MaintenanceResult, MaintenanceTask};
+use crate::migrations;
+use crate::query::{self, BucketQuery, BucketStats, ChannelBuckets};
+use crate::rollup::{self, Aggregate, Coverage, Pass, Segment, Source, Tier};
+use crate::storage::{ImportCounts, MeterMetadata, MeterRole, MeterSummary, RolledEnergy, RollupCounts, Storage, StorageResult, StorageStats};
+use crate::meters::{Channel, ChannelInfo, Measurement, Phase, Quantity};
+
+/// One row of `meter_readings`. Channels that were not read in a cycle are `None`.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Model {
     pub meter_name: String,
     pub timestamp: DateTime<Utc>,
-    pub total_power: f32,
-    pub import_power: f32,
-    pub export_power: f32,
-    pub total_kwh: f32,
+    pub total_power: Option<f32>,
+    pub import_power: Option<f32>,
+    pub export_power: Option<f32>,
+    pub total_kwh: Option<f32>,
+}
+
+impl Model {
+    pub fn empty(meter_name: String, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            meter_name,
+            timestamp,
+            total_power: None,
+            import_power: None,
+            export_power: None,
+            total_kwh: None,
+        }
+    }
+
+    pub fn value(&self, channel: Channel) -> Option<f32> {
+        match channel {
+            Channel::TOTAL_POWER => self.total_power,
+            Channel::IMPORT_POWER => self.import_power,
+            Channel::EXPORT_POWER => self.export_power,
+            Channel::TOTAL_KWH => self.total_kwh,
+            _ => None,
+        }
+    }
+
+    /// Stores `value` in the column for `channel`. Returns false if the channel
+    /// has no column in `meter_readings`.
+    pub fn set_value(&mut self, channel: Channel, value: f32) -> bool {
+        let slot = match channel {
+            Channel::TOTAL_POWER => &mut self.total_power,
+            Channel::IMPORT_POWER => &mut self.import_power,
+            Channel::EXPORT_POWER => &mut self.export_power,
+            Channel::TOTAL_KWH => &mut self.total_kwh,
+            _ => return false,
+        };
+        *slot = Some(value);
+        true
+    }
+}
+
+/// How long a connection waits for a lock held by another connection.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a query waits for a free read connection before giving up.
+const READ_POOL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Retries of a backup step that found the database locked, spread over `BUSY_TIMEOUT`.
+const BACKUP_ATTEMPTS: u32 = 10;
+/// Free pages one incremental vacuum step returns while holding the writer.
+const VACUUM_PAGES_PER_STEP: u32 = 1024;
+/// `PRAGMA auto_vacuum` of a database that returns freed pages on request.
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// Read-only connections, handed out one per query. In WAL mode a reader sees a
+/// consistent snapshot and neither blocks nor waits for the writer.
+struct ReadPool {
+    idle: Mutex<Vec<Connection>>,
+    returned: Condvar,
 }
 
+/// A connection borrowed from the read pool, returned when dropped.
+pub struct ReadConnection<'a> {
+    pool: &'a ReadPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for ReadConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for ReadConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+/// Access to the SQLite database.
+///
+/// All writes go through a single writer connection; queries use a pool of
+/// read-only connections so slow API requests don't hold up ingestion. Every
+/// method blocks on SQLite, so async code should call them via `run_blocking`.
 pub struct DatabaseSync {
     conn: Mutex<Connection>,
+    readers: ReadPool,
     database_url: String,
-    meter_cache: Mutex<HashMap<String, u8>>,
+    meter_cache: Mutex<HashMap<String, i64>>,
+    power_encoding: PowerEncoding,
 }
 
 impl DatabaseSync {
-    pub fn get_connection(&self) -> Result<std::sync::MutexGuard<Connection>, Box<dyn std::error::Error>> {
-        Ok(self.conn.lock().map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?)
+    /// The writer connection. Holding it blocks all other writes, so keep
+    /// transactions short and use `read_connection` for queries.
+    pub fn get_connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>, Box<dyn std::error::Error>> {
+        Ok(self.conn.lock().map_err(|e| Box::new(std::io::Error::other(e.to_string())))?)
     }
 
-    pub fn get_database_path(&self) -> String {
-        self.database_url.clone()
+    /// Borrows a read-only connection, waiting for one to become free if all
+    /// are in use.
+    pub fn read_connection(&self) -> Result<ReadConnection<'_>, Box<dyn std::error::Error>> {
+        let idle = self.readers.idle.lock().unwrap();
+        let (mut idle, timeout) = self.readers.returned
+            .wait_timeout_while(idle, READ_POOL_TIMEOUT, |idle| idle.is_empty())
+            .unwrap();
+        if timeout.timed_out() {
+            return Err("Timed out waiting for a database read connection".into());
+        }
+        Ok(ReadConnection {
+            pool: &self.readers,
+            conn: idle.pop(),
+        })
     }
 
-    pub fn f32_to_f16(value: f32) -> i16 {
-        f16::from_f32(value).to_bits() as i16
+    pub fn get_database_path(&self) -> String {
+        self.database_url.clone()
     }
 
-    pub fn f16_to_f32(value: i16) -> f32 {
-        f16::from_bits(value as u16).to_f32()
+    /// Encoding of the power columns, see `encode_power`/`decode_power` in SQL.
+    pub fn power_encoding(&self) -> PowerEncoding {
+        self.power_encoding
     }
 
-    pub fn new(database_url: &str, create_database: bool) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(database_url: &str, create_database: bool, storage: &StorageConfig) -> Result<Self, Box<dyn std::error::Error>> {
         if let Some(parent) = Path::new(database_url).parent() {
             fs::create_dir_all(parent)?;
         }
 
-        let conn = Connection::open(database_url)?;
-        
-        if create_database {
-            // Create meter names lookup table with u8 primary key
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS meter_names (
-                    meter_id INTEGER PRIMARY KEY CHECK (meter_id >= 0 AND meter_id <= 255),
-                    name TEXT NOT NULL UNIQUE
-                )",
-                [],
-            )?;
+        let mut conn = Connection::open(database_url)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Self::configure_journal(&conn, storage)?;
+        let schema_version = migrations::run(&mut conn, database_url, create_database)?;
+        info!("Database schema at version {}", schema_version);
 
-            // Create optimized readings table
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS meter_readings (
-                    meter_id INTEGER NOT NULL CHECK (meter_id >= 0 AND meter_id <= 255),
-                    timestamp INTEGER NOT NULL,  -- Unix timestamp in seconds
-                    total_power SMALLINT NOT NULL,  -- f16 stored as i16
-                    import_power SMALLINT NOT NULL, -- f16 stored as i16
-                    export_power SMALLINT NOT NULL, -- f16 stored as i16
-                    total_kwh REAL NOT NULL,       -- f32 stored as REAL
-                    PRIMARY KEY (meter_id, timestamp),
-                    FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
-                )",
-                [],
-            )?;
-
-            conn.execute(
-                "CREATE INDEX IF NOT EXISTS idx_meter_timestamp 
-                 ON meter_readings (meter_id, timestamp)",
-                [],
-            )?;
-        }
+        let power_encoding = Self::apply_power_encoding(&conn, database_url, storage)?;
 
-        // Load existing meter names into cache
+        // Load existing meter keys into cache
         let meter_cache = {
             let mut cache = HashMap::new();
-            let mut stmt = conn.prepare("SELECT meter_id, name FROM meter_names")?;
+            let mut stmt = conn.prepare("SELECT meter_id, meter_key FROM meter_names")?;
             let rows = stmt.query_map([], |row| {
-                Ok((row.get::<_, String>(1)?, row.get::<_, u8>(0)?))
+                Ok((row.get::<_, String>(1)?, row.get::<_, i64>(0)?))
             })?;
 
             for row in rows {
-                let (name, id) = row?;
-                cache.insert(name, id);
+                let (meter_key, id) = row?;
+                cache.insert(meter_key, id);
             }
             cache
         }; // stmt is dropped here
 
+        // Opened after migrations so readers never see an older schema
+        let readers = (0..storage.read_connections)
+            .map(|_| Self::open_reader(database_url, power_encoding))
+            .collect::<Result<Vec<_>, _>>()?;
+        info!("Opened {} read connections", readers.len());
+
         Ok(Self { 
             conn: Mutex::new(conn),
+            readers: ReadPool {
+                idle: Mutex::new(readers),
+                returned: Condvar::new(),
+            },
             database_url: database_url.to_string(),
             meter_cache: Mutex::new(meter_cache),
+            power_encoding,
         })
     }
 
-    fn get_or_create_meter_id(&self, meter_name: &str) -> Result<u8, Box<dyn std::error::Error>> {
+    fn open_reader(database_url: &str, power_encoding: PowerEncoding) -> rusqlite::Result<Connection> {
+        let conn = Connection::open_with_flags(
+            database_url,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        power_encoding.register_sql_functions(&conn)?;
+        query::register_sql_functions(&conn)?;
+        Ok(conn)
+    }
+
+    /// WAL turns every commit into a sequential append instead of rewriting the
+    /// rollback journal and the database pages, which is far easier on SD cards.
+    /// `synchronous = NORMAL` is safe in WAL mode: a power cut can lose the last
+    /// commits but never corrupts the database.
+    fn configure_journal(conn: &Connection, storage: &StorageConfig) -> Result<(), Box<dyn std::error::Error>> {
+        if !storage.wal_mode {
+            return Ok(());
+        }
+
+        let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            return Err(format!("Could not enable WAL mode, journal mode is {}", mode).into());
+        }
+        conn.execute_batch(&format!(
+            "PRAGMA synchronous = NORMAL;
+             PRAGMA wal_autocheckpoint = {};
+             PRAGMA journal_size_limit = {};",
+            storage.wal_autocheckpoint,
+            // Truncate the WAL back to its checkpoint size instead of letting it keep its peak size
+            storage.wal_autocheckpoint as u64 * 4096,
+        ))?;
+        info!("WAL mode enabled, checkpoint every {} pages", storage.wal_autocheckpoint);
+        Ok(())
+    }
+
+    /// Switches a database created before incremental auto-vacuum, so the
+    /// vacuum task can hand freed pages back. That takes a VACUUM, which
+    /// rebuilds the file through the WAL and needs room for two more copies of
+    /// the data, so it fails while the disk is short of that. `false` if the
+    /// database already was incremental.
+    fn enable_incremental_vacuum(conn: &Connection, database_url: &str) -> StorageResult<bool> {
+        let mode: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+        if mode == AUTO_VACUUM_INCREMENTAL {
+            return Ok(false);
+        }
+        let used: i64 = conn.query_row(
+            "SELECT (page_count - freelist_count) * page_size FROM pragma_page_count, pragma_freelist_count, pragma_page_size",
+            [],
+            |row| row.get(0),
+        )?;
+        let needed = 2 * used as u64;
+        if let Some(free) = free_bytes(Path::new(database_url)) {
+            if free < needed {
+                return Err(format!(
+                    "switching to incremental auto-vacuum needs {} MB free next to the database, only {} MB are",
+                    needed / 1024 / 1024,
+                    free / 1024 / 1024,
+                ).into());
+            }
+        }
+
+        info!("Rebuilding the database for incremental auto-vacuum, this can take a while");
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        Ok(true)
+    }
+
+    /// Registers the power SQL functions for the configured encoding, re-encoding
+    /// existing rows first if the database was written with a different one.
+    /// The database is backed up before re-encoding, and going back to f16 is
+    /// refused unless `allow_lossy_power_encoding` is set.
+    /// Databases without a recorded encoding predate the setting and hold f16.
+    fn apply_power_encoding(conn: &Connection, database_url: &str, storage: &StorageConfig) -> Result<PowerEncoding, Box<dyn std::error::Error>> {
+        let configured = storage.power_encoding();
+        let stored: Option<String> = conn.query_row(
+            "SELECT value FROM db_settings WHERE key = 'power_encoding'",
+            [],
+            |row| row.get(0),
+        ).optional()?;
+
+        let has_readings: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM meter_readings)",
+            [],
+            |row| row.get(0),
+        )?;
+
+        let current = match stored {
+            Some(value) => Some(PowerEncoding::parse(&value)
+                .ok_or_else(|| format!("Unknown power encoding in database: {}", value))?),
+            None if has_readings => Some(PowerEncoding::F16),
+            None => None,
+        };
+
+        let reencode = current.filter(|&c| c != configured);
+        if let Some(current) = reencode {
+            if configured == PowerEncoding::F16 && !storage.allow_lossy_power_encoding {
+                return Err(format!(
+                    "Refusing to re-encode stored power values from {} to f16, which loses precision above 2 kW; \
+                     set storage.allow_lossy_power_encoding = true to do it anyway",
+                    current
+                ).into());
+            }
+            if has_readings {
+                let label = current.to_string().replace(':', "");
+                migrations::backup(conn, database_url, &label, "re-encoding power values")?;
+            }
+        }
+
+        configured.register_sql_functions(conn)?;
+
+        let tx = conn.unchecked_transaction()?;
+
+        if let Some(current) = reencode {
+            info!("Re-encoding stored power values from {} to {}", current, configured);
+            current.register_as(&tx, "encode_power_previous", "decode_power_previous")?;
+            // Quarantined rows can be put back, so they use the same encoding
+            for table in ["meter_readings", "quarantined_readings"] {
+                tx.execute(
+                    &format!(
+                        "UPDATE {} SET
+                            total_power = encode_power(decode_power_previous(total_power)),
+                            import_power = encode_power(decode_power_previous(import_power)),
+                            export_power = encode_power(decode_power_previous(export_power))",
+                        table
+                    ),
+                    [],
+                )?;
+            }
+        }
+
+        tx.execute(
+            "INSERT OR REPLACE INTO db_settings (key, value) VALUES ('power_encoding', ?1)",
+            params![configured.to_string()],
+        )?;
+        tx.commit()?;
+        Ok(configured)
+    }
+
+    /// Only for write paths. A meter created here is named after its key
+    /// until `register_meter` sets its display name.
+    fn get_or_create_meter_id(&self, meter_key: &str) -> Result<i64, Box<dyn std::error::Error>> {
         let mut cache = self.meter_cache.lock().unwrap();
         
-        if let Some(&id) = cache.get(meter_name) {
+        if let Some(&id) = cache.get(meter_key) {
             return Ok(id);
         }
 
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "INSERT OR IGNORE INTO meter_names (name) 
-             SELECT ?1 WHERE NOT EXISTS (
-                SELECT 1 FROM meter_names WHERE meter_id = 255
-             )",
-            params![meter_name],
+            "INSERT OR IGNORE INTO meter_names (meter_key, name) VALUES (?1, ?1)",
+            params![meter_key],
         )?;
 
-        let meter_id: u8 = conn.query_row(
-            "SELECT meter_id FROM meter_names WHERE name = ?1",
-            params![meter_name],
+        let meter_id: i64 = conn.query_row(
+            "SELECT meter_id FROM meter_names WHERE meter_key = ?1",
+            params![meter_key],
             |row| row.get(0),
-        ).map_err(|_| "Failed to create meter ID - maximum number of meters (256) reached")?;
+        )?;
 
-        cache.insert(meter_name.to_string(), meter_id);
+        cache.insert(meter_key.to_string(), meter_id);
         Ok(meter_id)
     }
 
     pub fn insert_meter_reading(&self, reading: &Model) -> Result<(), Box<dyn std::error::Error>> {
         let meter_id = self.get_or_create_meter_id(&reading.meter_name)?;
-        let timestamp = i64::try_from(reading.timestamp.timestamp())?;
-
         let conn = self.conn.lock().unwrap();
+        Self::upsert_reading(&conn, meter_id, reading)?;
+        Ok(())
+    }
+
+    /// Merges a row into `meter_readings`, keeping columns the reading leaves empty.
+    fn upsert_reading(conn: &Connection, meter_id: i64, reading: &Model) -> rusqlite::Result<usize> {
         conn.execute(
-            "INSERT OR REPLACE INTO meter_readings 
+            "INSERT INTO meter_readings 
             (meter_id, timestamp, total_power, import_power, export_power, total_kwh)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            VALUES (?1, ?2, encode_power(?3), encode_power(?4), encode_power(?5), ?6)
+            ON CONFLICT (meter_id, timestamp) DO UPDATE SET
+                total_power = COALESCE(excluded.total_power, total_power),
+                import_power = COALESCE(excluded.import_power, import_power),
+                export_power = COALESCE(excluded.export_power, export_power),
+                total_kwh = COALESCE(excluded.total_kwh, total_kwh)",
             params![
                 meter_id,
-                timestamp,
-                Self::f32_to_f16(reading.total_power),
-                Self::f32_to_f16(reading.import_power),
-                Self::f32_to_f16(reading.export_power),
+                reading.timestamp.timestamp(),
+                reading.total_power,
+                reading.import_power,
+                reading.export_power,
                 reading.total_kwh,
             ],
+        )
+    }
+
+    /// Reads `meter_type, unit, location, role, phase, installed_on, removed_on` starting at column `first`.
+    fn metadata_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<MeterMetadata> {
+        let date = |index: usize| -> rusqlite::Result<_> {
+            Ok(row.get::<_, Option<String>>(index)?.and_then(|d| d.parse().ok()))
+        };
+        Ok(MeterMetadata {
+            meter_type: row.get(first)?,
+            unit: row.get(first + 1)?,
+            location: row.get(first + 2)?,
+            role: row.get::<_, Option<String>>(first + 3)?.as_deref().and_then(MeterRole::parse),
+            phase: row.get::<_, Option<String>>(first + 4)?.as_deref().and_then(Phase::parse),
+            installed_on: date(first + 5)?,
+            removed_on: date(first + 6)?,
+        })
+    }
+
+    /// Looks up a meter without creating it, for read paths.
+    fn find_meter_id(conn: &Connection, meter_key: &str) -> rusqlite::Result<Option<i64>> {
+        conn.query_row("SELECT meter_id FROM meter_names WHERE meter_key = ?", [meter_key], |row| row.get(0))
+            .optional()
+    }
+
+    /// Deletes a meter's raw readings before `cutoff`, a day per transaction.
+    /// A dry run counts them.
+    fn delete_raw(&self, meter_id: i64, cutoff: i64, dry_run: bool) -> StorageResult<u64> {
+        let mut deleted = 0;
+        if dry_run {
+            let conn = self.read_connection()?;
+            for table in ["meter_readings", "channel_readings"] {
+                deleted += Self::delete_rows(
+                    &conn,
+                    &format!("FROM {} WHERE meter_id = ?1 AND timestamp < ?2", table),
+                    params![meter_id, cutoff],
+                    true,
+                )?;
+            }
+            return Ok(deleted);
+        }
+        loop {
+            let mut conn = self.get_connection()?;
+            let Some(oldest) = Self::next_source_time(&conn, meter_id, Source::Raw, i64::MIN)?.filter(|&t| t < cutoff) else {
+                break;
+            };
+            let step_end = oldest.saturating_add(86400).min(cutoff);
+            let tx = conn.transaction()?;
+            for table in ["meter_readings", "channel_readings"] {
+                deleted += tx.execute(
+                    &format!("DELETE FROM {} WHERE meter_id = ?1 AND timestamp < ?2", table),
+                    params![meter_id, step_end],
+                )? as u64;
+            }
+            tx.commit()?;
+        }
+        Ok(deleted)
+    }
+
+    /// Stores one polling cycle. Channels with a `meter_readings` column are
+    /// merged into the row for their second, all others go to `channel_readings`.
+    pub fn insert_measurements(
+        &self,
+        meter_name: &str,
+        measurements: &[Measurement],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.insert_measurement_batch(&[(meter_name.to_string(), measurements.to_vec())])
+    }
+
+    fn write_measurements(
+        conn: &Connection,
+        meter_id: i64,
+        meter_name: &str,
+        measurements: &[Measurement],
+    ) -> rusqlite::Result<()> {
+        let mut rows: Vec<Model> = Vec::new();
+        let mut extra = Vec::new();
+        for m in measurements {
+            let timestamp = Utc.timestamp_opt(m.timestamp.timestamp(), 0).unwrap();
+            let row = match rows.iter_mut().find(|r| r.timestamp == timestamp) {
+                Some(row) => row,
+                None => {
+                    rows.push(Model::empty(meter_name.to_string(), timestamp));
+                    rows.last_mut().unwrap()
+                }
+            };
+            if !row.set_value(m.channel, m.value) {
+                extra.push(m);
+            }
+        }
+
+        for row in rows.iter().filter(|r| Channel::COLUMNS.iter().any(|&c| r.value(c).is_some())) {
+            Self::upsert_reading(conn, meter_id, row)?;
+        }
+        for m in extra {
+            conn.execute(
+                "INSERT OR REPLACE INTO channel_readings (meter_id, channel, timestamp, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![meter_id, m.channel.name(), m.timestamp.timestamp(), m.value],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Where the raw readings of a meter start and how far each tier is rolled up.
+    fn coverage(conn: &Connection, meter_id: i64) -> rusqlite::Result<Coverage> {
+        Ok(Coverage {
+            raw_from: Self::next_source_time(conn, meter_id, Source::Raw, i64::MIN)?,
+            tiers: Self::tier_coverage(conn, meter_id, "rollups")?,
+        })
+    }
+
+    /// (width, oldest bucket in `table`, watermark) of a meter's tiers.
+    fn tier_coverage(conn: &Connection, meter_id: i64, table: &str) -> rusqlite::Result<Vec<(i64, i64, i64)>> {
+        // Tiers with all buckets expired have nothing to offer
+        let mut stmt = conn.prepare(&format!(
+            "SELECT width_secs, oldest, rolled_until FROM (
+                SELECT width_secs, rolled_until,
+                       (SELECT MIN(bucket_start) FROM {} r WHERE r.meter_id = w.meter_id AND r.width_secs = w.width_secs) AS oldest
+                FROM rollup_watermarks w
+                WHERE meter_id = ?1
+             )
+             WHERE oldest IS NOT NULL
+             ORDER BY width_secs",
+            table
+        ))?;
+        let tiers = stmt.query_map([meter_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        tiers.collect()
+    }
+
+    /// Raw readings of one channel in `[from, to)`, oldest first.
+    fn raw_samples(conn: &Connection, meter_id: i64, channel: Channel, from: i64, to: i64) -> rusqlite::Result<Vec<(i64, f64)>> {
+        let range = format!("meter_id = ?1 AND timestamp >= {} AND timestamp < {}", from, to);
+        let mut stmt = conn.prepare(&format!(
+            "SELECT ts, value FROM ({}) WHERE value IS NOT NULL ORDER BY ts",
+            Self::raw_samples_sql(channel, &range)
+        ))?;
+        let samples = stmt.query_map([meter_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        samples.collect()
+    }
+
+    /// `SELECT ts, value` of one channel's raw readings matching `range`.
+    fn raw_samples_sql(channel: Channel, range: &str) -> String {
+        match channel.column() {
+            Some(column) if channel.is_power() => format!(
+                "SELECT timestamp AS ts, decode_power({0}) AS value FROM meter_readings WHERE {1} AND {0} IS NOT NULL",
+                column, range
+            ),
+            Some(column) => format!(
+                "SELECT timestamp AS ts, {0} AS value FROM meter_readings WHERE {1} AND {0} IS NOT NULL",
+                column, range
+            ),
+            None => format!(
+                "SELECT timestamp AS ts, value FROM channel_readings WHERE {} AND channel = '{}'",
+                range, channel.name()
+            ),
+        }
+    }
+
+    /// Newest reading since `since` of each channel energy is computed from.
+    fn newest_energy_readings(conn: &Connection, meter_id: i64, since: i64) -> rusqlite::Result<Vec<i64>> {
+        let range = format!("meter_id = ?1 AND timestamp >= {}", since);
+        let mut newest = Vec::new();
+        for channel in energy::CHANNELS {
+            let sql = format!("SELECT MAX(ts) FROM ({})", Self::raw_samples_sql(channel, &range));
+            newest.extend(conn.query_row(&sql, [meter_id], |row| row.get::<_, Option<i64>>(0))?);
+        }
+        Ok(newest)
+    }
+
+    /// Stores the energy of a meter's `width_secs` buckets in `[start, end)`,
+    /// computed from raw readings or summed up from the `source_width` tier.
+    fn rollup_energy(conn: &Connection, meter_id: i64, width_secs: i64, source_width: Option<i64>, start: i64, end: i64) -> StorageResult<u64> {
+        if let Some(source_width) = source_width {
+            let written = conn.execute(
+                "INSERT OR REPLACE INTO rollup_energy (meter_id, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from)
+                 SELECT meter_id, ?2, bucket_start / ?2 * ?2, SUM(imported_kwh), SUM(exported_kwh),
+                        MAX(import_from & 1) | MAX(import_from & 2), MAX(export_from & 1) | MAX(export_from & 2)
+                 FROM rollup_energy
+                 WHERE meter_id = ?1 AND width_secs = ?3 AND bucket_start >= ?4 AND bucket_start < ?5
+                 GROUP BY 3",
+                params![meter_id, width_secs, source_width, start, end],
+            )?;
+            return Ok(written as u64);
+        }
+
+        // Readings around the buckets too, for the differences reaching into them
+        let lookaround = energy::LOOKAROUND.num_seconds();
+        let samples = EnergySamples::read(|channel| {
+            Ok(Some(Self::raw_samples(conn, meter_id, channel, start - lookaround, end + lookaround)?))
+        })?;
+        let Some(samples) = samples else {
+            return Ok(0);
+        };
+        let mut stmt = conn.prepare(
+            "INSERT OR REPLACE INTO rollup_energy (meter_id, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        let mut written = 0;
+        let (buckets, _) = samples.periods(&rollup::buckets(start, end, width_secs));
+        for bucket in buckets.iter().filter(|b| b.import_source != EnergySource::None || b.export_source != EnergySource::None) {
+            written += stmt.execute(params![
+                meter_id,
+                width_secs,
+                bucket.start,
+                bucket.imported_kwh,
+                bucket.exported_kwh,
+                bucket.import_source.bits(),
+                bucket.export_source.bits(),
+            ])? as u64;
+        }
+        Ok(written)
+    }
+
+    /// Watermark of a meter's `width_secs` tier, its buckets before it are final.
+    fn rolled_until(conn: &Connection, meter_id: i64, width_secs: i64) -> rusqlite::Result<Option<i64>> {
+        conn.query_row(
+            "SELECT rolled_until FROM rollup_watermarks WHERE meter_id = ?1 AND width_secs = ?2",
+            params![meter_id, width_secs],
+            |row| row.get(0),
+        ).optional()
+    }
+
+    /// Moves every watermark of a meter back to the bucket holding `from`, so
+    /// raw readings written behind them, e.g. by an import or merge, are rolled
+    /// up too before raw expiry deletes them.
+    fn rewind_watermarks(conn: &Connection, meter_id: i64, from: i64) -> rusqlite::Result<()> {
+        conn.execute(
+            "UPDATE rollup_watermarks SET rolled_until = MIN(rolled_until, ?2 / width_secs * width_secs) WHERE meter_id = ?1",
+            params![meter_id, from],
         )?;
         Ok(())
     }
 
-    pub fn get_meter_readings(
+    /// Oldest reading or bucket of `source` at or after `from`.
+    fn next_source_time(conn: &Connection, meter_id: i64, source: Source, from: i64) -> rusqlite::Result<Option<i64>> {
+        match source {
+            Source::Raw => conn.query_row(
+                "SELECT MIN(timestamp) FROM (
+                    SELECT MIN(timestamp) AS timestamp FROM meter_readings WHERE meter_id = ?1 AND timestamp >= ?2
+                    UNION ALL
+                    SELECT MIN(timestamp) FROM channel_readings WHERE meter_id = ?1 AND timestamp >= ?2
+                 )",
+                params![meter_id, from],
+                |row| row.get(0),
+            ),
+            Source::Tier(width) => conn.query_row(
+                "SELECT MIN(bucket_start) FROM rollups WHERE meter_id = ?1 AND width_secs = ?2 AND bucket_start >= ?3",
+                params![meter_id, width, from],
+                |row| row.get(0),
+            ),
+        }
+    }
+
+    /// Rows of `source` a rollup of `[start, end)` reads.
+    fn source_rows(conn: &Connection, meter_id: i64, source: Source, start: i64, end: i64) -> rusqlite::Result<u64> {
+        let rows: i64 = match source {
+            Source::Raw => conn.query_row(
+                "SELECT (SELECT COUNT(*) FROM meter_readings WHERE meter_id = ?1 AND timestamp >= ?2 AND timestamp < ?3)
+                      + (SELECT COUNT(*) FROM channel_readings WHERE meter_id = ?1 AND timestamp >= ?2 AND timestamp < ?3)",
+                params![meter_id, start, end],
+                |row| row.get(0),
+            )?,
+            Source::Tier(width) => conn.query_row(
+                "SELECT COUNT(*) FROM rollups WHERE meter_id = ?1 AND width_secs = ?2 AND bucket_start >= ?3 AND bucket_start < ?4",
+                params![meter_id, width, start, end],
+                |row| row.get(0),
+            )?,
+        };
+        Ok(rows as u64)
+    }
+
+    /// `DELETE` with `from_where`, or for a dry run `SELECT COUNT(*)`.
+    fn delete_rows(conn: &Connection, from_where: &str, params: impl rusqlite::Params, dry_run: bool) -> rusqlite::Result<u64> {
+        if dry_run {
+            conn.query_row(&format!("SELECT COUNT(*) {}", from_where), params, |row| row.get::<_, i64>(0).map(|n| n as u64))
+        } else {
+            conn.execute(&format!("DELETE {}", from_where), params).map(|n| n as u64)
+        }
+    }
+
+    /// One `SELECT channel, ts, count, sum, min, max, first, last` per segment
+    /// of meter `?1`, raw readings counting as buckets of one value. All
+    /// channels if `channels` is empty.
+    fn partials(segments: &[Segment], channels: &[Channel]) -> Vec<String> {
+        let wanted = |channel: &Channel| channels.is_empty() || channels.contains(channel);
+        let names = |channels: &mut dyn Iterator<Item = &Channel>| {
+            channels.map(|channel| format!("'{}'", channel.name())).collect::<Vec<_>>().join(", ")
+        };
+        let extra = names(&mut channels.iter().filter(|channel| channel.column().is_none()));
+
+        let mut partials = Vec::new();
+        for segment in segments {
+            match segment.source {
+                Source::Raw => {
+                    let range = format!("meter_id = ?1 AND timestamp >= {} AND timestamp < {}", segment.start, segment.end);
+                    let mut samples: Vec<String> = Channel::COLUMNS.iter()
+                        .filter(|channel| wanted(channel))
+                        .map(|channel| {
+                            let column = channel.column().unwrap();
+                            let value = if channel.is_power() { format!("decode_power({})", column) } else { column.to_string() };
+                            format!(
+                                "SELECT '{}' AS channel, timestamp AS ts, {} AS value FROM meter_readings WHERE {} AND {} IS NOT NULL",
+                                channel.name(), value, range, column
+                            )
+                        })
+                        .collect();
+                    if channels.is_empty() {
+                        samples.push(format!("SELECT channel, timestamp AS ts, value FROM channel_readings WHERE {}", range));
+                    } else if !extra.is_empty() {
+                        samples.push(format!(
+                            "SELECT channel, timestamp AS ts, value FROM channel_readings WHERE {} AND channel IN ({})",
+                            range, extra
+                        ));
+                    }
+                    if !samples.is_empty() {
+                        // decode_power() turns NaN into NULL
+                        partials.push(format!(
+                            "SELECT channel, ts, 1, value, value, value, value, value FROM ({}) WHERE value IS NOT NULL",
+                            samples.join(" UNION ALL ")
+                        ));
+                    }
+                }
+                Source::Tier(width) => {
+                    let filter = if channels.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" AND channel IN ({})", names(&mut channels.iter()))
+                    };
+                    partials.push(format!(
+                        "SELECT channel, bucket_start, count, avg * count,
+                                COALESCE(min, avg), COALESCE(max, avg), COALESCE(first, avg), COALESCE(last, avg)
+                         FROM rollups
+                         WHERE meter_id = ?1 AND width_secs = {} AND bucket_start >= {} AND bucket_start < {}{}",
+                        width, segment.start, segment.end, filter
+                    ));
+                }
+            }
+        }
+        partials
+    }
+
+    /// Combines `partials` into `SELECT channel, bucket, count, avg, min, max,
+    /// first, last` per channel and `bucket` expression over `ts`.
+    fn aggregate_sql(partials: &[String], bucket: &str) -> String {
+        // first and last come from window functions, they are the same on every row of a bucket
+        format!(
+            "WITH partials(channel, ts, count, sum, min, max, first, last) AS ({}),
+             keyed AS (SELECT *, {} AS bucket FROM partials),
+             bucketed AS (
+                SELECT channel, bucket, count, sum, min, max,
+                       FIRST_VALUE(first) OVER (PARTITION BY channel, bucket ORDER BY ts) AS first_value,
+                       FIRST_VALUE(last) OVER (PARTITION BY channel, bucket ORDER BY ts DESC) AS last_value
+                FROM keyed
+             )
+             SELECT channel, bucket, SUM(count) AS count, TOTAL(sum) / SUM(count) AS avg, MIN(min) AS min, MAX(max) AS max,
+                    MAX(first_value) AS first, MAX(last_value) AS last
+             FROM bucketed
+             GROUP BY channel, bucket
+             ORDER BY channel, bucket",
+            partials.join(" UNION ALL "),
+            bucket
+        )
+    }
+}
+
+impl Storage for DatabaseSync {
+    fn insert_measurement_batch(
         &self,
-        meter_name: &str,
+        batch: &[(String, Vec<Measurement>)],
+    ) -> StorageResult<()> {
+        let meter_ids = batch
+            .iter()
+            .map(|(meter_key, _)| self.get_or_create_meter_id(meter_key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for ((meter_key, measurements), meter_id) in batch.iter().zip(meter_ids) {
+            Self::write_measurements(&tx, meter_id, meter_key, measurements)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn import_measurements(&self, meter_key: &str, measurements: &[Measurement]) -> StorageResult<ImportCounts> {
+        let meter_id = self.get_or_create_meter_id(meter_key)?;
+        let mut by_time: BTreeMap<i64, Vec<&Measurement>> = BTreeMap::new();
+        for m in measurements {
+            by_time.entry(m.timestamp.timestamp()).or_default().push(m);
+        }
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut counts = ImportCounts::default();
+        {
+            let mut row_exists = tx.prepare("SELECT 1 FROM meter_readings WHERE meter_id = ?1 AND timestamp = ?2")?;
+            let mut channel_exists = tx.prepare(
+                "SELECT 1 FROM channel_readings WHERE meter_id = ?1 AND channel = ?2 AND timestamp = ?3",
+            )?;
+            let mut insert_channel = tx.prepare(
+                "INSERT INTO channel_readings (meter_id, channel, timestamp, value) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (timestamp, group) in by_time {
+                let mut duplicate = row_exists.exists(params![meter_id, timestamp])?;
+                for m in group.iter().filter(|m| m.channel.column().is_none()) {
+                    duplicate = duplicate || channel_exists.exists(params![meter_id, m.channel.name(), timestamp])?;
+                }
+                if duplicate {
+                    counts.duplicates += 1;
+                    continue;
+                }
+
+                let mut row = Model::empty(meter_key.to_string(), Utc.timestamp_opt(timestamp, 0).unwrap());
+                for m in group {
+                    if !row.set_value(m.channel, m.value) {
+                        insert_channel.execute(params![meter_id, m.channel.name(), timestamp, m.value])?;
+                    }
+                }
+                if Channel::COLUMNS.iter().any(|&c| row.value(c).is_some()) {
+                    Self::upsert_reading(&tx, meter_id, &row)?;
+                }
+                if counts.inserted == 0 {
+                    Self::rewind_watermarks(&tx, meter_id, timestamp)?;
+                }
+                counts.inserted += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    fn register_meter(&self, meter_key: &str, name: &str, metadata: &MeterMetadata) -> StorageResult<()> {
+        if meter_key != name {
+            let mut cache = self.meter_cache.lock().unwrap();
+            let conn = self.conn.lock().unwrap();
+            let known = Self::find_meter_id(&conn, meter_key)?.is_some();
+            if !known && conn.execute("UPDATE meter_names SET meter_key = ?1 WHERE meter_key = ?2", params![meter_key, name])? > 0 {
+                info!("Meter {} is now identified by its key {}", name, meter_key);
+                cache.remove(name);
+            }
+        }
+
+        let meter_id = self.get_or_create_meter_id(meter_key)?;
+        let conn = self.conn.lock().unwrap();
+        let previous: (String, Option<i64>) = conn.query_row(
+            "SELECT name, retired_at FROM meter_names WHERE meter_id = ?1",
+            params![meter_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if previous.0 != name {
+            info!("Meter {} renamed from {} to {}", meter_key, previous.0, name);
+        }
+        if previous.1.is_some() {
+            info!("Meter {} is configured again, no longer retired", meter_key);
+        }
+        conn.execute(
+            "UPDATE meter_names
+             SET name = ?1, retired_at = NULL, meter_type = ?3, unit = ?4, location = ?5,
+                 role = ?6, phase = ?7, installed_on = ?8, removed_on = ?9
+             WHERE meter_id = ?2",
+            params![
+                name,
+                meter_id,
+                metadata.meter_type,
+                metadata.unit,
+                metadata.location,
+                metadata.role.map(|r| r.as_str()),
+                metadata.phase.map(|p| p.as_str()),
+                metadata.installed_on.map(|d| d.to_string()),
+                metadata.removed_on.map(|d| d.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn rename_meter(&self, meter_key: &str, new_key: &str) -> StorageResult<bool> {
+        let mut cache = self.meter_cache.lock().unwrap();
+        let conn = self.conn.lock().unwrap();
+        if Self::find_meter_id(&conn, new_key)?.is_some() {
+            return Err(format!("Meter {} already exists, merge into it instead", new_key).into());
+        }
+
+        let renamed = conn.execute(
+            "UPDATE meter_names SET meter_key = ?2 WHERE meter_key = ?1",
+            params![meter_key, new_key],
+        )? > 0;
+        cache.remove(meter_key);
+        Ok(renamed)
+    }
+
+    fn merge_meters(&self, from_key: &str, into_key: &str) -> StorageResult<Option<u64>> {
+        let mut cache = self.meter_cache.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        let (Some(from), Some(into)) = (Self::find_meter_id(&conn, from_key)?, Self::find_meter_id(&conn, into_key)?) else {
+            return Ok(None);
+        };
+        if from == into {
+            return Err("Cannot merge a meter into itself".into());
+        }
+
+        let tx = conn.transaction()?;
+        if let Some(oldest) = Self::next_source_time(&tx, from, Source::Raw, i64::MIN)? {
+            Self::rewind_watermarks(&tx, into, oldest)?;
+        }
+        let mut moved = tx.execute(
+            "INSERT INTO meter_readings (meter_id, timestamp, total_power, import_power, export_power, total_kwh)
+             SELECT ?2, timestamp, total_power, import_power, export_power, total_kwh
+             FROM meter_readings WHERE meter_id = ?1
+             ON CONFLICT (meter_id, timestamp) DO UPDATE SET
+                total_power = COALESCE(total_power, excluded.total_power),
+                import_power = COALESCE(import_power, excluded.import_power),
+                export_power = COALESCE(export_power, excluded.export_power),
+                total_kwh = COALESCE(total_kwh, excluded.total_kwh)",
+            params![from, into],
+        )?;
+        moved += tx.execute(
+            "INSERT OR IGNORE INTO channel_readings (meter_id, channel, timestamp, value)
+             SELECT ?2, channel, timestamp, value FROM channel_readings WHERE meter_id = ?1",
+            params![from, into],
+        )?;
+        // Rollups don't keep when their first and last value were read. Where
+        // both meters have a bucket, the one whose finest tier starts earlier goes first.
+        let from_first: bool = tx.query_row(
+            "SELECT COALESCE((SELECT bucket_start FROM rollups WHERE meter_id = ?1 ORDER BY width_secs, bucket_start LIMIT 1)
+                              < (SELECT bucket_start FROM rollups WHERE meter_id = ?2 ORDER BY width_secs, bucket_start LIMIT 1), FALSE)",
+            params![from, into],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "INSERT INTO rollups (meter_id, width_secs, bucket_start, channel, count, avg, min, max, first, last)
+             SELECT ?2, width_secs, bucket_start, channel, count, avg, min, max, first, last FROM rollups WHERE meter_id = ?1
+             ON CONFLICT (meter_id, width_secs, bucket_start, channel) DO UPDATE SET
+                count = count + excluded.count,
+                avg = (avg * count + excluded.avg * excluded.count) / (count + excluded.count),
+                min = MIN(min, excluded.min),
+                max = MAX(max, excluded.max),
+                first = CASE WHEN ?3 THEN excluded.first ELSE first END,
+                last = CASE WHEN ?3 THEN last ELSE excluded.last END",
+            params![from, into, from_first],
+        )?;
+        // Each meter measured part of a shared bucket, so its energy adds up
+        tx.execute(
+            "INSERT INTO rollup_energy (meter_id, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from)
+             SELECT ?2, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from FROM rollup_energy WHERE meter_id = ?1
+             ON CONFLICT (meter_id, width_secs, bucket_start) DO UPDATE SET
+                imported_kwh = imported_kwh + excluded.imported_kwh,
+                exported_kwh = exported_kwh + excluded.exported_kwh,
+                import_from = import_from | excluded.import_from,
+                export_from = export_from | excluded.export_from",
+            params![from, into],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO meter_channels (meter_id, channel, quantity, unit, phase, direction)
+             SELECT ?2, channel, quantity, unit, phase, direction FROM meter_channels WHERE meter_id = ?1",
+            params![from, into],
+        )?;
+        tx.execute("UPDATE capture_sessions SET meter_id = ?2 WHERE meter_id = ?1", params![from, into])?;
+        for table in ["meter_readings", "channel_readings", "rollups", "rollup_energy", "rollup_watermarks", "meter_channels", "meter_names"] {
+            tx.execute(&format!("DELETE FROM {} WHERE meter_id = ?1", table), params![from])?;
+        }
+        tx.commit()?;
+
+        cache.remove(from_key);
+        info!("Merged {} rows of meter {} into {}", moved, from_key, into_key);
+        Ok(Some(moved as u64))
+    }
+
+    fn retire_meter(&self, meter_key: &str) -> StorageResult<bool> {
+        let conn = self.conn.lock().unwrap();
+        let retired = conn.execute(
+            "UPDATE meter_names SET retired_at = COALESCE(retired_at, ?2) WHERE meter_key = ?1",
+            params![meter_key, Utc::now().timestamp()],
+        )? > 0;
+        Ok(retired)
+    }
+
+    fn register_channels(&self, meter_key: &str, channels: &[Channel]) -> StorageResult<()> {
+        let meter_id = self.get_or_create_meter_id(meter_key)?;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM meter_channels WHERE meter_id = ?1", params![meter_id])?;
+        for channel in channels {
+            tx.execute(
+                "INSERT INTO meter_channels (meter_id, channel, quantity, unit, phase, direction)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    meter_id,
+                    channel.name(),
+                    channel.quantity.as_str(),
+                    channel.unit(),
+                    channel.phase.as_str(),
+                    channel.direction.as_str(),
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_meter_channels(&self) -> StorageResult<HashMap<String, Vec<ChannelInfo>>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT m.meter_key, c.channel
+             FROM meter_channels c
+             JOIN meter_names m ON c.meter_id = m.meter_id
+             ORDER BY m.meter_key, c.rowid",
+        )?;
+
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut channels: HashMap<String, Vec<ChannelInfo>> = HashMap::new();
+        for row in rows {
+            let (meter_key, channel) = row?;
+            if let Some(channel) = Channel::parse(&channel) {
+                channels.entry(meter_key).or_default().push(channel.into());
+            }
+        }
+        Ok(channels)
+    }
+
+    fn get_meter_readings(
+        &self,
+        meter_key: &str,
         start_time: Option<DateTime<Utc>>,
         end_time: Option<DateTime<Utc>>,
-    ) -> Result<Vec<Model>, Box<dyn std::error::Error>> {
-        let meter_id = self.get_or_create_meter_id(meter_name)?;
-        let conn = self.conn.lock().unwrap();
+    ) -> StorageResult<Vec<Model>> {
+        let conn = self.read_connection()?;
+        let Some(meter_id) = Self::find_meter_id(&conn, meter_key)? else {
+            return Ok(Vec::new());
+        };
 
         let mut query = String::from(
-            "SELECT m.name, r.timestamp, r.total_power, r.import_power, r.export_power, r.total_kwh 
+            "SELECT m.name, r.timestamp, decode_power(r.total_power), decode_power(r.import_power),
+                    decode_power(r.export_power), r.total_kwh 
              FROM meter_readings r 
              JOIN meter_names m ON r.meter_id = m.meter_id 
              WHERE r.meter_id = ?"
@@ -186,13 +1053,848 @@ impl DatabaseSync {
             Ok(Model {
                 meter_name: row.get(0)?,
                 timestamp: Utc.timestamp_opt(row.get(1)?, 0).unwrap(),
-                total_power: Self::f16_to_f32(row.get(2)?),
-                import_power: Self::f16_to_f32(row.get(3)?),
-                export_power: Self::f16_to_f32(row.get(4)?),
+                total_power: row.get(2)?,
+                import_power: row.get(3)?,
+                export_power: row.get(4)?,
                 total_kwh: row.get(5)?,
             })
         })?;
 
         Ok(readings.collect::<Result<Vec<_>, _>>()?)
     }
-}
\ No newline at end of file
+
+    fn get_buckets(&self, query: &BucketQuery) -> StorageResult<Option<Vec<ChannelBuckets>>> {
+        let conn = self.read_connection()?;
+        let Some(meter_id) = Self::find_meter_id(&conn, &query.meter_key)? else {
+            return Ok(None);
+        };
+
+        // One read transaction, so retention can't move data between planning and reading
+        let tx = conn.unchecked_transaction()?;
+        let segments = rollup::plan_buckets(query, &Self::coverage(&tx, meter_id)?);
+        let partials = Self::partials(&segments, &query.channels);
+        if partials.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        let sql = Self::aggregate_sql(&partials, "local_bucket(ts, ?2, ?3)");
+
+        let mut stmt = tx.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![meter_id, query.width_secs, query.timezone.name()],
+            |row| {
+                let channel: String = row.get(0)?;
+                Ok((channel, BucketStats {
+                    start: row.get(1)?,
+                    count: row.get(2)?,
+                    avg: row.get(3)?,
+                    min: row.get(4)?,
+                    max: row.get(5)?,
+                    first: row.get(6)?,
+                    last: row.get(7)?,
+                }))
+            },
+        )?;
+
+        let mut parsed = Vec::new();
+        for row in rows {
+            let (name, stats) = row?;
+            if let Some(channel) = Channel::parse(&name) {
+                parsed.push((channel, stats));
+            }
+        }
+        Ok(Some(query::collect_series(parsed)))
+    }
+
+    fn get_channel_samples(
+        &self,
+        meter_key: &str,
+        channel: Channel,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> StorageResult<Option<Vec<(i64, f64)>>> {
+        let conn = self.read_connection()?;
+        let Some(meter_id) = Self::find_meter_id(&conn, meter_key)? else {
+            return Ok(None);
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let segments = rollup::plan_samples(start.timestamp(), end.timestamp(), &Self::coverage(&tx, meter_id)?);
+        let selects: Vec<String> = segments.iter().map(|segment| match segment.source {
+            Source::Raw => {
+                let range = format!("meter_id = ?1 AND timestamp >= {} AND timestamp < {}", segment.start, segment.end);
+                Self::raw_samples_sql(channel, &range)
+            }
+            Source::Tier(width) => {
+                let (offset, value) = if channel.quantity == Quantity::Energy { (width - 1, "COALESCE(last, avg)") } else { (width / 2, "avg") };
+                format!(
+                    "SELECT bucket_start + {}, {} FROM rollups
+                     WHERE meter_id = ?1 AND width_secs = {} AND channel = '{}' AND bucket_start >= {} AND bucket_start < {}",
+                    offset, value, width, channel.name(), segment.start, segment.end
+                )
+            }
+        }).collect();
+        if selects.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+
+        let mut stmt = tx.prepare(&format!("{} ORDER BY 1", selects.join(" UNION ALL ")))?;
+        let samples = stmt.query_map([meter_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(Some(samples.collect::<Result<Vec<_>, _>>()?))
+    }
+
+    fn get_rolled_energy(&self, meter_key: &str, start: i64, end: i64, edges: &[i64]) -> StorageResult<Option<RolledEnergy>> {
+        let conn = self.read_connection()?;
+        let Some(meter_id) = Self::find_meter_id(&conn, meter_key)? else {
+            return Ok(None);
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        let coverage = Coverage {
+            raw_from: Self::next_source_time(&tx, meter_id, Source::Raw, i64::MIN)?,
+            tiers: Self::tier_coverage(&tx, meter_id, "rollup_energy")?,
+        };
+        let segments = rollup::plan_energy(start, end, edges, &coverage);
+        let mut stmt = tx.prepare(
+            "SELECT bucket_start, imported_kwh, exported_kwh, import_from, export_from FROM rollup_energy
+             WHERE meter_id = ?1 AND width_secs = ?2 AND bucket_start >= ?3 AND bucket_start < ?4
+             ORDER BY bucket_start",
+        )?;
+        let mut buckets = Vec::new();
+        for segment in &segments {
+            let Source::Tier(width) = segment.source else {
+                continue;
+            };
+            let rows = stmt.query_map(params![meter_id, width, segment.start, segment.end], |row| {
+                Ok(PeriodEnergy {
+                    start: row.get(0)?,
+                    end: row.get::<_, i64>(0)? + width,
+                    imported_kwh: row.get(1)?,
+                    exported_kwh: row.get(2)?,
+                    import_source: EnergySource::from_bits(row.get(3)?),
+                    export_source: EnergySource::from_bits(row.get(4)?),
+                })
+            })?;
+            for row in rows {
+                buckets.push(row?);
+            }
+        }
+        Ok(Some(RolledEnergy { buckets, uncovered: rollup::uncovered(start, end, &segments) }))
+    }
+
+    fn latest_readings(&self) -> StorageResult<Vec<MeterSummary>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT 
+                m.meter_key,
+                m.name,
+                m.retired_at,
+                m.meter_type, m.unit, m.location, m.role, m.phase, m.installed_on, m.removed_on,
+                r.last_reading,
+                (SELECT MAX(timestamp) FROM channel_readings c WHERE c.meter_id = m.meter_id) as last_channel_reading,
+                -- Rollups only tell which bucket the last reading fell into
+                (SELECT MAX(bucket_start) FROM rollups u WHERE u.meter_id = m.meter_id) as last_rollup,
+                COALESCE(
+                    (SELECT decode_power(total_power) 
+                     FROM meter_readings mr2 
+                     WHERE mr2.meter_id = m.meter_id
+                     AND mr2.total_power IS NOT NULL
+                     ORDER BY timestamp DESC 
+                     LIMIT 1),
+                    (SELECT last FROM rollups u
+                     WHERE u.meter_id = m.meter_id AND u.channel = ?1 AND u.last IS NOT NULL
+                     ORDER BY bucket_start DESC, width_secs
+                     LIMIT 1)
+                ) as last_power,
+                COALESCE(
+                    r.total_readings,
+                    (SELECT COUNT(DISTINCT timestamp) FROM channel_readings c WHERE c.meter_id = m.meter_id)
+                ) as total_readings
+             FROM meter_names m
+             LEFT JOIN (
+                SELECT meter_id, MAX(timestamp) as last_reading, COUNT(*) as total_readings
+                FROM meter_readings
+                GROUP BY meter_id
+             ) r ON r.meter_id = m.meter_id
+             ORDER BY m.meter_key"
+        )?;
+        let meters = stmt.query_map(params![Channel::TOTAL_POWER.name()], |row| {
+            let last: [Option<i64>; 3] = [row.get(10)?, row.get(11)?, row.get(12)?];
+            Ok(MeterSummary {
+                meter_key: row.get(0)?,
+                meter_name: row.get(1)?,
+                retired_at: row.get(2)?,
+                metadata: Self::metadata_from_row(row, 3)?,
+                last_reading_timestamp: last.into_iter().flatten().max(),
+                last_power_reading: row.get(13)?,
+                total_readings: row.get(14)?,
+            })
+        })?;
+        Ok(meters.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn stats(&self) -> StorageResult<StorageStats> {
+        let conn = self.read_connection()?;
+        let (meters_count, last_write, total_records) = conn.query_row(
+            "SELECT COUNT(DISTINCT meter_id), MAX(timestamp), COUNT(*) FROM meter_readings",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        // The WAL holds recent commits until the next checkpoint
+        let size_bytes = [self.database_url.clone(), format!("{}-wal", self.database_url)]
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+
+        Ok(StorageStats {
+            backend: "sqlite",
+            location: self.database_url.clone(),
+            size_bytes,
+            meters_count,
+            last_write,
+            total_records,
+        })
+    }
+
+    /// Copies all pages in a single step from a read connection. In WAL mode
+    /// that is one read transaction, a snapshot that writers don't wait for.
+    fn backup(&self, destination: &Path) -> StorageResult<()> {
+        let source = self.read_connection()?;
+        let mut target = Connection::open(destination)?;
+        let backup = Backup::new(&source, &mut target)?;
+        let mut attempts = 0;
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => break,
+                StepResult::Busy | StepResult::Locked if attempts < BACKUP_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(BUSY_TIMEOUT / BACKUP_ATTEMPTS);
+                }
+                result => return Err(format!("Backup did not complete: {:?}", result).into()),
+            }
+        }
+        drop(backup);
+        // The copy inherits WAL mode, a single self-contained file is easier to move around
+        target.pragma_update(None, "journal_mode", "DELETE")?;
+        Ok(())
+    }
+
+    fn meter_keys(&self) -> StorageResult<Vec<String>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT meter_key FROM meter_names ORDER BY meter_id")?;
+        let keys = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
+        Ok(keys)
+    }
+
+    /// Works `BUCKETS_PER_PASS` buckets at a time, each in its own transaction
+    /// together with the watermark, so batched readings get written in
+    /// between and an interrupted run loses nothing.
+    fn rollup(&self, meter_key: &str, tier: &Tier, source_width: Option<i64>, until: DateTime<Utc>, dry_run: bool) -> StorageResult<RollupCounts> {
+        let Some(meter_id) = Self::find_meter_id(&*self.read_connection()?, meter_key)? else {
+            return Ok(RollupCounts::default());
+        };
+        let width_secs = tier.width_secs;
+        let source = source_width.map_or(Source::Raw, Source::Tier);
+        let bucket = format!("ts / {0} * {0}", width_secs);
+        let kept = |aggregate| rollup::kept_column(tier, aggregate);
+
+        let mut counts = RollupCounts::default();
+        // Where a dry run's passes would have moved the watermark
+        let mut rehearsed = None;
+        loop {
+            let mut conn = self.get_connection()?;
+            let ready = match source_width {
+                Some(source_width) => match Self::rolled_until(&conn, meter_id, source_width)? {
+                    Some(rolled) => rolled.min(until.timestamp()),
+                    None => break,
+                },
+                None => {
+                    let since = until.timestamp() - energy::LOOKAROUND.num_seconds();
+                    energy::complete_until(until.timestamp(), &Self::newest_energy_readings(&conn, meter_id, since)?)
+                }
+            };
+            let watermark = match rehearsed {
+                Some(watermark) => Some(watermark),
+                None => Self::rolled_until(&conn, meter_id, width_secs)?,
+            };
+            let next = Self::next_source_time(&conn, meter_id, source, watermark.unwrap_or(i64::MIN))?;
+
+            let tx = conn.transaction()?;
+            let watermark = match rollup::next_pass(watermark, next, ready, width_secs) {
+                Pass::Roll { start, end } => {
+                    let partials = Self::partials(&[Segment { source, start, end }], &[]);
+                    counts.read += Self::source_rows(&tx, meter_id, source, start, end)?;
+                    counts.written += tx.execute(
+                        &format!(
+                            "INSERT OR REPLACE INTO rollups (meter_id, width_secs, channel, bucket_start, count, avg, min, max, first, last)
+                             SELECT ?1, {}, channel, bucket, count, avg, {}, {}, {}, {} FROM ({})",
+                            width_secs,
+                            kept(Aggregate::Min),
+                            kept(Aggregate::Max),
+                            kept(Aggregate::First),
+                            kept(Aggregate::Last),
+                            Self::aggregate_sql(&partials, &bucket)
+                        ),
+                        [meter_id],
+                    )? as u64;
+                    counts.written += Self::rollup_energy(&tx, meter_id, width_secs, source_width, start, end)?;
+                    end
+                }
+                Pass::Skip(until) => until,
+                Pass::Done => break,
+            };
+            if dry_run {
+                rehearsed = Some(watermark);
+                tx.rollback()?;
+                continue;
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO rollup_watermarks (meter_id, width_secs, rolled_until) VALUES (?1, ?2, ?3)",
+                params![meter_id, width_secs, watermark],
+            )?;
+            tx.commit()?;
+        }
+        counts.rolled_until = match rehearsed {
+            Some(watermark) => Some(watermark),
+            None => Self::rolled_until(&*self.get_connection()?, meter_id, width_secs)?,
+        };
+        Ok(counts)
+    }
+
+    /// Deletes a day of readings per transaction.
+    fn expire_raw(&self, meter_key: &str, before: DateTime<Utc>, width_secs: i64, rolled_until: Option<i64>, dry_run: bool) -> StorageResult<u64> {
+        let (meter_id, cutoff) = {
+            let conn = self.read_connection()?;
+            let Some(meter_id) = Self::find_meter_id(&conn, meter_key)? else {
+                return Ok(0);
+            };
+            let rolled = match rolled_until {
+                Some(rolled) => Some(rolled),
+                None => Self::rolled_until(&conn, meter_id, width_secs)?,
+            };
+            let Some(rolled) = rolled else {
+                return Ok(0);
+            };
+            let newest: Option<i64> = conn.query_row(
+                "SELECT MAX(timestamp) FROM meter_readings WHERE meter_id = ?1",
+                [meter_id],
+                |row| row.get(0),
+            )?;
+            // Whole buckets only, raw readings have to pick up where the rollups end
+            (meter_id, rollup::align_down(newest.unwrap_or(i64::MAX).min(rolled).min(before.timestamp()), width_secs))
+        };
+        self.delete_raw(meter_id, cutoff, dry_run)
+    }
+
+    fn expire_rollups(&self, meter_key: &str, width_secs: i64, before: DateTime<Utc>, dry_run: bool) -> StorageResult<u64> {
+        let conn = self.get_connection()?;
+        let Some(meter_id) = Self::find_meter_id(&conn, meter_key)? else {
+            return Ok(0);
+        };
+        let mut deleted = 0;
+        for table in ["rollups", "rollup_energy"] {
+            deleted += Self::delete_rows(
+                &conn,
+                &format!("FROM {} WHERE meter_id = ?1 AND width_secs = ?2 AND bucket_start + width_secs <= ?3", table),
+                params![meter_id, width_secs, before.timestamp()],
+                dry_run,
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete_before(&self, meter_key: &str, before: DateTime<Utc>, dry_run: bool) -> StorageResult<u64> {
+        let Some(meter_id) = Self::find_meter_id(&*self.read_connection()?, meter_key)? else {
+            return Ok(0);
+        };
+        let mut deleted = self.delete_raw(meter_id, before.timestamp(), dry_run)?;
+        let conn = self.get_connection()?;
+        for table in ["rollups", "rollup_energy"] {
+            deleted += Self::delete_rows(
+                &conn,
+                &format!("FROM {} WHERE meter_id = ?1 AND bucket_start + width_secs <= ?2", table),
+                params![meter_id, before.timestamp()],
+                dry_run,
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    fn oldest_data(&self) -> StorageResult<Option<DateTime<Utc>>> {
+        let oldest: Option<i64> = self.read_connection()?.query_row(
+            "SELECT MIN(timestamp) FROM (
+                SELECT MIN(timestamp) AS timestamp FROM meter_readings
+                UNION ALL
+                SELECT MIN(timestamp) FROM channel_readings
+                UNION ALL
+                SELECT MIN(bucket_start) FROM rollups
+             )",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(oldest.and_then(|t| DateTime::from_timestamp(t, 0)))
+    }
+
+    fn used_bytes(&self) -> StorageResult<u64> {
+        let conn = self.read_connection()?;
+        let pages: i64 = conn.query_row(
+            "SELECT (page_count - freelist_count) * page_size FROM pragma_page_count, pragma_freelist_count, pragma_page_size",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(pages as u64)
+    }
+
+    fn undeletable_bytes(&self) -> StorageResult<u64> {
+        let conn = self.read_connection()?;
+        // dbstat only skips the pages of other tables when asked for one by name
+        let mut statement = conn.prepare(
+            "SELECT name FROM sqlite_schema
+             WHERE rootpage > 0 AND tbl_name NOT IN ('meter_readings', 'channel_readings', 'rollups', 'rollup_energy')",
+        )?;
+        let names = statement.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?;
+        let mut bytes = 0;
+        for name in names {
+            let size: i64 = conn.query_row(
+                "SELECT pgsize FROM dbstat WHERE name = ?1 AND aggregate = TRUE",
+                params![name],
+                |row| row.get(0),
+            )?;
+            bytes += size as u64;
+        }
+        Ok(bytes)
+    }
+
+    fn insert_retention_report(&self, report: &RetentionReport) -> StorageResult<i64> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO retention_runs (started_at, duration_ms, size_before_bytes, size_after_bytes, errors)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                report.started_at,
+                report.duration_ms as i64,
+                report.size_before_bytes as i64,
+                report.size_after_bytes as i64,
+                report.errors.join("\n"),
+            ],
+        )?;
+        let run_id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO retention_run_steps (run_id, meter_key, width_secs, rows_read, rows_written, rows_deleted)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for step in &report.steps {
+                insert.execute(params![
+                    run_id,
+                    step.meter_key,
+                    step.width_secs,
+                    step.rows_read as i64,
+                    step.rows_written as i64,
+                    step.rows_deleted as i64,
+                ])?;
+            }
+        }
+        for table in ["retention_run_steps", "retention_runs"] {
+            tx.execute(&format!("DELETE FROM {} WHERE run_id <= ?1", table), [run_id - REPORTS_KEPT as i64])?;
+        }
+        tx.commit()?;
+        Ok(run_id)
+    }
+
+    fn get_retention_reports(&self, limit: usize) -> StorageResult<Vec<RetentionReport>> {
+        let conn = self.read_connection()?;
+        let mut runs = conn.prepare(
+            "SELECT run_id, started_at, duration_ms, size_before_bytes, size_after_bytes, errors
+             FROM retention_runs ORDER BY run_id DESC LIMIT ?1",
+        )?;
+        let mut reports = runs.query_map([limit as i64], |row| {
+            Ok(RetentionReport {
+                run_id: Some(row.get(0)?),
+                started_at: row.get(1)?,
+                duration_ms: row.get::<_, i64>(2)? as u64,
+                dry_run: false,
+                size_before_bytes: row.get::<_, i64>(3)? as u64,
+                size_after_bytes: row.get::<_, i64>(4)? as u64,
+                steps: Vec::new(),
+                errors: row.get::<_, String>(5)?.lines().map(String::from).collect(),
+                size_limit_skipped: false,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+
+        let mut steps = conn.prepare(
+            "SELECT meter_key, width_secs, rows_read, rows_written, rows_deleted
+             FROM retention_run_steps WHERE run_id = ?1 ORDER BY rowid",
+        )?;
+        for report in &mut reports {
+            report.steps = steps.query_map([report.run_id], |row| {
+                Ok(RetentionStep {
+                    meter_key: row.get(0)?,
+                    width_secs: row.get(1)?,
+                    rows_read: row.get::<_, i64>(2)? as u64,
+                    rows_written: row.get::<_, i64>(3)? as u64,
+                    rows_deleted: row.get::<_, i64>(4)? as u64,
+                })
+            })?.collect::<Result<_, _>>()?;
+        }
+        Ok(reports)
+    }
+
+    fn maintain(&self, task: MaintenanceTask) -> StorageResult<String> {
+        match task {
+            MaintenanceTask::Vacuum => {
+                if Self::enable_incremental_vacuum(&*self.get_connection()?, &self.database_url)? {
+                    return Ok("switched to incremental auto-vacuum, the file was rebuilt".to_string());
+                }
+                let page_size: i64 = self.read_connection()?.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+                let mut freed = 0;
+                loop {
+                    // In chunks, so writes queue up for one chunk at most
+                    let conn = self.get_connection()?;
+                    let free: i64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+                    if free == 0 {
+                        break;
+                    }
+                    conn.execute_batch(&format!("PRAGMA incremental_vacuum({})", VACUUM_PAGES_PER_STEP))?;
+                    let left: i64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+                    if left >= free {
+                        return Err(format!("incremental vacuum freed no pages, {} are left", left).into());
+                    }
+                    freed += free - left;
+                }
+                Ok(format!("{} pages ({} bytes) returned to the file system", freed, freed * page_size))
+            }
+            MaintenanceTask::Optimize => {
+                self.get_connection()?.execute_batch("PRAGMA optimize")?;
+                Ok("optimized".to_string())
+            }
+            MaintenanceTask::Analyze => {
+                self.get_connection()?.execute_batch("ANALYZE")?;
+                Ok("statistics updated".to_string())
+            }
+            MaintenanceTask::Checkpoint => {
+                let (busy, log, checkpointed): (i64, i64, i64) = self.get_connection()?.query_row(
+                    "PRAGMA wal_checkpoint(TRUNCATE)",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?;
+                if log < 0 {
+                    Ok("not in WAL mode".to_string())
+                } else if busy != 0 {
+                    Err(format!("readers kept the WAL busy, {} of {} pages checkpointed", checkpointed, log).into())
+                } else {
+                    Ok(format!("{} pages checkpointed, WAL truncated", checkpointed))
+                }
+            }
+            MaintenanceTask::IntegrityCheck => {
+                let conn = self.read_connection()?;
+                let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+                let problems = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?;
+                if problems != ["ok"] {
+                    return Err(problems.join("\n").into());
+                }
+                Ok("ok".to_string())
+            }
+        }
+    }
+
+    fn record_maintenance(&self, result: &MaintenanceResult) -> StorageResult<()> {
+        self.get_connection()?.execute(
+            "INSERT OR REPLACE INTO maintenance_results (task, finished_at, duration_ms, ok, detail)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![result.task.as_str(), result.finished_at, result.duration_ms as i64, result.ok, result.detail],
+        )?;
+        Ok(())
+    }
+
+    fn maintenance_results(&self) -> StorageResult<Vec<MaintenanceResult>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare("SELECT task, finished_at, duration_ms, ok, detail FROM maintenance_results")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get::<_, i64>(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        let mut results = Vec::new();
+        for row in rows {
+            let (task, finished_at, duration_ms, ok, detail) = row?;
+            if let Some(task) = MaintenanceTask::parse(&task) {
+                results.push(MaintenanceResult { task, finished_at, duration_ms: duration_ms as u64, ok, detail });
+            }
+        }
+        Ok(results)
+    }
+
+    fn create_capture_session(&self, params: &CaptureParams) -> StorageResult<i64> {
+        let meter_id = self.get_or_create_meter_id(&params.meter)?;
+        let channels = params.channels.iter().map(|c| c.name()).collect::<Vec<_>>().join(",");
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO capture_sessions
+             (meter_id, channels, interval_ms, duration_secs, status, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                meter_id,
+                channels,
+                params.interval_ms as i64,
+                params.duration_secs as i64,
+                CaptureStatus::Pending.as_str(),
+                Utc::now().timestamp(),
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn set_capture_running(&self, session_id: i64) -> StorageResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE capture_sessions SET status = ?1, started_at = ?2 WHERE session_id = ?3",
+            params![CaptureStatus::Running.as_str(), Utc::now().timestamp(), session_id],
+        )?;
+        Ok(())
+    }
+
+    fn finish_capture_session(
+        &self,
+        session_id: i64,
+        status: CaptureStatus,
+        error: Option<&str>,
+    ) -> StorageResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE capture_sessions SET status = ?1, finished_at = ?2, error = ?3 WHERE session_id = ?4",
+            params![status.as_str(), Utc::now().timestamp(), error, session_id],
+        )?;
+        Ok(())
+    }
+
+    fn insert_capture_samples(
+        &self,
+        session_id: i64,
+        measurements: &[Measurement],
+    ) -> StorageResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for m in measurements {
+            tx.execute(
+                "INSERT OR REPLACE INTO capture_samples (session_id, timestamp_ms, channel, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![session_id, m.timestamp.timestamp_millis(), m.channel.name(), m.value],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_capture_session(&self, session_id: i64) -> StorageResult<Option<CaptureSession>> {
+        let conn = self.read_connection()?;
+        let session = conn.query_row(
+            "SELECT s.session_id, m.name, s.channels, s.interval_ms, s.duration_secs, s.status,
+                    s.started_at, s.finished_at, s.error,
+                    (SELECT COUNT(DISTINCT timestamp_ms) FROM capture_samples c WHERE c.session_id = s.session_id)
+             FROM capture_sessions s
+             JOIN meter_names m ON s.meter_id = m.meter_id
+             WHERE s.session_id = ?1",
+            params![session_id],
+            |row| {
+                let channels: String = row.get(2)?;
+                let status: String = row.get(5)?;
+                Ok(CaptureSession {
+                    session_id: row.get(0)?,
+                    meter_name: row.get(1)?,
+                    channels: channels.split(',').filter_map(Channel::parse).collect(),
+                    interval_ms: row.get::<_, i64>(3)? as u64,
+                    duration_secs: row.get::<_, i64>(4)? as u64,
+                    status: CaptureStatus::parse(&status).unwrap_or(CaptureStatus::Failed),
+                    started_at: row.get(6)?,
+                    finished_at: row.get(7)?,
+                    error: row.get(8)?,
+                    sample_count: row.get(9)?,
+                })
+            },
+        ).optional()?;
+        Ok(session)
+    }
+
+    fn get_capture_samples(&self, session_id: i64) -> StorageResult<Vec<CaptureSample>> {
+        let conn = self.read_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT timestamp_ms, channel, value
+             FROM capture_samples
+             WHERE session_id = ?1
+             ORDER BY timestamp_ms",
+        )?;
+
+        let rows = stmt.query_map(params![session_id], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, f32>(2)?))
+        })?;
+
+        let mut samples: Vec<CaptureSample> = Vec::new();
+        for row in rows {
+            let (timestamp_ms, channel, value) = row?;
+            let Some(channel) = Channel::parse(&channel) else {
+                continue;
+            };
+            match samples.last_mut() {
+                Some(sample) if sample.timestamp_ms == timestamp_ms => {
+                    sample.values.insert(channel, value);
+                }
+                _ => samples.push(CaptureSample {
+                    timestamp_ms,
+                    values: HashMap::from([(channel, value)]),
+                }),
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Bytes this process may still write on the file system holding `path`,
+/// `None` if unknown.
+#[cfg(unix)]
+fn free_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `dir` is NUL-terminated and `stat` lives for the call
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_bytes(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PowerEncodingKind;
+    use crate::testing::TempDb;
+
+    /// An in-memory database holding one reading of `watts`, written as `encoding`.
+    fn database(encoding: PowerEncoding, watts: f32) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, ":memory:", true).unwrap();
+        encoding.register_sql_functions(&conn).unwrap();
+        conn.execute_batch("INSERT INTO meter_names (meter_key, name) VALUES ('Roof', 'Roof')").unwrap();
+        conn.execute(
+            "INSERT INTO meter_readings (meter_id, timestamp, total_power) VALUES (1, 1700000000, encode_power(?1))",
+            params![watts],
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO quarantined_readings (meter_id, timestamp, total_power, reason, quarantined_at)
+             VALUES (1, 1600000000, encode_power(?1), 'bad_timestamp', 1700000000)",
+            params![watts],
+        ).unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO db_settings (key, value) VALUES ('power_encoding', ?1)",
+            params![encoding.to_string()],
+        ).unwrap();
+        conn
+    }
+
+    /// Power of the reading and of its quarantined copy.
+    fn stored_watts(conn: &Connection) -> (f32, f32) {
+        let watts = |table: &str| conn.query_row(
+            &format!("SELECT decode_power(total_power) FROM {}", table), [], |row| row.get(0),
+        ).unwrap();
+        (watts("meter_readings"), watts("quarantined_readings"))
+    }
+
+    /// Backups written next to `database_url`, removed before returning.
+    fn take_backups(database_url: &Path) -> Vec<String> {
+        let prefix = format!("{}.", database_url.file_name().unwrap().to_str().unwrap());
+        let mut backups: Vec<String> = fs::read_dir(database_url.parent().unwrap()).unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with(&prefix) && name.ends_with(".bak"))
+            .collect();
+        for name in &backups {
+            fs::remove_file(database_url.with_file_name(name)).unwrap();
+        }
+        backups.sort();
+        backups
+    }
+
+    fn storage(power_encoding: PowerEncodingKind) -> StorageConfig {
+        StorageConfig { power_encoding, ..StorageConfig::default() }
+    }
+
+    #[test]
+    fn reencodes_f16_after_a_backup() {
+        let temp = TempDb::new("reencode");
+        let url = temp.url();
+
+        let conn = database(PowerEncoding::F16, 1500.0);
+        let encoding = DatabaseSync::apply_power_encoding(&conn, url, &storage(PowerEncodingKind::F32)).unwrap();
+        assert_eq!(encoding, PowerEncoding::F32);
+        assert_eq!(stored_watts(&conn), (1500.0, 1500.0));
+        let backups = take_backups(temp.path());
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].contains(".db.f16-"), "{:?}", backups);
+
+        // The scale is part of the encoding, a new one re-encodes again
+        let conn = database(PowerEncoding::Scaled(10), 1234.5);
+        let config = StorageConfig { power_scale: 100, ..storage(PowerEncodingKind::Scaled) };
+        DatabaseSync::apply_power_encoding(&conn, url, &config).unwrap();
+        assert_eq!(stored_watts(&conn), (1234.5, 1234.5));
+        let raw: i64 = conn.query_row("SELECT total_power FROM meter_readings", [], |row| row.get(0)).unwrap();
+        assert_eq!(raw, 123450);
+        assert_eq!(take_backups(temp.path()).len(), 1);
+
+        // Nothing to re-encode, nothing to back up
+        DatabaseSync::apply_power_encoding(&conn, url, &config).unwrap();
+        assert!(take_backups(temp.path()).is_empty());
+    }
+
+    #[test]
+    fn refuses_lossy_reencode_unless_allowed() {
+        let temp = TempDb::new("lossy");
+        let url = temp.url();
+
+        let conn = database(PowerEncoding::F32, 20001.0);
+        let err = DatabaseSync::apply_power_encoding(&conn, url, &storage(PowerEncodingKind::F16)).unwrap_err();
+        assert!(err.to_string().contains("allow_lossy_power_encoding"), "{}", err);
+        assert_eq!(stored_watts(&conn), (20001.0, 20001.0));
+        assert!(take_backups(temp.path()).is_empty());
+
+        let config = StorageConfig { allow_lossy_power_encoding: true, ..storage(PowerEncodingKind::F16) };
+        DatabaseSync::apply_power_encoding(&conn, url, &config).unwrap();
+        assert_eq!(stored_watts(&conn), (20000.0, 20000.0));
+        assert_eq!(take_backups(temp.path()).len(), 1);
+    }
+
+    #[test]
+    fn reads_do_not_wait_for_the_writer() {
+        let temp = TempDb::new("read-pool");
+        let storage = StorageConfig { read_connections: 1, ..StorageConfig::default() };
+        let db = DatabaseSync::new(temp.url(), true, &storage).unwrap();
+        let count = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM meter_names", [], |row| row.get(0)).unwrap()
+        };
+
+        // A write transaction in progress neither blocks readers nor shows to them
+        let writer = db.get_connection().unwrap();
+        writer.execute_batch("BEGIN IMMEDIATE; INSERT INTO meter_names (meter_key, name) VALUES ('Roof', 'Roof');").unwrap();
+        let reader = db.read_connection().unwrap();
+        assert_eq!(count(&reader), 0);
+        assert!(reader.execute_batch("DELETE FROM meter_names").is_err());
+        writer.execute_batch("COMMIT").unwrap();
+        drop(writer);
+        assert_eq!(count(&reader), 1);
+
+        // With every connection borrowed, the next reader waits for one to come back
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| count(&db.read_connection().unwrap()));
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(!waiting.is_finished());
+            drop(reader);
+            assert_eq!(waiting.join().unwrap(), 1);
+        });
+    }
+}
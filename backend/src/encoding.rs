@@ -0,0 +1,92 @@
+use half::f16;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::Connection;
+use std::fmt;
+
+/// How power columns of `meter_readings` are stored.
+///
+/// `F16` is the original half-precision format: compact, but its resolution
+/// drops to 16 W at 20 kW and values above 65504 W become infinity. `F32` and
+/// `Scaled` (a fixed number of integer steps per watt, e.g. 10 for deci-watts)
+/// are lossless for any realistic installation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEncoding {
+    F16,
+    F32,
+    Scaled(u32),
+}
+
+impl PowerEncoding {
+    pub fn encode(&self, value: f32) -> Value {
+        match self {
+            PowerEncoding::F16 => Value::Integer(f16::from_f32(value).to_bits() as i16 as i64),
+            PowerEncoding::F32 => Value::Real(value as f64),
+            PowerEncoding::Scaled(scale) => {
+                let scaled = (value as f64 * *scale as f64).round();
+                Value::Integer(scaled.clamp(i32::MIN as f64, i32::MAX as f64) as i64)
+            }
+        }
+    }
+
+    pub fn decode(&self, value: ValueRef<'_>) -> Option<f32> {
+        let number = match value {
+            ValueRef::Integer(i) => i as f64,
+            ValueRef::Real(r) => r,
+            _ => return None,
+        };
+        Some(match self {
+            PowerEncoding::F16 => f16::from_bits(number as i64 as i16 as u16).to_f32(),
+            PowerEncoding::F32 => number as f32,
+            PowerEncoding::Scaled(scale) => (number / *scale as f64) as f32,
+        })
+    }
+
+    /// Parses the form stored in `db_settings`, e.g. `f16` or `scaled:10`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "f16" => Some(PowerEncoding::F16),
+            "f32" => Some(PowerEncoding::F32),
+            _ => value
+                .strip_prefix("scaled:")
+                .and_then(|scale| scale.parse().ok())
+                .filter(|&scale| scale > 0)
+                .map(PowerEncoding::Scaled),
+        }
+    }
+
+    /// Registers `encode_power(x)` and `decode_power(x)` on `conn` so SQL can
+    /// work with real watt values regardless of the storage format.
+    pub fn register_sql_functions(&self, conn: &Connection) -> rusqlite::Result<()> {
+        self.register_as(conn, "encode_power", "decode_power")
+    }
+
+    /// Same as `register_sql_functions` under different names, used to read
+    /// one encoding while writing another during a migration.
+    pub fn register_as(&self, conn: &Connection, encode_name: &str, decode_name: &str) -> rusqlite::Result<()> {
+        let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+        let encoding = *self;
+        conn.create_scalar_function(encode_name, 1, flags, move |ctx| {
+            let value: Option<f64> = ctx.get(0)?;
+            Ok(value.map(|v| encoding.encode(v as f32)))
+        })?;
+
+        let encoding = *self;
+        conn.create_scalar_function(decode_name, 1, flags, move |ctx| {
+            Ok(encoding.decode(ctx.get_raw(0)).map(|v| v as f64))
+        })?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for PowerEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerEncoding::F16 => write!(f, "f16"),
+            PowerEncoding::F32 => write!(f, "f32"),
+            PowerEncoding::Scaled(scale) => write!(f, "scaled:{}", scale),
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::meters::{Channel, Direction, Phase, Quantity};
+use crate::storage::{Storage, StorageResult};
+
+/// Counter increases faster than this are a meter replacement, not consumption.
+pub const MAX_METER_POWER_KW: f64 = 100.0;
+/// Power samples further apart than this are a gap and are not integrated.
+const MAX_INTEGRATION_GAP: i64 = 3600;
+/// How far outside the requested range samples are read, so counter deltas
+/// spanning a period boundary are split between both periods.
+pub const LOOKAROUND: TimeDelta = TimeDelta::days(1);
+/// Upper bound on periods in one query.
+pub const MAX_PERIODS: usize = 10_000;
+
+const ENERGY_IMPORT: Channel = Channel::new(Quantity::Energy, Phase::All, Direction::Import);
+const ENERGY_EXPORT: Channel = Channel::new(Quantity::Energy, Phase::All, Direction::Export);
+
+/// Channels energy is computed from.
+pub const CHANNELS: [Channel; 6] = [
+    ENERGY_IMPORT,
+    Channel::TOTAL_KWH,
+    ENERGY_EXPORT,
+    Channel::IMPORT_POWER,
+    Channel::EXPORT_POWER,
+    Channel::TOTAL_POWER,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// First day of the period containing `date`. Weeks start on Monday.
+    fn first_day(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date - Days::new(date.weekday().num_days_from_monday() as u64),
+            Period::Month => date.with_day(1).unwrap(),
+            Period::Year => date.with_ordinal(1).unwrap(),
+        }
+    }
+
+    fn next(&self, first_day: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => first_day + Days::new(1),
+            Period::Week => first_day + Days::new(7),
+            Period::Month => first_day + Months::new(1),
+            Period::Year => first_day + Months::new(12),
+        }
+    }
+}
+
+/// Where the energy of a period came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnergySource {
+    /// Differences of the meter's kWh counter
+    Counter,
+    /// Integrated power, the counter has no data for this period
+    Power,
+    /// Counter where it has data, integrated power for the rest
+    Mixed,
+    None,
+}
+
+impl EnergySource {
+    fn from_usage(counter: bool, power: bool) -> Self {
+        match (counter, power) {
+            (true, false) => EnergySource::Counter,
+            (false, true) => EnergySource::Power,
+            (true, true) => EnergySource::Mixed,
+            (false, false) => EnergySource::None,
+        }
+    }
+
+    /// Stored with rollup buckets: 1 if the counter was used, 2 if power was.
+    pub fn bits(self) -> i64 {
+        match self {
+            EnergySource::Counter => 1,
+            EnergySource::Power => 2,
+            EnergySource::Mixed => 3,
+            EnergySource::None => 0,
+        }
+    }
+
+    pub fn from_bits(bits: i64) -> Self {
+        Self::from_usage(bits & 1 != 0, bits & 2 != 0)
+    }
+
+    fn combine(self, other: Self) -> Self {
+        Self::from_bits(self.bits() | other.bits())
+    }
+}
+
+/// Energy of one meter in one local period `[start, end)`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeriodEnergy {
+    pub start: i64,  // Unix timestamp
+    pub end: i64,
+    pub imported_kwh: f64,
+    pub exported_kwh: f64,
+    pub import_source: EnergySource,
+    pub export_source: EnergySource,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MeterEnergy {
+    pub meter_key: String,
+    pub period: Period,
+    pub periods: Vec<PeriodEnergy>,
+    /// Counter decreases or implausible jumps, i.e. resets and meter replacements
+    pub counter_resets: usize,
+}
+
+/// Local periods covering `[start, end)` as unix timestamp pairs.
+pub fn period_bounds(period: Period, start: DateTime<Utc>, end: DateTime<Utc>, timezone: Tz) -> Result<Vec<(i64, i64)>, String> {
+    if end < start {
+        return Err("end must not be before start".to_string());
+    }
+
+    let mut day = period.first_day(start.with_timezone(&timezone).date_naive());
+    let mut bounds = Vec::new();
+    loop {
+        let next = period.next(day);
+        bounds.push((local_midnight(day, timezone), local_midnight(next, timezone)));
+        if bounds.len() > MAX_PERIODS {
+            return Err(format!("query would return more than {} periods", MAX_PERIODS));
+        }
+        if local_midnight(next, timezone) >= end.timestamp() {
+            return Ok(bounds);
+        }
+        day = next;
+    }
+}
+
+/// Start of `date` in `timezone`, or the first instant after it if midnight
+/// falls into a DST gap.
+fn local_midnight(date: NaiveDate, timezone: Tz) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    (0..3)
+        .find_map(|hours| timezone.from_local_datetime(&(midnight + TimeDelta::hours(hours))).earliest())
+        .map(|t| t.timestamp())
+        .unwrap_or_else(|| midnight.and_utc().timestamp())
+}
+
+/// Imported and exported energy of one meter per period. `None` if the meter is unknown.
+///
+/// Imports come from the `energy_import` counter, or `energy_total` for meters
+/// without one that exported nothing, since it counts both directions;
+/// exports from `energy_export`. Each counter difference is
+/// spread evenly over the time between its two readings, so a gap in the data
+/// still adds up to the right total and is split across the periods it spans.
+/// A counter that goes backwards or rises faster than `MAX_METER_POWER_KW`
+/// was reset or replaced: that one difference is dropped and counting resumes
+/// from the new value. Wherever the counter has no data, `power_import` and
+/// `power_export` (or the sign of `power`) are integrated instead.
+///
+/// Where rollups exist, the energy they stored per bucket is used, computed
+/// the same way when they were rolled up, so totals don't change once raw
+/// readings expire.
+pub fn meter_energy(
+    db: &dyn Storage,
+    meter_key: &str,
+    period: Period,
+    bounds: &[(i64, i64)],
+) -> StorageResult<Option<MeterEnergy>> {
+    let (Some(&(first, _)), Some(&(_, last))) = (bounds.first(), bounds.last()) else {
+        return Ok(None);
+    };
+    let edges: Vec<i64> = bounds.iter().flat_map(|&(start, end)| [start, end]).collect();
+    let Some(rolled) = db.get_rolled_energy(meter_key, first, last, &edges)? else {
+        return Ok(None);
+    };
+
+    let mut periods: Vec<PeriodEnergy> = bounds.iter()
+        .map(|&(start, end)| PeriodEnergy {
+            start,
+            end,
+            imported_kwh: 0.0,
+            exported_kwh: 0.0,
+            import_source: EnergySource::None,
+            export_source: EnergySource::None,
+        })
+        .collect();
+    for bucket in &rolled.buckets {
+        add(&mut periods, bucket);
+    }
+
+    let mut counter_resets = 0;
+    for &(start, end) in &rolled.uncovered {
+        let from = DateTime::from_timestamp(start, 0).ok_or("Invalid period start")? - LOOKAROUND;
+        let to = DateTime::from_timestamp(end, 0).ok_or("Invalid period end")? + LOOKAROUND;
+        let Some(samples) = EnergySamples::read(|channel| db.get_channel_samples(meter_key, channel, from, to))? else {
+            return Ok(None);
+        };
+        // Only the part of each period in this span, the rest comes from rollups
+        let clipped: Vec<(i64, i64)> = bounds.iter().map(|&(s, e)| (s.max(start), e.min(end))).collect();
+        let (parts, resets) = samples.periods(&clipped);
+        for part in &parts {
+            add(&mut periods, part);
+        }
+        counter_resets += resets;
+    }
+
+    Ok(Some(MeterEnergy {
+        meter_key: meter_key.to_string(),
+        period,
+        periods,
+        counter_resets,
+    }))
+}
+
+/// Adds `energy`, used evenly over its time, to the periods it overlaps.
+fn add(periods: &mut [PeriodEnergy], energy: &PeriodEnergy) {
+    let length = energy.end - energy.start;
+    for period in periods.iter_mut() {
+        let overlap = period.end.min(energy.end) - period.start.max(energy.start);
+        if overlap > 0 && length > 0 {
+            let share = overlap as f64 / length as f64;
+            period.imported_kwh += energy.imported_kwh * share;
+            period.exported_kwh += energy.exported_kwh * share;
+            period.import_source = period.import_source.combine(energy.import_source);
+            period.export_source = period.export_source.combine(energy.export_source);
+        }
+    }
+}
+
+/// Counter readings and power samples of one meter, per direction, as
+/// (unix seconds, value) oldest first.
+pub struct EnergySamples {
+    import_counter: Vec<(i64, f64)>,
+    export_counter: Vec<(i64, f64)>,
+    import_power: Vec<(i64, f64)>,
+    export_power: Vec<(i64, f64)>,
+}
+
+impl EnergySamples {
+    /// Picks the channels `meter_energy` describes, reading them through
+    /// `samples`. `None` if that finds no meter.
+    pub fn read(
+        mut samples: impl FnMut(Channel) -> StorageResult<Option<Vec<(i64, f64)>>>,
+    ) -> StorageResult<Option<Self>> {
+        let Some(mut import_counter) = samples(ENERGY_IMPORT)? else {
+            return Ok(None);
+        };
+        let export_counter = samples(ENERGY_EXPORT)?.unwrap_or_default();
+
+        let mut import_power = samples(Channel::IMPORT_POWER)?.unwrap_or_default();
+        let mut export_power = samples(Channel::EXPORT_POWER)?.unwrap_or_default();
+        if import_power.is_empty() || export_power.is_empty() {
+            let net = samples(Channel::TOTAL_POWER)?.unwrap_or_default();
+            if import_power.is_empty() {
+                import_power = net.iter().map(|&(t, p)| (t, p.max(0.0))).collect();
+            }
+            if export_power.is_empty() {
+                export_power = net.iter().map(|&(t, p)| (t, (-p).max(0.0))).collect();
+            }
+        }
+
+        // The total counter only equals imports while nothing is exported,
+        // otherwise imports are integrated from power
+        let exported = export_counter.first().zip(export_counter.last()).is_some_and(|(first, last)| last.1 > first.1)
+            || export_power.iter().any(|&(_, p)| p > 0.0);
+        if import_counter.is_empty() && !exported {
+            import_counter = samples(Channel::TOTAL_KWH)?.unwrap_or_default();
+        }
+        Ok(Some(Self { import_counter, export_counter, import_power, export_power }))
+    }
+
+    /// Energy per `[start, end)` of `bounds`, which must be sorted, and the
+    /// number of counter resets.
+    pub fn periods(&self, bounds: &[(i64, i64)]) -> (Vec<PeriodEnergy>, usize) {
+        let imported = DirectionEnergy::compute(bounds, &self.import_counter, &self.import_power);
+        let exported = DirectionEnergy::compute(bounds, &self.export_counter, &self.export_power);
+        let periods = bounds.iter().enumerate()
+            .map(|(i, &(start, end))| PeriodEnergy {
+                start,
+                end,
+                imported_kwh: imported.kwh[i],
+                exported_kwh: exported.kwh[i],
+                import_source: imported.source(i),
+                export_source: exported.source(i),
+            })
+            .collect();
+        (periods, imported.resets + exported.resets)
+    }
+}
+
+/// How far a meter's buckets are complete as of `until` for energy: up to
+/// the newest reading of every channel in `CHANNELS`, so a counter difference
+/// reaching past a bucket is split between buckets as it would be between
+/// periods. Channels without readings for `LOOKAROUND` don't hold it back.
+pub fn complete_until(until: i64, newest: &[i64]) -> i64 {
+    let quiet = until - LOOKAROUND.num_seconds();
+    newest.iter().copied().filter(|&t| t > quiet).fold(until, i64::min)
+}
+
+/// Energy of one direction per period.
+struct DirectionEnergy {
+    kwh: Vec<f64>,
+    from_counter: Vec<bool>,
+    from_power: Vec<bool>,
+    resets: usize,
+}
+
+impl DirectionEnergy {
+    fn compute(bounds: &[(i64, i64)], counter: &[(i64, f64)], power: &[(i64, f64)]) -> Self {
+        let mut energy = Self {
+            kwh: vec![0.0; bounds.len()],
+            from_counter: vec![false; bounds.len()],
+            from_power: vec![false; bounds.len()],
+            resets: 0,
+        };
+
+        // Spans with valid counter data, sorted and non-overlapping
+        let mut covered: Vec<(i64, i64)> = Vec::new();
+        for pair in counter.windows(2) {
+            let ((t0, v0), (t1, v1)) = (pair[0], pair[1]);
+            let delta = v1 - v0;
+            if t1 <= t0 {
+                continue;
+            }
+            if delta < 0.0 || delta > MAX_METER_POWER_KW * (t1 - t0) as f64 / 3600.0 {
+                energy.resets += 1;
+                continue;
+            }
+            spread(bounds, t0, t1, delta, &mut energy.kwh, &mut energy.from_counter);
+            covered.push((t0, t1));
+        }
+
+        for pair in power.windows(2) {
+            let ((t0, p0), (t1, p1)) = (pair[0], pair[1]);
+            if t1 <= t0 || t1 - t0 > MAX_INTEGRATION_GAP {
+                continue;
+            }
+            let middle = t0 + (t1 - t0) / 2;
+            let index = covered.partition_point(|&(_, end)| end <= middle);
+            if covered.get(index).is_some_and(|&(start, _)| start <= middle) {
+                continue;
+            }
+            let kwh = (p0 + p1) / 2.0 * (t1 - t0) as f64 / 3_600_000.0;
+            spread(bounds, t0, t1, kwh, &mut energy.kwh, &mut energy.from_power);
+        }
+
+        energy
+    }
+
+    fn source(&self, index: usize) -> EnergySource {
+        EnergySource::from_usage(self.from_counter[index], self.from_power[index])
+    }
+}
+
+/// Adds `kwh`, used evenly between `t0` and `t1`, to the periods it overlaps.
+fn spread(bounds: &[(i64, i64)], t0: i64, t1: i64, kwh: f64, totals: &mut [f64], used: &mut [bool]) {
+    let first = bounds.partition_point(|&(_, end)| end <= t0);
+    for (i, &(start, end)) in bounds.iter().enumerate().skip(first) {
+        if start >= t1 {
+            break;
+        }
+        let overlap = end.min(t1) - start.max(t0);
+        if overlap > 0 {
+            totals[i] += kwh * overlap as f64 / (t1 - t0) as f64;
+            used[i] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A day of 15 minute samples of `power` watts and the `energy_total` counter.
+    fn total_counter_meter(power: f64) -> impl FnMut(Channel) -> StorageResult<Option<Vec<(i64, f64)>>> {
+        move |channel| {
+            let value = |i: i64| match channel {
+                Channel::TOTAL_KWH => Some(power.abs() / 4000.0 * i as f64),
+                Channel::TOTAL_POWER => Some(power),
+                _ => None,
+            };
+            Ok(Some((0..=96).filter_map(|i| Some((i * 900, value(i)?))).collect()))
+        }
+    }
+
+    #[test]
+    fn total_counter_counts_as_import_only_without_exports() {
+        let bounds = [(0, 86400)];
+
+        let samples = EnergySamples::read(total_counter_meter(500.0)).unwrap().unwrap();
+        let (periods, _) = samples.periods(&bounds);
+        assert_eq!(periods[0].import_source, EnergySource::Counter);
+        assert!((periods[0].imported_kwh - 12.0).abs() < 1e-9);
+
+        // Exports raise the total counter too, so imports come from power instead
+        let samples = EnergySamples::read(total_counter_meter(-500.0)).unwrap().unwrap();
+        let (periods, _) = samples.periods(&bounds);
+        assert_eq!((periods[0].import_source, periods[0].imported_kwh), (EnergySource::Power, 0.0));
+        assert_eq!(periods[0].export_source, EnergySource::Power);
+        assert!((periods[0].exported_kwh - 12.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::meters::{Channel, Quantity};
+use crate::query::{self, BucketQuery, BucketWidth};
+use crate::storage::{Storage, StorageResult};
+
+/// Readings are read from the database one window of this length at a time,
+/// so an export of any range runs in bounded memory.
+const WINDOW: TimeDelta = TimeDelta::days(1);
+/// Rows buffered per Parquet row group.
+const ROW_GROUP_ROWS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "csv" => Some(ExportFormat::Csv),
+            "parquet" => Some(ExportFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// CSV layout. German Excel expects `;` between fields and `,` as decimal separator.
+#[derive(Debug, Clone, Copy)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub decimal_separator: char,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self { delimiter: ',', decimal_separator: '.' }
+    }
+}
+
+/// Parses a delimiter given on the command line or in a query string, where
+/// a literal tab is awkward to type.
+pub fn parse_delimiter(value: &str) -> Option<char> {
+    match value {
+        "tab" | "\\t" => Some('\t'),
+        _ => {
+            let mut chars = value.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        }
+    }
+}
+
+/// Readings of some meters over `[start, end)`, one row per meter and timestamp.
+#[derive(Debug, Clone)]
+pub struct ExportRequest {
+    /// Meter keys, all meters with data if empty
+    pub meters: Vec<String>,
+    /// Columns to export, every channel the meters declare if empty
+    pub channels: Vec<Channel>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Average into buckets of this width instead of exporting every reading.
+    /// Energy counters keep the last value of each bucket.
+    pub resample: Option<BucketWidth>,
+    /// Local time of the CSV `time` column and of resampling bucket boundaries
+    pub timezone: Tz,
+    pub format: ExportFormat,
+    pub csv: CsvOptions,
+}
+
+impl ExportRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.end <= self.start {
+            return Err("end must be after start".to_string());
+        }
+        if let Some(BucketWidth::Points(_)) = self.resample {
+            return Err("resampling needs a fixed width such as 15m".to_string());
+        }
+        let csv = self.csv;
+        if csv.delimiter == csv.decimal_separator || ['"', '\n', '\r'].contains(&csv.delimiter) {
+            return Err(format!("{:?} can't be used as delimiter with decimal separator {:?}", csv.delimiter, csv.decimal_separator));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that every requested meter exists, or lists all meters if none
+/// were requested. The inner error names the first unknown meter.
+pub fn resolve_meters(db: &dyn Storage, requested: &[String]) -> StorageResult<Result<Vec<String>, String>> {
+    let mut known = db.meter_keys()?;
+    if requested.is_empty() {
+        known.sort();
+        return Ok(Ok(known));
+    }
+    Ok(match requested.iter().find(|key| !known.contains(key)) {
+        Some(unknown) => Err(unknown.clone()),
+        None => Ok(requested.to_vec()),
+    })
+}
+
+/// Writes the readings selected by `request` to `out` in real units and
+/// returns the number of rows written. Meters must already be resolved.
+pub fn export(db: &dyn Storage, request: &ExportRequest, out: impl Write + Send) -> StorageResult<u64> {
+    let columns = if request.channels.is_empty() {
+        let declared = db.get_meter_channels()?;
+        let mut columns = BTreeSet::new();
+        for meter_key in &request.meters {
+            match declared.get(meter_key) {
+                Some(channels) => columns.extend(channels.iter().filter_map(|c| Channel::parse(&c.name))),
+                // Meters from before channels were declared only have the fixed columns
+                None => columns.extend(Channel::COLUMNS),
+            }
+        }
+        columns.into_iter().collect()
+    } else {
+        request.channels.clone()
+    };
+
+    match request.format {
+        ExportFormat::Csv => write_rows(db, request, &columns, CsvSink::new(out, &columns, request.csv, request.timezone)?),
+        ExportFormat::Parquet => write_rows(db, request, &columns, ParquetSink::new(out, &columns)?),
+    }
+}
+
+fn write_rows(db: &dyn Storage, request: &ExportRequest, columns: &[Channel], mut sink: impl RowSink) -> StorageResult<u64> {
+    let mut rows = 0;
+    for meter_key in &request.meters {
+        let mut window_start = request.start;
+        while window_start < request.end {
+            let window_end = window_end(request, window_start);
+            let window = match request.resample {
+                Some(width) => resampled_rows(db, meter_key, columns, window_start, window_end, width, request.timezone)?,
+                None => raw_rows(db, meter_key, columns, window_start, window_end)?,
+            };
+            rows += window.len() as u64;
+            sink.write_rows(meter_key, &window)?;
+            window_start = window_end;
+        }
+    }
+    sink.finish()?;
+    Ok(rows)
+}
+
+/// End of the window starting at `start`. When resampling, windows end on a
+/// bucket boundary so no bucket is split between two windows.
+fn window_end(request: &ExportRequest, start: DateTime<Utc>) -> DateTime<Utc> {
+    let mut end = start + WINDOW;
+    if let Some(width) = request.resample {
+        let width_secs = width.seconds(0);
+        let target = start.timestamp() + width_secs.max(WINDOW.num_seconds());
+        let boundary = query::local_bucket_start(target, width_secs, request.timezone);
+        end = DateTime::from_timestamp(boundary, 0).filter(|&b| b > start).unwrap_or(end);
+    }
+    end.min(request.end)
+}
+
+/// Values per timestamp, one entry per export column.
+type Rows = BTreeMap<i64, Vec<Option<f64>>>;
+
+fn raw_rows(db: &dyn Storage, meter_key: &str, columns: &[Channel], start: DateTime<Utc>, end: DateTime<Utc>) -> StorageResult<Rows> {
+    let mut rows = Rows::new();
+    for (index, &channel) in columns.iter().enumerate() {
+        let samples = db.get_channel_samples(meter_key, channel, start, end)?
+            .ok_or_else(|| format!("Unknown meter {}", meter_key))?;
+        for (timestamp, value) in samples {
+            rows.entry(timestamp).or_insert_with(|| vec![None; columns.len()])[index] = Some(value);
+        }
+    }
+    Ok(rows)
+}
+
+fn resampled_rows(
+    db: &dyn Storage,
+    meter_key: &str,
+    columns: &[Channel],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    width: BucketWidth,
+    timezone: Tz,
+) -> StorageResult<Rows> {
+    let query = BucketQuery::new(meter_key.to_string(), columns.to_vec(), start, end, width, timezone)?;
+    let series = db.get_buckets(&query)?.ok_or_else(|| format!("Unknown meter {}", meter_key))?;
+
+    let mut rows = Rows::new();
+    for channel in series {
+        let Some(index) = columns.iter().position(|&c| c == channel.channel) else {
+            continue;
+        };
+        for bucket in channel.buckets {
+            let value = match channel.channel.quantity {
+                Quantity::Energy => bucket.last,
+                _ => bucket.avg,
+            };
+            rows.entry(bucket.start).or_insert_with(|| vec![None; columns.len()])[index] = Some(value);
+        }
+    }
+    Ok(rows)
+}
+
+trait RowSink {
+    fn write_rows(&mut self, meter_key: &str, rows: &Rows) -> StorageResult<()>;
+    fn finish(self) -> StorageResult<()>;
+}
+
+/// `time` in local time, `timestamp` in unix seconds to stay unambiguous when
+/// clocks go back, then the meter key and one column per channel with its unit.
+struct CsvSink<W: Write> {
+    out: std::io::BufWriter<W>,
+    options: CsvOptions,
+    timezone: Tz,
+}
+
+impl<W: Write> CsvSink<W> {
+    fn new(out: W, columns: &[Channel], options: CsvOptions, timezone: Tz) -> StorageResult<Self> {
+        let mut sink = Self { out: std::io::BufWriter::new(out), options, timezone };
+        let mut header = vec!["time".to_string(), "timestamp".to_string(), "meter".to_string()];
+        header.extend(columns.iter().map(|c| format!("{} [{}]", c.name(), c.unit())));
+        sink.write_record(header.iter().map(String::as_str))?;
+        Ok(sink)
+    }
+
+    fn write_record<'a>(&mut self, fields: impl Iterator<Item = &'a str>) -> std::io::Result<()> {
+        let delimiter = self.options.delimiter;
+        let mut line = String::new();
+        for (i, field) in fields.enumerate() {
+            if i > 0 {
+                line.push(delimiter);
+            }
+            if field.contains([delimiter, '"', '\n', '\r']) {
+                line.push('"');
+                line.push_str(&field.replace('"', "\"\""));
+                line.push('"');
+            } else {
+                line.push_str(field);
+            }
+        }
+        line.push_str("\r\n");
+        self.out.write_all(line.as_bytes())
+    }
+}
+
+impl<W: Write> RowSink for CsvSink<W> {
+    fn write_rows(&mut self, meter_key: &str, rows: &Rows) -> StorageResult<()> {
+        for (&timestamp, values) in rows {
+            let time = DateTime::from_timestamp(timestamp, 0).ok_or("Invalid timestamp")?
+                .with_timezone(&self.timezone)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string();
+            let mut fields = vec![time, timestamp.to_string(), meter_key.to_string()];
+            fields.extend(values.iter().map(|value| match value {
+                // Readings are f32, printing them as such avoids digits the meter never reported
+                Some(value) => (*value as f32).to_string().replace('.', &self.options.decimal_separator.to_string()),
+                None => String::new(),
+            }));
+            self.write_record(fields.iter().map(String::as_str))?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> StorageResult<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// `time` as UTC milliseconds, the meter key and one nullable double per channel.
+struct ParquetSink<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    times: Vec<i64>,
+    meters: Vec<ByteArray>,
+    values: Vec<Vec<Option<f64>>>,
+}
+
+impl<W: Write + Send> ParquetSink<W> {
+    fn new(out: W, columns: &[Channel]) -> StorageResult<Self> {
+        let mut schema = String::from(
+            "message readings { REQUIRED INT64 time (TIMESTAMP(MILLIS,true)); REQUIRED BYTE_ARRAY meter (UTF8);",
+        );
+        for channel in columns {
+            schema.push_str(&format!(" OPTIONAL DOUBLE {};", channel.name()));
+        }
+        schema.push_str(" }");
+
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let writer = SerializedFileWriter::new(out, Arc::new(parse_message_type(&schema)?), Arc::new(properties))?;
+        Ok(Self {
+            writer,
+            times: Vec::new(),
+            meters: Vec::new(),
+            values: vec![Vec::new(); columns.len()],
+        })
+    }
+
+    fn write_row_group(&mut self) -> StorageResult<()> {
+        if self.times.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => {
+                    column.typed::<Int64Type>().write_batch(&self.times, None, None)?;
+                }
+                1 => {
+                    column.typed::<ByteArrayType>().write_batch(&self.meters, None, None)?;
+                }
+                _ => {
+                    let values = &self.values[index - 2];
+                    let present: Vec<f64> = values.iter().flatten().copied().collect();
+                    let levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+                    column.typed::<DoubleType>().write_batch(&present, Some(&levels), None)?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+
+        self.times.clear();
+        self.meters.clear();
+        self.values.iter_mut().for_each(Vec::clear);
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> RowSink for ParquetSink<W> {
+    fn write_rows(&mut self, meter_key: &str, rows: &Rows) -> StorageResult<()> {
+        for (&timestamp, values) in rows {
+            self.times.push(timestamp * 1000);
+            self.meters.push(ByteArray::from(meter_key));
+            for (column, value) in self.values.iter_mut().zip(values) {
+                column.push(*value);
+            }
+        }
+        if self.times.len() >= ROW_GROUP_ROWS {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> StorageResult<()> {
+        self.write_row_group()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::encoding::PowerEncoding;
+use crate::meters::{Channel, Measurement, Quantity};
+use crate::storage::{Storage, StorageResult};
+
+/// Measurements buffered before they are written in one transaction.
+const FLUSH_MEASUREMENTS: usize = 10_000;
+/// Skipped rows listed individually in the report, the rest are only counted.
+const MAX_PROBLEMS: usize = 20;
+/// Local time formats tried for timestamps without an offset.
+const LOCAL_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+    "%d.%m.%Y %H:%M:%S",
+    "%d.%m.%Y %H:%M",
+    "%d/%m/%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M",
+];
+
+/// A source column imported into a channel, parsed from `column=channel[:unit]`,
+/// e.g. `Active power W=power:W` or `Energy (Wh)=energy_import:Wh`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnMapping {
+    pub column: String,
+    pub channel: Channel,
+    /// Multiplier from the source unit to the unit the channel is stored in
+    pub factor: f64,
+}
+
+impl ColumnMapping {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (column, target) = value.rsplit_once('=')
+            .ok_or_else(|| format!("Expected column=channel[:unit], got {}", value))?;
+        let (channel, unit) = match target.split_once(':') {
+            Some((channel, unit)) => (channel, Some(unit)),
+            None => (target, None),
+        };
+        let channel = Channel::parse(channel.trim()).ok_or_else(|| format!("Unknown channel {}", channel))?;
+        let factor = match unit {
+            Some(unit) => unit_factor(channel.quantity, unit.trim())
+                .ok_or_else(|| format!("Unit {} does not fit channel {}", unit, channel))?,
+            None => 1.0,
+        };
+        Ok(Self { column: column.trim().to_string(), channel, factor })
+    }
+
+    /// Maps a header such as `power [W]` or `energy_import`, the form `export`
+    /// writes, to its channel. `None` for other headers.
+    fn from_header(header: &str) -> Option<Self> {
+        let (name, unit) = match header.trim().strip_suffix(']').and_then(|h| h.split_once(" [")) {
+            Some((name, unit)) => (name, Some(unit)),
+            None => (header.trim(), None),
+        };
+        let channel = Channel::parse(name)?;
+        let factor = match unit {
+            Some(unit) => unit_factor(channel.quantity, unit)?,
+            None => 1.0,
+        };
+        Some(Self { column: header.to_string(), channel, factor })
+    }
+}
+
+/// Factor converting values in `unit` to the unit `quantity` is stored in.
+fn unit_factor(quantity: Quantity, unit: &str) -> Option<f64> {
+    let factor = match (quantity, unit) {
+        (Quantity::Power, "W") => 1.0,
+        (Quantity::Power, "kW") => 1e3,
+        (Quantity::Power, "MW") => 1e6,
+        (Quantity::Energy, "Wh") => 1e-3,
+        (Quantity::Energy, "kWh") => 1.0,
+        (Quantity::Energy, "MWh") => 1e3,
+        (Quantity::Voltage, "V") => 1.0,
+        (Quantity::Voltage, "kV") => 1e3,
+        (Quantity::Current, "A") => 1.0,
+        (Quantity::Current, "mA") => 1e-3,
+        (Quantity::Frequency, "Hz") => 1.0,
+        (Quantity::PowerFactor, "") => 1.0,
+        (Quantity::PowerFactor, "%") => 1e-2,
+        _ => return None,
+    };
+    Some(factor)
+}
+
+/// Where meter, time and values are found in the rows of a CSV file or query.
+#[derive(Debug, Clone)]
+pub struct TableLayout {
+    /// `timestamp` or `time` if present when not set
+    pub time_column: Option<String>,
+    /// strftime format of the time column. Without one, unix seconds or
+    /// milliseconds, RFC 3339 and common local formats are recognized.
+    pub time_format: Option<String>,
+    /// Timezone of times without an offset
+    pub timezone: Tz,
+    /// Column naming each row's meter, `meter` if present when not set
+    pub meter_column: Option<String>,
+    /// Meter of all rows when there is no meter column
+    pub meter: Option<String>,
+    /// Columns to import, every header naming a channel when empty
+    pub columns: Vec<ColumnMapping>,
+    pub decimal_separator: char,
+}
+
+impl TableLayout {
+    fn resolve(&self, headers: &[String]) -> StorageResult<ResolvedLayout> {
+        let find = |name: &str| headers.iter().position(|h| h.trim() == name);
+        let time = match &self.time_column {
+            Some(column) => find(column).ok_or_else(|| format!("No column {}", column))?,
+            None => find("timestamp").or_else(|| find("time")).ok_or("No timestamp or time column, set the time column")?,
+        };
+        let meter = match &self.meter_column {
+            Some(column) => Some(find(column).ok_or_else(|| format!("No column {}", column))?),
+            None if self.meter.is_some() => None,
+            None => Some(find("meter").ok_or("No meter column, set the meter or the meter column")?),
+        };
+        let columns = if self.columns.is_empty() {
+            let mapped: Vec<_> = headers.iter().enumerate()
+                .filter(|&(i, _)| i != time && Some(i) != meter)
+                .filter_map(|(i, h)| ColumnMapping::from_header(h).map(|m| (i, m)))
+                .collect();
+            if mapped.is_empty() {
+                return Err("No column is named after a channel, map columns explicitly".into());
+            }
+            mapped
+        } else {
+            self.columns.iter()
+                .map(|m| find(&m.column).map(|i| (i, m.clone())).ok_or_else(|| format!("No column {}", m.column)))
+                .collect::<Result<_, _>>()?
+        };
+        Ok(ResolvedLayout { time, meter, columns })
+    }
+
+    fn parse_time(&self, value: &str) -> Option<DateTime<Utc>> {
+        let value = value.trim();
+        if let Some(format) = &self.time_format {
+            return match DateTime::parse_from_str(value, format) {
+                Ok(time) => Some(time.with_timezone(&Utc)),
+                Err(_) => local_time(NaiveDateTime::parse_from_str(value, format).ok()?, self.timezone),
+            };
+        }
+        if let Ok(number) = value.parse::<i64>() {
+            // Anything past the year 5000 in seconds is milliseconds
+            return if number.abs() > 100_000_000_000 {
+                DateTime::from_timestamp_millis(number)
+            } else {
+                DateTime::from_timestamp(number, 0)
+            };
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Some(time.with_timezone(&Utc));
+        }
+        LOCAL_FORMATS.iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+            .and_then(|naive| local_time(naive, self.timezone))
+    }
+
+    fn parse_value(&self, value: &str) -> Option<f64> {
+        let value = value.trim();
+        match self.decimal_separator {
+            '.' => value.parse().ok(),
+            separator => value.replace(separator, ".").parse().ok(),
+        }
+    }
+}
+
+/// Local time in `timezone`. The earlier instant when clocks go back, `None`
+/// for times skipped when they go forward.
+fn local_time(naive: NaiveDateTime, timezone: Tz) -> Option<DateTime<Utc>> {
+    timezone.from_local_datetime(&naive).earliest().map(|t| t.with_timezone(&Utc))
+}
+
+/// Column indexes of a `TableLayout` in one source.
+struct ResolvedLayout {
+    time: usize,
+    meter: Option<usize>,
+    columns: Vec<(usize, ColumnMapping)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MeterReport {
+    /// Source rows read for this meter
+    pub rows: u64,
+    /// Timestamps added to the database
+    pub inserted: u64,
+    /// Timestamps the meter already had readings for
+    pub duplicates: u64,
+    pub first: Option<i64>,  // Unix timestamp
+    pub last: Option<i64>,
+    /// Start and end of history another solarmeter database only holds as
+    /// rollups, which is not imported
+    pub rollup_only: Option<(i64, i64)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    /// Per meter key
+    pub meters: BTreeMap<String, MeterReport>,
+    /// Rows without a valid time, meter or value
+    pub skipped: u64,
+    /// Why the first skipped rows were skipped
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = |t: Option<i64>| t.and_then(|t| DateTime::from_timestamp(t, 0))
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        writeln!(f, "{:<20} {:>10} {:>10} {:>10}  {:<19}  {:<19}", "meter", "rows", "inserted", "duplicates", "first (UTC)", "last (UTC)")?;
+        for (meter, report) in &self.meters {
+            writeln!(
+                f,
+                "{:<20} {:>10} {:>10} {:>10}  {:<19}  {:<19}",
+                meter, report.rows, report.inserted, report.duplicates, time(report.first), time(report.last),
+            )?;
+        }
+        for (meter, report) in &self.meters {
+            if let Some((start, end)) = report.rollup_only {
+                writeln!(f, "{}: {} to {} is only covered by rollups, not imported", meter, time(Some(start)), time(Some(end)))?;
+            }
+        }
+        if self.skipped > 0 {
+            writeln!(f, "{} rows skipped", self.skipped)?;
+            for problem in &self.problems {
+                writeln!(f, "  {}", problem)?;
+            }
+            if self.skipped as usize > self.problems.len() {
+                writeln!(f, "  ...")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Source meter names mapped to meter keys. Unmapped meters keep their name.
+pub type MeterMap = HashMap<String, String>;
+
+/// Collects measurements and writes them in batches through `import_measurements`.
+struct Importer<'a> {
+    db: &'a dyn Storage,
+    meter_map: &'a MeterMap,
+    pending: HashMap<String, Vec<Measurement>>,
+    pending_count: usize,
+    report: ImportReport,
+}
+
+impl<'a> Importer<'a> {
+    fn new(db: &'a dyn Storage, meter_map: &'a MeterMap) -> Self {
+        Self { db, meter_map, pending: HashMap::new(), pending_count: 0, report: ImportReport::default() }
+    }
+
+    fn add(&mut self, meter: &str, measurements: Vec<Measurement>) -> StorageResult<()> {
+        let Some(timestamp) = measurements.first().map(|m| m.timestamp.timestamp()) else {
+            return Ok(());
+        };
+        let meter_key = self.meter_map.get(meter).map(String::as_str).unwrap_or(meter);
+        let report = self.report.meters.entry(meter_key.to_string()).or_default();
+        report.rows += 1;
+        report.first = Some(report.first.map_or(timestamp, |t| t.min(timestamp)));
+        report.last = Some(report.last.map_or(timestamp, |t| t.max(timestamp)));
+
+        self.pending_count += measurements.len();
+        self.pending.entry(meter_key.to_string()).or_default().extend(measurements);
+        if self.pending_count >= FLUSH_MEASUREMENTS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, row: u64, reason: String) {
+        self.report.skipped += 1;
+        if self.report.problems.len() < MAX_PROBLEMS {
+            self.report.problems.push(format!("row {}: {}", row, reason));
+        }
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        for (meter_key, measurements) in self.pending.drain() {
+            let counts = self.db.import_measurements(&meter_key, &measurements)?;
+            let report = self.report.meters.entry(meter_key).or_default();
+            report.inserted += counts.inserted;
+            report.duplicates += counts.duplicates;
+        }
+        self.pending_count = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> StorageResult<ImportReport> {
+        self.flush()?;
+        Ok(self.report)
+    }
+
+    /// Imports one row of a CSV file or query, `get` returning the cell of a column.
+    fn add_row<'r>(
+        &mut self,
+        layout: &TableLayout,
+        resolved: &ResolvedLayout,
+        row: u64,
+        get: impl Fn(usize) -> Option<Cow<'r, str>>,
+    ) -> StorageResult<()> {
+        let cell = |index: usize| get(index).filter(|value| !value.trim().is_empty());
+        let Some(time) = cell(resolved.time) else {
+            self.skip(row, "no time".to_string());
+            return Ok(());
+        };
+        let Some(timestamp) = layout.parse_time(&time) else {
+            self.skip(row, format!("invalid time {:?}", time));
+            return Ok(());
+        };
+        let meter = match resolved.meter {
+            Some(index) => match cell(index) {
+                Some(meter) => meter.trim().to_string(),
+                None => {
+                    self.skip(row, "no meter".to_string());
+                    return Ok(());
+                }
+            },
+            None => layout.meter.clone().unwrap_or_default(),
+        };
+
+        let mut measurements = Vec::new();
+        for (index, mapping) in &resolved.columns {
+            let Some(text) = cell(*index) else {
+                continue;
+            };
+            match layout.parse_value(&text).map(|v| v * mapping.factor).filter(|v| v.is_finite()) {
+                Some(value) => measurements.push(Measurement::new(mapping.channel, timestamp, value as f32)),
+                None => {
+                    self.skip(row, format!("invalid value {:?} in {}", text, mapping.column));
+                    return Ok(());
+                }
+            }
+        }
+        if measurements.is_empty() {
+            self.skip(row, "no values".to_string());
+            return Ok(());
+        }
+        self.add(&meter, measurements)
+    }
+}
+
+/// Imports a CSV file with a header row.
+pub fn import_csv(
+    db: &dyn Storage,
+    input: impl Read,
+    delimiter: char,
+    layout: &TableLayout,
+    meter_map: &MeterMap,
+) -> StorageResult<ImportReport> {
+    let delimiter = u8::try_from(delimiter).map_err(|_| "The delimiter must be an ASCII character")?;
+    let mut reader = csv::ReaderBuilder::new().delimiter(delimiter).flexible(true).from_reader(input);
+    let headers: Vec<String> = reader.headers()?.iter().map(str::to_string).collect();
+    let resolved = layout.resolve(&headers)?;
+
+    let mut importer = Importer::new(db, meter_map);
+    for (line, record) in reader.records().enumerate() {
+        // Line 1 is the header
+        let row = line as u64 + 2;
+        match record {
+            Ok(record) => importer.add_row(layout, &resolved, row, |i| record.get(i).map(Cow::Borrowed))?,
+            Err(e) => importer.skip(row, e.to_string()),
+        }
+    }
+    importer.finish()
+}
+
+/// Imports a SQLite database. With `query`, its result rows are imported
+/// according to `layout` like a CSV file. Without one, the database must be
+/// a solarmeter database, whose meters, readings and channels are copied.
+pub fn import_sqlite(
+    db: &dyn Storage,
+    path: &Path,
+    query: Option<&str>,
+    layout: &TableLayout,
+    meter_map: &MeterMap,
+) -> StorageResult<ImportReport> {
+    let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    match query {
+        Some(query) => import_query(db, &source, query, layout, meter_map),
+        None => import_solarmeter(db, &source, meter_map),
+    }
+}
+
+fn import_query(
+    db: &dyn Storage,
+    source: &Connection,
+    query: &str,
+    layout: &TableLayout,
+    meter_map: &MeterMap,
+) -> StorageResult<ImportReport> {
+    let mut statement = source.prepare(query)?;
+    let headers: Vec<String> = statement.column_names().iter().map(|c| c.to_string()).collect();
+    let resolved = layout.resolve(&headers)?;
+
+    let mut importer = Importer::new(db, meter_map);
+    let mut rows = statement.query([])?;
+    let mut row_number = 0;
+    while let Some(row) = rows.next()? {
+        row_number += 1;
+        let cells: Vec<Option<String>> = (0..headers.len())
+            .map(|i| match row.get_ref(i) {
+                Ok(ValueRef::Integer(n)) => Some(n.to_string()),
+                // Real numbers use '.' whatever the layout says about text
+                Ok(ValueRef::Real(r)) => Some(r.to_string().replace('.', &layout.decimal_separator.to_string())),
+                Ok(ValueRef::Text(text)) => Some(String::from_utf8_lossy(text).into_owned()),
+                _ => None,
+            })
+            .collect();
+        importer.add_row(layout, &resolved, row_number, |i| cells.get(i)?.as_deref().map(Cow::Borrowed))?;
+    }
+    importer.finish()
+}
+
+/// Copies the readings of another solarmeter database, decoding power with
+/// the encoding recorded in it. History its retention already replaced with
+/// rollups is listed in the report.
+fn import_solarmeter(db: &dyn Storage, source: &Connection, meter_map: &MeterMap) -> StorageResult<ImportReport> {
+    let has_table = |name: &str| -> rusqlite::Result<bool> {
+        source.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?.exists([name])
+    };
+    if !has_table("meter_readings")? || !has_table("meter_names")? {
+        return Err("Not a solarmeter database, pass a query to import other databases".into());
+    }
+    let encoding = match source.query_row("SELECT value FROM db_settings WHERE key = 'power_encoding'", [], |row| row.get::<_, String>(0)) {
+        Ok(value) => PowerEncoding::parse(&value).ok_or_else(|| format!("Unknown power encoding {}", value))?,
+        // Databases from before the setting existed use f16
+        Err(_) => PowerEncoding::F16,
+    };
+    // Databases from before stable keys identify meters by name
+    let has_keys = source.prepare("SELECT meter_key FROM meter_names").is_ok();
+    let key_column = if has_keys { "n.meter_key" } else { "n.name" };
+
+    let has_channels = has_table("channel_readings")?;
+    let channels_query = match has_channels {
+        true => format!(
+            "UNION ALL
+             SELECT {}, r.timestamp, r.channel, NULL, NULL, NULL, NULL, r.value
+             FROM channel_readings r JOIN meter_names n ON n.meter_id = r.meter_id",
+            key_column,
+        ),
+        false => String::new(),
+    };
+    // Both tables in one pass, so every timestamp is imported as a whole
+    let mut statement = source.prepare(&format!(
+        "SELECT {}, r.timestamp, NULL, r.total_power, r.import_power, r.export_power, r.total_kwh, NULL
+         FROM meter_readings r JOIN meter_names n ON n.meter_id = r.meter_id
+         {}
+         ORDER BY 1, 2",
+        key_column, channels_query,
+    ))?;
+
+    let mut importer = Importer::new(db, meter_map);
+    let mut current: Option<(String, i64)> = None;
+    let mut measurements = Vec::new();
+    let mut rows = statement.query([])?;
+    let mut row_number = 0;
+    while let Some(row) = rows.next()? {
+        row_number += 1;
+        let key = (row.get::<_, String>(0)?, row.get::<_, i64>(1)?);
+        if current.as_ref() != Some(&key) {
+            if let Some((meter, _)) = current.replace(key.clone()) {
+                importer.add(&meter, std::mem::take(&mut measurements))?;
+            }
+        }
+        let Some(timestamp) = DateTime::from_timestamp(key.1, 0) else {
+            importer.skip(row_number, format!("invalid timestamp {}", key.1));
+            continue;
+        };
+
+        match row.get::<_, Option<String>>(2)? {
+            Some(name) => match (Channel::parse(&name), row.get::<_, f64>(7)?) {
+                (Some(channel), value) if value.is_finite() => measurements.push(Measurement::new(channel, timestamp, value as f32)),
+                _ => importer.skip(row_number, format!("invalid channel {} or value", name)),
+            },
+            None => {
+                for (i, channel) in Channel::COLUMNS.into_iter().enumerate() {
+                    let value = match channel.is_power() {
+                        true => encoding.decode(row.get_ref(3 + i)?),
+                        false => row.get::<_, Option<f64>>(3 + i)?.map(|v| v as f32),
+                    };
+                    if let Some(value) = value.filter(|v| v.is_finite()) {
+                        measurements.push(Measurement::new(channel, timestamp, value));
+                    }
+                }
+            }
+        }
+    }
+    if let Some((meter, _)) = current {
+        importer.add(&meter, measurements)?;
+    }
+    let mut report = importer.finish()?;
+
+    if has_table("rollups")? {
+        for (meter, span) in rollup_only_spans(source, key_column, has_channels)? {
+            let meter_key = meter_map.get(&meter).cloned().unwrap_or(meter);
+            report.meters.entry(meter_key).or_default().rollup_only = Some(span);
+        }
+    }
+    Ok(report)
+}
+
+/// Per source meter, the span covered by rollup buckets that end before its
+/// oldest raw reading.
+fn rollup_only_spans(source: &Connection, key_column: &str, has_channels: bool) -> rusqlite::Result<Vec<(String, (i64, i64))>> {
+    let oldest_channel = match has_channels {
+        true => "(SELECT MIN(timestamp) FROM channel_readings c WHERE c.meter_id = n.meter_id)",
+        false => "NULL",
+    };
+    let mut statement = source.prepare(&format!(
+        "WITH oldest AS (
+            SELECT n.meter_id, {} AS meter,
+                   (SELECT MIN(timestamp) FROM meter_readings r WHERE r.meter_id = n.meter_id) AS readings,
+                   {} AS channels
+            FROM meter_names n
+         )
+         SELECT o.meter, MIN(u.bucket_start), MAX(u.bucket_start + u.width_secs)
+         FROM rollups u JOIN oldest o ON o.meter_id = u.meter_id
+         WHERE u.bucket_start + u.width_secs <= COALESCE(
+             MIN(COALESCE(o.readings, o.channels), COALESCE(o.channels, o.readings)), {})
+         GROUP BY o.meter_id",
+        key_column, oldest_channel, i64::MAX,
+    ))?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+    rows.collect()
+}
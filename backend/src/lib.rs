@@ -1,5 +1,24 @@
+pub mod backup;
+pub mod capture;
+pub mod check;
+pub mod cli;
 pub mod config;
 pub mod database_sync;
+pub mod encoding;
 pub mod meters;
+pub mod migrations;
 pub mod web_server;
 pub mod data_retention;
+pub mod supervisor;
+pub mod writer;
+pub mod spill;
+pub mod storage;
+pub mod query;
+pub mod rollup;
+pub mod energy;
+pub mod export;
+pub mod import;
+pub mod maintenance;
+
+#[cfg(test)]
+mod testing;
@@ -1,13 +1,21 @@
 use solarmeter::{
-    config::AppConfig,
-    database_sync::DatabaseSync,
-    meters::{create_meter, MeterReader},
+    cli::{self, Cli},
+    config::{AppConfig, LocationConfig, MeterConfig},
+    meters::{create_meter, AdaptiveController, Measurement, MeterReader, PollingSchedule},
     web_server::WebServer,
+    capture::{run_capture, CaptureManager, CaptureRequest},
+    storage::{self, Storage},
+    supervisor::Supervisor,
     data_retention::RetentionService,
+    backup::BackupService,
+    maintenance::{MaintenanceService, MaintenanceTask},
+    writer::BatchWriter,
 };
-use log::{error, info, LevelFilter};
+use clap::Parser;
+use log::{error, info, warn, LevelFilter};
+use chrono::Utc;
 
-use tokio::{task, time::{sleep, Duration}};
+use tokio::{sync::mpsc, time::{sleep, Duration}};
 use std::sync::Arc;
 use log4rs::{
     append::{
@@ -69,67 +77,116 @@ fn initialize_logging(log_dir: &str, log_level: LevelFilter) -> Result<(), Box<d
 }
 
 async fn handle_meter(
+    meter_key: String,
     mut meter: Box<dyn MeterReader>,
-    db_sync: Arc<DatabaseSync>,
-    polling_rate: u32,
+    db_sync: Arc<dyn Storage>,
+    writer: BatchWriter,
+    mut schedule: PollingSchedule,
+    mut adaptive: Option<AdaptiveController>,
+    mut captures: mpsc::Receiver<CaptureRequest>,
 ) {
-    // Log initial meter setup
-    let meter_name = match meter.get_value().await {
-        Ok(reading) => reading.meter_name.clone(),
-        Err(e) => {
-            error!("Failed to get initial reading from meter: {}", e);
-            "Unknown Meter".to_string()
-        }
-    };
+    let meter_name = meter.name().to_string();
+
+    info!(
+        "Started polling loop for meter: {} (fastest group every {}s)",
+        meter_name,
+        schedule.shortest_interval().as_secs()
+    );
 
-    info!("Started polling loop for meter: {}", meter_name);
-    let polling_duration = Duration::from_secs(polling_rate.into());
+    if let Some(controller) = &adaptive {
+        schedule.set_adaptive_interval(Some(controller.interval()));
+    }
 
     loop {
-        // Get reading from meter
-        let reading_result = meter.get_value().await;
+        // Wait for the next due polling group(s), unless a capture is requested first
+        let channels = tokio::select! {
+            channels = schedule.next_plan() => channels,
+            Some(request) = captures.recv() => {
+                info!("Starting capture session {} for {}", request.session_id, meter_name);
+                run_capture(meter.as_mut(), &db_sync, request).await;
+                continue;
+            }
+        };
+        let reading_result = meter.read_channels(&channels).await;
         
         match reading_result {
-            Ok(reading) => {
+            Ok(measurements) => {
                 // Log the successful meter reading
-                info!(
-                    "Got reading from {}: Power: {:.2}W, Import: {:.2}W, Export: {:.2}W, Total: {:.2}kWh",
-                    reading.meter_name, reading.total_power, reading.import_power,
-                    reading.export_power, reading.total_kwh
-                );
+                info!("Got reading from {}: {}", meter_name, Measurement::describe(&measurements));
+
+                if let Some(controller) = adaptive.as_mut() {
+                    let interval = controller.observe(&meter_name, &measurements, Utc::now());
+                    schedule.set_adaptive_interval(Some(interval));
+                }
                 
-                // Store reading in database
-                match db_sync.insert_meter_reading(&reading) {
-                    Ok(_) => {
-                        info!(
-                            "Successfully stored reading from {}",
-                            reading.meter_name
-                        );
-                    }
-                    Err(e) => {
-                        error!(
-                            "Failed to insert reading for {}: {}",
-                            reading.meter_name,
-                            e
-                        );
-                    }
+                // Queue reading for the batch writer
+                if let Err(e) = writer.submit(&meter_key, measurements).await {
+                    error!(
+                        "Failed to queue reading for {}: {}",
+                        meter_name,
+                        e
+                    );
                 }
             }
             Err(e) => {
                 error!("Failed to read meter {}: {}", meter_name, e);
                 // On error, wait 30 seconds before retrying to avoid spamming logs
                 sleep(Duration::from_secs(30)).await;
-                continue; // Skip the normal polling delay and retry immediately after error timeout
             }
         }
+    }
+}
+
+/// Builds a meter from its configuration and runs its polling loop. Called
+/// again by the supervisor after a panic, so every start gets a fresh driver.
+async fn start_meter(
+    meter_key: String,
+    meter_config: Arc<MeterConfig>,
+    location: Option<LocationConfig>,
+    db_sync: Arc<dyn Storage>,
+    writer: BatchWriter,
+    capture_manager: CaptureManager,
+) {
+    let meter = create_meter(
+        meter_config.name.clone(),
+        meter_config.meter_type.clone(),
+        meter_config.port.clone(),
+        meter_config.baud_rate,
+        meter_config.polling_rate,
+        meter_config.modbus_address,
+        meter_config.timeout,
+    ).await;
 
-        // Wait for the next polling interval
-        sleep(polling_duration).await;
+    for group in &meter_config.polling_groups {
+        for channel in group.channels.iter().filter(|c| !meter.channels().contains(c)) {
+            warn!("Meter {} does not provide channel {}, ignoring it", meter_config.name, channel);
+        }
     }
+    let schedule = PollingSchedule::new(meter_config.effective_polling_groups(meter.channels()));
+    let adaptive = meter_config.adaptive.clone()
+        .map(|a| AdaptiveController::new(a, location));
+    let captures = capture_manager.register(&meter_key, meter.as_ref());
+
+    let (key, name, metadata, channels) = (
+        meter_key.clone(),
+        meter_config.name.clone(),
+        meter_config.metadata(),
+        meter.channels().to_vec(),
+    );
+    let registered = db_sync.run_blocking(move |db| {
+        db.register_meter(&key, &name, &metadata)?;
+        db.register_channels(&key, &channels)
+    }).await;
+    if let Err(e) = registered {
+        error!("Failed to register meter {}: {}", meter_config.name, e);
+    }
+
+    handle_meter(meter_key, meter, db_sync, writer, schedule, adaptive, captures).await;
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
 
     let config = match AppConfig::load() {
         Ok(config) => config,
@@ -138,10 +195,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             return Err(e);
         }
     };
+
+    // Commands write their output to stdout, so they run without logging
+    if let Some(command) = cli.command {
+        return tokio::task::block_in_place(|| cli::run(&config, command));
+    }
+
     // Expand the tilde in the log directory path if it exists
     let log_dir = if config.global.log_dir.starts_with("~/") {
         let home = dirs::home_dir()
-            .ok_or_else(|| "Could not determine home directory")?;
+            .ok_or("Could not determine home directory")?;
         home.join(&config.global.log_dir[2..]).to_string_lossy().into_owned()
     } else {
         config.global.log_dir.clone()
@@ -152,54 +215,89 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Configuration loaded successfully");
 
-    let db_sync = Arc::new(match DatabaseSync::new(&config.global.database_url, config.global.create_database) {
+    // The PostgreSQL client blocks on its own runtime, which must not run on a runtime thread
+    let db_sync = match tokio::task::block_in_place(|| storage::open(&config.global, &config.storage)) {
         Ok(db) => db,
         Err(e) => {
             error!("Failed to initialize database: {}", e);
             return Err(e);
         }
-    });
-    info!("Database initialized at {}", config.global.database_url);
+    };
+    info!("Database initialized ({:?} backend)", config.storage.backend);
 
     // Create shutdown channel
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let capture_manager = CaptureManager::new();
+  
+    let supervisor = Supervisor::new();
+
+    let writer = match BatchWriter::new(Arc::clone(&db_sync), &config.storage) {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Failed to initialize the batch writer: {}", e);
+            return Err(e);
+        }
+    };
+    let writer_task = writer.clone();
+    supervisor.spawn("writer", move || writer_task.clone().run());
   
+    let retention = RetentionService::new(Arc::clone(&db_sync), config.retention.clone());
     let web_server = WebServer::new(
         Arc::clone(&db_sync), 
-        Some(config.global.bind_address.clone()), 
-        shutdown_tx
+        &config,
+        shutdown_tx,
+        capture_manager.clone(),
+        supervisor.clone(),
+        writer.clone(),
+        retention.clone(),
     );
     let web_server_port = config.global.web_server_port.unwrap_or(8080);
-    task::spawn(web_server.run(web_server_port));
+    supervisor.spawn("web_server", move || web_server.clone().run(web_server_port));
 
-    let retention_db = Arc::clone(&db_sync);
-    task::spawn(async move {
-        let retention_service = RetentionService::new(retention_db);
-        retention_service.run().await;
+    supervisor.spawn("retention", move || {
+        let retention = retention.clone();
+        async move { retention.run().await }
     });
 
-    let mut meter_tasks = Vec::new();
+    let maintenance_config = config.maintenance.clone();
+    if MaintenanceTask::ALL.iter().any(|&task| maintenance_config.interval_hours(task) > 0) {
+        let maintenance_db = Arc::clone(&db_sync);
+        supervisor.spawn("maintenance", move || {
+            let maintenance = MaintenanceService::new(Arc::clone(&maintenance_db), maintenance_config.clone());
+            async move { maintenance.run().await }
+        });
+    }
+
+    if let Some(backup_config) = config.backup.clone().filter(|b| b.interval_hours > 0) {
+        let backup_db = Arc::clone(&db_sync);
+        supervisor.spawn("backup", move || {
+            let backup_service = BackupService::new(Arc::clone(&backup_db), backup_config.clone());
+            async move { backup_service.run().await }
+        });
+    }
+
     for (meter_id, meter_config) in &config.meters {
         info!("Creating meter {}: {}", meter_id, meter_config.name);
-        
-        let meter = create_meter(
-            meter_config.name.clone(),
-            meter_config.meter_type.clone(),
-            meter_config.port.clone(),
-            meter_config.baud_rate,
-            meter_config.polling_rate,
-            meter_config.modbus_address,
-            meter_config.timeout,
-        ).await;  // Note the .await here
 
+        let meter_key = meter_config.key(meter_id).to_string();
+        let meter_config = Arc::new(meter_config.clone());
+        let location = config.location.clone();
         let db_sync = Arc::clone(&db_sync);
-        let polling_rate = meter.get_polling_rate();
-        
-        meter_tasks.push(task::spawn(async move {
-            handle_meter(meter, db_sync, polling_rate).await;
-        }));
+        let writer = writer.clone();
+        let capture_manager = capture_manager.clone();
+
+        supervisor.spawn(format!("meter:{}", meter_config.name), move || {
+            start_meter(
+                meter_key.clone(),
+                Arc::clone(&meter_config),
+                location.clone(),
+                Arc::clone(&db_sync),
+                writer.clone(),
+                capture_manager.clone(),
+            )
+        });
     }
-    info!("Created {} meter polling tasks", meter_tasks.len());
+    info!("Created {} meter polling tasks", config.meters.len());
 
     tokio::select! {
         _ = ctrl_c() => {
@@ -210,5 +308,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    info!("Writing queued readings before exit...");
+    if tokio::time::timeout(Duration::from_secs(30), writer.flush()).await.is_err() {
+        error!("Timed out writing queued readings, {} cycles lost", writer.status().queued);
+    }
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+
+use crate::config::MaintenanceConfig;
+use crate::storage::{Storage, StorageResult};
+
+/// Failed tasks are tried again after at most this many hours.
+const RETRY_HOURS: u64 = 1;
+
+/// Database upkeep, each task on its own schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceTask {
+    Vacuum,
+    Optimize,
+    Analyze,
+    Checkpoint,
+    IntegrityCheck,
+}
+
+impl MaintenanceTask {
+    /// In the order due tasks run, so a checkpoint picks up what vacuum wrote.
+    pub const ALL: [MaintenanceTask; 5] = [
+        MaintenanceTask::Vacuum,
+        MaintenanceTask::Optimize,
+        MaintenanceTask::Analyze,
+        MaintenanceTask::Checkpoint,
+        MaintenanceTask::IntegrityCheck,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaintenanceTask::Vacuum => "vacuum",
+            MaintenanceTask::Optimize => "optimize",
+            MaintenanceTask::Analyze => "analyze",
+            MaintenanceTask::Checkpoint => "checkpoint",
+            MaintenanceTask::IntegrityCheck => "integrity_check",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.as_str() == value)
+    }
+}
+
+/// Outcome of the latest run of a task.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MaintenanceResult {
+    pub task: MaintenanceTask,
+    pub finished_at: i64,  // Unix timestamp
+    pub duration_ms: u64,
+    pub ok: bool,
+    /// What the task did, or why it failed
+    pub detail: String,
+}
+
+/// Schedule and latest result of a task, as reported by `/status`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MaintenanceStatus {
+    pub task: MaintenanceTask,
+    /// 0 if the task is disabled
+    pub interval_hours: u64,
+    pub next_run: Option<i64>,  // Unix timestamp
+    pub last: Option<MaintenanceResult>,
+}
+
+/// Where every task stands given the latest `results`. Tasks that never ran
+/// are due right away.
+pub fn statuses(config: &MaintenanceConfig, results: &[MaintenanceResult], now: DateTime<Utc>) -> Vec<MaintenanceStatus> {
+    MaintenanceTask::ALL.iter().map(|&task| {
+        let interval_hours = config.interval_hours(task);
+        let last = results.iter().find(|r| r.task == task).cloned();
+        let next_run = (interval_hours > 0).then(|| match &last {
+            Some(last) if last.ok => last.finished_at + interval_hours as i64 * 3600,
+            Some(last) => last.finished_at + interval_hours.min(RETRY_HOURS) as i64 * 3600,
+            None => now.timestamp(),
+        });
+        MaintenanceStatus { task, interval_hours, next_run, last }
+    }).collect()
+}
+
+/// Runs the tasks due at `now` and records their results, one after the other.
+pub fn run_due(db: &dyn Storage, config: &MaintenanceConfig, now: DateTime<Utc>) -> StorageResult<Vec<MaintenanceResult>> {
+    let due: Vec<MaintenanceTask> = statuses(config, &db.maintenance_results()?, now)
+        .into_iter()
+        .filter(|status| status.next_run.is_some_and(|next| next <= now.timestamp()))
+        .map(|status| status.task)
+        .collect();
+
+    let mut results = Vec::new();
+    for task in due {
+        let started = Instant::now();
+        let outcome = db.maintain(task);
+        let elapsed = started.elapsed();
+        let result = MaintenanceResult {
+            task,
+            finished_at: now.timestamp() + elapsed.as_secs() as i64,
+            duration_ms: elapsed.as_millis() as u64,
+            ok: outcome.is_ok(),
+            detail: outcome.unwrap_or_else(|e| e.to_string()),
+        };
+        db.record_maintenance(&result)?;
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Runs every maintenance task when it is due, counted from its last run in
+/// the database so restarts don't delay or repeat them.
+pub struct MaintenanceService {
+    db: Arc<dyn Storage>,
+    config: MaintenanceConfig,
+}
+
+impl MaintenanceService {
+    pub fn new(db: Arc<dyn Storage>, config: MaintenanceConfig) -> Self {
+        Self { db, config }
+    }
+
+    pub async fn run(&self) {
+        info!("Starting database maintenance service");
+
+        loop {
+            let config = self.config.clone();
+            match self.db.run_blocking(move |db| run_due(db, &config, Utc::now())).await {
+                Ok(results) => {
+                    for result in results {
+                        if result.ok {
+                            info!("Maintenance {} took {} ms: {}", result.task.as_str(), result.duration_ms, result.detail);
+                        } else {
+                            warn!("Maintenance {} failed: {}", result.task.as_str(), result.detail);
+                        }
+                    }
+                }
+                Err(e) => error!("Error running database maintenance: {}", e),
+            }
+
+            let config = self.config.clone();
+            let next = self.db.run_blocking(move |db| {
+                let now = Utc::now();
+                Ok(statuses(&config, &db.maintenance_results()?, now).iter().filter_map(|s| s.next_run).min()
+                    .map(|next| (next - now.timestamp()).max(0) as u64))
+            }).await;
+            // A minute at least, so a task that keeps failing to record doesn't spin
+            let wait = next.ok().flatten().unwrap_or(RETRY_HOURS * 3600).max(60);
+            sleep(Duration::from_secs(wait)).await;
+        }
+    }
+}
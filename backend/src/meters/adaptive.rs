@@ -0,0 +1,182 @@
+// In meters/adaptive.rs
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use log::debug;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use super::{Channel, Measurement};
+use crate::config::{AdaptiveConfig, LocationConfig};
+
+/// Sun elevation (degrees) below which it counts as night, accounting for
+/// refraction and the solar disc radius as in the usual sunrise definition.
+const SUNSET_ELEVATION: f64 = -0.833;
+
+/// Picks the polling interval for a meter's power groups from recent readings.
+pub struct AdaptiveController {
+    config: AdaptiveConfig,
+    location: Option<LocationConfig>,
+    interval: Duration,
+    last_power: Option<f32>,
+}
+
+impl AdaptiveController {
+    pub fn new(config: AdaptiveConfig, location: Option<LocationConfig>) -> Self {
+        let interval = Duration::from_secs(config.min_interval.into());
+        Self {
+            config,
+            location,
+            interval,
+            last_power: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Updates the interval from a new reading. Readings without a total power
+    /// value (e.g. an energy-only cycle) leave the interval unchanged.
+    pub fn observe(&mut self, meter_name: &str, measurements: &[Measurement], now: DateTime<Utc>) -> Duration {
+        let Some(power) = measurements.iter().find(|m| m.channel == Channel::TOTAL_POWER).map(|m| m.value) else {
+            return self.interval;
+        };
+
+        let min = Duration::from_secs(self.config.min_interval.into());
+        let max = Duration::from_secs(self.config.max_interval.into());
+        let is_night = self.is_night(now);
+
+        let changed = self
+            .last_power
+            .is_some_and(|last| (power - last).abs() > self.config.change_threshold);
+        self.last_power = Some(power);
+
+        self.interval = if changed {
+            min
+        } else if is_night {
+            // Steady at night: go straight to the night rate
+            self.config
+                .night_interval
+                .map(|n| Duration::from_secs(n.into()))
+                .unwrap_or(max)
+                .clamp(min, max)
+        } else {
+            self.interval.mul_f32(self.config.backoff_factor).clamp(min, max)
+        };
+
+        debug!(
+            "{}: adaptive interval {}s (changed: {}, night: {})",
+            meter_name,
+            self.interval.as_secs(),
+            changed,
+            is_night
+        );
+        self.interval
+    }
+
+    fn is_night(&self, now: DateTime<Utc>) -> bool {
+        if !self.config.daylight_throttling {
+            return false;
+        }
+        match &self.location {
+            Some(location) => solar_elevation(location.latitude, location.longitude, now) < SUNSET_ELEVATION,
+            None => false,
+        }
+    }
+}
+
+/// Approximate solar elevation in degrees (NOAA general solar position formulas).
+fn solar_elevation(latitude: f64, longitude: f64, time: DateTime<Utc>) -> f64 {
+    let hour = time.hour() as f64 + time.minute() as f64 / 60.0 + time.second() as f64 / 3600.0;
+    let gamma = 2.0 * PI / 365.0 * (time.ordinal0() as f64 + (hour - 12.0) / 24.0);
+
+    let eqtime = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+    let decl = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+
+    let true_solar_minutes = hour * 60.0 + eqtime + 4.0 * longitude;
+    let hour_angle = (true_solar_minutes / 4.0 - 180.0).to_radians();
+
+    let lat = latitude.to_radians();
+    let cos_zenith = lat.sin() * decl.sin() + lat.cos() * decl.cos() * hour_angle.cos();
+    90.0 - cos_zenith.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn munich(night_interval: Option<u32>) -> AdaptiveController {
+        let config = AdaptiveConfig {
+            min_interval: 2,
+            max_interval: 30,
+            change_threshold: 50.0,
+            backoff_factor: 2.0,
+            daylight_throttling: night_interval.is_some(),
+            night_interval,
+        };
+        let munich = LocationConfig { city: None, timezone: None, latitude: 48.1351, longitude: 11.5820 };
+        AdaptiveController::new(config, Some(munich))
+    }
+
+    fn power(watts: f32, now: DateTime<Utc>) -> Vec<Measurement> {
+        vec![Measurement::new(Channel::TOTAL_POWER, now, watts)]
+    }
+
+    #[test]
+    fn backs_off_while_steady_and_resets_on_change() {
+        let mut controller = munich(None);
+        let now = Utc.with_ymd_and_hms(2025, 6, 21, 0, 0, 0).unwrap();
+        let secs = |controller: &mut AdaptiveController, watts: f32| controller.observe("Roof", &power(watts, now), now).as_secs();
+
+        assert_eq!(controller.interval(), Duration::from_secs(2));
+        let steady: Vec<u64> = (0..5).map(|_| secs(&mut controller, 1000.0)).collect();
+        assert_eq!(steady, [4, 8, 16, 30, 30]);
+        assert_eq!(secs(&mut controller, 1040.0), 30);
+        assert_eq!(secs(&mut controller, 1100.0), 2);
+
+        // Cycles without total power don't count as steady
+        let energy = [Measurement::new(Channel::TOTAL_KWH, now, 1.0)];
+        assert_eq!(controller.observe("Roof", &energy, now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn steady_nights_use_the_night_interval() {
+        let midnight = Utc.with_ymd_and_hms(2025, 6, 21, 0, 0, 0).unwrap();
+        let noon = Utc.with_ymd_and_hms(2025, 6, 21, 11, 0, 0).unwrap();
+
+        let mut controller = munich(Some(20));
+        assert_eq!(controller.observe("Roof", &power(0.0, midnight), midnight).as_secs(), 20);
+        assert_eq!(controller.observe("Roof", &power(500.0, midnight), midnight).as_secs(), 2);
+        assert_eq!(controller.observe("Roof", &power(500.0, noon), noon).as_secs(), 4);
+
+        // Clamped to the configured range
+        let mut clamped = munich(Some(600));
+        assert_eq!(clamped.observe("Roof", &power(0.0, midnight), midnight).as_secs(), 30);
+    }
+
+    #[test]
+    fn solar_elevation_follows_the_sun() {
+        let elevation = |lat: f64, lon: f64, (month, day, hour, minute): (u32, u32, u32, u32)| {
+            solar_elevation(lat, lon, Utc.with_ymd_and_hms(2025, month, day, hour, minute, 0).unwrap())
+        };
+
+        // Munich at solar noon on the June solstice: 90° - 48.1° + 23.4°
+        let noon = elevation(48.1351, 11.5820, (6, 21, 11, 15));
+        assert!((noon - 65.3).abs() < 0.5, "{}", noon);
+        assert!(elevation(48.1351, 11.5820, (6, 21, 23, 15)) < -15.0);
+        assert!(elevation(48.1351, 11.5820, (12, 21, 6, 30)) < SUNSET_ELEVATION);
+        assert!(elevation(48.1351, 11.5820, (12, 21, 7, 30)) > SUNSET_ELEVATION);
+
+        // Overhead at the equator around the March equinox
+        assert!(elevation(0.0, 0.0, (3, 20, 12, 7)) > 88.0);
+    }
+}
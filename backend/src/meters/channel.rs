@@ -0,0 +1,325 @@
+// In meters/channel.rs
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantity {
+    Power,
+    Energy,
+    Voltage,
+    Current,
+    Frequency,
+    PowerFactor,
+}
+
+impl Quantity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quantity::Power => "power",
+            Quantity::Energy => "energy",
+            Quantity::Voltage => "voltage",
+            Quantity::Current => "current",
+            Quantity::Frequency => "frequency",
+            Quantity::PowerFactor => "power_factor",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Quantity::Power => "W",
+            Quantity::Energy => "kWh",
+            Quantity::Voltage => "V",
+            Quantity::Current => "A",
+            Quantity::Frequency => "Hz",
+            Quantity::PowerFactor => "",
+        }
+    }
+
+    /// Cumulative counters as opposed to instantaneous values.
+    pub fn is_counter(&self) -> bool {
+        matches!(self, Quantity::Energy)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Sum over all phases, or the only phase of a single-phase meter
+    All,
+    L1,
+    L2,
+    L3,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::All => "all",
+            Phase::L1 => "l1",
+            Phase::L2 => "l2",
+            Phase::L3 => "l3",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [Phase::All, Phase::L1, Phase::L2, Phase::L3].into_iter().find(|p| p.as_str() == value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Signed value, import positive and export negative
+    Net,
+    Import,
+    Export,
+    /// Import plus export, e.g. the SDM72D total energy counter
+    Total,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Net => "net",
+            Direction::Import => "import",
+            Direction::Export => "export",
+            Direction::Total => "total",
+        }
+    }
+}
+
+/// A value a meter can expose, identified by what it measures.
+///
+/// Channels are named `<quantity>[_<direction>][_<phase>]`, leaving out a net
+/// direction and the all-phase sum, e.g. `power`, `energy_import`, `voltage_l1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Channel {
+    pub quantity: Quantity,
+    pub phase: Phase,
+    pub direction: Direction,
+}
+
+impl Channel {
+    pub const TOTAL_POWER: Channel = Channel::new(Quantity::Power, Phase::All, Direction::Net);
+    pub const IMPORT_POWER: Channel = Channel::new(Quantity::Power, Phase::All, Direction::Import);
+    pub const EXPORT_POWER: Channel = Channel::new(Quantity::Power, Phase::All, Direction::Export);
+    pub const TOTAL_KWH: Channel = Channel::new(Quantity::Energy, Phase::All, Direction::Total);
+
+    /// Channels with a dedicated column in `meter_readings`.
+    pub const COLUMNS: [Channel; 4] = [
+        Channel::TOTAL_POWER,
+        Channel::IMPORT_POWER,
+        Channel::EXPORT_POWER,
+        Channel::TOTAL_KWH,
+    ];
+
+    pub const fn new(quantity: Quantity, phase: Phase, direction: Direction) -> Self {
+        Self { quantity, phase, direction }
+    }
+
+    pub fn name(&self) -> String {
+        let mut name = self.quantity.as_str().to_string();
+        if self.direction != Direction::Net {
+            name.push('_');
+            name.push_str(self.direction.as_str());
+        }
+        if self.phase != Phase::All {
+            name.push('_');
+            name.push_str(self.phase.as_str());
+        }
+        name
+    }
+
+    /// Parses a channel name, also accepting the `meter_readings` column names.
+    pub fn parse(value: &str) -> Option<Self> {
+        if let Some(channel) = Channel::from_column(value) {
+            return Some(channel);
+        }
+
+        // power_factor has to be tried before power
+        let quantities = [
+            Quantity::PowerFactor,
+            Quantity::Power,
+            Quantity::Energy,
+            Quantity::Voltage,
+            Quantity::Current,
+            Quantity::Frequency,
+        ];
+        let quantity = quantities.into_iter().find(|q| value.starts_with(q.as_str()))?;
+        // Nothing or `_` and the parts, so "voltagel1" or "power__import" don't pass as typos
+        let rest = &value[quantity.as_str().len()..];
+        let mut parts = match rest.strip_prefix('_') {
+            Some(rest) => rest.split('_'),
+            None if rest.is_empty() => return Some(Channel::new(quantity, Phase::All, Direction::Net)),
+            None => return None,
+        };
+
+        let mut direction = Direction::Net;
+        let mut phase = Phase::All;
+        let mut part = parts.next();
+
+        if let Some(d) = [Direction::Import, Direction::Export, Direction::Total]
+            .into_iter()
+            .find(|d| part == Some(d.as_str()))
+        {
+            direction = d;
+            part = parts.next();
+        }
+        if let Some(p) = [Phase::L1, Phase::L2, Phase::L3].into_iter().find(|p| part == Some(p.as_str())) {
+            phase = p;
+            part = parts.next();
+        }
+
+        match part {
+            None => Some(Channel::new(quantity, phase, direction)),
+            Some(_) => None,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        self.quantity.unit()
+    }
+
+    pub fn is_power(&self) -> bool {
+        self.quantity == Quantity::Power
+    }
+
+    /// The `meter_readings` column storing this channel, if it has one.
+    pub fn column(&self) -> Option<&'static str> {
+        match *self {
+            Channel::TOTAL_POWER => Some("total_power"),
+            Channel::IMPORT_POWER => Some("import_power"),
+            Channel::EXPORT_POWER => Some("export_power"),
+            Channel::TOTAL_KWH => Some("total_kwh"),
+            _ => None,
+        }
+    }
+
+    fn from_column(column: &str) -> Option<Self> {
+        Channel::COLUMNS.into_iter().find(|c| c.column() == Some(column))
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Serialize for Channel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Channel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Channel::parse(&name).ok_or_else(|| serde::de::Error::custom(format!("unknown channel: {}", name)))
+    }
+}
+
+/// Channel metadata as stored in `meter_channels` and reported by the API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub quantity: Quantity,
+    pub unit: String,
+    pub phase: Phase,
+    pub direction: Direction,
+}
+
+impl From<Channel> for ChannelInfo {
+    fn from(channel: Channel) -> Self {
+        Self {
+            name: channel.name(),
+            quantity: channel.quantity,
+            unit: channel.unit().to_string(),
+            phase: channel.phase,
+            direction: channel.direction,
+        }
+    }
+}
+
+/// A single timestamped value read from a meter.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub channel: Channel,
+    pub timestamp: DateTime<Utc>,
+    pub value: f32,
+}
+
+impl Measurement {
+    pub fn new(channel: Channel, timestamp: DateTime<Utc>, value: f32) -> Self {
+        Self { channel, timestamp, value }
+    }
+
+    /// Formats a set of measurements, e.g. for log output.
+    pub fn describe(measurements: &[Measurement]) -> String {
+        measurements
+            .iter()
+            .map(|m| format!("{}: {:.2}{}", m.channel, m.value, m.channel.unit()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::IntoDeserializer;
+
+    #[test]
+    fn names_round_trip() {
+        let quantities = [
+            Quantity::Power,
+            Quantity::Energy,
+            Quantity::Voltage,
+            Quantity::Current,
+            Quantity::Frequency,
+            Quantity::PowerFactor,
+        ];
+        let directions = [Direction::Net, Direction::Import, Direction::Export, Direction::Total];
+        let phases = [Phase::All, Phase::L1, Phase::L2, Phase::L3];
+        for quantity in quantities {
+            for direction in directions {
+                for phase in phases {
+                    let channel = Channel::new(quantity, phase, direction);
+                    assert_eq!(Channel::parse(&channel.name()), Some(channel), "{}", channel);
+                }
+            }
+        }
+
+        assert_eq!(Channel::TOTAL_POWER.name(), "power");
+        assert_eq!(Channel::TOTAL_KWH.name(), "energy_total");
+        assert_eq!(Channel::new(Quantity::PowerFactor, Phase::L2, Direction::Net).name(), "power_factor_l2");
+        assert_eq!(Channel::new(Quantity::Energy, Phase::L3, Direction::Export).name(), "energy_export_l3");
+    }
+
+    #[test]
+    fn parses_legacy_column_names() {
+        for channel in Channel::COLUMNS {
+            assert_eq!(Channel::parse(channel.column().unwrap()), Some(channel));
+        }
+        assert_eq!(Channel::parse("total_power"), Some(Channel::TOTAL_POWER));
+        assert_eq!(Channel::parse("import_power"), Channel::parse("power_import"));
+        assert_eq!(Channel::parse("total_kwh"), Some(Channel::TOTAL_KWH));
+        assert_eq!(Channel::new(Quantity::Voltage, Phase::L1, Direction::Net).column(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        for name in [
+            "", "temperature", "power_l4", "energy_import_l1_extra", "l1_voltage", "total_voltage",
+            "voltagel1", "power__import", "energyimport", "power_", "voltage_l1_",
+        ] {
+            assert_eq!(Channel::parse(name), None, "{}", name);
+        }
+        let deserialize = |name: &str| Channel::deserialize(IntoDeserializer::<serde::de::value::Error>::into_deserializer(name));
+        assert!(deserialize("power_l4").is_err());
+        assert_eq!(deserialize("voltage_l1").unwrap().to_string(), "voltage_l1");
+    }
+}
@@ -1,15 +1,25 @@
 // In meters/mock_meter.rs
 
 use async_trait::async_trait;
-use super::MeterReader;
-use crate::database_sync::Model;
+use super::{Channel, Direction, Measurement, MeterReader, Phase, Quantity};
 use chrono::Utc;
 use std::time::Duration;
 use anyhow::Result;
 
+const CHANNELS: [Channel; 6] = [
+    Channel::TOTAL_POWER,
+    Channel::IMPORT_POWER,
+    Channel::EXPORT_POWER,
+    Channel::TOTAL_KWH,
+    Channel::new(Quantity::Energy, Phase::All, Direction::Import),
+    Channel::new(Quantity::Energy, Phase::All, Direction::Export),
+];
+
 pub struct MockMeter {
     name: String,
     kwh_accumulator: f32,
+    import_kwh: f32,
+    export_kwh: f32,
     last_update: Option<chrono::DateTime<Utc>>,
 }
 
@@ -18,6 +28,8 @@ impl MockMeter {
         Self {
             name,
             kwh_accumulator: 0.0,
+            import_kwh: 0.0,
+            export_kwh: 0.0,
             last_update: None,
         }
     }
@@ -25,31 +37,50 @@ impl MockMeter {
 
 #[async_trait]
 impl MeterReader for MockMeter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn channels(&self) -> &[Channel] {
+        &CHANNELS
+    }
+
     fn get_polling_rate(&self) -> u32 {
         10
     }
 
-    async fn get_value(&mut self) -> Result<Model> {
+    async fn read_channels(&mut self, channels: &[Channel]) -> Result<Vec<Measurement>> {
         let now = Utc::now();
         let total_power = (now.timestamp() as f32 / 3600.0).sin() * 1000.0;
         
         if let Some(last_update) = self.last_update {
             let hours = now.signed_duration_since(last_update).num_milliseconds() as f32 / 3_600_000.0;
-            self.kwh_accumulator += total_power * hours;
+            self.kwh_accumulator += total_power.abs() * hours / 1000.0;
+            self.import_kwh += total_power.max(0.0) * hours / 1000.0;
+            self.export_kwh += (-total_power).max(0.0) * hours / 1000.0;
         }
         self.last_update = Some(now);
 
-        Ok(Model {
-            meter_name: self.name.clone(),
-            timestamp: now,
-            total_power,
-            import_power: total_power.max(0.0),
-            export_power: (-total_power).max(0.0),
-            total_kwh: self.kwh_accumulator,
-        })
+        let measurements = channels
+            .iter()
+            .filter_map(|&channel| {
+                let value = match channel {
+                    Channel::TOTAL_POWER => total_power,
+                    Channel::IMPORT_POWER => total_power.max(0.0),
+                    Channel::EXPORT_POWER => (-total_power).max(0.0),
+                    Channel::TOTAL_KWH => self.kwh_accumulator,
+                    c if c == CHANNELS[4] => self.import_kwh,
+                    c if c == CHANNELS[5] => self.export_kwh,
+                    _ => return None,
+                };
+                Some(Measurement::new(channel, now, value))
+            })
+            .collect();
+
+        Ok(measurements)
     }
 
     fn get_timeout(&self) -> Duration {
         Duration::from_secs(5)
     }
-}
\ No newline at end of file
+}
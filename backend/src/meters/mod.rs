@@ -6,22 +6,37 @@ use tokio::sync::Mutex;
 use std::sync::Arc;
 use tokio_modbus::client::Context;
 use std::collections::HashMap;
-use crate::database_sync::Model;
 use tokio::time::timeout;
-use log::{debug, error, info, warn};
+use log::{debug, error};
 
 
+mod adaptive;
+mod channel;
 mod mock_meter;
+mod schedule;
 mod sdm72d;
 
+pub use adaptive::AdaptiveController;
+pub use channel::{Channel, ChannelInfo, Direction, Measurement, Phase, Quantity};
 pub use mock_meter::MockMeter;
+pub use schedule::PollingSchedule;
 pub use sdm72d::SDM72DMeter;
 
 #[async_trait]
 pub trait MeterReader: Send {
-    async fn get_value(&mut self) -> Result<Model, Error>;
+    /// Display name of the meter as configured.
+    fn name(&self) -> &str;
+    /// The channels this meter can read, in the order they are read.
+    fn channels(&self) -> &[Channel];
+    /// Reads the given channels. Channels the meter does not declare are skipped.
+    async fn read_channels(&mut self, channels: &[Channel]) -> Result<Vec<Measurement>, Error>;
     fn get_timeout(&self) -> Duration;
     fn get_polling_rate(&self) -> u32;
+
+    async fn read_all(&mut self) -> Result<Vec<Measurement>, Error> {
+        let channels = self.channels().to_vec();
+        self.read_channels(&channels).await
+    }
 }
 
 pub struct SharedSerial {
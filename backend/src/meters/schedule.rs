@@ -0,0 +1,164 @@
+// In meters/schedule.rs
+
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+use super::Channel;
+use crate::config::PollingGroup;
+
+struct ScheduledGroup {
+    channels: Vec<Channel>,
+    base_interval: Duration,
+    interval: Duration,
+    /// Groups reading a power channel follow the adaptive interval, if enabled.
+    adaptive: bool,
+    last_run: Option<Instant>,
+}
+
+impl ScheduledGroup {
+    fn due_at(&self) -> Option<Instant> {
+        self.last_run.map(|last| last + self.interval)
+    }
+}
+
+/// Combines a meter's polling groups into per-cycle read plans.
+///
+/// Each group keeps its own due time. A cycle waits for the earliest due group
+/// and reads the union of all channels that are due at that moment, so groups
+/// with coinciding intervals share a single bus transaction.
+pub struct PollingSchedule {
+    groups: Vec<ScheduledGroup>,
+}
+
+impl PollingSchedule {
+    pub fn new(groups: Vec<PollingGroup>) -> Self {
+        let groups = groups
+            .into_iter()
+            .map(|g| {
+                let interval = Duration::from_secs(g.interval.into());
+                ScheduledGroup {
+                    adaptive: g.channels.iter().any(|c| c.is_power()),
+                    channels: g.channels,
+                    base_interval: interval,
+                    interval,
+                    last_run: None,
+                }
+            })
+            .collect();
+        Self { groups }
+    }
+
+    /// Interval of the fastest group, used as the meter's effective polling rate.
+    pub fn shortest_interval(&self) -> Duration {
+        self.groups
+            .iter()
+            .map(|g| g.interval)
+            .min()
+            .unwrap_or(Duration::from_secs(1))
+    }
+
+    /// Overrides the interval of all power groups, or restores their configured
+    /// interval with `None`. A shorter interval takes effect immediately.
+    pub fn set_adaptive_interval(&mut self, interval: Option<Duration>) {
+        for group in self.groups.iter_mut().filter(|g| g.adaptive) {
+            group.interval = interval.unwrap_or(group.base_interval);
+        }
+    }
+
+    /// Waits until the next group is due and returns the channels to read.
+    pub async fn next_plan(&mut self) -> Vec<Channel> {
+        // Groups that never ran are due immediately
+        let earliest = self.groups.iter().map(|g| g.due_at()).min().flatten();
+        if let Some(earliest) = earliest {
+            sleep_until(earliest).await;
+        }
+        self.take_due(Instant::now())
+    }
+
+    fn take_due(&mut self, now: Instant) -> Vec<Channel> {
+        let mut due = Vec::new();
+
+        for group in &mut self.groups {
+            let scheduled = match group.due_at() {
+                Some(at) if at > now => continue,
+                Some(at) => at,
+                None => now,
+            };
+
+            for channel in &group.channels {
+                if !due.contains(channel) {
+                    due.push(*channel);
+                }
+            }
+
+            // Keep the cadence, but don't try to catch up on missed cycles
+            // after a stall or read error
+            group.last_run = Some(if now - scheduled < group.interval { scheduled } else { now });
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> PollingSchedule {
+        PollingSchedule::new(vec![
+            PollingGroup { interval: 2, channels: vec![Channel::TOTAL_POWER, Channel::IMPORT_POWER] },
+            PollingGroup { interval: 6, channels: vec![Channel::TOTAL_KWH, Channel::TOTAL_POWER] },
+        ])
+    }
+
+    #[test]
+    fn due_groups_share_a_cycle() {
+        let mut schedule = schedule();
+        let t0 = Instant::now();
+        let secs = |s: f64| t0 + Duration::from_secs_f64(s);
+
+        assert_eq!(schedule.take_due(t0), [Channel::TOTAL_POWER, Channel::IMPORT_POWER, Channel::TOTAL_KWH]);
+        assert!(schedule.take_due(secs(1.0)).is_empty());
+        assert_eq!(schedule.take_due(secs(2.0)), [Channel::TOTAL_POWER, Channel::IMPORT_POWER]);
+        assert_eq!(schedule.take_due(secs(4.0)).len(), 2);
+        assert_eq!(schedule.take_due(secs(6.0)), [Channel::TOTAL_POWER, Channel::IMPORT_POWER, Channel::TOTAL_KWH]);
+        assert_eq!(schedule.shortest_interval(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn keeps_cadence_without_catching_up() {
+        let mut schedule = schedule();
+        let t0 = Instant::now();
+        let secs = |s: f64| t0 + Duration::from_secs_f64(s);
+        schedule.take_due(t0);
+
+        // A late wake-up keeps the original cadence
+        schedule.take_due(secs(2.5));
+        assert!(schedule.take_due(secs(3.9)).is_empty());
+        assert_eq!(schedule.take_due(secs(4.0)).len(), 2);
+
+        // After a stall the power group skips the missed cycles and counts
+        // from now, the slower group was late by less than its interval
+        assert_eq!(schedule.take_due(secs(11.0)).len(), 3);
+        assert_eq!(schedule.take_due(secs(12.0)), [Channel::TOTAL_KWH, Channel::TOTAL_POWER]);
+        assert_eq!(schedule.take_due(secs(13.0)), [Channel::TOTAL_POWER, Channel::IMPORT_POWER]);
+    }
+
+    #[test]
+    fn adaptive_interval_applies_to_power_groups() {
+        let mut schedule = PollingSchedule::new(vec![
+            PollingGroup { interval: 10, channels: vec![Channel::TOTAL_POWER] },
+            PollingGroup { interval: 60, channels: vec![Channel::TOTAL_KWH] },
+        ]);
+        let t0 = Instant::now();
+        schedule.take_due(t0);
+
+        schedule.set_adaptive_interval(Some(Duration::from_secs(2)));
+        assert_eq!(schedule.shortest_interval(), Duration::from_secs(2));
+        assert_eq!(schedule.take_due(t0 + Duration::from_secs(2)), [Channel::TOTAL_POWER]);
+
+        schedule.set_adaptive_interval(None);
+        assert!(schedule.take_due(t0 + Duration::from_secs(11)).is_empty());
+        assert_eq!(schedule.take_due(t0 + Duration::from_secs(12)), [Channel::TOTAL_POWER]);
+    }
+}
@@ -4,8 +4,7 @@ use tokio_modbus::prelude::*;
 use tokio_serial::SerialStream;
 use std::time::Duration;
 use async_trait::async_trait;
-use super::{MeterReader, SharedSerial};
-use crate::database_sync::Model;
+use super::{Channel, Direction, Measurement, MeterReader, Phase, Quantity, SharedSerial};
 use chrono::Utc;
 use anyhow::{Context, Result, Error};
 use std::sync::Arc;
@@ -17,6 +16,29 @@ mod registers {
     pub const IMPORT_POWER: u16 = 0x500;    // Import power (W)
     pub const EXPORT_POWER: u16 = 0x502;    // Export power (W)
     pub const TOTAL_ENERGY: u16 = 0x156;    // Total energy (kWh)
+    pub const IMPORT_ENERGY: u16 = 0x48;    // Total import energy (kWh)
+    pub const EXPORT_ENERGY: u16 = 0x4A;    // Total export energy (kWh)
+}
+
+const CHANNELS: [Channel; 6] = [
+    Channel::TOTAL_POWER,
+    Channel::IMPORT_POWER,
+    Channel::EXPORT_POWER,
+    Channel::TOTAL_KWH,
+    Channel::new(Quantity::Energy, Phase::All, Direction::Import),
+    Channel::new(Quantity::Energy, Phase::All, Direction::Export),
+];
+
+fn channel_register(channel: Channel) -> Option<(u16, &'static str)> {
+    match channel {
+        Channel::TOTAL_POWER => Some((registers::TOTAL_POWER, "Total Power")),
+        Channel::IMPORT_POWER => Some((registers::IMPORT_POWER, "Import Power")),
+        Channel::EXPORT_POWER => Some((registers::EXPORT_POWER, "Export Power")),
+        Channel::TOTAL_KWH => Some((registers::TOTAL_ENERGY, "Total Energy")),
+        c if c == CHANNELS[4] => Some((registers::IMPORT_ENERGY, "Import Energy")),
+        c if c == CHANNELS[5] => Some((registers::EXPORT_ENERGY, "Export Energy")),
+        _ => None,
+    }
 }
 
 pub struct SDM72DMeter {
@@ -113,11 +135,19 @@ impl SDM72DMeter {
 
 #[async_trait]
 impl MeterReader for SDM72DMeter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn channels(&self) -> &[Channel] {
+        &CHANNELS
+    }
+
     fn get_polling_rate(&self) -> u32 {
         self.polling_rate
     }
 
-    async fn get_value(&mut self) -> Result<Model, Error> {
+    async fn read_channels(&mut self, channels: &[Channel]) -> Result<Vec<Measurement>, Error> {
         info!("{}: Starting new reading cycle", self.name);
 
         // Acquire lock before starting communication
@@ -125,29 +155,21 @@ impl MeterReader for SDM72DMeter {
 
         // Use a closure to ensure we always release the lock
         let result = async {
-            let total_power = self.read_float_register(registers::TOTAL_POWER, "Total Power")
-                .await?;
-
-            let import_power = self.read_float_register(registers::IMPORT_POWER, "Import Power")
-                .await?;
-
-            let export_power = self.read_float_register(registers::EXPORT_POWER, "Export Power")
-                .await?;
-
-            let total_kwh = self.read_float_register(registers::TOTAL_ENERGY, "Total Energy")
-                .await?;
+            // One timestamp per cycle so the values land in the same row
+            let timestamp = Utc::now();
+            let mut measurements = Vec::with_capacity(channels.len());
+
+            for &channel in channels {
+                let Some((register, description)) = channel_register(channel) else {
+                    continue;
+                };
+                let value = self.read_float_register(register, description).await?;
+                measurements.push(Measurement::new(channel, timestamp, value));
+            }
 
-            info!("{}: Completed reading cycle. Total Power: {:.2}W, Import: {:.2}W, Export: {:.2}W, Total: {:.2}kWh",
-                self.name, total_power, import_power, export_power, total_kwh);
+            info!("{}: Completed reading cycle. {}", self.name, Measurement::describe(&measurements));
 
-            Ok(Model {
-                meter_name: self.name.clone(),
-                timestamp: Utc::now(),
-                total_power,
-                import_power,
-                export_power,
-                total_kwh,
-            })
+            Ok(measurements)
         }.await;
 
         // Always release the lock
@@ -0,0 +1,625 @@
+use chrono::Utc;
+use log::{info, warn};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use std::path::Path;
+
+/// One schema change. Migrations run in order, each in its own transaction
+/// together with its `schema_version` row.
+///
+/// Databases created before versioning have no `schema_version` table and start
+/// at version 0, so every migration has to tolerate objects that already exist.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial meter and readings tables",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "Allow NULL values for channels not polled in a cycle",
+        up: nullable_reading_columns,
+    },
+    Migration {
+        version: 3,
+        description: "Declared meter channels and channel readings",
+        up: meter_channels,
+    },
+    Migration {
+        version: 4,
+        description: "Diagnostic capture sessions",
+        up: capture_sessions,
+    },
+    Migration {
+        version: 5,
+        description: "Database settings",
+        up: db_settings,
+    },
+    Migration {
+        version: 6,
+        description: "Stable meter keys separate from display names",
+        up: meter_keys,
+    },
+    Migration {
+        version: 7,
+        description: "Meter ids beyond 255 and meter metadata",
+        up: meter_metadata,
+    },
+    Migration {
+        version: 8,
+        description: "Quarantine for rows removed by check --repair",
+        up: quarantine,
+    },
+    Migration {
+        version: 9,
+        description: "Rollups kept after raw readings expire",
+        up: rollups,
+    },
+    Migration {
+        version: 10,
+        description: "Rollup watermarks",
+        up: rollup_watermarks,
+    },
+    Migration {
+        version: 11,
+        description: "Optional rollup aggregates",
+        up: optional_rollup_aggregates,
+    },
+    Migration {
+        version: 12,
+        description: "Energy per rollup bucket",
+        up: rollup_energy,
+    },
+    Migration {
+        version: 13,
+        description: "Retention run reports",
+        up: retention_runs,
+    },
+    Migration {
+        version: 14,
+        description: "Maintenance results",
+        up: maintenance_results,
+    },
+];
+
+/// Schema version this build writes.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Brings the database up to `latest_version`, backing it up first if it
+/// already holds data. Refuses databases written by a newer build.
+///
+/// New databases are created with incremental auto-vacuum. Switching an
+/// existing one rebuilds the whole file, which is left to the vacuum task.
+pub fn run(conn: &mut Connection, database_url: &str, create_database: bool) -> Result<u32, Box<dyn std::error::Error>> {
+    let table_count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    if table_count == 0 && !create_database {
+        return Err(format!(
+            "Database {} is empty and create_database is false; set create_database = true to initialize it",
+            database_url
+        ).into());
+    }
+
+    if table_count == 0 {
+        // Switching to WAL already wrote the header, vacuuming the empty file is free
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL  -- Unix timestamp in seconds
+        )",
+        [],
+    )?;
+
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        return Err(format!(
+            "Database schema version {} is newer than this build supports ({}); refusing to start",
+            current, latest
+        ).into());
+    }
+
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    if pending.is_empty() {
+        return Ok(current);
+    }
+
+    if table_count > 0 {
+        backup(conn, database_url, &format!("v{}", current), "migrating")?;
+    }
+
+    // Rebuilding a table means dropping one other tables reference, which SQLite
+    // only allows with foreign key enforcement off. It can't change inside a transaction.
+    conn.pragma_update(None, "foreign_keys", false)?;
+    for migration in pending {
+        info!("Applying database migration {}: {}", migration.version, migration.description);
+        let tx = conn.transaction()?;
+        (migration.up)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.description, Utc::now().timestamp()],
+        )?;
+        tx.commit()?;
+    }
+    conn.pragma_update(None, "foreign_keys", true)?;
+
+    info!("Database schema migrated from version {} to {}", current, latest);
+    Ok(latest)
+}
+
+pub fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get::<_, Option<u32>>(0))
+        .optional()
+        .map(|v| v.flatten().unwrap_or(0))
+}
+
+/// Writes a consistent copy next to the database before `reason`, e.g.
+/// `solar_db.db.v3-20250101T120000.bak` for `label` v3.
+pub fn backup(conn: &Connection, database_url: &str, label: &str, reason: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let backup_path = format!("{}.{}-{}.bak", database_url, label, Utc::now().format("%Y%m%dT%H%M%S"));
+    if Path::new(&backup_path).exists() {
+        warn!("Backup {} already exists, keeping it", backup_path);
+        return Ok(());
+    }
+
+    info!("Backing up database to {} before {}", backup_path, reason);
+    conn.execute("VACUUM INTO ?1", params![backup_path])?;
+    Ok(())
+}
+
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS meter_names (
+            meter_id INTEGER PRIMARY KEY CHECK (meter_id >= 0 AND meter_id <= 255),
+            name TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE IF NOT EXISTS meter_readings (
+            meter_id INTEGER NOT NULL CHECK (meter_id >= 0 AND meter_id <= 255),
+            timestamp INTEGER NOT NULL,  -- Unix timestamp in seconds
+            total_power SMALLINT,  -- encoded per db_settings.power_encoding, NULL if not polled
+            import_power SMALLINT, -- encoded per db_settings.power_encoding, NULL if not polled
+            export_power SMALLINT, -- encoded per db_settings.power_encoding, NULL if not polled
+            total_kwh REAL,        -- f32 stored as REAL, NULL if not polled
+            PRIMARY KEY (meter_id, timestamp),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_meter_timestamp
+            ON meter_readings (meter_id, timestamp);",
+    )
+}
+
+/// Databases created before per-channel polling declare every value column
+/// `NOT NULL`. SQLite can't alter column constraints, so rebuild the table.
+fn nullable_reading_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    let total_power_not_null: Option<bool> = tx.query_row(
+        "SELECT \"notnull\" FROM pragma_table_info('meter_readings') WHERE name = 'total_power'",
+        [],
+        |row| row.get(0),
+    ).optional()?;
+
+    if total_power_not_null != Some(true) {
+        return Ok(());
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE meter_readings_new (
+            meter_id INTEGER NOT NULL CHECK (meter_id >= 0 AND meter_id <= 255),
+            timestamp INTEGER NOT NULL,
+            total_power SMALLINT,
+            import_power SMALLINT,
+            export_power SMALLINT,
+            total_kwh REAL,
+            PRIMARY KEY (meter_id, timestamp),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );
+        INSERT INTO meter_readings_new SELECT * FROM meter_readings;
+        DROP TABLE meter_readings;
+        ALTER TABLE meter_readings_new RENAME TO meter_readings;
+        CREATE INDEX IF NOT EXISTS idx_meter_timestamp ON meter_readings (meter_id, timestamp);",
+    )
+}
+
+fn meter_channels(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "-- Channels declared by each meter driver
+        CREATE TABLE IF NOT EXISTS meter_channels (
+            meter_id INTEGER NOT NULL,
+            channel TEXT NOT NULL,
+            quantity TEXT NOT NULL,
+            unit TEXT NOT NULL,
+            phase TEXT NOT NULL,
+            direction TEXT NOT NULL,
+            PRIMARY KEY (meter_id, channel),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );
+
+        -- Values of channels without a dedicated meter_readings column
+        CREATE TABLE IF NOT EXISTS channel_readings (
+            meter_id INTEGER NOT NULL,
+            channel TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,  -- Unix timestamp in seconds
+            value REAL NOT NULL,
+            PRIMARY KEY (meter_id, channel, timestamp),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );",
+    )
+}
+
+fn capture_sessions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "-- Diagnostic captures live outside meter_readings so retention never touches them
+        CREATE TABLE IF NOT EXISTS capture_sessions (
+            session_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            meter_id INTEGER NOT NULL,
+            channels TEXT NOT NULL,        -- comma separated channel names
+            interval_ms INTEGER NOT NULL,
+            duration_secs INTEGER NOT NULL,
+            status TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER,
+            error TEXT,
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );
+
+        CREATE TABLE IF NOT EXISTS capture_samples (
+            session_id INTEGER NOT NULL,
+            timestamp_ms INTEGER NOT NULL,  -- Unix timestamp in milliseconds
+            channel TEXT NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (session_id, timestamp_ms, channel),
+            FOREIGN KEY (session_id) REFERENCES capture_sessions(session_id)
+        );",
+    )
+}
+
+fn db_settings(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS db_settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );",
+    )
+}
+
+/// Meters used to be identified by their display name. Existing meters keep
+/// their name as key until `register_meter` adopts them under their config id.
+/// SQLite can't drop the UNIQUE constraint on `name`, so rebuild the table.
+fn meter_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    let has_key: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('meter_names') WHERE name = 'meter_key'",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_key {
+        return Ok(());
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE meter_names_new (
+            meter_id INTEGER PRIMARY KEY CHECK (meter_id >= 0 AND meter_id <= 255),
+            meter_key TEXT NOT NULL UNIQUE,  -- config table id or explicit id, never changes on its own
+            name TEXT NOT NULL,              -- display name from the config
+            retired_at INTEGER               -- Unix timestamp, NULL while the meter is in use
+        );
+        INSERT INTO meter_names_new (meter_id, meter_key, name) SELECT meter_id, name, name FROM meter_names;
+        DROP TABLE meter_names;
+        ALTER TABLE meter_names_new RENAME TO meter_names;",
+    )
+}
+
+/// Meter ids used to be limited to 0-255 by CHECK constraints on `meter_names`
+/// and `meter_readings`. Both tables are rebuilt without them.
+fn meter_metadata(tx: &Transaction) -> rusqlite::Result<()> {
+    let has_metadata: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('meter_names') WHERE name = 'role'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_metadata {
+        tx.execute_batch(
+            "CREATE TABLE meter_names_new (
+                meter_id INTEGER PRIMARY KEY,
+                meter_key TEXT NOT NULL UNIQUE,  -- config table id or explicit id, never changes on its own
+                name TEXT NOT NULL,              -- display name from the config
+                retired_at INTEGER,              -- Unix timestamp, NULL while the meter is in use
+                meter_type TEXT,                 -- driver, e.g. sdm72d
+                unit TEXT,
+                location TEXT,
+                role TEXT,                       -- grid, producer, consumer or storage
+                phase TEXT,                      -- all, l1, l2 or l3
+                installed_on TEXT,               -- ISO 8601 date
+                removed_on TEXT                  -- ISO 8601 date
+            );
+            INSERT INTO meter_names_new (meter_id, meter_key, name, retired_at)
+                SELECT meter_id, meter_key, name, retired_at FROM meter_names;
+            DROP TABLE meter_names;
+            ALTER TABLE meter_names_new RENAME TO meter_names;",
+        )?;
+    }
+
+    let readings_sql: String = tx.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'meter_readings'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !readings_sql.contains("CHECK") {
+        return Ok(());
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE meter_readings_new (
+            meter_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,  -- Unix timestamp in seconds
+            total_power SMALLINT,  -- encoded per db_settings.power_encoding, NULL if not polled
+            import_power SMALLINT, -- encoded per db_settings.power_encoding, NULL if not polled
+            export_power SMALLINT, -- encoded per db_settings.power_encoding, NULL if not polled
+            total_kwh REAL,        -- f32 stored as REAL, NULL if not polled
+            PRIMARY KEY (meter_id, timestamp),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );
+        INSERT INTO meter_readings_new SELECT * FROM meter_readings;
+        DROP TABLE meter_readings;
+        ALTER TABLE meter_readings_new RENAME TO meter_readings;
+        CREATE INDEX IF NOT EXISTS idx_meter_timestamp ON meter_readings (meter_id, timestamp);",
+    )
+}
+
+/// Rows `check --repair` took out of `meter_readings` and `channel_readings`,
+/// kept as they were stored so a wrong repair can be undone by hand.
+fn quarantine(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS quarantined_readings (
+            meter_id INTEGER NOT NULL,   -- not a foreign key, orphaned rows end up here too
+            timestamp INTEGER NOT NULL,
+            total_power SMALLINT,        -- encoded per db_settings.power_encoding
+            import_power SMALLINT,
+            export_power SMALLINT,
+            total_kwh REAL,
+            reason TEXT NOT NULL,
+            quarantined_at INTEGER NOT NULL  -- Unix timestamp in seconds
+        );
+
+        CREATE TABLE IF NOT EXISTS quarantined_channel_readings (
+            meter_id INTEGER NOT NULL,
+            channel TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            value REAL NOT NULL,
+            reason TEXT NOT NULL,
+            quarantined_at INTEGER NOT NULL
+        );",
+    )
+}
+
+/// Retention used to replace old readings with 10 to 60 minute averages in
+/// place. Those rows stay, `legacy_downsampled_until` tells `check` not to
+/// report their spacing as gaps.
+fn rollups(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "-- Aggregates per meter, channel and UTC-aligned bucket, values in real units
+        CREATE TABLE IF NOT EXISTS rollups (
+            meter_id INTEGER NOT NULL,
+            width_secs INTEGER NOT NULL,    -- 600, 3600 or 86400
+            bucket_start INTEGER NOT NULL,  -- Unix timestamp in seconds
+            channel TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            avg REAL NOT NULL,
+            min REAL NOT NULL,
+            max REAL NOT NULL,
+            first REAL NOT NULL,
+            last REAL NOT NULL,
+            PRIMARY KEY (meter_id, width_secs, bucket_start, channel),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        ) WITHOUT ROWID;
+
+        CREATE INDEX IF NOT EXISTS idx_channel_readings_meter_time ON channel_readings (meter_id, timestamp);",
+    )?;
+
+    let has_readings: bool = tx.query_row("SELECT EXISTS (SELECT 1 FROM meter_readings)", [], |row| row.get(0))?;
+    if has_readings {
+        tx.execute(
+            "INSERT OR IGNORE INTO db_settings (key, value) VALUES ('legacy_downsampled_until', ?1)",
+            params![Utc::now().timestamp().to_string()],
+        )?;
+    }
+    Ok(())
+}
+
+/// How far each tier is rolled up, so a pass starts where the last one ended
+/// even across stretches without readings.
+fn rollup_watermarks(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS rollup_watermarks (
+            meter_id INTEGER NOT NULL,
+            width_secs INTEGER NOT NULL,
+            rolled_until INTEGER NOT NULL,  -- Unix timestamp, buckets before it are final
+            PRIMARY KEY (meter_id, width_secs),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        );
+
+        INSERT OR IGNORE INTO rollup_watermarks (meter_id, width_secs, rolled_until)
+            SELECT meter_id, width_secs, MAX(bucket_start) + width_secs FROM rollups GROUP BY meter_id, width_secs;",
+    )
+}
+
+/// Tiers keep only the aggregates the retention policy asks for, the others
+/// are NULL. SQLite can't drop NOT NULL, so the table is rebuilt.
+fn optional_rollup_aggregates(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE rollups_new (
+            meter_id INTEGER NOT NULL,
+            width_secs INTEGER NOT NULL,
+            bucket_start INTEGER NOT NULL,  -- Unix timestamp in seconds
+            channel TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            avg REAL NOT NULL,
+            min REAL,
+            max REAL,
+            first REAL,
+            last REAL,
+            PRIMARY KEY (meter_id, width_secs, bucket_start, channel),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        ) WITHOUT ROWID;
+
+        INSERT INTO rollups_new SELECT meter_id, width_secs, bucket_start, channel, count, avg, min, max, first, last FROM rollups;
+        DROP TABLE rollups;
+        ALTER TABLE rollups_new RENAME TO rollups;",
+    )
+}
+
+/// Energy imported and exported per bucket, so totals survive raw readings.
+/// Rolls up again whatever raw readings are left, this time with energy.
+fn rollup_energy(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS rollup_energy (
+            meter_id INTEGER NOT NULL,
+            width_secs INTEGER NOT NULL,
+            bucket_start INTEGER NOT NULL,  -- Unix timestamp in seconds
+            imported_kwh REAL NOT NULL,
+            exported_kwh REAL NOT NULL,
+            import_from INTEGER NOT NULL,   -- 1 counter, 2 power, 3 both
+            export_from INTEGER NOT NULL,
+            PRIMARY KEY (meter_id, width_secs, bucket_start),
+            FOREIGN KEY (meter_id) REFERENCES meter_names(meter_id)
+        ) WITHOUT ROWID;
+
+        UPDATE rollup_watermarks SET rolled_until = MIN(
+            rolled_until,
+            COALESCE((SELECT MIN(timestamp) FROM meter_readings m WHERE m.meter_id = rollup_watermarks.meter_id), rolled_until)
+                / width_secs * width_secs,
+            COALESCE((SELECT MIN(timestamp) FROM channel_readings c WHERE c.meter_id = rollup_watermarks.meter_id), rolled_until)
+                / width_secs * width_secs
+        );",
+    )
+}
+
+/// What each retention run read, wrote and deleted. Steps keep the meter key
+/// rather than its id, so reports outlive merged meters.
+fn retention_runs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS retention_runs (
+            run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at INTEGER NOT NULL,  -- Unix timestamp in seconds
+            duration_ms INTEGER NOT NULL,
+            size_before_bytes INTEGER NOT NULL,
+            size_after_bytes INTEGER NOT NULL,
+            errors TEXT NOT NULL          -- one failed meter per line
+        );
+
+        CREATE TABLE IF NOT EXISTS retention_run_steps (
+            run_id INTEGER NOT NULL,
+            meter_key TEXT NOT NULL,
+            width_secs INTEGER,           -- NULL for raw readings
+            rows_read INTEGER NOT NULL,
+            rows_written INTEGER NOT NULL,
+            rows_deleted INTEGER NOT NULL,
+            FOREIGN KEY (run_id) REFERENCES retention_runs(run_id)
+        );
+        CREATE INDEX IF NOT EXISTS idx_retention_run_steps_run ON retention_run_steps (run_id);",
+    )
+}
+
+/// Latest result of each maintenance task.
+fn maintenance_results(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS maintenance_results (
+            task TEXT PRIMARY KEY,
+            finished_at INTEGER NOT NULL,  -- Unix timestamp in seconds
+            duration_ms INTEGER NOT NULL,
+            ok INTEGER NOT NULL,
+            detail TEXT NOT NULL
+        );",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDb;
+
+    fn backups(temp: &TempDb) -> Vec<std::path::PathBuf> {
+        let prefix = format!("{}.v", temp.path().file_name().unwrap().to_string_lossy());
+        std::fs::read_dir(temp.path().parent().unwrap()).unwrap()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(&prefix))
+            .collect()
+    }
+
+    #[test]
+    fn creates_the_schema_only_when_asked() {
+        let temp = TempDb::new("migrations-new");
+        let mut conn = Connection::open(temp.path()).unwrap();
+        assert!(run(&mut conn, temp.url(), false).unwrap_err().to_string().contains("create_database"));
+
+        assert_eq!(run(&mut conn, temp.url(), true).unwrap(), latest_version());
+        assert_eq!(run(&mut conn, temp.url(), true).unwrap(), latest_version());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0)).unwrap();
+        assert_eq!(auto_vacuum, 2);
+        // Nothing worth a backup yet
+        assert!(backups(&temp).is_empty());
+    }
+
+    #[test]
+    fn backs_up_before_migrating() {
+        let temp = TempDb::new("migrations-legacy");
+        let mut conn = Connection::open(temp.path()).unwrap();
+        // A database from before versioning
+        let tx = conn.transaction().unwrap();
+        initial_schema(&tx).unwrap();
+        tx.commit().unwrap();
+        conn.execute_batch(
+            "INSERT INTO meter_names (meter_id, name) VALUES (1, 'Roof');
+             INSERT INTO meter_readings (meter_id, timestamp, total_power, import_power, export_power, total_kwh)
+             VALUES (1, 1700000000, 0, 0, 0, 12.5);",
+        ).unwrap();
+
+        assert_eq!(run(&mut conn, temp.url(), false).unwrap(), latest_version());
+        let kwh: f64 = conn.query_row("SELECT total_kwh FROM meter_readings", [], |row| row.get(0)).unwrap();
+        assert_eq!(kwh, 12.5);
+        // Left to the vacuum task, it rebuilds the file
+        let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0)).unwrap();
+        assert_eq!(auto_vacuum, 0);
+
+        let backups = backups(&temp);
+        assert_eq!(backups.len(), 1, "{:?}", backups);
+        assert!(backups[0].to_string_lossy().contains(".db.v0-"), "{:?}", backups);
+        let backup = Connection::open(&backups[0]).unwrap();
+        let rows: i64 = backup.query_row("SELECT COUNT(*) FROM meter_readings", [], |row| row.get(0)).unwrap();
+        assert_eq!((rows, current_version(&backup).unwrap()), (1, 0));
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let temp = TempDb::new("migrations-newer");
+        let mut conn = Connection::open(temp.path()).unwrap();
+        run(&mut conn, temp.url(), true).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'From the future', 0)",
+            params![latest_version() + 1],
+        ).unwrap();
+
+        let err = run(&mut conn, temp.url(), false).unwrap_err();
+        assert!(err.to_string().contains("newer than this build supports"), "{}", err);
+        assert_eq!(current_version(&conn).unwrap(), latest_version() + 1);
+        assert!(backups(&temp).is_empty());
+    }
+}
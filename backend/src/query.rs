@@ -0,0 +1,194 @@
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::meters::Channel;
+
+/// Local time all buckets are counted from, 1970-01-05 00:00 (a Monday), so
+/// weekly buckets start on Mondays. Widths that divide a day align to midnight.
+const BUCKET_ORIGIN: i64 = 4 * 86400;
+/// Upper bound on buckets per channel in one query.
+pub const MAX_BUCKETS: i64 = 100_000;
+/// Widths a target point count is rounded up to, so buckets line up with the clock.
+const NICE_WIDTHS: &[i64] = &[
+    1, 2, 5, 10, 15, 30, 60, 120, 300, 600, 900, 1800, 3600, 7200, 10800, 21600, 43200, 86400,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketWidth {
+    Seconds(i64),
+    /// Roughly this many buckets across the range
+    Points(i64),
+}
+
+impl BucketWidth {
+    /// Parses a width such as `900`, `30s`, `15m`, `1h`, `1d` or `1w`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+            Some(at) => value.split_at(at),
+            None => (value, "s"),
+        };
+        let factor = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 7 * 86400,
+            _ => return None,
+        };
+        number.parse::<i64>().ok()
+            .filter(|&n| n > 0)
+            .and_then(|n| n.checked_mul(factor))
+            .map(BucketWidth::Seconds)
+    }
+
+    /// Width in seconds for a query over `range_secs`.
+    pub fn seconds(&self, range_secs: i64) -> i64 {
+        match *self {
+            BucketWidth::Seconds(width) => width,
+            BucketWidth::Points(points) => {
+                let raw = (range_secs / points.max(1)).max(1);
+                NICE_WIDTHS.iter().copied().find(|&w| w >= raw)
+                    .unwrap_or_else(|| (raw + 86399) / 86400 * 86400)
+            }
+        }
+    }
+}
+
+/// Parses unix seconds or an RFC 3339 timestamp.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    match value.parse::<i64>() {
+        Ok(seconds) => DateTime::from_timestamp(seconds, 0),
+        Err(_) => DateTime::parse_from_rfc3339(value).ok().map(|t| t.with_timezone(&Utc)),
+    }
+}
+
+/// Parses a comma separated channel list such as `power,voltage_l1`.
+pub fn parse_channels(list: &str) -> Result<Vec<Channel>, String> {
+    list.split(',')
+        .map(|name| Channel::parse(name.trim()).ok_or(format!("Unknown channel {}", name)))
+        .collect()
+}
+
+/// A time-bucketed query over one meter. Buckets are half-open `[start, end)`.
+#[derive(Debug, Clone)]
+pub struct BucketQuery {
+    pub meter_key: String,
+    /// Channels to aggregate, all stored channels if empty
+    pub channels: Vec<Channel>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub width_secs: i64,
+    /// Bucket boundaries follow local time here, e.g. daily buckets start at local midnight
+    pub timezone: Tz,
+}
+
+impl BucketQuery {
+    pub fn new(
+        meter_key: String,
+        channels: Vec<Channel>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        width: BucketWidth,
+        timezone: Tz,
+    ) -> Result<Self, String> {
+        let range_secs = (end - start).num_seconds();
+        if range_secs <= 0 {
+            return Err("end must be after start".to_string());
+        }
+        let width_secs = width.seconds(range_secs);
+        if width_secs <= 0 {
+            return Err("bucket width must be positive".to_string());
+        }
+        if range_secs / width_secs > MAX_BUCKETS {
+            return Err(format!("query would return more than {} buckets per channel", MAX_BUCKETS));
+        }
+
+        Ok(Self { meter_key, channels, start, end, width_secs, timezone })
+    }
+}
+
+/// Aggregates of one channel within one bucket.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BucketStats {
+    pub start: i64,  // Unix timestamp of the bucket start
+    pub count: i64,
+    pub avg: f64,
+    pub min: f64,
+    pub max: f64,
+    pub first: f64,
+    pub last: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChannelBuckets {
+    pub channel: Channel,
+    pub unit: &'static str,
+    pub buckets: Vec<BucketStats>,
+}
+
+/// Groups rows sorted by channel and bucket start into one series per channel.
+pub fn collect_series(rows: impl IntoIterator<Item = (Channel, BucketStats)>) -> Vec<ChannelBuckets> {
+    let mut series: Vec<ChannelBuckets> = Vec::new();
+    for (channel, stats) in rows {
+        match series.last_mut() {
+            Some(last) if last.channel == channel => last.buckets.push(stats),
+            _ => series.push(ChannelBuckets {
+                channel,
+                unit: channel.unit(),
+                buckets: vec![stats],
+            }),
+        }
+    }
+    series
+}
+
+/// Start of the `width_secs` bucket containing `timestamp`, with boundaries
+/// in local time of `timezone`. Around DST changes the affected buckets are an
+/// hour shorter or longer, so daily buckets always run midnight to midnight.
+pub fn local_bucket_start(timestamp: i64, width_secs: i64, timezone: Tz) -> i64 {
+    let Some(utc) = DateTime::from_timestamp(timestamp, 0) else {
+        return timestamp;
+    };
+    let local = utc.with_timezone(&timezone).naive_local().and_utc().timestamp();
+    let start = (local - BUCKET_ORIGIN).div_euclid(width_secs) * width_secs + BUCKET_ORIGIN;
+
+    let naive = DateTime::from_timestamp(start, 0).unwrap().naive_utc();
+    match timezone.from_local_datetime(&naive).earliest() {
+        Some(start) => start.timestamp(),
+        // The bucket starts in a DST gap: keep the offset of `timestamp`
+        None => start - (local - timestamp),
+    }
+}
+
+/// Registers `local_bucket(timestamp, width_secs, timezone)` on `conn`.
+pub fn register_sql_functions(conn: &Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("local_bucket", 3, flags, |ctx| {
+        let timestamp: i64 = ctx.get(0)?;
+        let width_secs: i64 = ctx.get(1)?;
+        let timezone: String = ctx.get(2)?;
+        let timezone: Tz = timezone.parse()
+            .map_err(|e| rusqlite::Error::UserFunctionError(format!("{}", e).into()))?;
+        Ok(local_bucket_start(timestamp, width_secs, timezone))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bucket_widths() {
+        assert_eq!(BucketWidth::parse("900"), Some(BucketWidth::Seconds(900)));
+        assert_eq!(BucketWidth::parse("15m"), Some(BucketWidth::Seconds(900)));
+        assert_eq!(BucketWidth::parse("1w"), Some(BucketWidth::Seconds(7 * 86400)));
+        assert_eq!(BucketWidth::parse("0h"), None);
+        assert_eq!(BucketWidth::parse("1y"), None);
+        // Overflows i64 once multiplied, instead of wrapping or panicking
+        assert_eq!(BucketWidth::parse(&format!("{}w", i64::MAX / 86400)), None);
+        assert_eq!(BucketWidth::parse(&format!("{}s", i64::MAX)), Some(BucketWidth::Seconds(i64::MAX)));
+    }
+}
@@ -0,0 +1,302 @@
+use chrono::{DateTime, Offset, TimeDelta, TimeZone};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::query::BucketQuery;
+
+/// How long a bucket has to be over before it is rolled up, so readings still
+/// in the write queue or the spill journal make it in.
+pub const SETTLE: TimeDelta = TimeDelta::minutes(5);
+/// Buckets rolled up per transaction, so the writer is only held briefly.
+pub const BUCKETS_PER_PASS: i64 = 144;
+
+/// Values a tier can keep per bucket besides count and average.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    Min,
+    Max,
+    First,
+    Last,
+}
+
+impl Aggregate {
+    pub const ALL: [Aggregate; 4] = [Aggregate::Min, Aggregate::Max, Aggregate::First, Aggregate::Last];
+
+    /// Column in the `rollups` table.
+    pub fn column(self) -> &'static str {
+        match self {
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::First => "first",
+            Aggregate::Last => "last",
+        }
+    }
+}
+
+/// One level of rollups. Aggregates a tier doesn't keep read as its average.
+/// Every tier stores the energy of each bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tier {
+    pub width_secs: i64,
+    pub aggregates: Vec<Aggregate>,
+    /// Buckets are deleted at this age, `None` keeps them
+    pub keep: Option<TimeDelta>,
+}
+
+/// What retention keeps of a meter. The first tier is built from raw
+/// readings, every other one from the tier before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// Raw readings are deleted at this age once the first tier covers them
+    pub raw: Option<TimeDelta>,
+    pub tiers: Vec<Tier>,
+    /// Raw readings and rollups are deleted at this age, covered or not
+    pub max_age: Option<TimeDelta>,
+}
+
+impl Default for Policy {
+    /// 10 minute, hourly and daily tiers, raw readings for 30 days.
+    fn default() -> Self {
+        let tier = |width_secs| Tier { width_secs, aggregates: Aggregate::ALL.to_vec(), keep: None };
+        Self {
+            raw: Some(TimeDelta::days(30)),
+            tiers: vec![tier(600), tier(3600), tier(86400)],
+            max_age: None,
+        }
+    }
+}
+
+impl Policy {
+    /// Checks that every tier can be built from the one before it and that
+    /// nothing is deleted before the data covering it.
+    pub fn validate(&self) -> Result<(), String> {
+        let forever = |keep: Option<TimeDelta>| keep.unwrap_or(TimeDelta::MAX);
+        if self.raw.is_some() && self.tiers.is_empty() {
+            return Err("raw readings can only expire into a tier".to_string());
+        }
+        let mut previous: Option<&Tier> = None;
+        for tier in &self.tiers {
+            if tier.width_secs < 60 || (86400 % tier.width_secs != 0 && tier.width_secs % 86400 != 0) {
+                return Err(format!("{}s tier: buckets must be at least a minute and divide a day or be whole days", tier.width_secs));
+            }
+            if forever(tier.keep) < forever(self.raw) {
+                return Err(format!("{}s tier: kept for less time than the raw readings", tier.width_secs));
+            }
+            if let Some(previous) = previous {
+                if tier.width_secs <= previous.width_secs || tier.width_secs % previous.width_secs != 0 {
+                    return Err(format!(
+                        "{}s tier: buckets must be a multiple of the {}s tier before it",
+                        tier.width_secs, previous.width_secs
+                    ));
+                }
+                if forever(tier.keep) < forever(previous.keep) {
+                    return Err(format!("{}s tier: kept for less time than the {}s tier", tier.width_secs, previous.width_secs));
+                }
+                if let Some(missing) = tier.aggregates.iter().find(|a| !previous.aggregates.contains(a)) {
+                    return Err(format!(
+                        "{}s tier: {} is not kept by the {}s tier it is built from",
+                        tier.width_secs, missing.column(), previous.width_secs
+                    ));
+                }
+            }
+            previous = Some(tier);
+        }
+        if let (Some(raw), Some(max_age)) = (self.raw, self.max_age) {
+            if max_age < raw {
+                return Err("max_age is shorter than the raw readings are kept".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a part of a query is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Raw,
+    /// Rollups of this bucket width
+    Tier(i64),
+}
+
+/// Part of a query range, `[start, end)`, read from one source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub source: Source,
+    pub start: i64,
+    pub end: i64,
+}
+
+/// What one meter has stored, in unix seconds.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Oldest raw reading
+    pub raw_from: Option<i64>,
+    /// (width, oldest bucket, watermark) of every tier with rollups, finest first
+    pub tiers: Vec<(i64, i64, i64)>,
+}
+
+impl Coverage {
+    /// Part of `[from, to)` `source` has data for.
+    fn widths(&self) -> impl Iterator<Item = i64> + '_ {
+        self.tiers.iter().map(|&(width, _, _)| width)
+    }
+
+    fn span(&self, source: Source, from: i64, to: i64) -> (i64, i64) {
+        match source {
+            Source::Raw => match self.raw_from {
+                Some(raw_from) => (from.max(raw_from), to),
+                None => (to, to),
+            },
+            Source::Tier(width) => match self.tiers.iter().find(|(w, _, _)| *w == width) {
+                Some(&(_, oldest, until)) => (align_up(from, width).max(oldest), align_down(to, width).min(until)),
+                None => (to, to),
+            },
+        }
+    }
+}
+
+/// Sources for a bucket query: tiers whose buckets fall entirely into one
+/// query bucket, coarsest first, then raw readings. Where neither has data,
+/// e.g. after raw readings expired, the remaining tiers fill in, finest first.
+pub fn plan_buckets(query: &BucketQuery, coverage: &Coverage) -> Vec<Segment> {
+    let (start, end) = (query.start.timestamp(), query.end.timestamp());
+    let (fitting, other): (Vec<i64>, Vec<i64>) = coverage.widths()
+        .partition(|&width| fits(width, query.width_secs, query.timezone, start, end));
+
+    let sources: Vec<Source> = fitting.iter().rev().map(|&w| Source::Tier(w))
+        .chain(std::iter::once(Source::Raw))
+        .chain(other.iter().map(|&w| Source::Tier(w)))
+        .collect();
+    plan(start, end, &sources, coverage)
+}
+
+/// Sources for individual samples: raw readings, then the finest tier with data.
+pub fn plan_samples(start: i64, end: i64, coverage: &Coverage) -> Vec<Segment> {
+    let sources: Vec<Source> = std::iter::once(Source::Raw)
+        .chain(coverage.widths().map(Source::Tier))
+        .collect();
+    plan(start, end, &sources, coverage)
+}
+
+/// Sources for energy over periods starting and ending at `edges`: tiers
+/// whose buckets never cross an edge, coarsest first, then raw readings, then
+/// the remaining tiers, finest first.
+pub fn plan_energy(start: i64, end: i64, edges: &[i64], coverage: &Coverage) -> Vec<Segment> {
+    let (fitting, other): (Vec<i64>, Vec<i64>) = coverage.widths()
+        .partition(|&width| edges.iter().all(|edge| edge % width == 0));
+
+    let sources: Vec<Source> = fitting.iter().rev().map(|&w| Source::Tier(w))
+        .chain(std::iter::once(Source::Raw))
+        .chain(other.iter().map(|&w| Source::Tier(w)))
+        .collect();
+    plan(start, end, &sources, coverage)
+}
+
+/// Parts of `[start, end)` none of the tier segments cover.
+pub fn uncovered(start: i64, end: i64, segments: &[Segment]) -> Vec<(i64, i64)> {
+    let mut spans = Vec::new();
+    let mut from = start;
+    for segment in segments.iter().filter(|s| s.source != Source::Raw) {
+        if segment.start > from {
+            spans.push((from, segment.start));
+        }
+        from = from.max(segment.end);
+    }
+    if from < end {
+        spans.push((from, end));
+    }
+    spans
+}
+
+/// Splits `[start, end)` into segments, sorted by start, each read from the
+/// first of `sources` with data for it.
+fn plan(start: i64, end: i64, sources: &[Source], coverage: &Coverage) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    assign(start, end, sources, coverage, &mut segments);
+    segments
+}
+
+fn assign(from: i64, to: i64, sources: &[Source], coverage: &Coverage, segments: &mut Vec<Segment>) {
+    let Some((&source, rest)) = sources.split_first() else {
+        return;
+    };
+    if from >= to {
+        return;
+    }
+    let (start, end) = coverage.span(source, from, to);
+    if start >= end {
+        return assign(from, to, rest, coverage, segments);
+    }
+    assign(from, start, rest, coverage, segments);
+    segments.push(Segment { source, start, end });
+    assign(end, to, rest, coverage, segments);
+}
+
+/// Whether every `tier_width` bucket lies within one local `width_secs` query
+/// bucket: the width must divide the query width and the timezone offset,
+/// checked once a day across the range for DST.
+fn fits(tier_width: i64, width_secs: i64, timezone: Tz, start: i64, end: i64) -> bool {
+    if width_secs % tier_width != 0 {
+        return false;
+    }
+    (start..end).step_by(86400).chain(std::iter::once(end)).all(|t| {
+        DateTime::from_timestamp(t, 0)
+            .map(|utc| timezone.offset_from_utc_datetime(&utc.naive_utc()).fix().local_minus_utc() as i64)
+            .is_some_and(|offset| offset % tier_width == 0)
+    })
+}
+
+/// What a rollup pass does next for one meter and tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// Aggregate `[start, end)` and move the watermark to `end`
+    Roll { start: i64, end: i64 },
+    /// No source data before this, move the watermark here
+    Skip(i64),
+    Done,
+}
+
+/// Next step given the tier's watermark, the oldest source data at or after
+/// it and how far the source is complete. A meter without rollups keeps no
+/// watermark until it has data, so history imported later is still rolled up.
+pub fn next_pass(watermark: Option<i64>, next_source: Option<i64>, ready_until: i64, width: i64) -> Pass {
+    let ready = align_down(ready_until, width);
+    if let Some(next) = next_source {
+        let start = align_down(next, width);
+        let end = (start + width * BUCKETS_PER_PASS).min(ready);
+        if start < end {
+            return Pass::Roll { start, end };
+        }
+    }
+    match watermark {
+        Some(watermark) if watermark < ready => Pass::Skip(ready),
+        _ => Pass::Done,
+    }
+}
+
+/// Buckets of `width` in `[start, end)`, which are aligned to it.
+pub fn buckets(start: i64, end: i64, width: i64) -> Vec<(i64, i64)> {
+    (start..end).step_by(width as usize).map(|bucket| (bucket, bucket + width)).collect()
+}
+
+/// SQL for a rollup column of `tier`: the aggregate if the tier keeps it,
+/// else NULL. Power channels always keep their minimum and peak.
+pub fn kept_column(tier: &Tier, aggregate: Aggregate) -> String {
+    let column = aggregate.column();
+    if tier.aggregates.contains(&aggregate) {
+        column.to_string()
+    } else if matches!(aggregate, Aggregate::Min | Aggregate::Max) {
+        format!("CASE WHEN channel LIKE 'power%' AND channel NOT LIKE 'power_factor%' THEN {} END", column)
+    } else {
+        "NULL".to_string()
+    }
+}
+
+pub fn align_down(timestamp: i64, width: i64) -> i64 {
+    timestamp.div_euclid(width) * width
+}
+
+fn align_up(timestamp: i64, width: i64) -> i64 {
+    align_down(timestamp + width - 1, width)
+}
@@ -0,0 +1,153 @@
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::meters::Measurement;
+
+/// One polling cycle, one JSON object per line.
+#[derive(Serialize, Deserialize)]
+struct SpilledCycle<'a> {
+    meter: Cow<'a, str>,
+    measurements: Cow<'a, [Measurement]>,
+}
+
+/// Cycles read from the front of the journal, not yet consumed.
+pub struct JournalChunk {
+    pub cycles: Vec<(String, Vec<Measurement>)>,
+    /// Position after the last cycle read
+    pub end: u64,
+    /// Lines read, including lines that could not be parsed
+    pub lines: usize,
+}
+
+/// Append-only file holding polling cycles the writer queue had no room for.
+///
+/// Cycles are replayed from the front and the file is truncated once all of
+/// them are written. The replay position is not persisted: after a crash the
+/// journal is replayed from the start, which is harmless because writing a
+/// reading again stores the same values.
+pub struct SpillJournal {
+    path: PathBuf,
+    file: File,
+    max_bytes: u64,
+    bytes: u64,
+    /// Start of the first cycle not yet written to the database
+    read_offset: u64,
+    cycles: usize,
+}
+
+impl SpillJournal {
+    /// Opens or creates the journal. A line cut short by a crash is dropped.
+    pub fn open(path: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let complete = contents.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if complete < contents.len() {
+            warn!("Dropping {} bytes of an incomplete cycle at the end of {}", contents.len() - complete, path.display());
+            file.set_len(complete as u64)?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            max_bytes,
+            bytes: complete as u64,
+            read_offset: 0,
+            cycles: contents[..complete].iter().filter(|&&b| b == b'\n').count(),
+        })
+    }
+
+    /// Cycles waiting to be replayed.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Adds a cycle at the end, synced to disk before returning.
+    pub fn append(&mut self, meter_key: &str, measurements: &[Measurement]) -> Result<(), Box<dyn std::error::Error>> {
+        let line = Self::encode(meter_key, measurements)?;
+        if self.bytes + line.len() as u64 > self.max_bytes {
+            return Err(format!("spill journal full ({} bytes)", self.bytes).into());
+        }
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.bytes += line.len() as u64;
+        self.cycles += 1;
+        Ok(())
+    }
+
+    /// Puts cycles in front of the journaled ones, for cycles that were
+    /// queued before anything was spilled. Rewrites the file.
+    pub fn prepend(&mut self, cycles: &[(String, Vec<Measurement>)]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut contents = Vec::new();
+        for (meter_key, measurements) in cycles {
+            contents.extend(Self::encode(meter_key, measurements)?);
+        }
+        let mut reader = File::open(&self.path)?;
+        reader.seek(SeekFrom::Start(self.read_offset))?;
+        reader.read_to_end(&mut contents)?;
+
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.bytes = contents.len() as u64;
+        self.read_offset = 0;
+        self.cycles += cycles.len();
+        Ok(())
+    }
+
+    /// Reads up to `max` cycles from the front without consuming them.
+    pub fn peek(&self, max: usize) -> std::io::Result<JournalChunk> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.read_offset))?;
+
+        let (mut cycles, mut offset, mut lines) = (Vec::new(), self.read_offset, 0);
+        let mut line = String::new();
+        while lines < max && offset < self.bytes {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            offset += read as u64;
+            lines += 1;
+            match serde_json::from_str::<SpilledCycle>(&line) {
+                Ok(cycle) => cycles.push((cycle.meter.into_owned(), cycle.measurements.into_owned())),
+                Err(e) => warn!("Skipping unreadable cycle in {}: {}", self.path.display(), e),
+            }
+        }
+        Ok(JournalChunk { cycles, end: offset, lines })
+    }
+
+    /// Marks a chunk from `peek` as written. Empties the file once nothing is left.
+    pub fn consume(&mut self, end: u64, lines: usize) -> std::io::Result<()> {
+        self.read_offset = end;
+        self.cycles = self.cycles.saturating_sub(lines);
+        if self.read_offset >= self.bytes {
+            self.file.set_len(0)?;
+            self.bytes = 0;
+            self.read_offset = 0;
+            self.cycles = 0;
+        }
+        Ok(())
+    }
+
+    fn encode(meter: &str, measurements: &[Measurement]) -> serde_json::Result<Vec<u8>> {
+        let cycle = SpilledCycle { meter: Cow::Borrowed(meter), measurements: Cow::Borrowed(measurements) };
+        let mut line = serde_json::to_vec(&cycle)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
@@ -0,0 +1,281 @@
+pub mod postgres;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::capture::{CaptureParams, CaptureSample, CaptureSession, CaptureStatus};
+use crate::config::{GlobalConfig, StorageBackend, StorageConfig};
+use crate::data_retention::RetentionReport;
+use crate::database_sync::{DatabaseSync, Model};
+use crate::energy::PeriodEnergy;
+use crate::maintenance::{MaintenanceResult, MaintenanceTask};
+use crate::meters::{Channel, ChannelInfo, Measurement, Phase};
+use crate::query::{BucketQuery, ChannelBuckets};
+use crate::rollup::Tier;
+use self::postgres::PostgresStorage;
+
+pub type StorageResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// What a meter measures in the installation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterRole {
+    Grid,
+    Producer,
+    Consumer,
+    Storage,
+}
+
+impl MeterRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MeterRole::Grid => "grid",
+            MeterRole::Producer => "producer",
+            MeterRole::Consumer => "consumer",
+            MeterRole::Storage => "storage",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [MeterRole::Grid, MeterRole::Producer, MeterRole::Consumer, MeterRole::Storage]
+            .into_iter()
+            .find(|r| r.as_str() == value)
+    }
+}
+
+/// Description of a meter, set in its config section and stored with it so
+/// the API can describe meters without the config file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeterMetadata {
+    /// Driver, e.g. `sdm72d`, taken from the meter's `type`
+    #[serde(skip_deserializing)]
+    pub meter_type: Option<String>,
+    /// Unit the meter is read or billed in, e.g. `kWh`
+    pub unit: Option<String>,
+    /// Where the meter is installed, e.g. "Building B, basement"
+    pub location: Option<String>,
+    pub role: Option<MeterRole>,
+    /// Phase a single-phase meter is connected to, `all` for three-phase meters
+    pub phase: Option<Phase>,
+    pub installed_on: Option<NaiveDate>,
+    pub removed_on: Option<NaiveDate>,
+}
+
+/// Latest state of one meter, as listed by `/meters`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MeterSummary {
+    pub meter_key: String,
+    pub meter_name: String,
+    pub retired_at: Option<i64>,  // Unix timestamp
+    pub metadata: MeterMetadata,
+    pub last_reading_timestamp: Option<i64>,  // Unix timestamp
+    pub last_power_reading: Option<f32>,
+    pub total_readings: i64,
+}
+
+/// Outcome of `import_measurements`, counted per timestamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImportCounts {
+    pub inserted: u64,
+    /// Timestamps the meter already had readings for
+    pub duplicates: u64,
+}
+
+/// Energy per rollup bucket, and the parts of the range left to raw readings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RolledEnergy {
+    pub buckets: Vec<PeriodEnergy>,
+    pub uncovered: Vec<(i64, i64)>,
+}
+
+/// Rows one `rollup` call read from its source and wrote, energy rows included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RollupCounts {
+    pub read: u64,
+    pub written: u64,
+    /// Watermark of the tier afterwards, for a dry run where it would be
+    pub rolled_until: Option<i64>,
+}
+
+/// Size and fill level of the storage, as reported by `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageStats {
+    pub backend: &'static str,
+    /// File path or connection target, without credentials
+    pub location: String,
+    pub size_bytes: u64,
+    pub meters_count: usize,
+    pub last_write: Option<i64>,  // Unix timestamp
+    pub total_records: i64,
+}
+
+/// Everything the application persists, independent of the database behind it.
+///
+/// Meters are identified by their key, the config table id or an explicit
+/// `id`, so renaming a meter in the config keeps its history. Only writes
+/// create unknown meters, reads report them as missing.
+///
+/// Implementations block on I/O. Async code calls them through `run_blocking`.
+pub trait Storage: Send + Sync {
+    /// Stores several polling cycles, keyed by meter key, atomically.
+    fn insert_measurement_batch(&self, batch: &[(String, Vec<Measurement>)]) -> StorageResult<()>;
+
+    /// Adds historical readings of one meter, creating it if needed. Timestamps
+    /// the meter already has readings for are skipped, so existing data is
+    /// never overwritten and importing the same file twice changes nothing.
+    fn import_measurements(&self, meter_key: &str, measurements: &[Measurement]) -> StorageResult<ImportCounts>;
+
+    /// Creates the meter or updates its display name and metadata, and brings
+    /// a retired meter back into use. A meter from before stable keys, still
+    /// keyed by `name`, is adopted under `meter_key`.
+    fn register_meter(&self, meter_key: &str, name: &str, metadata: &MeterMetadata) -> StorageResult<()>;
+
+    /// Gives a meter a new key, e.g. after its config id changed. Fails if
+    /// `new_key` is taken, `false` if the meter is unknown.
+    fn rename_meter(&self, meter_key: &str, new_key: &str) -> StorageResult<bool>;
+
+    /// Moves the history of `from_key` to `into_key` and deletes `from_key`.
+    /// Values `into_key` already has for the same time are kept. Returns the
+    /// number of rows moved, `None` if either meter is unknown.
+    fn merge_meters(&self, from_key: &str, into_key: &str) -> StorageResult<Option<u64>>;
+
+    /// Marks a meter as no longer in use, keeping its history. `false` if unknown.
+    fn retire_meter(&self, meter_key: &str) -> StorageResult<bool>;
+
+    /// Records the channels a meter declares so the API can describe them.
+    fn register_channels(&self, meter_key: &str, channels: &[Channel]) -> StorageResult<()>;
+
+    /// Declared channels of every meter, keyed by meter key.
+    fn get_meter_channels(&self) -> StorageResult<HashMap<String, Vec<ChannelInfo>>>;
+
+    /// Raw readings of one meter between `start_time` and `end_time`, newest
+    /// first. Readings past the raw expiry are only left in the rollups.
+    fn get_meter_readings(
+        &self,
+        meter_key: &str,
+        start_time: Option<DateTime<Utc>>,
+        end_time: Option<DateTime<Utc>>,
+    ) -> StorageResult<Vec<Model>>;
+
+    /// Count, avg, min, max, first and last value per channel and time bucket,
+    /// computed by the database from raw readings and rollups. `None` if the
+    /// meter is unknown.
+    fn get_buckets(&self, query: &BucketQuery) -> StorageResult<Option<Vec<ChannelBuckets>>>;
+
+    /// Values of one channel with `start <= timestamp < end` as (unix seconds,
+    /// value), oldest first. Where raw readings expired, each rollup bucket
+    /// gives one sample: its average at the middle, or its last value at the
+    /// end for energy counters. `None` if the meter is unknown.
+    fn get_channel_samples(
+        &self,
+        meter_key: &str,
+        channel: Channel,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> StorageResult<Option<Vec<(i64, f64)>>>;
+
+    /// Energy stored with the rollups of one meter in `[start, end)`, unix
+    /// seconds, preferring tiers whose buckets don't cross any of `edges`.
+    /// `None` if the meter is unknown.
+    fn get_rolled_energy(&self, meter_key: &str, start: i64, end: i64, edges: &[i64]) -> StorageResult<Option<RolledEnergy>>;
+
+    /// Latest reading and raw reading count of every meter, including meters
+    /// without readings and those whose raw readings only live on in rollups.
+    fn latest_readings(&self) -> StorageResult<Vec<MeterSummary>>;
+
+    fn stats(&self) -> StorageResult<StorageStats>;
+
+    /// Writes a consistent copy of the database to `destination` while
+    /// ingestion continues.
+    fn backup(&self, destination: &Path) -> StorageResult<()>;
+
+    /// Keys of all meters, retired ones included.
+    fn meter_keys(&self) -> StorageResult<Vec<String>>;
+
+    /// Aggregates a meter's `tier` buckets that ended before `until` and are
+    /// not rolled up yet, from raw readings if `source_width` is `None`,
+    /// otherwise from the `source_width` rollups. A dry run rolls every pass
+    /// back, so it only counts.
+    fn rollup(&self, meter_key: &str, tier: &Tier, source_width: Option<i64>, until: DateTime<Utc>, dry_run: bool) -> StorageResult<RollupCounts>;
+
+    /// Deletes a meter's raw readings before `before` that the `width_secs`
+    /// rollups cover. The bucket with its newest reading is kept, so
+    /// `/meters` still shows its last reading.
+    /// Returns the rows deleted, or for a dry run the rows it would delete.
+    /// `rolled_until` replaces the stored watermark, for dry runs whose
+    /// rollups were never stored.
+    fn expire_raw(&self, meter_key: &str, before: DateTime<Utc>, width_secs: i64, rolled_until: Option<i64>, dry_run: bool) -> StorageResult<u64>;
+
+    /// Deletes a meter's `width_secs` buckets that ended before `before`.
+    fn expire_rollups(&self, meter_key: &str, width_secs: i64, before: DateTime<Utc>, dry_run: bool) -> StorageResult<u64>;
+
+    /// Deletes a meter's raw readings and rollups from before `before`,
+    /// whether other tiers cover them or not.
+    fn delete_before(&self, meter_key: &str, before: DateTime<Utc>, dry_run: bool) -> StorageResult<u64>;
+
+    /// Oldest raw reading or rollup bucket of any meter.
+    fn oldest_data(&self) -> StorageResult<Option<DateTime<Utc>>>;
+
+    /// Bytes taken up by data, without space that deletes freed for reuse.
+    fn used_bytes(&self) -> StorageResult<u64>;
+
+    /// The part of `used_bytes` retention never deletes: everything but
+    /// readings and rollups, like capture samples, quarantined readings and
+    /// retention reports.
+    fn undeletable_bytes(&self) -> StorageResult<u64>;
+
+    /// Stores the report of a retention run and returns its id. Only the
+    /// newest `data_retention::REPORTS_KEPT` reports are kept.
+    fn insert_retention_report(&self, report: &RetentionReport) -> StorageResult<i64>;
+
+    /// The newest `limit` retention reports, newest first.
+    fn get_retention_reports(&self, limit: usize) -> StorageResult<Vec<RetentionReport>>;
+
+    /// Runs one maintenance task and describes what it did. Fails if the task
+    /// could not finish or found the database damaged. Tasks the backend
+    /// doesn't need succeed without doing anything.
+    fn maintain(&self, task: MaintenanceTask) -> StorageResult<String>;
+
+    /// Stores the result of a maintenance run, replacing the task's previous one.
+    fn record_maintenance(&self, result: &MaintenanceResult) -> StorageResult<()>;
+
+    /// Latest result of every task that ran.
+    fn maintenance_results(&self) -> StorageResult<Vec<MaintenanceResult>>;
+
+    fn create_capture_session(&self, params: &CaptureParams) -> StorageResult<i64>;
+    fn set_capture_running(&self, session_id: i64) -> StorageResult<()>;
+    fn finish_capture_session(&self, session_id: i64, status: CaptureStatus, error: Option<&str>) -> StorageResult<()>;
+    fn insert_capture_samples(&self, session_id: i64, measurements: &[Measurement]) -> StorageResult<()>;
+    fn get_capture_session(&self, session_id: i64) -> StorageResult<Option<CaptureSession>>;
+    fn get_capture_samples(&self, session_id: i64) -> StorageResult<Vec<CaptureSample>>;
+}
+
+impl dyn Storage {
+    /// Runs `f` on tokio's blocking thread pool so database calls never stall
+    /// the async runtime threads that drive meter polling and the web server.
+    pub async fn run_blocking<T, F>(self: &Arc<Self>, f: F) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: FnOnce(&dyn Storage) -> StorageResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = Arc::clone(self);
+        tokio::task::spawn_blocking(move || f(storage.as_ref()).map_err(|e| e.to_string()))
+            .await?
+            .map_err(Into::into)
+    }
+}
+
+/// Opens the configured backend, creating or migrating its schema.
+pub fn open(global: &GlobalConfig, storage: &StorageConfig) -> StorageResult<Arc<dyn Storage>> {
+    Ok(match storage.backend {
+        StorageBackend::Sqlite => Arc::new(DatabaseSync::new(&global.database_url, global.create_database, storage)?),
+        StorageBackend::Postgres => {
+            let config = storage.postgres.as_ref()
+                .ok_or("storage.backend = \"postgres\" requires a [storage.postgres] section")?;
+            Arc::new(PostgresStorage::new(config, global.create_database)?)
+        }
+    })
+}
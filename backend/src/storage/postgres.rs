@@ -0,0 +1,1436 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, TimeZone, Utc};
+use log::info;
+use postgres::config::Host;
+use postgres::{Client, Config, GenericClient, IsolationLevel, NoTls};
+
+use super::{ImportCounts, MeterMetadata, MeterRole, MeterSummary, RolledEnergy, RollupCounts, Storage, StorageResult, StorageStats};
+use crate::capture::{CaptureParams, CaptureSample, CaptureSession, CaptureStatus};
+use crate::config::PostgresConfig;
+use crate::data_retention::{RetentionReport, RetentionStep, REPORTS_KEPT};
+use crate::database_sync::Model;
+use crate::energy::{self, EnergySamples, EnergySource, PeriodEnergy};
+use crate::maintenance::{MaintenanceResult, MaintenanceTask};
+use crate::meters::{Channel, ChannelInfo, Measurement, Phase, Quantity};
+use crate::query::{self, BucketQuery, BucketStats, ChannelBuckets};
+use crate::rollup::{self, Aggregate, Coverage, Pass, Segment, Source, Tier};
+
+/// Schema changes, applied in order like `migrations` does for SQLite.
+const MIGRATIONS: &[(i32, &str, &str)] = &[(
+    1,
+    "Initial schema",
+    "CREATE TABLE IF NOT EXISTS meter_names (
+        meter_id SERIAL PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+
+    CREATE TABLE IF NOT EXISTS meter_channels (
+        meter_id INTEGER NOT NULL REFERENCES meter_names (meter_id),
+        channel TEXT NOT NULL,
+        quantity TEXT NOT NULL,
+        unit TEXT NOT NULL,
+        phase TEXT NOT NULL,
+        direction TEXT NOT NULL,
+        position INTEGER NOT NULL,  -- declaration order
+        PRIMARY KEY (meter_id, channel)
+    );
+
+    -- One row per channel and second, values in real units
+    CREATE TABLE IF NOT EXISTS readings (
+        meter_id INTEGER NOT NULL REFERENCES meter_names (meter_id),
+        channel TEXT NOT NULL,
+        time TIMESTAMPTZ NOT NULL,
+        value DOUBLE PRECISION NOT NULL,
+        PRIMARY KEY (meter_id, channel, time)
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_readings_meter_time ON readings (meter_id, time DESC);
+
+    CREATE TABLE IF NOT EXISTS capture_sessions (
+        session_id BIGSERIAL PRIMARY KEY,
+        meter_id INTEGER NOT NULL REFERENCES meter_names (meter_id),
+        channels TEXT NOT NULL,  -- comma separated channel names
+        interval_ms BIGINT NOT NULL,
+        duration_secs BIGINT NOT NULL,
+        status TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL,
+        started_at TIMESTAMPTZ,
+        finished_at TIMESTAMPTZ,
+        error TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS capture_samples (
+        session_id BIGINT NOT NULL REFERENCES capture_sessions (session_id),
+        timestamp_ms BIGINT NOT NULL,  -- Unix timestamp in milliseconds
+        channel TEXT NOT NULL,
+        value DOUBLE PRECISION NOT NULL,
+        PRIMARY KEY (session_id, timestamp_ms, channel)
+    );",
+), (
+    2,
+    "Stable meter keys separate from display names",
+    "ALTER TABLE meter_names ADD COLUMN IF NOT EXISTS meter_key TEXT;
+    UPDATE meter_names SET meter_key = name WHERE meter_key IS NULL;
+    ALTER TABLE meter_names ALTER COLUMN meter_key SET NOT NULL;
+    ALTER TABLE meter_names DROP CONSTRAINT IF EXISTS meter_names_name_key;
+    ALTER TABLE meter_names ADD CONSTRAINT meter_names_meter_key_key UNIQUE (meter_key);
+    ALTER TABLE meter_names ADD COLUMN IF NOT EXISTS retired_at TIMESTAMPTZ;",
+), (
+    3,
+    "Meter metadata",
+    "ALTER TABLE meter_names
+        ADD COLUMN IF NOT EXISTS meter_type TEXT,
+        ADD COLUMN IF NOT EXISTS unit TEXT,
+        ADD COLUMN IF NOT EXISTS location TEXT,
+        ADD COLUMN IF NOT EXISTS role TEXT,
+        ADD COLUMN IF NOT EXISTS phase TEXT,
+        ADD COLUMN IF NOT EXISTS installed_on DATE,
+        ADD COLUMN IF NOT EXISTS removed_on DATE;",
+), (
+    4,
+    "Rollups kept after raw readings expire",
+    "-- Aggregates per meter, channel and UTC-aligned bucket
+    CREATE TABLE IF NOT EXISTS rollups (
+        meter_id INTEGER NOT NULL REFERENCES meter_names (meter_id),
+        width_secs INTEGER NOT NULL,   -- 600, 3600 or 86400
+        bucket_start BIGINT NOT NULL,  -- Unix timestamp in seconds
+        channel TEXT NOT NULL,
+        count BIGINT NOT NULL,
+        avg DOUBLE PRECISION NOT NULL,
+        min DOUBLE PRECISION NOT NULL,
+        max DOUBLE PRECISION NOT NULL,
+        first DOUBLE PRECISION NOT NULL,
+        last DOUBLE PRECISION NOT NULL,
+        PRIMARY KEY (meter_id, width_secs, bucket_start, channel)
+    );",
+), (
+    5,
+    "Rollup watermarks",
+    "CREATE TABLE IF NOT EXISTS rollup_watermarks (
+        meter_id INTEGER NOT NULL REFERENCES meter_names (meter_id),
+        width_secs INTEGER NOT NULL,
+        rolled_until BIGINT NOT NULL,  -- Unix timestamp, buckets before it are final
+        PRIMARY KEY (meter_id, width_secs)
+    );
+
+    INSERT INTO rollup_watermarks (meter_id, width_secs, rolled_until)
+        SELECT meter_id, width_secs, MAX(bucket_start) + width_secs FROM rollups GROUP BY meter_id, width_secs
+        ON CONFLICT DO NOTHING;",
+), (
+    6,
+    "Optional rollup aggregates",
+    "ALTER TABLE rollups
+        ALTER COLUMN min DROP NOT NULL,
+        ALTER COLUMN max DROP NOT NULL,
+        ALTER COLUMN first DROP NOT NULL,
+        ALTER COLUMN last DROP NOT NULL;",
+), (
+    7,
+    "Energy per rollup bucket",
+    "CREATE TABLE IF NOT EXISTS rollup_energy (
+        meter_id INTEGER NOT NULL REFERENCES meter_names (meter_id),
+        width_secs INTEGER NOT NULL,
+        bucket_start BIGINT NOT NULL,  -- Unix timestamp in seconds
+        imported_kwh DOUBLE PRECISION NOT NULL,
+        exported_kwh DOUBLE PRECISION NOT NULL,
+        import_from INTEGER NOT NULL,  -- 1 counter, 2 power, 3 both
+        export_from INTEGER NOT NULL,
+        PRIMARY KEY (meter_id, width_secs, bucket_start)
+    );
+
+    -- Rolls up again whatever raw readings are left, this time with energy
+    UPDATE rollup_watermarks w SET rolled_until = LEAST(
+        rolled_until,
+        (SELECT FLOOR(EXTRACT(EPOCH FROM MIN(time)))::BIGINT / w.width_secs * w.width_secs FROM readings r WHERE r.meter_id = w.meter_id)
+    );",
+), (
+    8,
+    "Retention run reports",
+    "CREATE TABLE IF NOT EXISTS retention_runs (
+        run_id BIGSERIAL PRIMARY KEY,
+        started_at TIMESTAMPTZ NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        size_before_bytes BIGINT NOT NULL,
+        size_after_bytes BIGINT NOT NULL,
+        errors TEXT NOT NULL  -- one failed meter per line
+    );
+
+    -- Meter keys rather than ids, so reports outlive merged meters
+    CREATE TABLE IF NOT EXISTS retention_run_steps (
+        run_id BIGINT NOT NULL REFERENCES retention_runs (run_id) ON DELETE CASCADE,
+        step INTEGER NOT NULL,
+        meter_key TEXT NOT NULL,
+        width_secs BIGINT,  -- NULL for raw readings
+        rows_read BIGINT NOT NULL,
+        rows_written BIGINT NOT NULL,
+        rows_deleted BIGINT NOT NULL,
+        PRIMARY KEY (run_id, step)
+    );",
+), (
+    9,
+    "Maintenance results",
+    "CREATE TABLE IF NOT EXISTS maintenance_results (
+        task TEXT PRIMARY KEY,
+        finished_at TIMESTAMPTZ NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        ok BOOLEAN NOT NULL,
+        detail TEXT NOT NULL
+    );",
+)];
+
+/// Storage on a PostgreSQL server, optionally with TimescaleDB.
+///
+/// Unlike the SQLite schema, readings are stored narrow (one row per channel)
+/// in real units, which suits a hypertable and needs no power encoding.
+/// Writes and queries use separate connections so a slow dashboard query
+/// doesn't hold up ingestion. Connections are not encrypted; reach remote
+/// servers through a VPN or SSH tunnel.
+pub struct PostgresStorage {
+    writer: Mutex<Connection>,
+    reader: Mutex<Connection>,
+    location: String,
+    meter_cache: Mutex<HashMap<String, i32>>,
+}
+
+/// A client that is closed on a plain thread when dropped. Closing blocks on
+/// the client's internal runtime, which tokio forbids on its worker threads,
+/// and the last reference to the storage may well be dropped on one.
+struct Connection(Option<Client>);
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(client) = self.0.take() {
+            let _ = std::thread::spawn(move || drop(client)).join();
+        }
+    }
+}
+
+impl PostgresStorage {
+    pub fn new(config: &PostgresConfig, create_database: bool) -> StorageResult<Self> {
+        let pg_config: Config = config.url.parse()?;
+        let location = describe(&pg_config);
+
+        let mut writer = pg_config.connect(NoTls)?;
+        let schema_version = migrate(&mut writer, &location, create_database)?;
+        info!("PostgreSQL schema at version {} on {}", schema_version, location);
+
+        if config.timescaledb {
+            writer.batch_execute(
+                "CREATE EXTENSION IF NOT EXISTS timescaledb;
+                 SELECT create_hypertable('readings', 'time', if_not_exists => TRUE, migrate_data => TRUE);",
+            )?;
+            info!("Readings stored in a TimescaleDB hypertable");
+        }
+
+        let reader = pg_config.connect(NoTls)?;
+
+        Ok(Self {
+            writer: Mutex::new(Connection(Some(writer))),
+            reader: Mutex::new(Connection(Some(reader))),
+            location,
+            meter_cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Only for write paths, like its SQLite counterpart.
+    fn get_or_create_meter_id(&self, meter_key: &str) -> StorageResult<i32> {
+        let mut cache = self.meter_cache.lock().unwrap();
+        if let Some(&id) = cache.get(meter_key) {
+            return Ok(id);
+        }
+
+        let mut client = self.writer.lock().unwrap();
+        client.execute(
+            "INSERT INTO meter_names (meter_key, name) VALUES ($1, $1) ON CONFLICT (meter_key) DO NOTHING",
+            &[&meter_key],
+        )?;
+        let meter_id: i32 = client
+            .query_one("SELECT meter_id FROM meter_names WHERE meter_key = $1", &[&meter_key])?
+            .get(0);
+
+        cache.insert(meter_key.to_string(), meter_id);
+        Ok(meter_id)
+    }
+
+    /// Deletes a meter's readings before `cutoff`, a day per statement.
+    fn delete_raw(&self, meter_id: i32, cutoff: i64, dry_run: bool) -> StorageResult<u64> {
+        if dry_run {
+            let mut reader = self.reader.lock().unwrap();
+            let where_clause = "FROM readings WHERE meter_id = $1 AND time < to_timestamp($2::BIGINT)";
+            return Ok(delete_rows(&mut **reader, where_clause, &[&meter_id, &cutoff], true)?);
+        }
+        let mut deleted = 0;
+        loop {
+            let mut client = self.writer.lock().unwrap();
+            let Some(oldest) = next_source_time(&mut **client, meter_id, Source::Raw, None)?.filter(|&t| t < cutoff) else {
+                break;
+            };
+            let step_end = oldest.saturating_add(86400).min(cutoff);
+            deleted += client.execute(
+                "DELETE FROM readings WHERE meter_id = $1 AND time < to_timestamp($2::BIGINT)",
+                &[&meter_id, &step_end],
+            )?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Looks up a meter without creating it, for read paths.
+fn find_meter_id(client: &mut Client, meter_key: &str) -> Result<Option<i32>, postgres::Error> {
+    Ok(client
+        .query_opt("SELECT meter_id FROM meter_names WHERE meter_key = $1", &[&meter_key])?
+        .map(|row| row.get(0)))
+}
+
+impl Storage for PostgresStorage {
+    fn insert_measurement_batch(&self, batch: &[(String, Vec<Measurement>)]) -> StorageResult<()> {
+        let meter_ids = batch
+            .iter()
+            .map(|(meter_key, _)| self.get_or_create_meter_id(meter_key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut client = self.writer.lock().unwrap();
+        let mut tx = client.transaction()?;
+        let insert = tx.prepare(
+            "INSERT INTO readings (meter_id, channel, time, value) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (meter_id, channel, time) DO UPDATE SET value = excluded.value",
+        )?;
+        for ((_, measurements), meter_id) in batch.iter().zip(meter_ids) {
+            for m in measurements {
+                // Whole seconds, like the SQLite rows
+                let time = Utc.timestamp_opt(m.timestamp.timestamp(), 0).unwrap();
+                tx.execute(&insert, &[&meter_id, &m.channel.name(), &time, &(m.value as f64)])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn import_measurements(&self, meter_key: &str, measurements: &[Measurement]) -> StorageResult<ImportCounts> {
+        let meter_id = self.get_or_create_meter_id(meter_key)?;
+        let mut by_time: BTreeMap<i64, Vec<&Measurement>> = BTreeMap::new();
+        for m in measurements {
+            by_time.entry(m.timestamp.timestamp()).or_default().push(m);
+        }
+
+        let mut client = self.writer.lock().unwrap();
+        let mut tx = client.transaction()?;
+        let exists = tx.prepare("SELECT EXISTS (SELECT 1 FROM readings WHERE meter_id = $1 AND time = $2)")?;
+        let insert = tx.prepare(
+            "INSERT INTO readings (meter_id, channel, time, value) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (meter_id, channel, time) DO NOTHING",
+        )?;
+        let mut counts = ImportCounts::default();
+        for (timestamp, group) in by_time {
+            let time = Utc.timestamp_opt(timestamp, 0).unwrap();
+            if tx.query_one(&exists, &[&meter_id, &time])?.get(0) {
+                counts.duplicates += 1;
+                continue;
+            }
+            for m in group {
+                tx.execute(&insert, &[&meter_id, &m.channel.name(), &time, &(m.value as f64)])?;
+            }
+            if counts.inserted == 0 {
+                rewind_watermarks(&mut tx, meter_id, timestamp)?;
+            }
+            counts.inserted += 1;
+        }
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    fn register_meter(&self, meter_key: &str, name: &str, metadata: &MeterMetadata) -> StorageResult<()> {
+        if meter_key != name {
+            let mut cache = self.meter_cache.lock().unwrap();
+            let mut client = self.writer.lock().unwrap();
+            let known = find_meter_id(&mut client, meter_key)?.is_some();
+            if !known && client.execute("UPDATE meter_names SET meter_key = $1 WHERE meter_key = $2", &[&meter_key, &name])? > 0 {
+                info!("Meter {} is now identified by its key {}", name, meter_key);
+                cache.remove(name);
+            }
+        }
+
+        let meter_id = self.get_or_create_meter_id(meter_key)?;
+        let mut client = self.writer.lock().unwrap();
+        let previous = client.query_one(
+            "SELECT name, retired_at IS NOT NULL FROM meter_names WHERE meter_id = $1",
+            &[&meter_id],
+        )?;
+        if previous.get::<_, &str>(0) != name {
+            info!("Meter {} renamed from {} to {}", meter_key, previous.get::<_, &str>(0), name);
+        }
+        if previous.get(1) {
+            info!("Meter {} is configured again, no longer retired", meter_key);
+        }
+        client.execute(
+            "UPDATE meter_names
+             SET name = $1, retired_at = NULL, meter_type = $3, unit = $4, location = $5,
+                 role = $6, phase = $7, installed_on = $8, removed_on = $9
+             WHERE meter_id = $2",
+            &[
+                &name,
+                &meter_id,
+                &metadata.meter_type,
+                &metadata.unit,
+                &metadata.location,
+                &metadata.role.map(|r| r.as_str()),
+                &metadata.phase.map(|p| p.as_str()),
+                &metadata.installed_on,
+                &metadata.removed_on,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn rename_meter(&self, meter_key: &str, new_key: &str) -> StorageResult<bool> {
+        let mut cache = self.meter_cache.lock().unwrap();
+        let mut client = self.writer.lock().unwrap();
+        if find_meter_id(&mut client, new_key)?.is_some() {
+            return Err(format!("Meter {} already exists, merge into it instead", new_key).into());
+        }
+
+        let renamed = client.execute(
+            "UPDATE meter_names SET meter_key = $2 WHERE meter_key = $1",
+            &[&meter_key, &new_key],
+        )? > 0;
+        cache.remove(meter_key);
+        Ok(renamed)
+    }
+
+    fn merge_meters(&self, from_key: &str, into_key: &str) -> StorageResult<Option<u64>> {
+        let mut cache = self.meter_cache.lock().unwrap();
+        let mut client = self.writer.lock().unwrap();
+        let (Some(from), Some(into)) = (find_meter_id(&mut client, from_key)?, find_meter_id(&mut client, into_key)?) else {
+            return Ok(None);
+        };
+        if from == into {
+            return Err("Cannot merge a meter into itself".into());
+        }
+
+        let mut tx = client.transaction()?;
+        if let Some(oldest) = next_source_time(&mut tx, from, Source::Raw, None)? {
+            rewind_watermarks(&mut tx, into, oldest)?;
+        }
+        let moved = tx.execute(
+            "INSERT INTO readings (meter_id, channel, time, value)
+             SELECT $2, channel, time, value FROM readings WHERE meter_id = $1
+             ON CONFLICT DO NOTHING",
+            &[&from, &into],
+        )?;
+        // Rollups don't keep when their first and last value were read. Where
+        // both meters have a bucket, the one whose finest tier starts earlier goes first.
+        // An aggregate one of them doesn't keep stays NULL, like MIN in SQLite.
+        let from_first: bool = tx.query_one(
+            "SELECT COALESCE((SELECT bucket_start FROM rollups WHERE meter_id = $1 ORDER BY width_secs, bucket_start LIMIT 1)
+                              < (SELECT bucket_start FROM rollups WHERE meter_id = $2 ORDER BY width_secs, bucket_start LIMIT 1), FALSE)",
+            &[&from, &into],
+        )?.get(0);
+        tx.execute(
+            "INSERT INTO rollups (meter_id, width_secs, bucket_start, channel, count, avg, min, max, first, last)
+             SELECT $2, width_secs, bucket_start, channel, count, avg, min, max, first, last FROM rollups WHERE meter_id = $1
+             ON CONFLICT (meter_id, width_secs, bucket_start, channel) DO UPDATE SET
+                count = rollups.count + excluded.count,
+                avg = (rollups.avg * rollups.count + excluded.avg * excluded.count) / (rollups.count + excluded.count),
+                min = CASE WHEN rollups.min IS NOT NULL AND excluded.min IS NOT NULL THEN LEAST(rollups.min, excluded.min) END,
+                max = CASE WHEN rollups.max IS NOT NULL AND excluded.max IS NOT NULL THEN GREATEST(rollups.max, excluded.max) END,
+                first = CASE WHEN $3 THEN excluded.first ELSE rollups.first END,
+                last = CASE WHEN $3 THEN rollups.last ELSE excluded.last END",
+            &[&from, &into, &from_first],
+        )?;
+        // Each meter measured part of a shared bucket, so its energy adds up
+        tx.execute(
+            "INSERT INTO rollup_energy (meter_id, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from)
+             SELECT $2, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from FROM rollup_energy WHERE meter_id = $1
+             ON CONFLICT (meter_id, width_secs, bucket_start) DO UPDATE SET
+                imported_kwh = rollup_energy.imported_kwh + excluded.imported_kwh,
+                exported_kwh = rollup_energy.exported_kwh + excluded.exported_kwh,
+                import_from = rollup_energy.import_from | excluded.import_from,
+                export_from = rollup_energy.export_from | excluded.export_from",
+            &[&from, &into],
+        )?;
+        tx.execute(
+            "INSERT INTO meter_channels (meter_id, channel, quantity, unit, phase, direction, position)
+             SELECT $2, channel, quantity, unit, phase, direction, position FROM meter_channels WHERE meter_id = $1
+             ON CONFLICT DO NOTHING",
+            &[&from, &into],
+        )?;
+        tx.execute("UPDATE capture_sessions SET meter_id = $2 WHERE meter_id = $1", &[&from, &into])?;
+        for table in ["readings", "rollups", "rollup_energy", "rollup_watermarks", "meter_channels", "meter_names"] {
+            tx.execute(&format!("DELETE FROM {} WHERE meter_id = $1", table), &[&from])?;
+        }
+        tx.commit()?;
+
+        cache.remove(from_key);
+        info!("Merged {} rows of meter {} into {}", moved, from_key, into_key);
+        Ok(Some(moved))
+    }
+
+    fn retire_meter(&self, meter_key: &str) -> StorageResult<bool> {
+        let retired = self.writer.lock().unwrap().execute(
+            "UPDATE meter_names SET retired_at = COALESCE(retired_at, now()) WHERE meter_key = $1",
+            &[&meter_key],
+        )? > 0;
+        Ok(retired)
+    }
+
+    fn register_channels(&self, meter_key: &str, channels: &[Channel]) -> StorageResult<()> {
+        let meter_id = self.get_or_create_meter_id(meter_key)?;
+
+        let mut client = self.writer.lock().unwrap();
+        let mut tx = client.transaction()?;
+        tx.execute("DELETE FROM meter_channels WHERE meter_id = $1", &[&meter_id])?;
+        for (position, channel) in channels.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO meter_channels (meter_id, channel, quantity, unit, phase, direction, position)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &meter_id,
+                    &channel.name(),
+                    &channel.quantity.as_str(),
+                    &channel.unit(),
+                    &channel.phase.as_str(),
+                    &channel.direction.as_str(),
+                    &(position as i32),
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_meter_channels(&self) -> StorageResult<HashMap<String, Vec<ChannelInfo>>> {
+        let rows = self.reader.lock().unwrap().query(
+            "SELECT m.meter_key, c.channel
+             FROM meter_channels c
+             JOIN meter_names m ON c.meter_id = m.meter_id
+             ORDER BY m.meter_key, c.position",
+            &[],
+        )?;
+
+        let mut channels: HashMap<String, Vec<ChannelInfo>> = HashMap::new();
+        for row in rows {
+            if let Some(channel) = Channel::parse(row.get(1)) {
+                channels.entry(row.get(0)).or_default().push(channel.into());
+            }
+        }
+        Ok(channels)
+    }
+
+    fn get_meter_readings(
+        &self,
+        meter_key: &str,
+        start_time: Option<DateTime<Utc>>,
+        end_time: Option<DateTime<Utc>>,
+    ) -> StorageResult<Vec<Model>> {
+        let rows = self.reader.lock().unwrap().query(
+            "SELECT r.time, m.name,
+                    MAX(r.value) FILTER (WHERE r.channel = $2),
+                    MAX(r.value) FILTER (WHERE r.channel = $3),
+                    MAX(r.value) FILTER (WHERE r.channel = $4),
+                    MAX(r.value) FILTER (WHERE r.channel = $5)
+             FROM readings r
+             JOIN meter_names m ON r.meter_id = m.meter_id
+             WHERE m.meter_key = $1
+             AND r.channel IN ($2, $3, $4, $5)
+             AND ($6::timestamptz IS NULL OR r.time >= $6)
+             AND ($7::timestamptz IS NULL OR r.time <= $7)
+             GROUP BY r.time, m.name
+             ORDER BY r.time DESC",
+            &[
+                &meter_key,
+                &Channel::TOTAL_POWER.name(),
+                &Channel::IMPORT_POWER.name(),
+                &Channel::EXPORT_POWER.name(),
+                &Channel::TOTAL_KWH.name(),
+                &start_time,
+                &end_time,
+            ],
+        )?;
+
+        Ok(rows
+            .iter()
+            .map(|row| Model {
+                meter_name: row.get(1),
+                timestamp: row.get(0),
+                total_power: row.get::<_, Option<f64>>(2).map(|v| v as f32),
+                import_power: row.get::<_, Option<f64>>(3).map(|v| v as f32),
+                export_power: row.get::<_, Option<f64>>(4).map(|v| v as f32),
+                total_kwh: row.get::<_, Option<f64>>(5).map(|v| v as f32),
+            })
+            .collect())
+    }
+
+    fn get_buckets(&self, query: &BucketQuery) -> StorageResult<Option<Vec<ChannelBuckets>>> {
+        let mut reader = self.reader.lock().unwrap();
+        let Some(meter_id) = find_meter_id(&mut reader, &query.meter_key)? else {
+            return Ok(None);
+        };
+        let channels: Vec<String> = query.channels.iter().map(Channel::name).collect();
+
+        // One snapshot, so retention can't move data between planning and reading
+        let mut tx = reader.build_transaction().isolation_level(IsolationLevel::RepeatableRead).read_only(true).start()?;
+        let segments = rollup::plan_buckets(query, &coverage(&mut tx, meter_id)?);
+        if segments.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        // Bucketing the local wall clock time makes boundaries follow DST like the SQLite backend
+        let sql = aggregate_sql(
+            &partials(&segments),
+            "EXTRACT(EPOCH FROM date_bin(make_interval(secs => $3), to_timestamp(ts) AT TIME ZONE $4,
+                                         TIMESTAMP '1970-01-05') AT TIME ZONE $4)::BIGINT",
+        );
+        let rows = tx.query(&sql, &[&meter_id, &channels, &(query.width_secs as f64), &query.timezone.name()])?;
+
+        let parsed = rows.iter().filter_map(|row| {
+            let channel = Channel::parse(row.get(0))?;
+            Some((channel, BucketStats {
+                start: row.get(1),
+                count: row.get(2),
+                avg: row.get(3),
+                min: row.get(4),
+                max: row.get(5),
+                first: row.get(6),
+                last: row.get(7),
+            }))
+        });
+        Ok(Some(query::collect_series(parsed)))
+    }
+
+    fn get_channel_samples(
+        &self,
+        meter_key: &str,
+        channel: Channel,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> StorageResult<Option<Vec<(i64, f64)>>> {
+        let mut reader = self.reader.lock().unwrap();
+        let Some(meter_id) = find_meter_id(&mut reader, meter_key)? else {
+            return Ok(None);
+        };
+
+        let mut tx = reader.build_transaction().isolation_level(IsolationLevel::RepeatableRead).read_only(true).start()?;
+        let segments = rollup::plan_samples(start.timestamp(), end.timestamp(), &coverage(&mut tx, meter_id)?);
+        if segments.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        let selects: Vec<String> = segments.iter().map(|segment| match segment.source {
+            Source::Raw => format!(
+                "SELECT EXTRACT(EPOCH FROM time)::BIGINT, value FROM readings
+                 WHERE meter_id = $1 AND channel = $2 AND time >= to_timestamp({}) AND time < to_timestamp({})",
+                segment.start, segment.end
+            ),
+            Source::Tier(width) => {
+                let (offset, value) = if channel.quantity == Quantity::Energy { (width - 1, "COALESCE(last, avg)") } else { (width / 2, "avg") };
+                format!(
+                    "SELECT bucket_start + {}, {} FROM rollups
+                     WHERE meter_id = $1 AND width_secs = {} AND channel = $2 AND bucket_start >= {} AND bucket_start < {}",
+                    offset, value, width, segment.start, segment.end
+                )
+            }
+        }).collect();
+
+        let rows = tx.query(&format!("{} ORDER BY 1", selects.join(" UNION ALL ")), &[&meter_id, &channel.name()])?;
+        Ok(Some(rows.iter().map(|row| (row.get(0), row.get(1))).collect()))
+    }
+
+    fn get_rolled_energy(&self, meter_key: &str, start: i64, end: i64, edges: &[i64]) -> StorageResult<Option<RolledEnergy>> {
+        let mut reader = self.reader.lock().unwrap();
+        let Some(meter_id) = find_meter_id(&mut reader, meter_key)? else {
+            return Ok(None);
+        };
+
+        let mut tx = reader.build_transaction().isolation_level(IsolationLevel::RepeatableRead).read_only(true).start()?;
+        let coverage = Coverage {
+            raw_from: next_source_time(&mut tx, meter_id, Source::Raw, None)?,
+            tiers: tier_coverage(&mut tx, meter_id, "rollup_energy")?,
+        };
+        let segments = rollup::plan_energy(start, end, edges, &coverage);
+        let mut buckets = Vec::new();
+        for segment in &segments {
+            let Source::Tier(width) = segment.source else {
+                continue;
+            };
+            let rows = tx.query(
+                "SELECT bucket_start, imported_kwh, exported_kwh, import_from::BIGINT, export_from::BIGINT FROM rollup_energy
+                 WHERE meter_id = $1 AND width_secs = $2::BIGINT AND bucket_start >= $3 AND bucket_start < $4
+                 ORDER BY bucket_start",
+                &[&meter_id, &width, &segment.start, &segment.end],
+            )?;
+            buckets.extend(rows.iter().map(|row| PeriodEnergy {
+                start: row.get(0),
+                end: row.get::<_, i64>(0) + width,
+                imported_kwh: row.get(1),
+                exported_kwh: row.get(2),
+                import_source: EnergySource::from_bits(row.get(3)),
+                export_source: EnergySource::from_bits(row.get(4)),
+            }));
+        }
+        tx.commit()?;
+        Ok(Some(RolledEnergy { buckets, uncovered: rollup::uncovered(start, end, &segments) }))
+    }
+
+    fn latest_readings(&self) -> StorageResult<Vec<MeterSummary>> {
+        let rows = self.reader.lock().unwrap().query(
+            "SELECT m.meter_key, m.name, EXTRACT(EPOCH FROM m.retired_at)::BIGINT,
+                    -- Rollups only tell which bucket the last reading fell into
+                    GREATEST(EXTRACT(EPOCH FROM s.last_time)::BIGINT, u.last_bucket),
+                    COALESCE(p.value, up.last), COALESCE(s.total, 0),
+                    m.meter_type, m.unit, m.location, m.role, m.phase, m.installed_on, m.removed_on
+             FROM meter_names m
+             LEFT JOIN (
+                SELECT meter_id, MAX(time) AS last_time, COUNT(DISTINCT time) AS total
+                FROM readings
+                GROUP BY meter_id
+             ) s ON s.meter_id = m.meter_id
+             LEFT JOIN (
+                SELECT meter_id, MAX(bucket_start) AS last_bucket
+                FROM rollups
+                GROUP BY meter_id
+             ) u ON u.meter_id = m.meter_id
+             LEFT JOIN LATERAL (
+                SELECT value FROM readings r
+                WHERE r.meter_id = m.meter_id AND r.channel = $1
+                ORDER BY time DESC
+                LIMIT 1
+             ) p ON TRUE
+             LEFT JOIN LATERAL (
+                SELECT last FROM rollups r
+                WHERE r.meter_id = m.meter_id AND r.channel = $1 AND r.last IS NOT NULL
+                ORDER BY bucket_start DESC, width_secs
+                LIMIT 1
+             ) up ON TRUE
+             ORDER BY m.meter_key",
+            &[&Channel::TOTAL_POWER.name()],
+        )?;
+
+        Ok(rows
+            .iter()
+            .map(|row| MeterSummary {
+                meter_key: row.get(0),
+                meter_name: row.get(1),
+                retired_at: row.get(2),
+                last_reading_timestamp: row.get(3),
+                last_power_reading: row.get::<_, Option<f64>>(4).map(|v| v as f32),
+                total_readings: row.get(5),
+                metadata: MeterMetadata {
+                    meter_type: row.get(6),
+                    unit: row.get(7),
+                    location: row.get(8),
+                    role: row.get::<_, Option<&str>>(9).and_then(MeterRole::parse),
+                    phase: row.get::<_, Option<&str>>(10).and_then(Phase::parse),
+                    installed_on: row.get(11),
+                    removed_on: row.get(12),
+                },
+            })
+            .collect())
+    }
+
+    fn stats(&self) -> StorageResult<StorageStats> {
+        let row = self.reader.lock().unwrap().query_one(
+            "SELECT COUNT(DISTINCT meter_id),
+                    EXTRACT(EPOCH FROM MAX(time))::BIGINT,
+                    COUNT(DISTINCT (meter_id, time)),
+                    pg_database_size(current_database())
+             FROM readings",
+            &[],
+        )?;
+
+        Ok(StorageStats {
+            backend: "postgres",
+            location: self.location.clone(),
+            size_bytes: row.get::<_, i64>(3) as u64,
+            meters_count: row.get::<_, i64>(0) as usize,
+            last_write: row.get(1),
+            total_records: row.get(2),
+        })
+    }
+
+    fn meter_keys(&self) -> StorageResult<Vec<String>> {
+        Ok(self.reader.lock().unwrap().query("SELECT meter_key FROM meter_names ORDER BY meter_id", &[])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn rollup(&self, meter_key: &str, tier: &Tier, source_width: Option<i64>, until: DateTime<Utc>, dry_run: bool) -> StorageResult<RollupCounts> {
+        let Some(meter_id) = find_meter_id(&mut self.writer.lock().unwrap(), meter_key)? else {
+            return Ok(RollupCounts::default());
+        };
+        let width_secs = tier.width_secs;
+        let source = source_width.map_or(Source::Raw, Source::Tier);
+        let bucket = format!("ts / {0} * {0}", width_secs);
+        let kept = |aggregate| rollup::kept_column(tier, aggregate);
+        let all_channels: Vec<String> = Vec::new();
+
+        // Each pass is a transaction with its watermark, the writer is released in between
+        let mut counts = RollupCounts::default();
+        // Where a dry run's passes would have moved the watermark
+        let mut rehearsed = None;
+        loop {
+            let mut client = self.writer.lock().unwrap();
+            let ready = match source_width {
+                Some(source_width) => match rolled_until(&mut **client, meter_id, source_width)? {
+                    Some(rolled) => rolled.min(until.timestamp()),
+                    None => break,
+                },
+                None => {
+                    let since = until.timestamp() - energy::LOOKAROUND.num_seconds();
+                    energy::complete_until(until.timestamp(), &newest_energy_readings(&mut **client, meter_id, since)?)
+                }
+            };
+            let watermark = match rehearsed {
+                Some(watermark) => Some(watermark),
+                None => rolled_until(&mut **client, meter_id, width_secs)?,
+            };
+            let next = next_source_time(&mut **client, meter_id, source, watermark)?;
+
+            let mut tx = client.transaction()?;
+            let watermark = match rollup::next_pass(watermark, next, ready, width_secs) {
+                Pass::Roll { start, end } => {
+                    let sql = format!(
+                        "INSERT INTO rollups (meter_id, width_secs, channel, bucket_start, count, avg, min, max, first, last)
+                         SELECT $1, {}, channel, bucket, count, avg, {}, {}, {}, {} FROM ({}) aggregated
+                         ON CONFLICT (meter_id, width_secs, bucket_start, channel) DO UPDATE SET
+                            count = excluded.count, avg = excluded.avg, min = excluded.min, max = excluded.max,
+                            first = excluded.first, last = excluded.last",
+                        width_secs,
+                        kept(Aggregate::Min),
+                        kept(Aggregate::Max),
+                        kept(Aggregate::First),
+                        kept(Aggregate::Last),
+                        aggregate_sql(&partials(&[Segment { source, start, end }]), &bucket)
+                    );
+                    counts.read += source_rows(&mut tx, meter_id, source, start, end)?;
+                    counts.written += tx.execute(&sql, &[&meter_id, &all_channels])?;
+                    counts.written += rollup_energy(&mut tx, meter_id, width_secs, source_width, start, end)?;
+                    end
+                }
+                Pass::Skip(until) => until,
+                Pass::Done => break,
+            };
+            if dry_run {
+                rehearsed = Some(watermark);
+                tx.rollback()?;
+                continue;
+            }
+            tx.execute(
+                "INSERT INTO rollup_watermarks (meter_id, width_secs, rolled_until) VALUES ($1, $2::BIGINT, $3)
+                 ON CONFLICT (meter_id, width_secs) DO UPDATE SET rolled_until = excluded.rolled_until",
+                &[&meter_id, &width_secs, &watermark],
+            )?;
+            tx.commit()?;
+        }
+        counts.rolled_until = match rehearsed {
+            Some(watermark) => Some(watermark),
+            None => rolled_until(&mut **self.writer.lock().unwrap(), meter_id, width_secs)?,
+        };
+        Ok(counts)
+    }
+
+    fn expire_raw(&self, meter_key: &str, before: DateTime<Utc>, width_secs: i64, rolled_until: Option<i64>, dry_run: bool) -> StorageResult<u64> {
+        let (meter_id, cutoff) = {
+            let mut client = self.writer.lock().unwrap();
+            let Some(meter_id) = find_meter_id(&mut client, meter_key)? else {
+                return Ok(0);
+            };
+            let rolled = match rolled_until {
+                Some(rolled) => Some(rolled),
+                None => self::rolled_until(&mut **client, meter_id, width_secs)?,
+            };
+            let Some(rolled) = rolled else {
+                return Ok(0);
+            };
+            let newest: Option<i64> = client.query_one(
+                "SELECT FLOOR(EXTRACT(EPOCH FROM MAX(time)))::BIGINT FROM readings WHERE meter_id = $1",
+                &[&meter_id],
+            )?.get(0);
+            // Whole buckets only, raw readings have to pick up where the rollups end
+            (meter_id, rollup::align_down(newest.unwrap_or(i64::MAX).min(rolled).min(before.timestamp()), width_secs))
+        };
+        self.delete_raw(meter_id, cutoff, dry_run)
+    }
+
+    fn expire_rollups(&self, meter_key: &str, width_secs: i64, before: DateTime<Utc>, dry_run: bool) -> StorageResult<u64> {
+        let mut client = self.writer.lock().unwrap();
+        let Some(meter_id) = find_meter_id(&mut client, meter_key)? else {
+            return Ok(0);
+        };
+        let mut deleted = 0;
+        for table in ["rollups", "rollup_energy"] {
+            deleted += delete_rows(
+                &mut **client,
+                &format!("FROM {} WHERE meter_id = $1 AND width_secs = $2::BIGINT AND bucket_start + width_secs <= $3", table),
+                &[&meter_id, &width_secs, &before.timestamp()],
+                dry_run,
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete_before(&self, meter_key: &str, before: DateTime<Utc>, dry_run: bool) -> StorageResult<u64> {
+        let Some(meter_id) = find_meter_id(&mut self.writer.lock().unwrap(), meter_key)? else {
+            return Ok(0);
+        };
+        let mut deleted = self.delete_raw(meter_id, before.timestamp(), dry_run)?;
+        let mut client = self.writer.lock().unwrap();
+        for table in ["rollups", "rollup_energy"] {
+            deleted += delete_rows(
+                &mut **client,
+                &format!("FROM {} WHERE meter_id = $1 AND bucket_start + width_secs <= $2", table),
+                &[&meter_id, &before.timestamp()],
+                dry_run,
+            )?;
+        }
+        Ok(deleted)
+    }
+
+    fn oldest_data(&self) -> StorageResult<Option<DateTime<Utc>>> {
+        let row = self.reader.lock().unwrap().query_one(
+            "SELECT LEAST((SELECT MIN(time) FROM readings), (SELECT to_timestamp(MIN(bucket_start)) FROM rollups))",
+            &[],
+        )?;
+        Ok(row.get(0))
+    }
+
+    /// The database size, PostgreSQL only hands space back to the system
+    /// after a `VACUUM FULL`.
+    fn used_bytes(&self) -> StorageResult<u64> {
+        let row = self.reader.lock().unwrap().query_one("SELECT pg_database_size(current_database())", &[])?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    fn undeletable_bytes(&self) -> StorageResult<u64> {
+        // TimescaleDB chunks of `readings` live in their own schema
+        let row = self.reader.lock().unwrap().query_one(
+            "SELECT COALESCE(SUM(pg_total_relation_size(relid)), 0)::BIGINT FROM pg_statio_user_tables
+             WHERE schemaname = current_schema() AND relname NOT IN ('readings', 'rollups', 'rollup_energy')",
+            &[],
+        )?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    fn backup(&self, _destination: &Path) -> StorageResult<()> {
+        Err("PostgreSQL databases are backed up with pg_dump".into())
+    }
+
+    fn insert_retention_report(&self, report: &RetentionReport) -> StorageResult<i64> {
+        let mut client = self.writer.lock().unwrap();
+        let mut tx = client.transaction()?;
+        let run_id: i64 = tx.query_one(
+            "INSERT INTO retention_runs (started_at, duration_ms, size_before_bytes, size_after_bytes, errors)
+             VALUES (to_timestamp($1::BIGINT), $2, $3, $4, $5)
+             RETURNING run_id",
+            &[
+                &report.started_at,
+                &(report.duration_ms as i64),
+                &(report.size_before_bytes as i64),
+                &(report.size_after_bytes as i64),
+                &report.errors.join("\n"),
+            ],
+        )?.get(0);
+        let insert = tx.prepare(
+            "INSERT INTO retention_run_steps (run_id, step, meter_key, width_secs, rows_read, rows_written, rows_deleted)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )?;
+        for (step, row) in report.steps.iter().enumerate() {
+            tx.execute(&insert, &[
+                &run_id,
+                &(step as i32),
+                &row.meter_key,
+                &row.width_secs,
+                &(row.rows_read as i64),
+                &(row.rows_written as i64),
+                &(row.rows_deleted as i64),
+            ])?;
+        }
+        tx.execute("DELETE FROM retention_runs WHERE run_id <= $1", &[&(run_id - REPORTS_KEPT as i64)])?;
+        tx.commit()?;
+        Ok(run_id)
+    }
+
+    fn get_retention_reports(&self, limit: usize) -> StorageResult<Vec<RetentionReport>> {
+        let mut client = self.reader.lock().unwrap();
+        let mut reports = client.query(
+            "SELECT run_id, EXTRACT(EPOCH FROM started_at)::BIGINT, duration_ms, size_before_bytes, size_after_bytes, errors
+             FROM retention_runs ORDER BY run_id DESC LIMIT $1",
+            &[&(limit as i64)],
+        )?.iter().map(|row| RetentionReport {
+            run_id: Some(row.get(0)),
+            started_at: row.get(1),
+            duration_ms: row.get::<_, i64>(2) as u64,
+            dry_run: false,
+            size_before_bytes: row.get::<_, i64>(3) as u64,
+            size_after_bytes: row.get::<_, i64>(4) as u64,
+            steps: Vec::new(),
+            errors: row.get::<_, String>(5).lines().map(String::from).collect(),
+            size_limit_skipped: false,
+        }).collect::<Vec<_>>();
+
+        let steps = client.prepare(
+            "SELECT meter_key, width_secs, rows_read, rows_written, rows_deleted
+             FROM retention_run_steps WHERE run_id = $1 ORDER BY step",
+        )?;
+        for report in &mut reports {
+            report.steps = client.query(&steps, &[&report.run_id])?.iter().map(|row| RetentionStep {
+                meter_key: row.get(0),
+                width_secs: row.get(1),
+                rows_read: row.get::<_, i64>(2) as u64,
+                rows_written: row.get::<_, i64>(3) as u64,
+                rows_deleted: row.get::<_, i64>(4) as u64,
+            }).collect();
+        }
+        Ok(reports)
+    }
+
+    /// Autovacuum usually keeps up on its own. PostgreSQL has no counterpart to
+    /// SQLite's optimize or WAL truncation, and checks integrity with amcheck.
+    fn maintain(&self, task: MaintenanceTask) -> StorageResult<String> {
+        match task {
+            MaintenanceTask::Vacuum => {
+                let before = self.used_bytes()?;
+                self.writer.lock().unwrap().batch_execute("VACUUM")?;
+                Ok(format!("dead rows freed for reuse, database takes {} bytes (was {})", self.used_bytes()?, before))
+            }
+            MaintenanceTask::Analyze => {
+                self.writer.lock().unwrap().batch_execute("ANALYZE")?;
+                Ok("statistics updated".to_string())
+            }
+            MaintenanceTask::Optimize | MaintenanceTask::Checkpoint | MaintenanceTask::IntegrityCheck => {
+                Ok("not needed on PostgreSQL".to_string())
+            }
+        }
+    }
+
+    fn record_maintenance(&self, result: &MaintenanceResult) -> StorageResult<()> {
+        self.writer.lock().unwrap().execute(
+            "INSERT INTO maintenance_results (task, finished_at, duration_ms, ok, detail)
+             VALUES ($1, to_timestamp($2::BIGINT), $3, $4, $5)
+             ON CONFLICT (task) DO UPDATE SET
+                finished_at = EXCLUDED.finished_at,
+                duration_ms = EXCLUDED.duration_ms,
+                ok = EXCLUDED.ok,
+                detail = EXCLUDED.detail",
+            &[&result.task.as_str(), &result.finished_at, &(result.duration_ms as i64), &result.ok, &result.detail],
+        )?;
+        Ok(())
+    }
+
+    fn maintenance_results(&self) -> StorageResult<Vec<MaintenanceResult>> {
+        let rows = self.reader.lock().unwrap().query(
+            "SELECT task, EXTRACT(EPOCH FROM finished_at)::BIGINT, duration_ms, ok, detail FROM maintenance_results",
+            &[],
+        )?;
+        Ok(rows.iter().filter_map(|row| {
+            Some(MaintenanceResult {
+                task: MaintenanceTask::parse(row.get(0))?,
+                finished_at: row.get(1),
+                duration_ms: row.get::<_, i64>(2) as u64,
+                ok: row.get(3),
+                detail: row.get(4),
+            })
+        }).collect())
+    }
+
+    fn create_capture_session(&self, params: &CaptureParams) -> StorageResult<i64> {
+        let meter_id = self.get_or_create_meter_id(&params.meter)?;
+        let channels = params.channels.iter().map(|c| c.name()).collect::<Vec<_>>().join(",");
+
+        let row = self.writer.lock().unwrap().query_one(
+            "INSERT INTO capture_sessions
+             (meter_id, channels, interval_ms, duration_secs, status, created_at)
+             VALUES ($1, $2, $3, $4, $5, now())
+             RETURNING session_id",
+            &[
+                &meter_id,
+                &channels,
+                &(params.interval_ms as i64),
+                &(params.duration_secs as i64),
+                &CaptureStatus::Pending.as_str(),
+            ],
+        )?;
+        Ok(row.get(0))
+    }
+
+    fn set_capture_running(&self, session_id: i64) -> StorageResult<()> {
+        self.writer.lock().unwrap().execute(
+            "UPDATE capture_sessions SET status = $1, started_at = now() WHERE session_id = $2",
+            &[&CaptureStatus::Running.as_str(), &session_id],
+        )?;
+        Ok(())
+    }
+
+    fn finish_capture_session(&self, session_id: i64, status: CaptureStatus, error: Option<&str>) -> StorageResult<()> {
+        self.writer.lock().unwrap().execute(
+            "UPDATE capture_sessions SET status = $1, finished_at = now(), error = $2 WHERE session_id = $3",
+            &[&status.as_str(), &error, &session_id],
+        )?;
+        Ok(())
+    }
+
+    fn insert_capture_samples(&self, session_id: i64, measurements: &[Measurement]) -> StorageResult<()> {
+        let mut client = self.writer.lock().unwrap();
+        let mut tx = client.transaction()?;
+        let insert = tx.prepare(
+            "INSERT INTO capture_samples (session_id, timestamp_ms, channel, value) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (session_id, timestamp_ms, channel) DO UPDATE SET value = excluded.value",
+        )?;
+        for m in measurements {
+            tx.execute(
+                &insert,
+                &[&session_id, &m.timestamp.timestamp_millis(), &m.channel.name(), &(m.value as f64)],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_capture_session(&self, session_id: i64) -> StorageResult<Option<CaptureSession>> {
+        let row = self.reader.lock().unwrap().query_opt(
+            "SELECT s.session_id, m.name, s.channels, s.interval_ms, s.duration_secs, s.status,
+                    EXTRACT(EPOCH FROM s.started_at)::BIGINT, EXTRACT(EPOCH FROM s.finished_at)::BIGINT, s.error,
+                    (SELECT COUNT(DISTINCT timestamp_ms) FROM capture_samples c WHERE c.session_id = s.session_id)
+             FROM capture_sessions s
+             JOIN meter_names m ON s.meter_id = m.meter_id
+             WHERE s.session_id = $1",
+            &[&session_id],
+        )?;
+
+        Ok(row.map(|row| {
+            let channels: String = row.get(2);
+            CaptureSession {
+                session_id: row.get(0),
+                meter_name: row.get(1),
+                channels: channels.split(',').filter_map(Channel::parse).collect(),
+                interval_ms: row.get::<_, i64>(3) as u64,
+                duration_secs: row.get::<_, i64>(4) as u64,
+                status: CaptureStatus::parse(row.get(5)).unwrap_or(CaptureStatus::Failed),
+                started_at: row.get(6),
+                finished_at: row.get(7),
+                error: row.get(8),
+                sample_count: row.get(9),
+            }
+        }))
+    }
+
+    fn get_capture_samples(&self, session_id: i64) -> StorageResult<Vec<CaptureSample>> {
+        let rows = self.reader.lock().unwrap().query(
+            "SELECT timestamp_ms, channel, value
+             FROM capture_samples
+             WHERE session_id = $1
+             ORDER BY timestamp_ms",
+            &[&session_id],
+        )?;
+
+        let mut samples: Vec<CaptureSample> = Vec::new();
+        for row in rows {
+            let timestamp_ms: i64 = row.get(0);
+            let Some(channel) = Channel::parse(row.get(1)) else {
+                continue;
+            };
+            let value = row.get::<_, f64>(2) as f32;
+            match samples.last_mut() {
+                Some(sample) if sample.timestamp_ms == timestamp_ms => {
+                    sample.values.insert(channel, value);
+                }
+                _ => samples.push(CaptureSample {
+                    timestamp_ms,
+                    values: HashMap::from([(channel, value)]),
+                }),
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Where the raw readings of a meter start and how far each tier is rolled up.
+fn coverage(client: &mut impl GenericClient, meter_id: i32) -> Result<Coverage, postgres::Error> {
+    Ok(Coverage {
+        raw_from: next_source_time(client, meter_id, Source::Raw, None)?,
+        tiers: tier_coverage(client, meter_id, "rollups")?,
+    })
+}
+
+/// (width, oldest bucket in `table`, watermark) of a meter's tiers.
+fn tier_coverage(client: &mut impl GenericClient, meter_id: i32, table: &str) -> Result<Vec<(i64, i64, i64)>, postgres::Error> {
+    // Tiers with all buckets expired have nothing to offer
+    let rows = client.query(
+        &format!(
+            "SELECT width_secs::BIGINT, oldest, rolled_until FROM (
+                SELECT width_secs, rolled_until,
+                       (SELECT MIN(bucket_start) FROM {} r WHERE r.meter_id = w.meter_id AND r.width_secs = w.width_secs) AS oldest
+                FROM rollup_watermarks w
+                WHERE meter_id = $1
+             ) tiers
+             WHERE oldest IS NOT NULL
+             ORDER BY width_secs",
+            table
+        ),
+        &[&meter_id],
+    )?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+}
+
+/// Raw readings of one channel in `[from, to)`, oldest first.
+fn raw_samples(client: &mut impl GenericClient, meter_id: i32, channel: Channel, from: i64, to: i64) -> Result<Vec<(i64, f64)>, postgres::Error> {
+    let rows = client.query(
+        "SELECT FLOOR(EXTRACT(EPOCH FROM time))::BIGINT, value FROM readings
+         WHERE meter_id = $1 AND channel = $2 AND time >= to_timestamp($3::BIGINT) AND time < to_timestamp($4::BIGINT)
+         ORDER BY time",
+        &[&meter_id, &channel.name(), &from, &to],
+    )?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// Newest reading since `since` of each channel energy is computed from.
+fn newest_energy_readings(client: &mut impl GenericClient, meter_id: i32, since: i64) -> Result<Vec<i64>, postgres::Error> {
+    let names: Vec<String> = energy::CHANNELS.iter().map(|channel| channel.name()).collect();
+    let rows = client.query(
+        "SELECT FLOOR(EXTRACT(EPOCH FROM MAX(time)))::BIGINT FROM readings
+         WHERE meter_id = $1 AND channel = ANY($2) AND time >= to_timestamp($3::BIGINT)
+         GROUP BY channel",
+        &[&meter_id, &names, &since],
+    )?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Stores the energy of a meter's `width_secs` buckets in `[start, end)`,
+/// computed from raw readings or summed up from the `source_width` tier.
+fn rollup_energy(
+    client: &mut impl GenericClient,
+    meter_id: i32,
+    width_secs: i64,
+    source_width: Option<i64>,
+    start: i64,
+    end: i64,
+) -> StorageResult<u64> {
+    let upsert = "ON CONFLICT (meter_id, width_secs, bucket_start) DO UPDATE SET
+        imported_kwh = excluded.imported_kwh, exported_kwh = excluded.exported_kwh,
+        import_from = excluded.import_from, export_from = excluded.export_from";
+    if let Some(source_width) = source_width {
+        return Ok(client.execute(
+            &format!(
+                "INSERT INTO rollup_energy (meter_id, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from)
+                 SELECT meter_id, $2::BIGINT, bucket_start / $2::BIGINT * $2::BIGINT, SUM(imported_kwh), SUM(exported_kwh),
+                        bit_or(import_from), bit_or(export_from)
+                 FROM rollup_energy
+                 WHERE meter_id = $1 AND width_secs = $3::BIGINT AND bucket_start >= $4 AND bucket_start < $5
+                 GROUP BY 1, 3
+                 {}",
+                upsert
+            ),
+            &[&meter_id, &width_secs, &source_width, &start, &end],
+        )?);
+    }
+
+    // Readings around the buckets too, for the differences reaching into them
+    let lookaround = energy::LOOKAROUND.num_seconds();
+    let samples = EnergySamples::read(|channel| {
+        Ok(Some(raw_samples(client, meter_id, channel, start - lookaround, end + lookaround)?))
+    })?;
+    let Some(samples) = samples else {
+        return Ok(0);
+    };
+    let sql = format!(
+        "INSERT INTO rollup_energy (meter_id, width_secs, bucket_start, imported_kwh, exported_kwh, import_from, export_from)
+         VALUES ($1, $2::BIGINT, $3, $4, $5, $6::BIGINT, $7::BIGINT)
+         {}",
+        upsert
+    );
+    let mut written = 0;
+    let (buckets, _) = samples.periods(&rollup::buckets(start, end, width_secs));
+    for bucket in buckets.iter().filter(|b| b.import_source != EnergySource::None || b.export_source != EnergySource::None) {
+        written += client.execute(&sql, &[
+            &meter_id,
+            &width_secs,
+            &bucket.start,
+            &bucket.imported_kwh,
+            &bucket.exported_kwh,
+            &bucket.import_source.bits(),
+            &bucket.export_source.bits(),
+        ])?;
+    }
+    Ok(written)
+}
+
+/// Watermark of a meter's `width_secs` tier, its buckets before it are final.
+fn rolled_until(client: &mut impl GenericClient, meter_id: i32, width_secs: i64) -> Result<Option<i64>, postgres::Error> {
+    Ok(client.query_opt(
+        "SELECT rolled_until FROM rollup_watermarks WHERE meter_id = $1 AND width_secs = $2::BIGINT",
+        &[&meter_id, &width_secs],
+    )?.map(|row| row.get(0)))
+}
+
+/// Moves every watermark of a meter back to the bucket holding `from`, so
+/// raw readings written behind them are rolled up too.
+fn rewind_watermarks(client: &mut impl GenericClient, meter_id: i32, from: i64) -> Result<(), postgres::Error> {
+    client.execute(
+        "UPDATE rollup_watermarks SET rolled_until = LEAST(rolled_until, $2::BIGINT / width_secs * width_secs) WHERE meter_id = $1",
+        &[&meter_id, &from],
+    )?;
+    Ok(())
+}
+
+/// Oldest reading or bucket of `source` at or after `from`, in unix seconds.
+fn next_source_time(client: &mut impl GenericClient, meter_id: i32, source: Source, from: Option<i64>) -> Result<Option<i64>, postgres::Error> {
+    let row = match source {
+        Source::Raw => client.query_one(
+            "SELECT FLOOR(EXTRACT(EPOCH FROM MIN(time)))::BIGINT FROM readings
+             WHERE meter_id = $1 AND ($2::BIGINT IS NULL OR time >= to_timestamp($2::BIGINT))",
+            &[&meter_id, &from],
+        )?,
+        Source::Tier(width) => client.query_one(
+            "SELECT MIN(bucket_start) FROM rollups
+             WHERE meter_id = $1 AND width_secs = $2 AND ($3::BIGINT IS NULL OR bucket_start >= $3)",
+            &[&meter_id, &(width as i32), &from],
+        )?,
+    };
+    Ok(row.get(0))
+}
+
+/// Rows of `source` a rollup of `[start, end)` reads.
+fn source_rows(client: &mut impl GenericClient, meter_id: i32, source: Source, start: i64, end: i64) -> Result<u64, postgres::Error> {
+    let row = match source {
+        Source::Raw => client.query_one(
+            "SELECT COUNT(*) FROM readings WHERE meter_id = $1 AND time >= to_timestamp($2::BIGINT) AND time < to_timestamp($3::BIGINT)",
+            &[&meter_id, &start, &end],
+        )?,
+        Source::Tier(width) => client.query_one(
+            "SELECT COUNT(*) FROM rollups WHERE meter_id = $1 AND width_secs = $2::BIGINT AND bucket_start >= $3 AND bucket_start < $4",
+            &[&meter_id, &width, &start, &end],
+        )?,
+    };
+    Ok(row.get::<_, i64>(0) as u64)
+}
+
+/// `DELETE` with `from_where`, or for a dry run `SELECT COUNT(*)`.
+fn delete_rows(
+    client: &mut impl GenericClient,
+    from_where: &str,
+    params: &[&(dyn postgres::types::ToSql + Sync)],
+    dry_run: bool,
+) -> Result<u64, postgres::Error> {
+    if dry_run {
+        Ok(client.query_one(&format!("SELECT COUNT(*) {}", from_where), params)?.get::<_, i64>(0) as u64)
+    } else {
+        client.execute(&format!("DELETE {}", from_where), params)
+    }
+}
+
+/// One `SELECT channel, ts, count, sum, min, max, first, last` per segment of
+/// meter `$1` and the channels in `$2` (all if empty), raw readings counting
+/// as buckets of one value.
+fn partials(segments: &[Segment]) -> Vec<String> {
+    let channels = "(cardinality($2::text[]) = 0 OR channel = ANY($2))";
+    segments.iter().map(|segment| match segment.source {
+        Source::Raw => format!(
+            "SELECT channel, FLOOR(EXTRACT(EPOCH FROM time))::BIGINT, 1::BIGINT, value, value, value, value, value
+             FROM readings
+             WHERE meter_id = $1 AND time >= to_timestamp({}) AND time < to_timestamp({}) AND {}",
+            segment.start, segment.end, channels
+        ),
+        Source::Tier(width) => format!(
+            "SELECT channel, bucket_start, count, avg * count,
+                    COALESCE(min, avg), COALESCE(max, avg), COALESCE(first, avg), COALESCE(last, avg)
+             FROM rollups
+             WHERE meter_id = $1 AND width_secs = {} AND bucket_start >= {} AND bucket_start < {} AND {}",
+            width, segment.start, segment.end, channels
+        ),
+    }).collect()
+}
+
+/// Combines `partials` into `SELECT channel, bucket, count, avg, min, max,
+/// first, last` per channel and `bucket` expression over `ts`.
+fn aggregate_sql(partials: &[String], bucket: &str) -> String {
+    format!(
+        "WITH partials(channel, ts, count, sum, min, max, first, last) AS ({}),
+         keyed AS (SELECT *, {} AS bucket FROM partials)
+         SELECT channel, bucket, SUM(count)::BIGINT AS count, SUM(sum) / SUM(count)::DOUBLE PRECISION AS avg,
+                MIN(min) AS min, MAX(max) AS max,
+                (array_agg(first ORDER BY ts))[1] AS first,
+                (array_agg(last ORDER BY ts DESC))[1] AS last
+         FROM keyed
+         GROUP BY channel, bucket
+         ORDER BY channel, bucket",
+        partials.join(" UNION ALL "),
+        bucket
+    )
+}
+
+/// Applies pending `MIGRATIONS`. Refuses schemas written by a newer build and,
+/// unless `create_database` is set, databases without any tables.
+fn migrate(client: &mut Client, location: &str, create_database: bool) -> StorageResult<i32> {
+    let table_count: i64 = client
+        .query_one("SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = current_schema()", &[])?
+        .get(0);
+    if table_count == 0 && !create_database {
+        return Err(format!(
+            "Database {} is empty and create_database is false; set create_database = true to initialize it",
+            location
+        ).into());
+    }
+
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL
+        )",
+    )?;
+
+    let current: i32 = client
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])?
+        .get(0);
+    let latest = MIGRATIONS.last().map(|(version, _, _)| *version).unwrap_or(0);
+    if current > latest {
+        return Err(format!(
+            "Database schema version {} is newer than this build supports ({}); refusing to start",
+            current, latest
+        ).into());
+    }
+
+    for (version, description, sql) in MIGRATIONS.iter().filter(|(version, _, _)| *version > current) {
+        info!("Applying PostgreSQL migration {}: {}", version, description);
+        let mut tx = client.transaction()?;
+        tx.batch_execute(sql)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, now())",
+            &[version, description],
+        )?;
+        tx.commit()?;
+    }
+    Ok(latest)
+}
+
+/// Connection target for logs and `/status`, without the password.
+fn describe(config: &Config) -> String {
+    let host = match config.get_hosts().first() {
+        Some(Host::Tcp(host)) => host.clone(),
+        #[cfg(unix)]
+        Some(Host::Unix(path)) => path.display().to_string(),
+        None => "localhost".to_string(),
+    };
+    format!(
+        "postgres://{}@{}:{}/{}",
+        config.get_user().unwrap_or_default(),
+        host,
+        config.get_ports().first().copied().unwrap_or(5432),
+        config.get_dbname().unwrap_or_default(),
+    )
+}
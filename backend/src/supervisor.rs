@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::task::{self, JoinError};
+use tokio::time::{sleep, Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A task that ran at least this long before panicking restarts with the initial backoff.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u32,
+    pub started_at: Option<i64>,     // Unix timestamp of the current/last run
+    pub last_panic_at: Option<i64>,  // Unix timestamp
+    pub last_error: Option<String>,
+}
+
+/// Runs long-lived tasks and restarts them with exponential backoff when they panic.
+///
+/// Each task is described by a factory that builds a fresh future for every
+/// (re)start, so state lost in a panic (e.g. a meter's serial context) is rebuilt.
+/// A task that returns normally is marked stopped and not restarted.
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    tasks: Arc<Mutex<BTreeMap<String, TaskStatus>>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F, Fut>(&self, name: impl Into<String>, mut factory: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        self.tasks.lock().unwrap().insert(name.clone(), TaskStatus {
+            name: name.clone(),
+            state: TaskState::Running,
+            restarts: 0,
+            started_at: None,
+            last_panic_at: None,
+            last_error: None,
+        });
+
+        let supervisor = self.clone();
+        task::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+
+            loop {
+                supervisor.update(&name, |status| {
+                    status.state = TaskState::Running;
+                    status.started_at = Some(Utc::now().timestamp());
+                });
+
+                let started = Instant::now();
+                match task::spawn(factory()).await {
+                    Ok(()) => {
+                        info!("Task {} finished", name);
+                        supervisor.update(&name, |status| status.state = TaskState::Stopped);
+                        return;
+                    }
+                    Err(e) if e.is_panic() => {
+                        let message = panic_message(e);
+                        if started.elapsed() >= STABLE_RUN {
+                            backoff = INITIAL_BACKOFF;
+                        }
+
+                        error!("Task {} panicked: {}. Restarting in {}s", name, message, backoff.as_secs());
+                        supervisor.update(&name, |status| {
+                            status.state = TaskState::Restarting;
+                            status.restarts += 1;
+                            status.last_panic_at = Some(Utc::now().timestamp());
+                            status.last_error = Some(message);
+                        });
+
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                    Err(e) => {
+                        warn!("Task {} was cancelled: {}", name, e);
+                        supervisor.update(&name, |status| status.state = TaskState::Stopped);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Current state of every supervised task, ordered by name.
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        self.tasks.lock().unwrap().values().cloned().collect()
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut TaskStatus)) {
+        if let Some(status) = self.tasks.lock().unwrap().get_mut(name) {
+            f(status);
+        }
+    }
+}
+
+fn panic_message(error: JoinError) -> String {
+    let payload = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spawns a task whose runs panic or return as `outcomes` says, each
+    /// after running for the given time, and records when each run started.
+    fn spawn_runs(supervisor: &Supervisor, outcomes: Vec<(u64, bool)>) -> Arc<Mutex<Vec<Instant>>> {
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let runs = Arc::clone(&starts);
+        supervisor.spawn("meter", move || {
+            let runs = Arc::clone(&runs);
+            let run = runs.lock().unwrap().len();
+            let (seconds, panics) = outcomes[run];
+            async move {
+                runs.lock().unwrap().push(Instant::now());
+                sleep(Duration::from_secs(seconds)).await;
+                if panics {
+                    panic!("run {} failed", run);
+                }
+            }
+        });
+        starts
+    }
+
+    async fn wait_until_stopped(supervisor: &Supervisor) -> TaskStatus {
+        loop {
+            let status = supervisor.statuses().remove(0);
+            if status.state == TaskState::Stopped {
+                return status;
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Seconds between consecutive starts, rounded down.
+    fn gaps(starts: &Mutex<Vec<Instant>>) -> Vec<u64> {
+        starts.lock().unwrap().windows(2).map(|pair| (pair[1] - pair[0]).as_secs()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_with_exponential_backoff() {
+        let supervisor = Supervisor::new();
+        let starts = spawn_runs(&supervisor, vec![(0, true), (0, true), (0, true), (0, false)]);
+
+        let status = wait_until_stopped(&supervisor).await;
+        assert_eq!(gaps(&starts), [1, 2, 4]);
+        assert_eq!(status.restarts, 3);
+        assert_eq!(status.last_error.as_deref(), Some("run 2 failed"));
+        assert!(status.last_panic_at.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stable_runs_reset_the_backoff() {
+        let supervisor = Supervisor::new();
+        let starts = spawn_runs(&supervisor, vec![(0, true), (0, true), (61, true), (0, false)]);
+
+        wait_until_stopped(&supervisor).await;
+        // The third run lasted over a minute, so it restarts after one second again
+        assert_eq!(gaps(&starts), [1, 2, 62]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_task_state() {
+        let supervisor = Supervisor::new();
+        let starts = spawn_runs(&supervisor, vec![(0, true), (30, false)]);
+
+        // Running, restarting after the panic, running again, stopped once it returns
+        tokio::task::yield_now().await;
+        sleep(Duration::from_millis(500)).await;
+        let status = supervisor.statuses().remove(0);
+        assert_eq!((status.name.as_str(), status.state, status.restarts), ("meter", TaskState::Restarting, 1));
+
+        sleep(Duration::from_secs(1)).await;
+        let status = supervisor.statuses().remove(0);
+        assert_eq!((status.state, status.restarts), (TaskState::Running, 1));
+        assert!(status.started_at.is_some());
+
+        let status = wait_until_stopped(&supervisor).await;
+        assert_eq!((status.restarts, starts.lock().unwrap().len()), (1, 2));
+    }
+}
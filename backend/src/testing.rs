@@ -0,0 +1,53 @@
+//! Helpers shared by the unit tests and `tests/`, which include this file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A database path in the temp directory, unique per test process.
+///
+/// Everything named after it is removed when it is dropped and before it is
+/// handed out: the database with its `-wal` and `-shm` files, backups written
+/// next to it and other paths with the same stem.
+pub struct TempDb {
+    path: PathBuf,
+}
+
+impl TempDb {
+    /// `solarmeter-<name>-<pid>.db` in the temp directory.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("solarmeter-{}-{}.db", name, std::process::id()));
+        let temp = Self { path };
+        temp.remove();
+        temp
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn url(&self) -> &str {
+        self.path().to_str().expect("temp directory is not valid UTF-8")
+    }
+
+    fn remove(&self) {
+        let prefix = format!("{}.", self.path.file_stem().unwrap().to_string_lossy());
+        let Ok(entries) = fs::read_dir(self.path.parent().unwrap()) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                continue;
+            }
+            let _ = match entry.file_type() {
+                Ok(kind) if kind.is_dir() => fs::remove_dir_all(entry.path()),
+                _ => fs::remove_file(entry.path()),
+            };
+        }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
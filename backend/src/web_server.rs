@@ -1,69 +1,203 @@
-use warp::{Filter, Reply};
-use serde::Serialize;
+use warp::{http::StatusCode, Filter, Reply};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use log::{error, info};
 use chrono::{DateTime, Utc};
-use rusqlite::Connection;
-use std::path::Path;
 use std::convert::Infallible;
-use tokio::sync::oneshot;
+use std::io::Write;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, oneshot};
+use warp::hyper::{body::Bytes, Body};
 use std::sync::Mutex;
+use chrono_tz::Tz;
 
-use crate::database_sync::DatabaseSync;
+use crate::capture::{CaptureManager, CaptureParams, CaptureStatus};
+use crate::backup;
+use crate::config::{AppConfig, BackupConfig, MaintenanceConfig};
+use crate::data_retention::{RetentionService, REPORTS_KEPT};
+use crate::energy::{self, Period};
+use crate::export::{self, CsvOptions, ExportFormat, ExportRequest};
+use crate::maintenance::{self, MaintenanceStatus};
+use crate::storage::{MeterMetadata, Storage};
+use crate::meters::ChannelInfo;
+use crate::query::{self, BucketQuery, BucketWidth};
+use crate::supervisor::{Supervisor, TaskStatus};
+use crate::writer::{BatchWriter, WriterStatus};
 
 #[derive(Serialize)]
 struct SystemStatus {
+    database_backend: &'static str,
     database_size_bytes: u64,
     database_path: String,
     meters_count: usize,
     last_write: Option<i64>,  // Unix timestamp as i64
     total_records: i64,
     uptime_seconds: u64,
+    tasks: Vec<TaskStatus>,
+    writer: WriterStatus,
+    maintenance: Vec<MaintenanceStatus>,
 }
 
 #[derive(Serialize)]
 struct MeterStatus {
+    meter_key: String,
     meter_name: String,
+    retired_at: Option<i64>,  // Unix timestamp as i64
+    #[serde(flatten)]
+    metadata: MeterMetadata,
     last_reading_timestamp: Option<i64>,  // Unix timestamp as i64
     last_power_reading: f32,
     total_readings: i64,
+    channels: Vec<ChannelInfo>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Query string of `/readings/buckets`. Either `bucket` or `points` sets the width.
+#[derive(Deserialize)]
+struct BucketParams {
+    meter: String,
+    start: String,  // Unix timestamp or RFC 3339
+    end: String,
+    bucket: Option<String>,
+    points: Option<i64>,
+    channels: Option<String>,  // comma separated, all channels if missing
+    tz: Option<String>,
+}
+
+/// Query string of `/energy`. Without `start` and `end` it reports the current
+/// period, without `meter` all meters.
+#[derive(Deserialize)]
+struct EnergyParams {
+    meter: Option<String>,
+    period: Option<Period>,
+    start: Option<String>,  // Unix timestamp or RFC 3339
+    end: Option<String>,
+    tz: Option<String>,
+}
+
+/// Query string of `/export`. Without `meters` all meters are exported.
+#[derive(Deserialize)]
+struct ExportParams {
+    meters: Option<String>,  // comma separated meter keys
+    start: String,  // Unix timestamp or RFC 3339
+    end: String,
+    format: Option<String>,  // csv (default) or parquet
+    channels: Option<String>,  // comma separated, all declared channels if missing
+    resample: Option<String>,  // bucket width such as 15m
+    delimiter: Option<String>,
+    decimal: Option<String>,
+    tz: Option<String>,
+}
+
+/// Query string of `/retention/runs`, the newest 20 reports if `limit` is missing.
+#[derive(Deserialize)]
+struct RetentionRunsParams {
+    limit: Option<usize>,
+}
+
+/// Query string of `POST /retention/run`. A dry run only reports what a run would do.
+#[derive(Deserialize)]
+struct RetentionRunParams {
+    dry_run: Option<bool>,
+}
+
+/// Body of `POST /meters/<key>/rename` and `/merge`.
+#[derive(Deserialize)]
+struct MeterOperation {
+    key: String,
+}
+
+#[derive(Serialize)]
+struct MergeResult {
+    moved_rows: u64,
+}
+
+#[derive(Serialize)]
+struct CaptureStarted {
+    session_id: i64,
+}
+
+/// Hands what the export writes to the response body in chunks. Fails once
+/// the client has gone away, which stops the export.
+struct BodyWriter {
+    sender: mpsc::Sender<Bytes>,
+    buffer: Vec<u8>,
+}
+
+impl BodyWriter {
+    const CHUNK_SIZE: usize = 64 * 1024;
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= Self::CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.sender.blocking_send(chunk)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+}
+
+fn error_reply(status: StatusCode, message: impl Into<String>) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorResponse { error: message.into() }),
+        status,
+    ).into_response()
 }
 
 #[derive(Clone)]
 pub struct WebServer {
-    db: Arc<DatabaseSync>,
+    db: Arc<dyn Storage>,
     start_time: DateTime<Utc>,
     bind_address: String,
     shutdown_sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    captures: CaptureManager,
+    supervisor: Supervisor,
+    writer: BatchWriter,
+    timezone: Tz,
+    /// Keys of the meters in the config, which are polled and can't be renamed away
+    configured_meters: Arc<Vec<String>>,
+    backup: Option<BackupConfig>,
+    retention: RetentionService,
+    maintenance: MaintenanceConfig,
 }
 
 impl WebServer {
-    pub fn new(db: Arc<DatabaseSync>, bind_address: Option<String>, shutdown_sender: oneshot::Sender<()>) -> Self {
+    pub fn new(
+        db: Arc<dyn Storage>,
+        config: &AppConfig,
+        shutdown_sender: oneshot::Sender<()>,
+        captures: CaptureManager,
+        supervisor: Supervisor,
+        writer: BatchWriter,
+        retention: RetentionService,
+    ) -> Self {
         Self {
             db,
             start_time: Utc::now(),
-            bind_address: bind_address.unwrap_or_else(|| "127.0.0.1".to_string()),
+            bind_address: config.global.bind_address.clone(),
             shutdown_sender: Arc::new(Mutex::new(Some(shutdown_sender))),
-        }
-    }
-
-    fn get_database_size(&self, path: &str) -> Result<u64, std::io::Error> {
-        let metadata = std::fs::metadata(path)?;
-        Ok(metadata.len())
-    }
-
-    fn get_last_write(&self, conn: &Connection) -> Option<i64> {
-        // Get the most recent timestamp directly as i64
-        match conn.query_row(
-            "SELECT MAX(timestamp) FROM meter_readings",
-            [],
-            |row| row.get::<_, Option<i64>>(0)
-        ) {
-            Ok(timestamp) => timestamp,
-            Err(e) => {
-                error!("Failed to get last write timestamp: {}", e);
-                None
-            }
+            captures,
+            supervisor,
+            writer,
+            timezone: config.timezone(),
+            configured_meters: Arc::new(config.meters.iter().map(|(id, meter)| meter.key(id).to_string()).collect()),
+            backup: config.backup.clone(),
+            retention,
+            maintenance: config.maintenance.clone(),
         }
     }
 
@@ -118,30 +252,36 @@ impl WebServer {
     }
 
     async fn handle_status(&self) -> Result<impl Reply, Infallible> {
-        let conn = match self.db.get_connection() {
-            Ok(conn) => conn,
+        let (stats, results) = match self.db.run_blocking(|db| Ok((db.stats()?, db.maintenance_results()?))).await {
+            Ok(status) => status,
             Err(e) => {
-                error!("Failed to get database connection: {}", e);
+                error!("Failed to query database status: {}", e);
                 return Ok(warp::reply::json(&SystemStatus {
+                    database_backend: "unknown",
                     database_size_bytes: 0,
                     database_path: String::from("unknown"),
                     meters_count: 0,
                     last_write: None,
                     total_records: 0,
                     uptime_seconds: 0,
+                    tasks: self.supervisor.statuses(),
+                    writer: self.writer.status(),
+                    maintenance: Vec::new(),
                 }));
             }
         };
 
-        let db_path = Path::new(&self.db.get_database_path()).to_string_lossy().into_owned();
-        
         let status = SystemStatus {
-            database_size_bytes: self.get_database_size(&db_path).unwrap_or(0),
-            database_path: db_path,
-            meters_count: self.get_unique_meters(&conn).unwrap_or(0),
-            last_write: self.get_last_write(&conn),
-            total_records: self.get_total_records(&conn).unwrap_or(0),
+            database_backend: stats.backend,
+            database_size_bytes: stats.size_bytes,
+            database_path: stats.location,
+            meters_count: stats.meters_count,
+            last_write: stats.last_write,
+            total_records: stats.total_records,
             uptime_seconds: (Utc::now() - self.start_time).num_seconds() as u64,
+            tasks: self.supervisor.statuses(),
+            writer: self.writer.status(),
+            maintenance: maintenance::statuses(&self.maintenance, &results, Utc::now()),
         };
 
         Ok(warp::reply::json(&status))
@@ -149,68 +289,432 @@ impl WebServer {
 
 
     async fn handle_meters(&self) -> Result<impl Reply, Infallible> {
-        let conn = match self.db.get_connection() {
-            Ok(conn) => conn,
+        let meters = self.db.run_blocking(|db| {
+            let mut channels = match db.get_meter_channels() {
+                Ok(channels) => channels,
+                Err(e) => {
+                    error!("Failed to load meter channels: {}", e);
+                    Default::default()
+                }
+            };
+
+            let meters = db.latest_readings()?
+                .into_iter()
+                .map(|meter| MeterStatus {
+                    channels: channels.remove(&meter.meter_key).unwrap_or_default(),
+                    last_reading_timestamp: meter.last_reading_timestamp,
+                    last_power_reading: meter.last_power_reading.unwrap_or(0.0),
+                    total_readings: meter.total_readings,
+                    meter_key: meter.meter_key,
+                    meter_name: meter.meter_name,
+                    retired_at: meter.retired_at,
+                    metadata: meter.metadata,
+                })
+                .collect::<Vec<_>>();
+            Ok(meters)
+        }).await;
+
+        match meters {
+            Ok(meters) => Ok(warp::reply::json(&meters)),
             Err(e) => {
-                error!("Failed to get database connection: {}", e);
-                return Ok(warp::reply::json(&Vec::<MeterStatus>::new()));
+                error!("Failed to query meters: {}", e);
+                Ok(warp::reply::json(&Vec::<MeterStatus>::new()))
             }
+        }
+    }
+
+    fn bucket_query(&self, params: BucketParams) -> Result<BucketQuery, String> {
+        let start = query::parse_timestamp(&params.start).ok_or("start must be unix seconds or RFC 3339")?;
+        let end = query::parse_timestamp(&params.end).ok_or("end must be unix seconds or RFC 3339")?;
+        let width = match (params.bucket, params.points) {
+            (Some(bucket), None) => BucketWidth::parse(&bucket).ok_or(format!("Invalid bucket width {}", bucket))?,
+            (None, Some(points)) if points > 0 => BucketWidth::Points(points),
+            (None, Some(_)) => return Err("points must be greater than 0".to_string()),
+            _ => return Err("Exactly one of bucket and points is required".to_string()),
         };
-    
-        let meters = match conn.prepare(
-            "SELECT 
-                m.name,
-                MAX(r.timestamp) as last_reading,
-                (SELECT total_power 
-                 FROM meter_readings mr2 
-                 WHERE mr2.meter_id = r.meter_id
-                 ORDER BY timestamp DESC 
-                 LIMIT 1) as last_power,
-                COUNT(*) as total_readings
-             FROM meter_readings r
-             JOIN meter_names m ON r.meter_id = m.meter_id
-             GROUP BY m.name"
-        ) {
-            Ok(mut stmt) => {
-                match stmt.query_map([], |row| {
-                    let last_timestamp: Option<i64> = row.get(1)?;
-                    Ok(MeterStatus {
-                        meter_name: row.get(0)?,
-                        last_reading_timestamp: last_timestamp,  // This will be the Unix timestamp
-                        last_power_reading: DatabaseSync::f16_to_f32(row.get::<_, i16>(2)?),
-                        total_readings: row.get(3)?,
-                    })
-                }) {
-                    Ok(rows) => rows.filter_map(Result::ok).collect::<Vec<_>>(),
-                    Err(e) => {
-                        error!("Failed to query meters: {}", e);
-                        Vec::new()
+        let channels = match params.channels {
+            Some(channels) => query::parse_channels(&channels)?,
+            None => Vec::new(),
+        };
+        let timezone = match params.tz {
+            Some(tz) => tz.parse().map_err(|e| format!("{}", e))?,
+            None => self.timezone,
+        };
+
+        BucketQuery::new(params.meter, channels, start, end, width, timezone)
+    }
+
+    async fn handle_buckets(&self, params: BucketParams) -> Result<warp::reply::Response, Infallible> {
+        let query = match self.bucket_query(params) {
+            Ok(query) => query,
+            Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, e)),
+        };
+
+        let meter_key = query.meter_key.clone();
+        match self.db.run_blocking(move |db| db.get_buckets(&query)).await {
+            Ok(Some(series)) => Ok(warp::reply::json(&series).into_response()),
+            Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown meter {}", meter_key))),
+            Err(e) => {
+                error!("Failed to query buckets of {}: {}", meter_key, e);
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }
+    }
+
+    async fn handle_energy(&self, params: EnergyParams) -> Result<warp::reply::Response, Infallible> {
+        let parse = |value: Option<String>, name: &str| match value {
+            Some(value) => query::parse_timestamp(&value).ok_or(format!("{} must be unix seconds or RFC 3339", name)),
+            None => Ok(Utc::now()),
+        };
+        let (start, end) = match (parse(params.start, "start"), parse(params.end, "end")) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(e), _) | (_, Err(e)) => return Ok(error_reply(StatusCode::BAD_REQUEST, e)),
+        };
+        let timezone = match params.tz.map(|tz| tz.parse::<Tz>()).transpose() {
+            Ok(timezone) => timezone.unwrap_or(self.timezone),
+            Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, e.to_string())),
+        };
+        let period = params.period.unwrap_or(Period::Day);
+        let bounds = match energy::period_bounds(period, start, end, timezone) {
+            Ok(bounds) => bounds,
+            Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, e)),
+        };
+
+        let meter = params.meter.clone();
+        let result = self.db.run_blocking(move |db| {
+            let names = match meter {
+                Some(name) => vec![name],
+                None => db.latest_readings()?.into_iter().map(|m| m.meter_key).collect(),
+            };
+            names.iter()
+                .map(|name| energy::meter_energy(db, name, period, &bounds))
+                .collect::<Result<Option<Vec<_>>, _>>()
+        }).await;
+
+        match result {
+            Ok(Some(energy)) => Ok(warp::reply::json(&energy).into_response()),
+            Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown meter {}", params.meter.unwrap_or_default()))),
+            Err(e) => {
+                error!("Failed to compute energy totals: {}", e);
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }
+    }
+
+    fn export_request(&self, params: ExportParams) -> Result<ExportRequest, String> {
+        let char_param = |value: Option<String>, name: &str, default: char| match value {
+            Some(value) => export::parse_delimiter(&value).ok_or(format!("{} must be a single character", name)),
+            None => Ok(default),
+        };
+        let defaults = CsvOptions::default();
+        let request = ExportRequest {
+            meters: params.meters
+                .map(|meters| meters.split(',').map(|key| key.trim().to_string()).collect())
+                .unwrap_or_default(),
+            channels: match params.channels {
+                Some(channels) => query::parse_channels(&channels)?,
+                None => Vec::new(),
+            },
+            start: query::parse_timestamp(&params.start).ok_or("start must be unix seconds or RFC 3339")?,
+            end: query::parse_timestamp(&params.end).ok_or("end must be unix seconds or RFC 3339")?,
+            resample: params.resample
+                .map(|width| BucketWidth::parse(&width).ok_or(format!("Invalid resample width {}", width)))
+                .transpose()?,
+            timezone: match params.tz {
+                Some(tz) => tz.parse().map_err(|e| format!("{}", e))?,
+                None => self.timezone,
+            },
+            format: match params.format {
+                Some(format) => ExportFormat::parse(&format).ok_or(format!("Unknown format {}, use csv or parquet", format))?,
+                None => ExportFormat::Csv,
+            },
+            csv: CsvOptions {
+                delimiter: char_param(params.delimiter, "delimiter", defaults.delimiter)?,
+                decimal_separator: char_param(params.decimal, "decimal", defaults.decimal_separator)?,
+            },
+        };
+        request.validate()?;
+        Ok(request)
+    }
+
+    /// Streams the export while it is read from the database, so large ranges
+    /// never have to fit in memory. An error after the first chunk aborts the body.
+    async fn handle_export(&self, params: ExportParams) -> Result<warp::reply::Response, Infallible> {
+        let mut request = match self.export_request(params) {
+            Ok(request) => request,
+            Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, e)),
+        };
+
+        let requested = request.meters.clone();
+        request.meters = match self.db.run_blocking(move |db| export::resolve_meters(db, &requested)).await {
+            Ok(Ok(meters)) => meters,
+            Ok(Err(unknown)) => return Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown meter {}", unknown))),
+            Err(e) => {
+                error!("Failed to list meters for export: {}", e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            }
+        };
+
+        let format = request.format;
+        let (sender, mut chunks) = mpsc::channel(4);
+        let (mut body_sender, body) = Body::channel();
+        let db = Arc::clone(&self.db);
+        tokio::spawn(async move {
+            let export = db.run_blocking(move |db| {
+                let mut out = BodyWriter { sender, buffer: Vec::new() };
+                let rows = export::export(db, &request, &mut out)?;
+                out.flush()?;
+                Ok(rows)
+            });
+            tokio::pin!(export);
+            loop {
+                tokio::select! {
+                    Some(chunk) = chunks.recv() => {
+                        if body_sender.send_data(chunk).await.is_err() {
+                            break;
+                        }
+                    }
+                    result = &mut export => {
+                        // Whatever the export wrote before it returned is still queued
+                        while let Ok(chunk) = chunks.try_recv() {
+                            if body_sender.send_data(chunk).await.is_err() {
+                                break;
+                            }
+                        }
+                        match result {
+                            Ok(rows) => info!("Exported {} rows", rows),
+                            Err(e) => {
+                                error!("Export failed: {}", e);
+                                body_sender.abort();
+                            }
+                        }
+                        return;
                     }
                 }
-            },
+            }
+        });
+
+        let filename = format!("readings_{}.{}", Utc::now().format("%Y%m%d_%H%M%S"), format.extension());
+        Ok(warp::http::Response::builder()
+            .header("Content-Type", format.content_type())
+            .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+            .body(body)
+            .map(|response| response.into_response())
+            .unwrap_or_else(|_| error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to build response")))
+    }
+
+    /// Streams a consistent copy of the SQLite database. Needs
+    /// `Authorization: Bearer <backup.api_token>`.
+    async fn handle_snapshot(&self, authorization: Option<String>) -> Result<warp::reply::Response, Infallible> {
+        let Some((directory, token)) = self.backup.as_ref().and_then(|b| Some((b.directory.clone(), b.api_token.as_deref()?))) else {
+            return Ok(error_reply(StatusCode::NOT_FOUND, "Snapshots are disabled, set backup.api_token"));
+        };
+        let presented = authorization.as_deref().and_then(|value| value.strip_prefix("Bearer "));
+        if !presented.is_some_and(|presented| backup::token_matches(token, presented)) {
+            let mut reply = error_reply(StatusCode::UNAUTHORIZED, "Invalid or missing bearer token");
+            reply.headers_mut().insert("WWW-Authenticate", warp::http::HeaderValue::from_static("Bearer"));
+            return Ok(reply);
+        }
+
+        let path = match self.db.run_blocking(move |db| backup::snapshot(db, std::path::Path::new(&directory))).await {
+            Ok(path) => path,
             Err(e) => {
-                error!("Failed to prepare statement: {}", e);
-                Vec::new()
+                error!("Failed to create snapshot: {}", e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            }
+        };
+        let file = tokio::fs::File::open(&path).await;
+        // Unlinked right away, the open file stays readable until the download ends
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            error!("Failed to remove snapshot {}: {}", path.display(), e);
+        }
+        let (mut file, length) = match file {
+            Ok(file) => {
+                let length = file.metadata().await.map(|m| m.len()).unwrap_or_default();
+                (file, length)
             }
+            Err(e) => return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
         };
-    
-        Ok(warp::reply::json(&meters))
+        info!("Streaming database snapshot, {} bytes", length);
+
+        let (mut body_sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; BodyWriter::CHUNK_SIZE];
+            loop {
+                match file.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if body_sender.send_data(Bytes::copy_from_slice(&buffer[..n])).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to read snapshot: {}", e);
+                        body_sender.abort();
+                        break;
+                    }
+                }
+            }
+        });
+
+        let filename = format!("solarmeter-{}.db", Utc::now().format("%Y%m%d-%H%M%S"));
+        Ok(warp::http::Response::builder()
+            .header("Content-Type", "application/vnd.sqlite3")
+            .header("Content-Length", length)
+            .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+            .body(body)
+            .map(|response| response.into_response())
+            .unwrap_or_else(|_| error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to build response")))
+    }
+
+    async fn handle_retention_runs(&self, params: RetentionRunsParams) -> Result<warp::reply::Response, Infallible> {
+        let limit = params.limit.unwrap_or(20).min(REPORTS_KEPT);
+        match self.db.run_blocking(move |db| db.get_retention_reports(limit)).await {
+            Ok(reports) => Ok(warp::reply::json(&reports).into_response()),
+            Err(e) => {
+                error!("Failed to load retention reports: {}", e);
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }
+    }
+
+    /// Runs retention right away and replies with its report once it is done.
+    async fn handle_retention_run(&self, params: RetentionRunParams) -> Result<warp::reply::Response, Infallible> {
+        let dry_run = params.dry_run.unwrap_or(false);
+        info!("Retention {} requested", if dry_run { "dry run" } else { "run" });
+        match self.retention.run_now(dry_run).await {
+            Ok(report) => Ok(warp::reply::json(&report).into_response()),
+            Err(e) => {
+                error!("Failed to run retention: {}", e);
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }
     }
 
-    fn get_unique_meters(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
-        conn.query_row(
-            "SELECT COUNT(DISTINCT meter_id) FROM meter_readings",
-            [],
-            |row| row.get(0),
-        )
+    /// Rename, merge and retire only apply to meters no longer in the config,
+    /// otherwise the next poll would recreate them under their old key.
+    fn configured_conflict(&self, meter_key: &str) -> Option<warp::reply::Response> {
+        self.configured_meters.iter().any(|key| key == meter_key).then(|| error_reply(
+            StatusCode::CONFLICT,
+            format!("Meter {} is still configured, remove it from the config first", meter_key),
+        ))
     }
 
-    fn get_total_records(&self, conn: &Connection) -> Result<i64, rusqlite::Error> {
-        conn.query_row(
-            "SELECT COUNT(*) FROM meter_readings",
-            [],
-            |row| row.get(0),
-        )
+    async fn handle_meter_rename(&self, meter_key: String, operation: MeterOperation) -> Result<warp::reply::Response, Infallible> {
+        if let Some(reply) = self.configured_conflict(&meter_key) {
+            return Ok(reply);
+        }
+        let key = meter_key.clone();
+        match self.db.run_blocking(move |db| db.rename_meter(&key, &operation.key)).await {
+            Ok(true) => Ok(StatusCode::NO_CONTENT.into_response()),
+            Ok(false) => Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown meter {}", meter_key))),
+            Err(e) => Ok(error_reply(StatusCode::CONFLICT, e.to_string())),
+        }
+    }
+
+    async fn handle_meter_merge(&self, meter_key: String, operation: MeterOperation) -> Result<warp::reply::Response, Infallible> {
+        if let Some(reply) = self.configured_conflict(&meter_key) {
+            return Ok(reply);
+        }
+        let key = meter_key.clone();
+        match self.db.run_blocking(move |db| db.merge_meters(&key, &operation.key)).await {
+            Ok(Some(moved_rows)) => Ok(warp::reply::json(&MergeResult { moved_rows }).into_response()),
+            Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "Unknown meter")),
+            Err(e) => {
+                error!("Failed to merge meter {}: {}", meter_key, e);
+                Ok(error_reply(StatusCode::BAD_REQUEST, e.to_string()))
+            }
+        }
+    }
+
+    async fn handle_meter_retire(&self, meter_key: String) -> Result<warp::reply::Response, Infallible> {
+        if let Some(reply) = self.configured_conflict(&meter_key) {
+            return Ok(reply);
+        }
+        let key = meter_key.clone();
+        match self.db.run_blocking(move |db| db.retire_meter(&key)).await {
+            Ok(true) => Ok(StatusCode::NO_CONTENT.into_response()),
+            Ok(false) => Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown meter {}", meter_key))),
+            Err(e) => {
+                error!("Failed to retire meter {}: {}", meter_key, e);
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }
+    }
+
+    async fn handle_capture_start(&self, params: CaptureParams) -> Result<warp::reply::Response, Infallible> {
+        let captures = self.captures.clone();
+        match self.db.run_blocking(move |db| captures.start(db, params)).await {
+            Ok(session_id) => Ok(warp::reply::with_status(
+                warp::reply::json(&CaptureStarted { session_id }),
+                StatusCode::ACCEPTED,
+            ).into_response()),
+            Err(e) => {
+                error!("Failed to start capture: {}", e);
+                Ok(error_reply(StatusCode::BAD_REQUEST, e.to_string()))
+            }
+        }
+    }
+
+    async fn handle_capture_status(&self, session_id: i64) -> Result<warp::reply::Response, Infallible> {
+        match self.db.run_blocking(move |db| db.get_capture_session(session_id)).await {
+            Ok(Some(session)) => Ok(warp::reply::json(&session).into_response()),
+            Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown capture session {}", session_id))),
+            Err(e) => {
+                error!("Failed to load capture session {}: {}", session_id, e);
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+        }
+    }
+
+    async fn handle_capture_download(&self, session_id: i64) -> Result<warp::reply::Response, Infallible> {
+        let session = match self.db.run_blocking(move |db| db.get_capture_session(session_id)).await {
+            Ok(Some(session)) => session,
+            Ok(None) => return Ok(error_reply(StatusCode::NOT_FOUND, format!("Unknown capture session {}", session_id))),
+            Err(e) => {
+                error!("Failed to load capture session {}: {}", session_id, e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            }
+        };
+
+        if matches!(session.status, CaptureStatus::Pending | CaptureStatus::Running) {
+            return Ok(error_reply(StatusCode::CONFLICT, format!("Capture session {} is still {}", session_id, session.status.as_str())));
+        }
+
+        let samples = match self.db.run_blocking(move |db| db.get_capture_samples(session_id)).await {
+            Ok(samples) => samples,
+            Err(e) => {
+                error!("Failed to load capture samples for {}: {}", session_id, e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            }
+        };
+
+        let channels = &session.channels;
+
+        let mut csv = String::from("timestamp_ms");
+        for channel in channels {
+            csv.push(',');
+            csv.push_str(&channel.name());
+        }
+        csv.push('\n');
+
+        for sample in &samples {
+            csv.push_str(&sample.timestamp_ms.to_string());
+            for channel in channels {
+                csv.push(',');
+                if let Some(value) = sample.values.get(channel) {
+                    csv.push_str(&value.to_string());
+                }
+            }
+            csv.push('\n');
+        }
+
+        let filename = format!("capture_{}_{}.csv", session_id, session.meter_name);
+        Ok(warp::http::Response::builder()
+            .header("Content-Type", "text/csv")
+            .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+            .body(csv.into())
+            .unwrap_or_else(|_| error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to build response")))
     }
 
     pub async fn run(self, port: u16) {
@@ -221,7 +725,7 @@ impl WebServer {
                 server.handle_status().await
             });
 
-        let meters_route = warp::path("meters")
+        let meters_route = warp::path!("meters")
             .and(warp::get())
             .and(with_server(self.clone()))
             .and_then(|server: WebServer| async move {
@@ -235,9 +739,114 @@ impl WebServer {
                 server.handle_kill().await
             });
 
+        let buckets_route = warp::path!("readings" / "buckets")
+            .and(warp::get())
+            .and(warp::query::<BucketParams>())
+            .and(with_server(self.clone()))
+            .and_then(|params: BucketParams, server: WebServer| async move {
+                server.handle_buckets(params).await
+            });
+
+        let energy_route = warp::path!("energy")
+            .and(warp::get())
+            .and(warp::query::<EnergyParams>())
+            .and(with_server(self.clone()))
+            .and_then(|params: EnergyParams, server: WebServer| async move {
+                server.handle_energy(params).await
+            });
+
+        let export_route = warp::path!("export")
+            .and(warp::get())
+            .and(warp::query::<ExportParams>())
+            .and(with_server(self.clone()))
+            .and_then(|params: ExportParams, server: WebServer| async move {
+                server.handle_export(params).await
+            });
+
+        let snapshot_route = warp::path!("backup" / "snapshot")
+            .and(warp::get())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(with_server(self.clone()))
+            .and_then(|authorization: Option<String>, server: WebServer| async move {
+                server.handle_snapshot(authorization).await
+            });
+
+        let retention_runs_route = warp::path!("retention" / "runs")
+            .and(warp::get())
+            .and(warp::query::<RetentionRunsParams>())
+            .and(with_server(self.clone()))
+            .and_then(|params: RetentionRunsParams, server: WebServer| async move {
+                server.handle_retention_runs(params).await
+            });
+
+        let retention_run_route = warp::path!("retention" / "run")
+            .and(warp::post())
+            .and(warp::query::<RetentionRunParams>())
+            .and(with_server(self.clone()))
+            .and_then(|params: RetentionRunParams, server: WebServer| async move {
+                server.handle_retention_run(params).await
+            });
+
+        let meter_rename_route = warp::path!("meters" / String / "rename")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_server(self.clone()))
+            .and_then(|meter_key: String, operation: MeterOperation, server: WebServer| async move {
+                server.handle_meter_rename(meter_key, operation).await
+            });
+
+        let meter_merge_route = warp::path!("meters" / String / "merge")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_server(self.clone()))
+            .and_then(|meter_key: String, operation: MeterOperation, server: WebServer| async move {
+                server.handle_meter_merge(meter_key, operation).await
+            });
+
+        let meter_retire_route = warp::path!("meters" / String / "retire")
+            .and(warp::post())
+            .and(with_server(self.clone()))
+            .and_then(|meter_key: String, server: WebServer| async move {
+                server.handle_meter_retire(meter_key).await
+            });
+
+        let capture_start_route = warp::path!("capture")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_server(self.clone()))
+            .and_then(|params: CaptureParams, server: WebServer| async move {
+                server.handle_capture_start(params).await
+            });
+
+        let capture_status_route = warp::path!("capture" / i64)
+            .and(warp::get())
+            .and(with_server(self.clone()))
+            .and_then(|session_id: i64, server: WebServer| async move {
+                server.handle_capture_status(session_id).await
+            });
+
+        let capture_download_route = warp::path!("capture" / i64 / "download")
+            .and(warp::get())
+            .and(with_server(self.clone()))
+            .and_then(|session_id: i64, server: WebServer| async move {
+                server.handle_capture_download(session_id).await
+            });
+
         let routes = status_route
             .or(meters_route)
-            .or(kill_route);
+            .or(meter_rename_route)
+            .or(meter_merge_route)
+            .or(meter_retire_route)
+            .or(kill_route)
+            .or(buckets_route)
+            .or(energy_route)
+            .or(export_route)
+            .or(snapshot_route)
+            .or(retention_runs_route)
+            .or(retention_run_route)
+            .or(capture_start_route)
+            .or(capture_status_route)
+            .or(capture_download_route);
 
         let addr: std::net::IpAddr = self.bind_address.parse()
             .expect("Invalid bind address");
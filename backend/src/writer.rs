@@ -0,0 +1,459 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::config::StorageConfig;
+use crate::storage::Storage;
+use crate::meters::Measurement;
+use crate::spill::SpillJournal;
+
+enum WriterMessage {
+    Cycle(String, Vec<Measurement>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Backlog and throughput of the batch writer, reported in `/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WriterStatus {
+    pub queued: usize,                // polling cycles waiting to be written
+    pub capacity: usize,
+    pub written: u64,                 // polling cycles written since start
+    pub batches: u64,
+    pub failed_batches: u64,
+    pub dropped: u64,                 // cycles rejected because queue and journal were full
+    pub spilled: u64,                 // cycles written to the spill journal since start
+    pub replayed: u64,                // cycles replayed from the spill journal since start
+    pub journal_cycles: usize,        // cycles in the spill journal waiting to be written
+    pub journal_bytes: u64,
+    pub last_batch_size: usize,
+    pub last_flush_ms: Option<u64>,
+    pub max_flush_ms: u64,
+    pub last_flush_at: Option<i64>,   // Unix timestamp
+    pub last_error: Option<String>,
+}
+
+/// Collects polling cycles from all meters and writes them in batches.
+///
+/// Meters hand their readings to `submit`, which never blocks. A single writer
+/// task commits the queue in one transaction once `batch_size` cycles are
+/// waiting or the oldest has waited `batch_interval`, whichever comes first.
+/// When the database can't keep up the queue grows up to `queue_capacity`,
+/// after which new cycles go to the spill journal. While the journal holds
+/// cycles all new ones are appended to it too, so they are written in the
+/// order they were read. Cycles are only dropped once the journal is full.
+#[derive(Clone)]
+pub struct BatchWriter {
+    db: Arc<dyn Storage>,
+    sender: mpsc::Sender<WriterMessage>,
+    // Shared so the supervisor can restart `run` on the same queue after a panic
+    receiver: Arc<tokio::sync::Mutex<mpsc::Receiver<WriterMessage>>>,
+    batch_size: usize,
+    batch_interval: Duration,
+    status: Arc<Mutex<WriterStatus>>,
+    // Only touched on the blocking pool, see `on_journal`
+    journal: Option<Arc<Mutex<SpillJournal>>>,
+    // Cycles in the journal including appends still in flight, read without
+    // waiting for the journal lock. Changed with the status lock held.
+    journaled: Arc<AtomicUsize>,
+}
+
+impl BatchWriter {
+    pub fn new(db: Arc<dyn Storage>, storage: &StorageConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        // Room for the flush requests on top of the readings
+        let (sender, receiver) = mpsc::channel(storage.queue_capacity + 16);
+        let (mut journaled, mut journal_bytes) = (0, 0);
+        let journal = match &storage.spill_journal {
+            Some(path) => {
+                let journal = SpillJournal::open(Path::new(path), storage.spill_max_mb * 1024 * 1024)
+                    .map_err(|e| format!("Spill journal {}: {}", path, e))?;
+                if journal.cycles() > 0 {
+                    info!("Spill journal {} holds {} cycles from before, replaying them", path, journal.cycles());
+                }
+                (journaled, journal_bytes) = (journal.cycles(), journal.bytes());
+                Some(Arc::new(Mutex::new(journal)))
+            }
+            None => None,
+        };
+        Ok(Self {
+            db,
+            sender,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+            batch_size: storage.batch_size,
+            batch_interval: Duration::from_secs(storage.batch_interval),
+            status: Arc::new(Mutex::new(WriterStatus {
+                capacity: storage.queue_capacity,
+                journal_bytes,
+                ..Default::default()
+            })),
+            journal,
+            journaled: Arc::new(AtomicUsize::new(journaled)),
+        })
+    }
+
+    /// Queues one polling cycle, or appends it to the spill journal if the
+    /// queue is full. Fails without waiting if both are full. The journal is
+    /// written on the blocking pool, so a slow disk never stalls the runtime.
+    pub async fn submit(&self, meter_key: &str, measurements: Vec<Measurement>) -> Result<(), Box<dyn std::error::Error>> {
+        let journal = {
+            let mut status = self.status.lock().unwrap();
+            let spilling = self.journal_cycles() > 0;
+            if !spilling && status.queued < status.capacity {
+                if self.sender.try_send(WriterMessage::Cycle(meter_key.to_string(), measurements)).is_err() {
+                    status.dropped += 1;
+                    return Err("writer stopped, reading dropped".into());
+                }
+                status.queued += 1;
+                return Ok(());
+            }
+
+            let Some(journal) = self.journal.clone() else {
+                status.dropped += 1;
+                return Err(format!("write queue full ({} cycles), reading dropped", status.queued).into());
+            };
+            if !spilling {
+                warn!("Write queue full ({} cycles), spilling to the journal", status.queued);
+            }
+            // Counted right away so later cycles follow this one into the journal
+            self.journaled.fetch_add(1, Ordering::SeqCst);
+            journal
+        };
+
+        let meter_key = meter_key.to_string();
+        let result = self.on_journal(journal, move |journal| {
+            journal.append(&meter_key, &measurements).map_err(|e| e.to_string())
+        }).await;
+
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                status.spilled += 1;
+                Ok(())
+            }
+            Err(e) => {
+                self.journaled.fetch_sub(1, Ordering::SeqCst);
+                status.dropped += 1;
+                Err(format!("write queue full and {}, reading dropped", e).into())
+            }
+        }
+    }
+
+    /// Writes everything queued so far and waits until it is committed.
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.sender.send(WriterMessage::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Never waits for the spill journal, whose file may be busy for a while.
+    pub fn status(&self) -> WriterStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.journal_cycles = self.journal_cycles();
+        status
+    }
+
+    /// The writer task. Runs until every sender is gone.
+    pub async fn run(self) {
+        let mut receiver = self.receiver.lock().await;
+        // Cycles held by a previous run that panicked are gone
+        self.status.lock().unwrap().queued = receiver.len();
+
+        let mut pending: Vec<(String, Vec<Measurement>)> = Vec::new();
+        let mut flush_at: Option<Instant> = None;
+        let mut retrying = false;
+        // Set after a failed replay so a failing database isn't retried in a tight loop
+        let mut replay_at: Option<Instant> = None;
+
+        loop {
+            // Journaled cycles are newer than everything queued, so replay once the queue is written
+            let replay_due = replay_at.is_none_or(|at| at <= Instant::now());
+            if pending.is_empty() && receiver.is_empty() && self.journal_cycles() > 0 && replay_due {
+                replay_at = (!self.replay_journal().await).then(|| Instant::now() + self.batch_interval);
+                continue;
+            }
+            let wake_at = flush_at.into_iter()
+                .chain(replay_at.filter(|_| self.journal_cycles() > 0))
+                .min();
+
+            let flush_now = tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(WriterMessage::Cycle(meter_key, measurements)) => {
+                        pending.push((meter_key, measurements));
+                        flush_at.get_or_insert_with(|| Instant::now() + self.batch_interval);
+                        pending.len() >= self.batch_size && !retrying
+                    }
+                    Some(WriterMessage::Flush(done)) => {
+                        let mut done = vec![done];
+                        if !self.write_pending(&mut pending).await {
+                            self.spill_pending(&mut pending, &mut receiver, &mut done).await;
+                        }
+                        for done in done {
+                            let _ = done.send(());
+                        }
+                        false
+                    }
+                    None => {
+                        if !self.write_pending(&mut pending).await {
+                            self.spill_pending(&mut pending, &mut receiver, &mut Vec::new()).await;
+                        }
+                        return;
+                    }
+                },
+                _ = sleep_until(wake_at.unwrap_or_else(Instant::now)), if wake_at.is_some() => {
+                    flush_at.is_some_and(|at| at <= Instant::now())
+                }
+            };
+
+            if flush_now {
+                retrying = !self.write_pending(&mut pending).await;
+            }
+            if pending.is_empty() {
+                flush_at = None;
+                retrying = false;
+            } else if flush_now && retrying {
+                // Back off for a full interval instead of hammering a failing disk
+                flush_at = Some(Instant::now() + self.batch_interval);
+            }
+        }
+    }
+
+    fn journal_cycles(&self) -> usize {
+        self.journaled.load(Ordering::SeqCst)
+    }
+
+    /// Runs `f` on the blocking pool, the journal's file I/O would stall the
+    /// runtime. Keeps `journal_bytes` in the status up to date.
+    async fn on_journal<T: Send + 'static>(
+        &self,
+        journal: Arc<Mutex<SpillJournal>>,
+        f: impl FnOnce(&mut SpillJournal) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let status = Arc::clone(&self.status);
+        tokio::task::spawn_blocking(move || {
+            let mut journal = journal.lock().unwrap();
+            let result = f(&mut journal);
+            status.lock().unwrap().journal_bytes = journal.bytes();
+            result
+        }).await.unwrap_or_else(|e| Err(e.to_string()))
+    }
+
+    /// Writes the oldest `batch_size` cycles of the spill journal.
+    async fn replay_journal(&self) -> bool {
+        let Some(journal) = self.journal.clone() else {
+            return true;
+        };
+        let batch_size = self.batch_size;
+        let chunk = match self.on_journal(journal.clone(), move |journal| journal.peek(batch_size).map_err(|e| e.to_string())).await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                error!("Failed to read the spill journal: {}", e);
+                self.status.lock().unwrap().last_error = Some(e);
+                return false;
+            }
+        };
+
+        if chunk.lines == 0 {
+            // The cycles counted so far are still being appended
+            return false;
+        }
+
+        let (batch, end, lines) = (chunk.cycles, chunk.end, chunk.lines);
+        let cycles = batch.len();
+        let result = self.db.run_blocking(move |db| db.insert_measurement_batch(&batch)).await;
+        if let Err(e) = result {
+            error!("Failed to replay {} cycles from the spill journal: {}", cycles, e);
+            let mut status = self.status.lock().unwrap();
+            status.failed_batches += 1;
+            status.last_error = Some(e.to_string());
+            return false;
+        }
+
+        let status = Arc::clone(&self.status);
+        let journaled = Arc::clone(&self.journaled);
+        let result = self.on_journal(journal, move |journal| {
+            let result = journal.consume(end, lines).map_err(|e| e.to_string());
+            // Together, so `/status` never shows cycles as both journaled and replayed
+            let mut status = status.lock().unwrap();
+            journaled.fetch_sub(lines, Ordering::SeqCst);
+            status.replayed += cycles as u64;
+            status.last_flush_at = Some(Utc::now().timestamp());
+            result.map(|()| journal.cycles() == 0)
+        }).await;
+        match result {
+            Ok(true) => info!("Spill journal replayed completely"),
+            Ok(false) => {}
+            Err(e) => error!("Failed to truncate the spill journal: {}", e),
+        }
+        true
+    }
+
+    /// Puts cycles that could not be written at the front of the spill journal,
+    /// so they survive a shutdown. Cycles still in the queue are newer, they
+    /// go along behind them and flush requests among them are returned in
+    /// `done`, so the journal keeps the order the cycles were read in.
+    async fn spill_pending(
+        &self,
+        pending: &mut Vec<(String, Vec<Measurement>)>,
+        receiver: &mut mpsc::Receiver<WriterMessage>,
+        done: &mut Vec<oneshot::Sender<()>>,
+    ) {
+        let Some(journal) = self.journal.clone() else {
+            return;
+        };
+        while let Ok(message) = receiver.try_recv() {
+            match message {
+                WriterMessage::Cycle(meter_key, measurements) => pending.push((meter_key, measurements)),
+                WriterMessage::Flush(flushed) => done.push(flushed),
+            }
+        }
+
+        let cycles = Arc::new(std::mem::take(pending));
+        let count = cycles.len();
+        let spilled = Arc::clone(&cycles);
+        let result = self.on_journal(journal, move |journal| journal.prepend(&spilled).map_err(|e| e.to_string())).await;
+        match result {
+            Ok(()) => {
+                warn!("Moved {} unwritten cycles to the spill journal", count);
+                let mut status = self.status.lock().unwrap();
+                status.queued = status.queued.saturating_sub(count);
+                status.spilled += count as u64;
+                self.journaled.fetch_add(count, Ordering::SeqCst);
+            }
+            Err(e) => {
+                error!("Failed to move {} unwritten cycles to the spill journal: {}", count, e);
+                *pending = Arc::try_unwrap(cycles).unwrap_or_else(|cycles| (*cycles).clone());
+            }
+        }
+    }
+
+    /// Commits `pending` in one transaction. On failure the cycles stay queued.
+    async fn write_pending(&self, pending: &mut Vec<(String, Vec<Measurement>)>) -> bool {
+        if pending.is_empty() {
+            return true;
+        }
+
+        let batch = Arc::new(std::mem::take(pending));
+        let started = Instant::now();
+        let write_batch = Arc::clone(&batch);
+        let result = self.db
+            .run_blocking(move |db| db.insert_measurement_batch(&write_batch))
+            .await;
+        let batch = Arc::try_unwrap(batch).unwrap_or_else(|batch| (*batch).clone());
+        let elapsed = started.elapsed().as_millis() as u64;
+
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                debug!("Wrote batch of {} cycles in {}ms", batch.len(), elapsed);
+                if elapsed > 1000 {
+                    warn!("Writing a batch of {} cycles took {}ms", batch.len(), elapsed);
+                }
+                status.queued = status.queued.saturating_sub(batch.len());
+                status.written += batch.len() as u64;
+                status.batches += 1;
+                status.last_batch_size = batch.len();
+                status.last_flush_ms = Some(elapsed);
+                status.max_flush_ms = status.max_flush_ms.max(elapsed);
+                status.last_flush_at = Some(Utc::now().timestamp());
+                true
+            }
+            Err(e) => {
+                error!("Failed to write batch of {} cycles, keeping them queued: {}", batch.len(), e);
+                status.failed_batches += 1;
+                status.last_error = Some(e.to_string());
+                *pending = batch;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_sync::DatabaseSync;
+    use crate::meters::Channel;
+    use crate::testing::TempDb;
+
+    fn writer(temp: &TempDb, storage: StorageConfig) -> (Arc<DatabaseSync>, BatchWriter) {
+        let db = Arc::new(DatabaseSync::new(temp.url(), true, &storage).unwrap());
+        let writer = BatchWriter::new(db.clone(), &storage).unwrap();
+        tokio::spawn(writer.clone().run());
+        (db, writer)
+    }
+
+    fn cycle(second: i64) -> Vec<Measurement> {
+        let at = chrono::DateTime::from_timestamp(1_700_000_000 + second, 0).unwrap();
+        vec![Measurement::new(Channel::TOTAL_POWER, at, second as f32)]
+    }
+
+    async fn written_after(writer: &BatchWriter, wait: Duration) -> u64 {
+        tokio::time::sleep(wait).await;
+        writer.status().written
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writes_full_batches_in_one_transaction() {
+        let temp = TempDb::new("writer-batch");
+        let storage = StorageConfig { batch_size: 3, batch_interval: 3600, ..StorageConfig::default() };
+        let (db, writer) = writer(&temp, storage);
+
+        for second in 0..2 {
+            writer.submit("Roof", cycle(second)).await.unwrap();
+        }
+        assert_eq!(written_after(&writer, Duration::from_millis(200)).await, 0);
+        assert_eq!(writer.status().queued, 2);
+
+        writer.submit("Roof", cycle(2)).await.unwrap();
+        for _ in 0..50 {
+            if writer.status().written == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let status = writer.status();
+        assert_eq!((status.written, status.batches, status.last_batch_size, status.queued), (3, 1, 3, 0));
+        assert_eq!(db.get_meter_readings("Roof", None, None).unwrap().len(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flushes_partial_batches() {
+        let temp = TempDb::new("writer-flush");
+        let storage = StorageConfig { batch_size: 100, batch_interval: 1, ..StorageConfig::default() };
+        let (db, writer) = writer(&temp, storage);
+
+        // On request
+        writer.submit("Roof", cycle(0)).await.unwrap();
+        writer.flush().await;
+        assert_eq!(writer.status().written, 1);
+        assert_eq!(db.get_meter_readings("Roof", None, None).unwrap().len(), 1);
+
+        // Once the oldest cycle waited for the batch interval
+        writer.submit("Roof", cycle(1)).await.unwrap();
+        assert_eq!(written_after(&writer, Duration::from_millis(300)).await, 1);
+        assert_eq!(written_after(&writer, Duration::from_millis(1200)).await, 2);
+        assert_eq!(writer.status().batches, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_cycles_once_the_queue_is_full() {
+        let temp = TempDb::new("writer-full");
+        let storage = StorageConfig { batch_size: 100, batch_interval: 3600, queue_capacity: 2, ..StorageConfig::default() };
+        let (_db, writer) = writer(&temp, storage);
+
+        writer.submit("Roof", cycle(0)).await.unwrap();
+        writer.submit("Roof", cycle(1)).await.unwrap();
+        assert!(writer.submit("Roof", cycle(2)).await.unwrap_err().to_string().contains("queue full"));
+        let status = writer.status();
+        assert_eq!((status.queued, status.dropped, status.spilled), (2, 1, 0));
+
+        writer.flush().await;
+        assert_eq!(writer.status().written, 2);
+        writer.submit("Roof", cycle(2)).await.unwrap();
+    }
+}
@@ -0,0 +1,1100 @@
+//! Runs the same scenarios against every storage backend.
+//!
+//! The PostgreSQL tests need a server and are ignored by default. Run them
+//! with `cargo test -- --include-ignored` and `SOLARMETER_TEST_POSTGRES_URL`
+//! set, e.g. `host=localhost user=solar password=solar dbname=solar_test`.
+//! Each drops and recreates the solarmeter tables in that database.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use solarmeter::backup;
+use solarmeter::check::{self, CheckOptions, ProblemKind};
+use solarmeter::capture::{CaptureParams, CaptureStatus};
+use solarmeter::config::{AppConfig, MaintenanceConfig, PostgresConfig, PowerEncodingKind, RetentionConfig, StorageConfig};
+use solarmeter::database_sync::DatabaseSync;
+use solarmeter::energy::{self, EnergySource, Period};
+use solarmeter::export::{self, CsvOptions, ExportFormat, ExportRequest};
+use solarmeter::import::{self, ColumnMapping, MeterMap, TableLayout};
+use solarmeter::maintenance::{self, MaintenanceResult, MaintenanceTask};
+use solarmeter::meters::{Channel, Measurement, Phase};
+use solarmeter::data_retention::{RetentionReport, RetentionService};
+use solarmeter::query::{self, BucketQuery, BucketWidth};
+use solarmeter::rollup;
+use solarmeter::storage::postgres::PostgresStorage;
+use solarmeter::spill::SpillJournal;
+use solarmeter::storage::{MeterMetadata, MeterRole, Storage};
+use solarmeter::writer::BatchWriter;
+
+#[path = "../src/testing.rs"]
+mod testing;
+use testing::TempDb;
+
+/// 2025-06-01 12:00 UTC, 14:00 in Berlin.
+fn t0() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap()
+}
+
+fn berlin() -> chrono_tz::Tz {
+    "Europe/Berlin".parse().unwrap()
+}
+
+/// Two cycles of "Roof" with a declared voltage channel it never reported,
+/// and one import power reading of "Garage" at `t0()`.
+fn insert_roof(storage: &dyn Storage) {
+    let channels = [Channel::TOTAL_POWER, Channel::TOTAL_KWH, Channel::parse("voltage_l1").unwrap()];
+    storage.register_channels("Roof", &channels).unwrap();
+
+    let cycle = |offset: i64, power: f32, kwh: f32| {
+        let at = t0() + Duration::seconds(offset);
+        vec![
+            Measurement::new(Channel::TOTAL_POWER, at, power),
+            Measurement::new(Channel::TOTAL_KWH, at, kwh),
+        ]
+    };
+    storage.insert_measurement_batch(&[
+        ("Roof".to_string(), cycle(0, 100.0, 10.0)),
+        ("Roof".to_string(), cycle(60, 300.0, 10.5)),
+        ("Garage".to_string(), vec![Measurement::new(Channel::IMPORT_POWER, t0(), 42.0)]),
+    ]).unwrap();
+}
+
+/// Hours and minutes from 2025-06-01 00:00 UTC, hours may run into the next days.
+fn grid_time(hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap() + Duration::hours(hour as i64) + Duration::minutes(minute as i64)
+}
+
+/// "Grid" with an import counter running across local midnight until the
+/// meter is replaced, and an hour of export power the next day.
+fn insert_grid(storage: &dyn Storage) {
+    let grid = |channel: Channel, samples: &[(u32, u32, f32)]| {
+        samples.iter()
+            .map(|&(hour, minute, value)| ("Grid".to_string(), vec![Measurement::new(channel, grid_time(hour, minute), value)]))
+            .collect::<Vec<_>>()
+    };
+    let import = Channel::parse("energy_import").unwrap();
+    storage.insert_measurement_batch(&grid(import, &[(21, 0, 100.0), (23, 0, 102.0), (25, 0, 0.5), (27, 0, 1.5)])).unwrap();
+    storage.insert_measurement_batch(&grid(Channel::EXPORT_POWER, &[(34, 0, 2000.0), (34, 30, 2000.0), (35, 0, 2000.0)])).unwrap();
+}
+
+fn reads_readings(storage: &dyn Storage) {
+    insert_roof(storage);
+    let t0 = t0();
+
+    let declared = storage.get_meter_channels().unwrap();
+    let names: Vec<_> = declared["Roof"].iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["power", "energy_total", "voltage_l1"]);
+
+    let readings = storage.get_meter_readings("Roof", Some(t0), None).unwrap();
+    assert_eq!(readings.len(), 2);
+    assert_eq!(readings[0].timestamp, t0 + Duration::seconds(60));
+    assert_eq!(readings[0].total_power, Some(300.0));
+    assert_eq!(readings[0].total_kwh, Some(10.5));
+    assert_eq!(readings[0].import_power, None);
+
+    let latest = storage.latest_readings().unwrap();
+    let roof = latest.iter().find(|m| m.meter_name == "Roof").unwrap();
+    assert_eq!(roof.last_reading_timestamp, Some((t0 + Duration::seconds(60)).timestamp()));
+    assert_eq!(roof.last_power_reading, Some(300.0));
+    assert_eq!(roof.total_readings, 2);
+
+    let stats = storage.stats().unwrap();
+    assert_eq!(stats.meters_count, 2);
+    assert_eq!(stats.total_records, 3);
+    assert_eq!(stats.last_write, Some((t0 + Duration::seconds(60)).timestamp()));
+
+    // Reads never create meters
+    assert!(storage.get_meter_readings("Typo", None, None).unwrap().is_empty());
+    let meters = storage.latest_readings().unwrap();
+    assert!(meters.iter().all(|m| m.meter_key != "Typo"));
+
+    // Meters without wide readings or without any readings are listed too
+    let voltage = Channel::parse("voltage_l1").unwrap();
+    storage.insert_measurement_batch(&[("Attic".to_string(), vec![Measurement::new(voltage, t0, 230.0)])]).unwrap();
+    storage.register_meter("Shed", "Shed", &MeterMetadata::default()).unwrap();
+    let summary = |key: &str| storage.latest_readings().unwrap().into_iter().find(|m| m.meter_key == key).unwrap();
+    assert_eq!((summary("Attic").last_reading_timestamp, summary("Attic").total_readings), (Some(t0.timestamp()), 1));
+    assert_eq!((summary("Shed").last_reading_timestamp, summary("Shed").total_readings), (None, 0));
+    let all = export::resolve_meters(storage, &[]).unwrap().unwrap();
+    assert_eq!(all, ["Attic", "Garage", "Roof", "Shed"]);
+}
+
+fn exports_readings(storage: &dyn Storage) {
+    insert_roof(storage);
+    let t0 = t0();
+
+    let mut request = ExportRequest {
+        meters: vec!["Roof".to_string()],
+        channels: Vec::new(),
+        start: t0,
+        end: t0 + Duration::hours(1),
+        resample: None,
+        timezone: berlin(),
+        format: ExportFormat::Csv,
+        csv: CsvOptions { delimiter: ';', decimal_separator: ',' },
+    };
+    let csv = |request: &ExportRequest| {
+        let mut out = Vec::new();
+        export::export(storage, request, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let t = t0.timestamp();
+    assert_eq!(csv(&request), format!(
+        "time;timestamp;meter;power [W];energy_total [kWh];voltage_l1 [V]\r\n\
+         2025-06-01 14:00:00;{};Roof;100;10;\r\n\
+         2025-06-01 14:01:00;{};Roof;300;10,5;\r\n",
+        t, t + 60,
+    ));
+    request.resample = Some(BucketWidth::Seconds(3600));
+    assert!(csv(&request).ends_with(&format!("\r\n2025-06-01 14:00:00;{};Roof;200;10,5;\r\n", t)));
+
+    request.format = ExportFormat::Parquet;
+    let temp = TempDb::new(&format!("export-{}", storage.stats().unwrap().backend));
+    let path = temp.path().with_extension("parquet");
+    assert_eq!(export::export(storage, &request, std::fs::File::create(&path).unwrap()).unwrap(), 1);
+    let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 1);
+    let fields: Vec<_> = reader.metadata().file_metadata().schema().get_fields().iter().map(|f| f.name().to_string()).collect();
+    assert_eq!(fields, ["time", "meter", "power", "energy_total", "voltage_l1"]);
+}
+
+fn buckets_readings(storage: &dyn Storage) {
+    insert_roof(storage);
+    let (t0, berlin) = (t0(), berlin());
+
+    let buckets = |channels: Vec<Channel>, width: BucketWidth| {
+        let query = BucketQuery::new(
+            "Roof".to_string(), channels, t0 - Duration::days(1), t0 + Duration::days(1), width, berlin,
+        ).unwrap();
+        storage.get_buckets(&query).unwrap().unwrap()
+    };
+    // The daily bucket starts at local midnight
+    let daily = buckets(vec![], BucketWidth::parse("1d").unwrap());
+    assert_eq!(daily.len(), 2);
+    assert_eq!(daily[0].channel, Channel::TOTAL_KWH);
+    assert_eq!(daily[1].channel, Channel::TOTAL_POWER);
+    let power = &daily[1].buckets;
+    assert_eq!(power.len(), 1);
+    assert_eq!(power[0].start, Utc.with_ymd_and_hms(2025, 5, 31, 22, 0, 0).unwrap().timestamp());
+    assert_eq!((power[0].count, power[0].avg, power[0].min, power[0].max), (2, 200.0, 100.0, 300.0));
+    assert_eq!((power[0].first, power[0].last), (100.0, 300.0));
+
+    let minutes = buckets(vec![Channel::TOTAL_POWER], BucketWidth::Points(2880));
+    assert_eq!(minutes.len(), 1);
+    assert_eq!(minutes[0].unit, "W");
+    let starts: Vec<_> = minutes[0].buckets.iter().map(|b| b.start).collect();
+    assert_eq!(starts, [t0.timestamp(), t0.timestamp() + 60]);
+    assert!(buckets(vec![Channel::parse("voltage_l1").unwrap()], BucketWidth::Seconds(60)).is_empty());
+
+    let unknown = BucketQuery::new(
+        "Shed".to_string(), vec![], t0, t0 + Duration::hours(1), BucketWidth::Seconds(60), berlin,
+    ).unwrap();
+    assert!(storage.get_buckets(&unknown).unwrap().is_none());
+}
+
+fn captures_samples(storage: &dyn Storage) {
+    insert_roof(storage);
+
+    let session_id = storage.create_capture_session(&CaptureParams {
+        meter: "Roof".to_string(),
+        channels: vec![Channel::TOTAL_POWER],
+        interval_ms: 0,
+        duration_secs: 1,
+    }).unwrap();
+    storage.set_capture_running(session_id).unwrap();
+    storage.insert_capture_samples(session_id, &[Measurement::new(Channel::TOTAL_POWER, t0(), 5.0)]).unwrap();
+    storage.finish_capture_session(session_id, CaptureStatus::Done, None).unwrap();
+
+    let session = storage.get_capture_session(session_id).unwrap().unwrap();
+    assert_eq!(session.status, CaptureStatus::Done);
+    assert_eq!(session.sample_count, 1);
+    let samples = storage.get_capture_samples(session_id).unwrap();
+    assert_eq!(samples[0].values[&Channel::TOTAL_POWER], 5.0);
+    assert!(storage.get_capture_session(session_id + 1).unwrap().is_none());
+}
+
+fn energy_per_period(storage: &dyn Storage) {
+    insert_grid(storage);
+    let (at, berlin) = (grid_time, berlin());
+
+    let bounds = energy::period_bounds(Period::Day, at(12, 0), at(36, 0), berlin).unwrap();
+    assert_eq!(bounds, [
+        (at(0, 0).timestamp() - 7200, at(22, 0).timestamp()),
+        (at(22, 0).timestamp(), at(46, 0).timestamp()),
+    ]);
+    let days = energy::meter_energy(storage, "Grid", Period::Day, &bounds).unwrap().unwrap();
+    assert_eq!(days.counter_resets, 1);
+    let totals: Vec<_> = days.periods.iter().map(|p| (p.imported_kwh, p.exported_kwh)).collect();
+    assert_eq!(totals, [(1.0, 0.0), (2.0, 2.0)]);
+    assert_eq!(days.periods[1].import_source, EnergySource::Counter);
+    assert_eq!(days.periods[1].export_source, EnergySource::Power);
+    assert_eq!(days.periods[0].export_source, EnergySource::None);
+
+    let bounds = energy::period_bounds(Period::Month, at(12, 0), at(36, 0), berlin).unwrap();
+    let month = energy::meter_energy(storage, "Grid", Period::Month, &bounds).unwrap().unwrap();
+    assert_eq!(month.periods.len(), 1);
+    assert_eq!(month.periods[0].imported_kwh, 3.0);
+    assert!(energy::meter_energy(storage, "Shed", Period::Month, &bounds).unwrap().is_none());
+}
+
+fn manages_meters(storage: &dyn Storage) {
+    insert_roof(storage);
+    insert_grid(storage);
+
+    // "Roof" was written before it had a config id and is adopted under it
+    storage.register_meter("SDM72D_1", "Roof", &MeterMetadata::default()).unwrap();
+    assert_eq!(storage.get_meter_readings("SDM72D_1", None, None).unwrap().len(), 2);
+    let metadata = MeterMetadata {
+        meter_type: Some("sdm72d".to_string()),
+        unit: Some("kWh".to_string()),
+        location: Some("Roof, east".to_string()),
+        role: Some(MeterRole::Producer),
+        phase: Some(Phase::All),
+        installed_on: NaiveDate::from_ymd_opt(2024, 3, 1),
+        removed_on: None,
+    };
+    storage.register_meter("SDM72D_1", "Dach", &metadata).unwrap();
+    let readings = storage.get_meter_readings("SDM72D_1", None, None).unwrap();
+    assert_eq!(readings[0].meter_name, "Dach");
+    assert!(storage.get_meter_channels().unwrap().contains_key("SDM72D_1"));
+
+    assert!(storage.rename_meter("SDM72D_1", "Grid").is_err());
+    assert!(storage.rename_meter("Garage", "garage").unwrap());
+    assert!(!storage.rename_meter("Garage", "garage2").unwrap());
+    assert_eq!(storage.merge_meters("garage", "SDM72D_1").unwrap(), Some(1));
+    assert_eq!(storage.merge_meters("garage", "SDM72D_1").unwrap(), None);
+    assert!(storage.merge_meters("SDM72D_1", "SDM72D_1").is_err());
+
+    assert!(storage.retire_meter("Grid").unwrap());
+    assert!(!storage.retire_meter("garage").unwrap());
+    let summary = |key: &str| storage.latest_readings().unwrap().into_iter().find(|m| m.meter_key == key).unwrap();
+    assert!(summary("Grid").retired_at.is_some());
+    assert_eq!(summary("SDM72D_1").meter_name, "Dach");
+    // Garage's reading shares Roof's timestamp and fills its empty column
+    assert_eq!(summary("SDM72D_1").total_readings, 2);
+    assert_eq!(storage.get_meter_readings("SDM72D_1", None, None).unwrap()[1].import_power, Some(42.0));
+    assert_eq!(summary("SDM72D_1").metadata, metadata);
+    storage.register_meter("Grid", "Grid", &MeterMetadata::default()).unwrap();
+    assert!(summary("Grid").retired_at.is_none());
+
+    // Meter ids are not limited to a byte
+    let many: Vec<_> = (0..300)
+        .map(|i| (format!("bulk_{}", i), vec![Measurement::new(Channel::TOTAL_POWER, t0(), i as f32)]))
+        .collect();
+    storage.insert_measurement_batch(&many).unwrap();
+    assert_eq!(summary("bulk_299").last_power_reading, Some(299.0));
+    assert_eq!(storage.stats().unwrap().meters_count, 302);
+}
+
+/// Imports skip timestamps that already have readings instead of overwriting them.
+fn imports_csv(storage: &dyn Storage) {
+    let layout = TableLayout {
+        time_column: None,
+        time_format: None,
+        timezone: berlin(),
+        meter_column: None,
+        meter: Some("Shed".to_string()),
+        columns: vec![ColumnMapping::parse("Leistung=power:kW").unwrap()],
+        decimal_separator: ',',
+    };
+    let import = |file: &str| import::import_csv(storage, file.as_bytes(), ';', &layout, &MeterMap::new()).unwrap();
+    let report = import("time;Leistung\n2025-06-01 14:00:00;0,5\n2025-06-01 14:01:00;x\n");
+    assert_eq!((report.meters["Shed"].inserted, report.skipped), (1, 1));
+    let report = import("time;Leistung\n2025-06-01 14:00:00;9\n2025-06-01 14:02:00;1\n");
+    assert_eq!((report.meters["Shed"].inserted, report.meters["Shed"].duplicates), (1, 1));
+    let readings = storage.get_meter_readings("Shed", None, None).unwrap();
+    let power: Vec<_> = readings.iter().map(|r| (r.timestamp, r.total_power)).collect();
+    assert_eq!(power, [(t0() + Duration::minutes(2), Some(1000.0)), (t0(), Some(500.0))]);
+}
+
+/// Rollups keep the raw readings, queries read them once raw readings expired.
+fn reads_rollups(storage: &dyn Storage) {
+    insert_roof(storage);
+    let (t0, berlin) = (t0(), berlin());
+
+    let voltage = Channel::parse("voltage_l1").unwrap();
+    let attic: Vec<_> = (0..180)
+        .map(|i| {
+            let at = t0 + Duration::minutes(i);
+            ("Attic".to_string(), vec![Measurement::new(Channel::TOTAL_POWER, at, i as f32), Measurement::new(voltage, at, 230.0)])
+        })
+        .collect();
+    storage.insert_measurement_batch(&attic).unwrap();
+    let attic_buckets = |width: i64| {
+        let query = BucketQuery::new(
+            "Attic".to_string(), vec![], t0, t0 + Duration::hours(3), BucketWidth::Seconds(width), berlin,
+        ).unwrap();
+        storage.get_buckets(&query).unwrap().unwrap()
+    };
+    let (hourly, ten_minutes) = (attic_buckets(3600), attic_buckets(600));
+
+    let until = t0 + Duration::minutes(150);
+    let tiers = rollup::Policy::default().tiers;
+    let every_meter = |f: &dyn Fn(&str) -> u64| -> u64 { storage.meter_keys().unwrap().iter().map(|key| f(key)).sum() };
+    assert!(every_meter(&|key| storage.rollup(key, &tiers[0], None, until, false).unwrap().written) > 0);
+    assert_eq!(every_meter(&|key| storage.rollup(key, &tiers[0], None, until, false).unwrap().written), 0);
+    assert!(every_meter(&|key| storage.rollup(key, &tiers[1], Some(600), until, false).unwrap().written) > 0);
+    assert_eq!(storage.get_meter_readings("Attic", None, None).unwrap().len(), 180);
+    assert_eq!(attic_buckets(3600), hourly);
+
+    // Up to 14:00, Roof and Garage keep the bucket of their newest reading
+    assert_eq!(every_meter(&|key| storage.expire_raw(key, t0 + Duration::hours(2), 600, None, false).unwrap()), 240);
+    assert_eq!(storage.get_meter_readings("Attic", None, None).unwrap().len(), 60);
+    assert_eq!(storage.get_meter_readings("Roof", None, None).unwrap().len(), 2);
+    assert_eq!((attic_buckets(3600), attic_buckets(600)), (hourly, ten_minutes));
+
+    // Minute buckets fall back to one per 10 minute rollup before 14:00
+    let minutes = attic_buckets(60);
+    assert_eq!(minutes[0].buckets.len(), 12 + 60);
+    assert_eq!((minutes[0].buckets[0].count, minutes[0].buckets[12].count), (10, 1));
+    let samples = storage.get_channel_samples("Attic", Channel::TOTAL_POWER, t0, t0 + Duration::hours(3)).unwrap().unwrap();
+    assert_eq!(samples.len(), 12 + 60);
+    assert_eq!(samples[0], (t0.timestamp() + 300, 4.5));
+    assert_eq!(samples[12], ((t0 + Duration::hours(2)).timestamp(), 120.0));
+}
+
+/// Bucket energy gives the same daily totals once raw readings are gone.
+fn energy_from_rollups(storage: &dyn Storage) {
+    insert_grid(storage);
+    let at = grid_time;
+
+    let tiers = rollup::Policy::default().tiers;
+    let bounds = energy::period_bounds(Period::Day, at(12, 0), at(36, 0), berlin()).unwrap();
+    let raw = energy::meter_energy(storage, "Grid", Period::Day, &bounds).unwrap().unwrap();
+    let dry = storage.rollup("Grid", &tiers[0], None, at(60, 0), true).unwrap();
+    assert_eq!(storage.rollup("Grid", &tiers[0], None, at(60, 0), false).unwrap(), dry);
+    assert_eq!(dry.read, 7);
+    assert!(storage.rollup("Grid", &tiers[1], Some(600), at(60, 0), false).unwrap().written > 0);
+    assert_eq!(storage.expire_raw("Grid", at(60, 0), 600, None, true).unwrap(), 6);
+    assert_eq!(storage.expire_raw("Grid", at(60, 0), 600, None, false).unwrap(), 6);
+    let rolled = energy::meter_energy(storage, "Grid", Period::Day, &bounds).unwrap().unwrap();
+    assert_eq!(rolled.periods.len(), raw.periods.len());
+    for (rolled, raw) in rolled.periods.iter().zip(&raw.periods) {
+        assert!((rolled.imported_kwh - raw.imported_kwh).abs() < 1e-9, "{:?} {:?}", rolled, raw);
+        assert!((rolled.exported_kwh - raw.exported_kwh).abs() < 1e-9, "{:?} {:?}", rolled, raw);
+        assert_eq!((rolled.import_source, rolled.export_source), (raw.import_source, raw.export_source));
+    }
+}
+
+/// Runs are reported per meter and tier and stored.
+fn reports_retention_runs(storage: &dyn Storage) {
+    insert_roof(storage);
+    insert_grid(storage);
+
+    let report = RetentionService::process_retention(storage, &RetentionConfig::default(), grid_time(60, 0), false).unwrap();
+    let grid: Vec<_> = report.steps.iter().filter(|s| s.meter_key == "Grid").map(|s| s.width_secs).collect();
+    assert_eq!(grid, [Some(600), Some(3600), Some(86400), None]);
+    assert_eq!(storage.get_retention_reports(5).unwrap(), [report]);
+}
+
+/// Every maintenance task succeeds on a healthy database, its latest result is kept.
+fn runs_maintenance(storage: &dyn Storage) {
+    insert_roof(storage);
+
+    for task in MaintenanceTask::ALL {
+        storage.maintain(task).unwrap_or_else(|e| panic!("{}: {}", task.as_str(), e));
+    }
+    let result = |ok| MaintenanceResult { task: MaintenanceTask::Vacuum, finished_at: 100, duration_ms: 5, ok, detail: "done".to_string() };
+    storage.record_maintenance(&result(false)).unwrap();
+    storage.record_maintenance(&result(true)).unwrap();
+    assert_eq!(storage.maintenance_results().unwrap(), [result(true)]);
+}
+
+/// Buckets both meters have rollups for are combined, not overwritten.
+fn merges_overlapping_buckets(storage: &dyn Storage) {
+    // "Pump old" ran until 00:35 and "Pump" from 00:25, each with a reading at 05:00
+    let t0 = Utc.with_ymd_and_hms(2025, 4, 1, 0, 0, 0).unwrap();
+    let cycle = |meter: &str, minute: i64, power: f32, kwh: f32| {
+        let at = t0 + Duration::minutes(minute);
+        (meter.to_string(), vec![Measurement::new(Channel::TOTAL_POWER, at, power), Measurement::new(Channel::TOTAL_KWH, at, kwh)])
+    };
+    let mut readings: Vec<_> = (0..35).map(|m| cycle("Pump old", m, m as f32, m as f32 / 60.0)).collect();
+    readings.extend((25..60).map(|m| cycle("Pump", m, 1000.0 + m as f32, 10.0 + m as f32 / 60.0)));
+    readings.push(cycle("Pump old", 300, 0.0, 34.0 / 60.0));
+    readings.push(cycle("Pump", 300, 0.0, 10.0 + 59.0 / 60.0));
+    storage.insert_measurement_batch(&readings).unwrap();
+
+    let now = t0 + Duration::days(40);
+    let tiers = RetentionConfig::default().policy("Pump").unwrap().tiers;
+    for meter in ["Pump old", "Pump"] {
+        let mut source = None;
+        for tier in &tiers {
+            storage.rollup(meter, tier, source, now, false).unwrap();
+            source = Some(tier.width_secs);
+        }
+        assert!(storage.expire_raw(meter, now, tiers[0].width_secs, None, false).unwrap() > 0);
+    }
+    let hour = |meter: &str| {
+        let query = BucketQuery::new(
+            meter.to_string(), vec![Channel::TOTAL_POWER], t0, t0 + Duration::hours(1), BucketWidth::Seconds(3600), chrono_tz::UTC,
+        ).unwrap();
+        storage.get_buckets(&query).unwrap().unwrap()[0].buckets[0].clone()
+    };
+    let energy = |meter: &str| -> f64 {
+        let (start, end) = (t0.timestamp(), t0.timestamp() + 3600);
+        let rolled = storage.get_rolled_energy(meter, start, end, &[start, end]).unwrap().unwrap();
+        assert!(rolled.uncovered.is_empty());
+        rolled.buckets.iter().map(|b| b.imported_kwh).sum()
+    };
+    let (old_energy, new_energy) = (energy("Pump old"), energy("Pump"));
+    assert!(old_energy > 0.0 && new_energy > 0.0);
+
+    storage.merge_meters("Pump old", "Pump").unwrap().unwrap();
+    let merged = hour("Pump");
+    assert_eq!((merged.count, merged.min, merged.max), (70, 0.0, 1059.0));
+    assert!((merged.avg - (595.0 + 35.0 * 1042.0) / 70.0).abs() < 1e-9, "{:?}", merged);
+    assert_eq!((merged.first, merged.last), (0.0, 1059.0));
+    assert!((energy("Pump") - (old_energy + new_energy)).abs() < 1e-9);
+}
+
+/// Another solarmeter database imports completely, and a second time not at all.
+#[test]
+fn imports_solarmeter_databases() {
+    let temp = TempDb::new("storage");
+    let storage = DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap();
+    insert_roof(&storage);
+    storage.register_meter("SDM72D_1", "Roof", &MeterMetadata::default()).unwrap();
+    imports_csv(&storage);
+
+    let copy_temp = TempDb::new("import");
+    let copy = DatabaseSync::new(copy_temp.url(), true, &StorageConfig::default()).unwrap();
+    let layout = TableLayout {
+        time_column: None,
+        time_format: None,
+        timezone: chrono_tz::UTC,
+        meter_column: None,
+        meter: None,
+        columns: Vec::new(),
+        decimal_separator: '.',
+    };
+    let meter_map = MeterMap::from([("Shed".to_string(), "Schuppen".to_string())]);
+    // A rollup from before the oldest reading, as retention leaves it behind
+    storage.get_connection().unwrap().execute_batch(
+        "INSERT INTO rollups (meter_id, width_secs, bucket_start, channel, count, avg, min, max, first, last)
+         SELECT meter_id, 3600, 946684800, 'power', 1, 1.0, 1.0, 1.0, 1.0, 1.0 FROM meter_names WHERE meter_key = 'SDM72D_1'",
+    ).unwrap();
+    let report = import::import_sqlite(&copy, temp.path(), None, &layout, &meter_map).unwrap();
+    assert_eq!(report.skipped, 0);
+    assert_eq!(report.meters["Schuppen"].inserted, 2);
+    // Up to the oldest reading, which is imported
+    let oldest = storage.get_meter_readings("SDM72D_1", None, None).unwrap().last().unwrap().timestamp.timestamp();
+    let (start, end) = report.meters["SDM72D_1"].rollup_only.unwrap();
+    assert!(start == 946684800 && end > start && end <= oldest, "{:?}", (start, end));
+    assert_eq!(report.meters["Schuppen"].rollup_only, None);
+    assert!(report.to_string().contains("SDM72D_1: 2000-01-01 00:00:00 to "), "{}", report);
+    assert_eq!(copy.stats().unwrap().total_records, storage.stats().unwrap().total_records);
+    let values = |db: &DatabaseSync| -> Vec<_> {
+        db.get_meter_readings("SDM72D_1", None, None).unwrap().into_iter()
+            .map(|r| (r.timestamp, r.total_power, r.import_power, r.export_power, r.total_kwh))
+            .collect()
+    };
+    assert_eq!(values(&copy), values(&storage));
+    let report = import::import_sqlite(&copy, temp.path(), None, &layout, &meter_map).unwrap();
+    assert!(report.meters.values().all(|m| m.inserted == 0));
+}
+
+/// Backups rotate, and a restore replaces another database with the original's data.
+#[test]
+fn restores_backups() {
+    let temp = TempDb::new("backup");
+    let storage = DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap();
+    insert_roof(&storage);
+    let copy_temp = TempDb::new("restore");
+    let copy = DatabaseSync::new(copy_temp.url(), true, &StorageConfig::default()).unwrap();
+    imports_csv(&copy);
+    let values = |db: &DatabaseSync| -> Vec<_> {
+        db.get_meter_readings("Roof", None, None).unwrap().into_iter()
+            .map(|r| (r.timestamp, r.total_power, r.import_power, r.export_power, r.total_kwh))
+            .collect()
+    };
+
+    let backup_dir = temp.path().with_extension("backups");
+    std::fs::create_dir_all(&backup_dir).unwrap();
+    let stale = backup_dir.join("solarmeter-20000101-000000.db");
+    std::fs::write(&stale, "not a database").unwrap();
+    let backup_path = backup::backup_now(&storage, &backup_dir, 1).unwrap();
+    assert!(!stale.exists());
+    assert_eq!(std::fs::read_dir(&backup_dir).unwrap().count(), 1);
+    backup::check_integrity(&backup_path).unwrap();
+
+    std::fs::write(&stale, "not a database").unwrap();
+    assert!(backup::restore(&stale, copy_temp.url()).is_err());
+    drop(copy);
+    let replaced = backup::restore(&backup_path, copy_temp.url()).unwrap().unwrap();
+    assert!(replaced.exists());
+    let copy = DatabaseSync::new(copy_temp.url(), false, &StorageConfig::default()).unwrap();
+    assert_eq!(values(&copy), values(&storage));
+    assert!(copy.get_meter_readings("Shed", None, None).unwrap().is_empty());
+}
+
+#[test]
+fn check_and_repair() {
+    let temp = TempDb::new("check");
+    let config = StorageConfig { power_encoding: PowerEncodingKind::F16, ..StorageConfig::default() };
+    let db = DatabaseSync::new(temp.url(), true, &config).unwrap();
+
+    // Hourly readings with a gap from 7h to 14h
+    let t0 = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let mut batch = Vec::new();
+    for hour in (0..8).chain(14..16) {
+        let at = t0 + Duration::hours(hour);
+        let kwh = match hour {
+            3 => 5000.0,      // glitch
+            14.. => 1.0 + (hour - 14) as f32,  // meter replaced
+            _ => 100.0 + hour as f32,
+        };
+        let power = if hour == 6 { 70000.0 } else { 500.0 };  // beyond f16
+        let mut cycle = vec![
+            Measurement::new(Channel::TOTAL_POWER, at, power),
+            Measurement::new(Channel::TOTAL_KWH, at, kwh),
+        ];
+        if hour == 7 {
+            cycle.push(Measurement::new(Channel::IMPORT_POWER, at, -5.0));
+        }
+        batch.push(("Roof".to_string(), cycle));
+    }
+    batch.push(("Roof".to_string(), vec![Measurement::new(Channel::TOTAL_POWER, Utc.with_ymd_and_hms(1990, 1, 1, 0, 0, 0).unwrap(), 1.0)]));
+    db.insert_measurement_batch(&batch).unwrap();
+    // Orphans are left over from databases written before foreign keys were enforced
+    db.get_connection().unwrap().execute_batch(
+        "PRAGMA foreign_keys = OFF;
+         INSERT INTO meter_readings (meter_id, timestamp, total_kwh) VALUES (999, 1704067200, 1.0);
+         INSERT INTO channel_readings (meter_id, channel, timestamp, value) VALUES (999, 'voltage_l1', 1704067200, 230.0);
+         PRAGMA foreign_keys = ON;",
+    ).unwrap();
+
+    let options = CheckOptions { default_interval: 3600, ..CheckOptions::default() };
+    let report = check::check(&db, &options).unwrap();
+    let counts = |report: &check::CheckReport| {
+        [
+            ProblemKind::Gap,
+            ProblemKind::CounterReset,
+            ProblemKind::CounterGlitch,
+            ProblemKind::NonFinite,
+            ProblemKind::OutOfRange,
+            ProblemKind::BadTimestamp,
+        ].map(|kind| report.count(kind))
+    };
+    assert_eq!(counts(&report), [1, 1, 1, 1, 1, 1]);
+    assert_eq!(report.orphans.len(), 1);
+    assert_eq!((report.orphans[0].readings, report.orphans[0].channel_readings), (1, 1));
+    assert_eq!(report.repairable(), 5);
+
+    assert_eq!(check::repair(&db, &report).unwrap(), 5);
+    let options = CheckOptions { default_interval: 3600, ..CheckOptions::default() };
+    let report = check::check(&db, &options).unwrap();
+    assert_eq!(counts(&report), [1, 1, 0, 0, 0, 0]);
+    assert!(report.orphans.is_empty());
+
+    let readings = db.get_meter_readings("Roof", None, None).unwrap();
+    assert_eq!(readings.len(), 10);
+    let at = |hour: i64| readings.iter().find(|r| r.timestamp == t0 + Duration::hours(hour)).unwrap();
+    assert_eq!((at(3).total_kwh, at(3).total_power), (None, Some(500.0)));
+    assert_eq!((at(6).total_kwh, at(6).total_power), (Some(106.0), None));
+    let conn = db.read_connection().unwrap();
+    let quarantined: i64 = conn.query_row("SELECT COUNT(*) FROM quarantined_readings", [], |row| row.get(0)).unwrap();
+    assert_eq!(quarantined, 5);
+    let quarantined: i64 = conn.query_row("SELECT COUNT(*) FROM quarantined_channel_readings", [], |row| row.get(0)).unwrap();
+    assert_eq!(quarantined, 1);
+}
+
+#[test]
+fn retention_is_incremental() {
+    let open = |name: &str| {
+        let temp = TempDb::new(&format!("retention-{}", name));
+        (DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap(), temp)
+    };
+    type Rollup = (i64, i64, String, i64, f64, f64, f64, f64, f64);
+    let rollups = |db: &DatabaseSync| -> Vec<Rollup> {
+        let conn = db.get_connection().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT width_secs, bucket_start, channel, count, avg, min, max, first, last FROM rollups ORDER BY 1, 2, 3",
+        ).unwrap();
+        let rows = statement.query_map([], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?, r.get(7)?, r.get(8)?))
+        }).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    };
+    let watermarks = |db: &DatabaseSync| -> Vec<(i64, i64)> {
+        let conn = db.get_connection().unwrap();
+        let mut statement = conn.prepare("SELECT width_secs, rolled_until FROM rollup_watermarks ORDER BY 1").unwrap();
+        let rows = statement.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    };
+
+    // Two days of minute readings, none from 10:00 to 16:00 on the first day
+    let t0 = Utc.with_ymd_and_hms(2025, 3, 1, 0, 0, 0).unwrap();
+    let readings: Vec<_> = (0..2 * 1440)
+        .filter(|minute| !(600..960).contains(minute))
+        .map(|minute| {
+            let at = t0 + Duration::minutes(minute);
+            ("Roof".to_string(), vec![
+                Measurement::new(Channel::TOTAL_POWER, at, (minute % 97) as f32),
+                Measurement::new(Channel::TOTAL_KWH, at, minute as f32 / 60.0),
+            ])
+        })
+        .collect();
+
+    // The clock moves on 7 hours per pass, readings arrive up to it
+    let config = RetentionConfig::default();
+    let policy = config.policy("Roof").unwrap();
+    let (incremental, _incremental_temp) = open("incremental");
+    let mut previous = Vec::new();
+    for hours in (7..=56).step_by(7) {
+        let now = t0 + Duration::hours(hours);
+        let arrived: Vec<_> = readings.iter()
+            .filter(|(_, cycle)| cycle[0].timestamp < now && cycle[0].timestamp >= now - Duration::hours(7))
+            .cloned()
+            .collect();
+        incremental.insert_measurement_batch(&arrived).unwrap();
+
+        RetentionService::process_retention(&incremental, &config, now, false).unwrap();
+        let state = (rollups(&incremental), watermarks(&incremental));
+        RetentionService::process_retention(&incremental, &config, now, false).unwrap();
+        assert_eq!((rollups(&incremental), watermarks(&incremental)), state);
+        assert_eq!(incremental.rollup("Roof", &policy.tiers[0], None, now - rollup::SETTLE, false).unwrap().written, 0);
+
+        // Buckets of earlier passes are never touched again
+        assert!(previous.iter().all(|row| state.0.contains(row)));
+        previous = state.0;
+    }
+    // The counter's last reading holds the tiers back until it has been quiet for a day
+    assert_eq!(watermarks(&incremental), [
+        (600, (t0 + Duration::minutes(47 * 60 + 50)).timestamp()),
+        (3600, (t0 + Duration::hours(47)).timestamp()),
+        (86400, (t0 + Duration::hours(24)).timestamp()),
+    ]);
+
+    // Every run left a report, the newest first
+    let reports = incremental.get_retention_reports(10).unwrap();
+    assert_eq!((reports.len(), reports[0].run_id), (10, Some(16)));
+    assert_eq!(reports[0].rows_written() + reports[0].rows_deleted(), 0);
+    assert!(reports.iter().all(|r| r.steps.len() == 4 && r.errors.is_empty()));
+
+    // Catching up in a single pass gives the same rollups. A dry run before
+    // it changes nothing and finds what the 10 minute tier gets
+    let (once, _once_temp) = open("once");
+    once.insert_measurement_batch(&readings).unwrap();
+    let dry = RetentionService::process_retention(&once, &config, t0 + Duration::hours(56), true).unwrap();
+    assert!(rollups(&once).is_empty() && once.get_retention_reports(10).unwrap().is_empty());
+    let report = RetentionService::process_retention(&once, &config, t0 + Duration::hours(56), false).unwrap();
+    assert_eq!((dry.run_id, report.run_id), (None, Some(1)));
+    assert_eq!(dry.steps[0], report.steps[0]);
+    let rolled = readings.iter().filter(|(_, cycle)| cycle[0].timestamp < t0 + Duration::minutes(47 * 60 + 50)).count();
+    assert_eq!(report.steps[0].rows_read, rolled as u64);
+    assert_eq!(once.get_retention_reports(10).unwrap(), [report]);
+    assert_eq!(rollups(&once), previous);
+    assert_eq!(watermarks(&once), watermarks(&incremental));
+
+    // A dry run over readings not rolled up yet counts the raw readings its
+    // rehearsed rollups would let expire, and says it left out the size limit
+    let (expiring, _expiring_temp) = open("expiring");
+    expiring.insert_measurement_batch(&readings).unwrap();
+    let strict = RetentionConfig { raw_days: 1, max_database_mb: 1000, ..RetentionConfig::default() };
+    let dry = RetentionService::process_retention(&expiring, &strict, t0 + Duration::hours(56), true).unwrap();
+    let report = RetentionService::process_retention(&expiring, &strict, t0 + Duration::hours(56), false).unwrap();
+    let raw = |report: &RetentionReport| report.steps.iter().find(|s| s.width_secs.is_none()).unwrap().rows_deleted;
+    assert!(raw(&report) > 0);
+    assert_eq!(raw(&dry), raw(&report));
+    assert_eq!(dry.steps[0], report.steps[0]);
+    assert!(dry.size_limit_skipped && !report.size_limit_skipped);
+}
+
+#[test]
+fn maintenance_shrinks_database() {
+    let temp = TempDb::new("maintenance");
+    let open = || DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap();
+    let auto_vacuum = |db: &DatabaseSync| -> i64 {
+        db.get_connection().unwrap().query_row("PRAGMA auto_vacuum", [], |r| r.get(0)).unwrap()
+    };
+    let disable = |db: &DatabaseSync| db.get_connection().unwrap().execute_batch("PRAGMA auto_vacuum = NONE; VACUUM;").unwrap();
+
+    // New databases are incremental, older ones are only switched by the vacuum task
+    let storage = open();
+    assert_eq!(auto_vacuum(&storage), 2);
+    disable(&storage);
+    drop(storage);
+    let storage = open();
+    assert_eq!(auto_vacuum(&storage), 0);
+    assert!(storage.maintain(MaintenanceTask::Vacuum).unwrap().starts_with("switched"));
+    assert_eq!(auto_vacuum(&storage), 2);
+
+    // A week of minute readings, then everything but the last day deleted
+    let t0 = Utc.with_ymd_and_hms(2025, 3, 1, 0, 0, 0).unwrap();
+    let readings: Vec<_> = (0..7 * 1440)
+        .map(|minute| {
+            let at = t0 + Duration::minutes(minute);
+            ("Roof".to_string(), vec![
+                Measurement::new(Channel::TOTAL_POWER, at, minute as f32),
+                Measurement::new(Channel::TOTAL_KWH, at, minute as f32 / 60.0),
+            ])
+        })
+        .collect();
+    storage.insert_measurement_batch(&readings).unwrap();
+    storage.delete_before("Roof", t0 + Duration::days(6), false).unwrap();
+    let file_size = || std::fs::metadata(temp.path()).unwrap().len() + std::fs::metadata(format!("{}-wal", temp.url())).map_or(0, |m| m.len());
+    let before = file_size();
+
+    // Every task is due on the first run, then only when its interval is over
+    let config = MaintenanceConfig::default();
+    let now = Utc::now();
+    let results = maintenance::run_due(&storage, &config, now).unwrap();
+    assert_eq!(results.iter().map(|r| r.task).collect::<Vec<_>>(), MaintenanceTask::ALL);
+    assert!(results.iter().all(|r| r.ok), "{:?}", results);
+    assert!(file_size() < before / 2, "{} bytes before, {} after", before, file_size());
+    assert!(maintenance::run_due(&storage, &config, now).unwrap().is_empty());
+    let later = maintenance::run_due(&storage, &config, now + Duration::hours(7)).unwrap();
+    assert_eq!(later.iter().map(|r| r.task).collect::<Vec<_>>(), [MaintenanceTask::Checkpoint]);
+
+    let statuses = maintenance::statuses(&config, &storage.maintenance_results().unwrap(), now);
+    let vacuum = &statuses[0];
+    assert_eq!(vacuum.next_run, Some(vacuum.last.as_ref().unwrap().finished_at + 24 * 3600));
+}
+
+/// Imports and merges behind the watermarks, then lets raw readings expire.
+fn rolls_up_history(storage: &dyn Storage) {
+    // A day of minute readings, 1 kW and a counter rising 1 kWh an hour
+    let t0 = Utc.with_ymd_and_hms(2025, 3, 1, 0, 0, 0).unwrap();
+    let day = |meter: &str, day: i64| -> Vec<(String, Vec<Measurement>)> {
+        (day * 1440..(day + 1) * 1440).map(|minute| {
+            let at = t0 + Duration::minutes(minute);
+            (meter.to_string(), vec![
+                Measurement::new(Channel::TOTAL_POWER, at, 1000.0),
+                Measurement::new(Channel::TOTAL_KWH, at, minute as f32 / 60.0),
+            ])
+        }).collect()
+    };
+    let config = RetentionConfig::default();
+    storage.insert_measurement_batch(&day("Carport", 2)).unwrap();
+    RetentionService::process_retention(storage, &config, t0 + Duration::days(4), false).unwrap();
+
+    // Day 1 is imported and day 0 merged in from another meter, both behind the watermarks
+    let imported: Vec<Measurement> = day("Carport", 1).into_iter().flat_map(|(_, cycle)| cycle).collect();
+    assert_eq!(storage.import_measurements("Carport", &imported).unwrap().inserted, 1440);
+    storage.insert_measurement_batch(&day("Old carport", 0)).unwrap();
+    storage.merge_meters("Old carport", "Carport").unwrap().unwrap();
+    let bounds = energy::period_bounds(Period::Day, t0, t0 + Duration::days(2), chrono_tz::UTC).unwrap();
+    let raw = energy::meter_energy(storage, "Carport", Period::Day, &bounds).unwrap().unwrap();
+
+    // Once raw readings expired, the rollups still hold every day
+    let report = RetentionService::process_retention(storage, &config, t0 + Duration::days(40), false).unwrap();
+    assert!(report.rows_deleted() > 0);
+    assert!(storage.get_meter_readings("Carport", None, Some(t0 + Duration::days(2))).unwrap().is_empty());
+    let samples = storage.get_channel_samples("Carport", Channel::TOTAL_POWER, t0, t0 + Duration::days(2)).unwrap().unwrap();
+    assert_eq!(samples.len(), 2 * 144);
+    let rolled = energy::meter_energy(storage, "Carport", Period::Day, &bounds).unwrap().unwrap();
+    for (rolled, raw) in rolled.periods.iter().zip(&raw.periods) {
+        assert!((rolled.imported_kwh - raw.imported_kwh).abs() < 1e-6, "{:?} {:?}", rolled, raw);
+        assert!(rolled.imported_kwh > 23.0, "{:?}", rolled);
+    }
+
+    // Still listed and exportable with nothing but the last buckets' rollups left
+    storage.delete_before("Carport", t0 + Duration::days(3) - Duration::seconds(59), false).unwrap();
+    assert!(storage.get_meter_readings("Carport", None, None).unwrap().is_empty());
+    let carport = storage.latest_readings().unwrap().into_iter().find(|m| m.meter_key == "Carport").unwrap();
+    assert_eq!(carport.last_reading_timestamp, Some((t0 + Duration::days(3) - Duration::minutes(10)).timestamp()));
+    assert_eq!((carport.last_power_reading, carport.total_readings), (Some(1000.0), 0));
+    assert_eq!(export::resolve_meters(storage, &[]).unwrap(), Ok(vec!["Carport".to_string()]));
+}
+
+#[test]
+fn retention_policy_from_config() {
+    let temp = TempDb::new("policy");
+    let config_path = temp.path().with_extension("toml");
+    let load = |retention: &str| {
+        std::fs::write(&config_path, format!(
+            "[global]\ndatabase_url = \"solar.db\"\nbind_address = \"127.0.0.1\"\n\n{}\n\n[meters]\n",
+            retention
+        )).unwrap();
+        AppConfig::from_file(&config_path)
+    };
+
+    // Tiers have to build on each other and outlive what they replace
+    for invalid in [
+        "[retention]\ntiers = [{ bucket = \"1h\" }, { bucket = \"10m\" }]",
+        "[retention]\ntiers = [{ bucket = \"10m\" }, { bucket = \"25m\" }]",
+        "[retention]\ntiers = [{ bucket = \"7m\" }]",
+        "[retention]\ntiers = [{ bucket = \"10m\", aggregates = [\"max\"] }, { bucket = \"1h\", aggregates = [\"min\"] }]",
+        "[retention]\nraw_days = 30\ntiers = [{ bucket = \"10m\", keep_days = 7 }]",
+        "[retention]\ntiers = [{ bucket = \"10m\", keep_days = 90 }, { bucket = \"1h\", keep_days = 60 }]",
+        "[retention]\ntiers = []",
+        "[retention]\nraw_days = 30\nmax_age_days = 10",
+        "[retention]\ntiers = [{ bucket = \"10m\", aggregates = [\"median\"] }]",
+        "[retention]\ninterval_minutes = 0",
+        "[retention.meters.Shed]\ntiers = [{ bucket = \"soon\" }]",
+    ] {
+        assert!(load(invalid).is_err(), "{}", invalid);
+    }
+    let config = load(
+        "[retention]\nraw_days = 0\nmax_database_mb = 1\n\n\
+         [retention.meters.Shed]\nraw_days = 1\nmax_age_days = 3\ntiers = [{ bucket = \"1h\", aggregates = [\"max\"] }]",
+    ).unwrap().retention;
+    assert_eq!(config.policy("Roof").unwrap(), rollup::Policy { raw: None, ..rollup::Policy::default() });
+
+    let db = DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap();
+
+    // Five days of readings every 10 minutes, then 45 days of minute readings for Roof
+    let t0 = Utc.with_ymd_and_hms(2025, 4, 1, 0, 0, 0).unwrap();
+    let cycles = |meter: &str, from: i64, to: i64, step: i64| -> Vec<(String, Vec<Measurement>)> {
+        (from..to).step_by(step as usize)
+            .map(|minute| (meter.to_string(), vec![Measurement::new(Channel::TOTAL_POWER, t0 + Duration::minutes(minute), (minute % 60) as f32)]))
+            .collect()
+    };
+    db.insert_measurement_batch(&cycles("Shed", 0, 5 * 1440, 10)).unwrap();
+    let now = t0 + Duration::days(5);
+    RetentionService::process_retention(&db, &RetentionConfig { max_database_mb: 0, ..config.clone() }, now, false).unwrap();
+
+    // Shed keeps a day of raw readings and hourly maxima for three days
+    let readings = db.get_meter_readings("Shed", None, None).unwrap();
+    assert_eq!(readings.last().unwrap().timestamp, now - Duration::days(1));
+    let conn = db.get_connection().unwrap();
+    let (widths, oldest, mins, maxima): (String, i64, i64, f64) = conn.query_row(
+        "SELECT GROUP_CONCAT(DISTINCT width_secs), MIN(bucket_start), COUNT(min), MAX(max) FROM rollups",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+    ).unwrap();
+    drop(conn);
+    assert_eq!((widths.as_str(), oldest, mins, maxima), ("3600", (now - Duration::days(3)).timestamp(), 71, 50.0));
+
+    // Power keeps its minimum and peak even where the tier doesn't
+    let query = BucketQuery::new(
+        "Shed".to_string(), vec![], t0, now, BucketWidth::Seconds(3600), chrono_tz::UTC,
+    ).unwrap();
+    let buckets = &db.get_buckets(&query).unwrap().unwrap()[0].buckets;
+    assert_eq!(buckets.len(), 3 * 24);
+    assert_eq!((buckets[0].start, buckets[0].count), ((now - Duration::days(3)).timestamp(), 6));
+    assert_eq!((buckets[0].min, buckets[0].avg, buckets[0].max), (0.0, 25.0, 50.0));
+    assert_eq!((buckets[71].min, buckets[71].max), (0.0, 50.0));
+
+    // Over the size limit, whole days are deleted from the oldest on
+    db.insert_measurement_batch(&cycles("Roof", -45 * 1440, 0, 1)).unwrap();
+    assert!(db.used_bytes().unwrap() > 1024 * 1024);
+    RetentionService::process_retention(&db, &config, now, false).unwrap();
+    assert!(db.used_bytes().unwrap() - db.undeletable_bytes().unwrap() <= 1024 * 1024);
+    let oldest = db.oldest_data().unwrap().unwrap();
+    assert!(oldest > t0 - Duration::days(44) && oldest < t0, "{}", oldest);
+    assert_eq!(db.get_meter_readings("Shed", None, None).unwrap().len(), 144);
+}
+
+/// Capture samples neither count towards the size limit nor cost meter history.
+#[test]
+fn size_limit_spares_meter_history() {
+    let temp = TempDb::new("size-limit");
+    let db = DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap();
+    let config = RetentionConfig { max_database_mb: 1, ..RetentionConfig::default() };
+    let t0 = Utc.with_ymd_and_hms(2025, 4, 1, 0, 0, 0).unwrap();
+    let now = t0 + Duration::days(30);
+    let readings: Vec<_> = (0..30 * 144)
+        .map(|i| ("Roof".to_string(), vec![Measurement::new(Channel::TOTAL_POWER, t0 + Duration::minutes(10 * i), 500.0)]))
+        .collect();
+    db.insert_measurement_batch(&readings).unwrap();
+    let capture = |samples: i64| {
+        let session_id = db.create_capture_session(&CaptureParams {
+            meter: "Roof".to_string(),
+            channels: vec![Channel::TOTAL_POWER],
+            interval_ms: 10,
+            duration_secs: 3600,
+        }).unwrap();
+        let samples: Vec<_> = (0..samples)
+            .map(|i| Measurement::new(Channel::TOTAL_POWER, now + Duration::milliseconds(10 * i), i as f32))
+            .collect();
+        db.insert_capture_samples(session_id, &samples).unwrap();
+    };
+    let history = || db.get_meter_readings("Roof", None, None).unwrap().len();
+
+    // Together over the limit, the readings alone within it
+    capture(16_000);
+    assert!(db.used_bytes().unwrap() > 1024 * 1024);
+    assert!(db.undeletable_bytes().unwrap() < 1024 * 1024);
+    let report = RetentionService::process_retention(&db, &config, now, false).unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(history(), 30 * 144);
+
+    // Captures alone over the limit fail the size limit, meter history stays
+    capture(16_000);
+    assert!(db.undeletable_bytes().unwrap() > 1024 * 1024);
+    let report = RetentionService::process_retention(&db, &config, now, false).unwrap();
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("size limit: capture samples"), "{}", report.errors[0]);
+    assert_eq!(history(), 30 * 144);
+    assert_eq!(db.get_retention_reports(1).unwrap()[0].errors, report.errors);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writer_spills_to_journal() {
+    let temp = TempDb::new("spill");
+    let journal_path = temp.path().with_extension("spill");
+    let config = StorageConfig {
+        batch_size: 1,
+        batch_interval: 1,
+        queue_capacity: 2,
+        spill_journal: Some(journal_path.to_str().unwrap().to_string()),
+        ..StorageConfig::default()
+    };
+    let db = Arc::new(DatabaseSync::new(temp.url(), true, &config).unwrap());
+    let writer = BatchWriter::new(db.clone(), &config).unwrap();
+    tokio::spawn(writer.clone().run());
+
+    // Writes fail while the table is gone
+    let set_failing = |failing: bool| {
+        let (from, to) = if failing { ("meter_readings", "hidden") } else { ("hidden", "meter_readings") };
+        db.get_connection().unwrap().execute_batch(&format!("ALTER TABLE {} RENAME TO {}", from, to)).unwrap();
+    };
+    let t0 = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+    let cycle = |second: i64| vec![Measurement::new(Channel::TOTAL_POWER, t0 + Duration::seconds(second), second as f32)];
+    let replayed = || async {
+        for _ in 0..100 {
+            if writer.status().journal_cycles == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("spill journal was not replayed");
+    };
+
+    set_failing(true);
+    for second in 0..5 {
+        writer.submit("Roof", cycle(second)).await.unwrap();
+    }
+    let status = writer.status();
+    assert_eq!((status.queued, status.spilled, status.journal_cycles), (2, 3, 3));
+
+    set_failing(false);
+    writer.flush().await;
+    replayed().await;
+    let readings = db.get_meter_readings("Roof", None, None).unwrap();
+    let seconds: Vec<_> = readings.iter().rev().map(|r| (r.timestamp - t0).num_seconds()).collect();
+    assert_eq!(seconds, [0, 1, 2, 3, 4]);
+    assert_eq!(writer.status().replayed, 3);
+    assert_eq!(std::fs::metadata(&journal_path).unwrap().len(), 0);
+
+    // Cycles that can't be written at shutdown are kept in the journal
+    set_failing(true);
+    writer.submit("Roof", cycle(5)).await.unwrap();
+    writer.flush().await;
+    assert_eq!(writer.status().queued, 0);
+    assert_eq!(SpillJournal::open(&journal_path, u64::MAX).unwrap().cycles(), 1);
+    set_failing(false);
+    replayed().await;
+    assert_eq!(db.get_meter_readings("Roof", None, None).unwrap().len(), 6);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writer_spills_queued_cycles_in_order() {
+    let temp = TempDb::new("spill-order");
+    let journal_path = temp.path().with_extension("spill");
+    let config = StorageConfig {
+        batch_size: 100,
+        batch_interval: 3600,
+        queue_capacity: 10,
+        spill_journal: Some(journal_path.to_str().unwrap().to_string()),
+        ..StorageConfig::default()
+    };
+    let db = Arc::new(DatabaseSync::new(temp.url(), true, &config).unwrap());
+    db.get_connection().unwrap().execute_batch("ALTER TABLE meter_readings RENAME TO hidden").unwrap();
+    let writer = BatchWriter::new(db.clone(), &config).unwrap();
+    let t0 = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+    let cycle = |second: i64| vec![Measurement::new(Channel::TOTAL_POWER, t0 + Duration::seconds(second), second as f32)];
+
+    // Cycles 2 and 3 are still queued behind the flush whose write fails
+    writer.submit("Roof", cycle(0)).await.unwrap();
+    writer.submit("Roof", cycle(1)).await.unwrap();
+    let flushed = tokio::spawn({
+        let writer = writer.clone();
+        async move { writer.flush().await }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    writer.submit("Roof", cycle(2)).await.unwrap();
+    writer.submit("Roof", cycle(3)).await.unwrap();
+    tokio::spawn(writer.clone().run());
+    flushed.await.unwrap();
+    writer.submit("Roof", cycle(4)).await.unwrap();
+
+    let journal = SpillJournal::open(&journal_path, u64::MAX).unwrap();
+    let seconds: Vec<_> = journal.peek(10).unwrap().cycles.iter()
+        .map(|(_, measurements)| (measurements[0].timestamp - t0).num_seconds())
+        .collect();
+    assert_eq!(seconds, [0, 1, 2, 3, 4]);
+    let status = writer.status();
+    assert_eq!((status.spilled, status.journal_cycles), (5, 5));
+    assert_eq!(status.journal_bytes, std::fs::metadata(&journal_path).unwrap().len());
+}
+
+/// Runs `scenario` on a new SQLite database.
+fn on_sqlite(name: &str, scenario: fn(&dyn Storage)) {
+    let temp = TempDb::new(name);
+    let storage = DatabaseSync::new(temp.url(), true, &StorageConfig::default()).unwrap();
+    scenario(&storage);
+}
+
+/// Runs `scenario` on recreated tables in the PostgreSQL test database, one
+/// scenario at a time since they share it.
+fn on_postgres(scenario: fn(&dyn Storage)) {
+    static DATABASE: Mutex<()> = Mutex::new(());
+    let url = std::env::var("SOLARMETER_TEST_POSTGRES_URL").expect("SOLARMETER_TEST_POSTGRES_URL not set");
+    // A failed scenario doesn't leave the tables in use
+    let _database = DATABASE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    client.batch_execute(
+        "DROP TABLE IF EXISTS capture_samples, capture_sessions, maintenance_results, readings, retention_run_steps, retention_runs, rollup_energy, rollup_watermarks, rollups, meter_channels, meter_names, schema_version",
+    ).unwrap();
+
+    let storage = PostgresStorage::new(&PostgresConfig { url, timescaledb: false }, true).unwrap();
+    scenario(&storage);
+}
+
+/// A `#[test]` per scenario and backend, e.g. `sqlite::reads_readings` and `pg::reads_readings`.
+macro_rules! scenarios {
+    ($($scenario:ident),* $(,)?) => {
+        mod sqlite {
+            $(#[test]
+            fn $scenario() {
+                super::on_sqlite(stringify!($scenario), super::$scenario);
+            })*
+        }
+
+        mod pg {
+            $(#[test]
+            #[ignore = "needs SOLARMETER_TEST_POSTGRES_URL"]
+            fn $scenario() {
+                super::on_postgres(super::$scenario);
+            })*
+        }
+    };
+}
+
+scenarios!(
+    reads_readings,
+    exports_readings,
+    buckets_readings,
+    captures_samples,
+    energy_per_period,
+    manages_meters,
+    imports_csv,
+    reads_rollups,
+    energy_from_rollups,
+    reports_retention_runs,
+    runs_maintenance,
+    merges_overlapping_buckets,
+    rolls_up_history,
+);
+
+#[test]
+#[ignore = "needs SOLARMETER_TEST_POSTGRES_URL"]
+fn postgres_has_no_file_backups() {
+    on_postgres(|storage| assert!(storage.backup(TempDb::new("pg-backup").path()).is_err()));
+}
+
+#[test]
+fn daily_buckets_follow_dst() {
+    let berlin: chrono_tz::Tz = "Europe/Berlin".parse().unwrap();
+    // 2025-03-30 has 23 hours in Berlin, the bucket still starts at local midnight (UTC+1)
+    let noon = Utc.with_ymd_and_hms(2025, 3, 30, 12, 0, 0).unwrap().timestamp();
+    let midnight = Utc.with_ymd_and_hms(2025, 3, 29, 23, 0, 0).unwrap().timestamp();
+    assert_eq!(query::local_bucket_start(noon, 86400, berlin), midnight);
+    // The next day starts at midnight UTC+2
+    let next = Utc.with_ymd_and_hms(2025, 3, 30, 22, 30, 0).unwrap().timestamp();
+    assert_eq!(query::local_bucket_start(next, 86400, berlin), next - 30 * 60);
+}
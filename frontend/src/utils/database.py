@@ -9,9 +9,31 @@ from datetime import timezone
 
 def float16_to_float32(int16_val: int) -> float:
     """Convert a 16-bit integer representing an f16 to a Python float."""
-    uint16_val = int16_val & 0xFFFF
+    if pd.isna(int16_val):
+        # Channel was not polled in this cycle
+        return float('nan')
+    uint16_val = int(int16_val) & 0xFFFF
     return float(np.frombuffer(np.array([uint16_val], dtype='uint16').tobytes(), dtype=np.float16)[0])
 
+def get_power_encoding(conn) -> str:
+    """Return the power column encoding recorded by the backend (f16, f32 or scaled:<n>)."""
+    try:
+        row = conn.execute("SELECT value FROM db_settings WHERE key = 'power_encoding'").fetchone()
+    except sqlite3.OperationalError:
+        row = None
+    # Databases written before the setting existed use f16
+    return row[0] if row else 'f16'
+
+def decode_power(value, encoding: str) -> float:
+    """Decode a stored power value to watts according to the database's encoding."""
+    if pd.isna(value):
+        return float('nan')
+    if encoding == 'f16':
+        return float16_to_float32(value)
+    if encoding.startswith('scaled:'):
+        return float(value) / int(encoding.split(':', 1)[1])
+    return float(value)
+
 def get_backend_status():
     try:
         response = requests.get(f"{BACKEND_URL}/status")
@@ -94,9 +116,10 @@ def load_data(start_time=None, end_time=None, meter_name=None):
             configured_tz = get_timezone()
             df['timestamp'] = df['timestamp'].dt.tz_convert(configured_tz)
             
-            # Convert f16 stored power values to f32
+            # Decode stored power values to watts
+            encoding = get_power_encoding(conn)
             for col in ['total_power', 'import_power', 'export_power']:
-                df[col] = df[col].apply(float16_to_float32)
+                df[col] = df[col].apply(lambda v: decode_power(v, encoding))
         
         return df
     except Exception as e: